@@ -0,0 +1,356 @@
+use crate::export::{collect_columns, row_cell};
+use crate::transforms::value_key;
+use serde_json::Value;
+
+// Arrow IPC stream output: a Schema message, one RecordBatch message holding
+// every row, and the end-of-stream marker. Message metadata is flatbuffer
+// encoded by the small builder at the bottom of this file, which only covers
+// the tables this writer needs.
+
+const METADATA_VERSION_V5: i16 = 4;
+const HEADER_SCHEMA: u8 = 1;
+const HEADER_RECORD_BATCH: u8 = 3;
+const CONTINUATION: [u8; 4] = [0xFF; 4];
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum ColumnType {
+    Null,
+    Bool,
+    Int64,
+    Float64,
+    Utf8,
+}
+
+impl ColumnType {
+    fn of(value: &Value) -> Option<ColumnType> {
+        match value {
+            Value::Null => None,
+            Value::Bool(_) => Some(ColumnType::Bool),
+            Value::Number(n) if n.as_i64().is_some() => Some(ColumnType::Int64),
+            Value::Number(_) => Some(ColumnType::Float64),
+            _ => Some(ColumnType::Utf8),
+        }
+    }
+
+    // Integers widen to float; any other mix is promoted to string.
+    fn merge(self, other: ColumnType) -> ColumnType {
+        use ColumnType::*;
+        match (self, other) {
+            (Null, t) | (t, Null) => t,
+            (a, b) if a == b => a,
+            (Int64, Float64) | (Float64, Int64) => Float64,
+            _ => Utf8,
+        }
+    }
+
+    fn type_table(self) -> (u8, Fb) {
+        match self {
+            ColumnType::Null => (1, Fb::Table(vec![])),
+            ColumnType::Int64 => (
+                2,
+                Fb::Table(vec![(0, Slot::I32(64)), (1, Slot::Bool(true))]),
+            ),
+            ColumnType::Float64 => (3, Fb::Table(vec![(0, Slot::I16(2))])),
+            ColumnType::Utf8 => (5, Fb::Table(vec![])),
+            ColumnType::Bool => (6, Fb::Table(vec![])),
+        }
+    }
+}
+
+pub(crate) fn infer_column_type(rows: &[Value], column: &str) -> ColumnType {
+    rows.iter()
+        .filter_map(|row| row_cell(row, column).and_then(ColumnType::of))
+        .fold(ColumnType::Null, ColumnType::merge)
+}
+
+/// Serialize rows as an Arrow IPC stream with one record batch. Column order
+/// follows `collect_columns`; every column is nullable.
+pub(crate) fn rows_to_arrow_ipc(rows: &[Value]) -> Result<Vec<u8>, String> {
+    let columns = collect_columns(rows);
+    let types: Vec<ColumnType> = columns.iter().map(|c| infer_column_type(rows, c)).collect();
+
+    let fields = columns
+        .iter()
+        .zip(&types)
+        .map(|(name, column_type)| {
+            let (type_id, type_table) = column_type.type_table();
+            Fb::Table(vec![
+                (0, Slot::Ref(Fb::Str(name.clone()))),
+                (1, Slot::Bool(true)),
+                (2, Slot::U8(type_id)),
+                (3, Slot::Ref(type_table)),
+                (5, Slot::Ref(Fb::Tables(vec![]))),
+            ])
+        })
+        .collect();
+    let schema = Fb::Table(vec![(0, Slot::I16(0)), (1, Slot::Ref(Fb::Tables(fields)))]);
+
+    let mut body = Body::default();
+    for (column, column_type) in columns.iter().zip(&types) {
+        let cells: Vec<Option<&Value>> = rows
+            .iter()
+            .map(|row| row_cell(row, column).filter(|v| !v.is_null()))
+            .collect();
+        body.push_column(&cells, *column_type)?;
+    }
+    let batch = Fb::Table(vec![
+        (0, Slot::I64(rows.len() as i64)),
+        (1, Slot::Ref(Fb::Structs(body.nodes))),
+        (2, Slot::Ref(Fb::Structs(body.buffers))),
+    ]);
+
+    let mut out = Vec::new();
+    write_message(&mut out, HEADER_SCHEMA, schema, &[]);
+    write_message(&mut out, HEADER_RECORD_BATCH, batch, &body.bytes);
+    out.extend_from_slice(&CONTINUATION);
+    out.extend_from_slice(&0i32.to_le_bytes());
+    Ok(out)
+}
+
+fn write_message(out: &mut Vec<u8>, header_type: u8, header: Fb, body: &[u8]) {
+    let message = Fb::Table(vec![
+        (0, Slot::I16(METADATA_VERSION_V5)),
+        (1, Slot::U8(header_type)),
+        (2, Slot::Ref(header)),
+        (3, Slot::I64(body.len() as i64)),
+    ]);
+    let mut metadata = finish(&message);
+    pad_to_8(&mut metadata);
+
+    out.extend_from_slice(&CONTINUATION);
+    out.extend_from_slice(&(metadata.len() as i32).to_le_bytes());
+    out.extend_from_slice(&metadata);
+    out.extend_from_slice(body);
+}
+
+fn pad_to_8(bytes: &mut Vec<u8>) {
+    bytes.resize(bytes.len().next_multiple_of(8), 0);
+}
+
+// Record batch body: buffers are 8-byte aligned, described by (offset, length)
+// pairs, with one (length, null_count) node per column.
+#[derive(Default)]
+struct Body {
+    bytes: Vec<u8>,
+    nodes: Vec<[i64; 2]>,
+    buffers: Vec<[i64; 2]>,
+}
+
+impl Body {
+    fn push_buffer(&mut self, data: &[u8]) {
+        self.buffers
+            .push([self.bytes.len() as i64, data.len() as i64]);
+        self.bytes.extend_from_slice(data);
+        pad_to_8(&mut self.bytes);
+    }
+
+    fn push_column(
+        &mut self,
+        cells: &[Option<&Value>],
+        column_type: ColumnType,
+    ) -> Result<(), String> {
+        let null_count = cells.iter().filter(|c| c.is_none()).count();
+        self.nodes.push([cells.len() as i64, null_count as i64]);
+
+        if column_type == ColumnType::Null {
+            return Ok(());
+        }
+
+        // The validity bitmap may be omitted when nothing is null
+        if null_count == 0 {
+            self.push_buffer(&[]);
+        } else {
+            self.push_buffer(&bitmap(cells.iter().map(Option::is_some)));
+        }
+
+        match column_type {
+            ColumnType::Bool => {
+                let bits = cells
+                    .iter()
+                    .map(|c| c.and_then(Value::as_bool).unwrap_or(false));
+                self.push_buffer(&bitmap(bits));
+            }
+            ColumnType::Int64 => {
+                let values: Vec<u8> = cells
+                    .iter()
+                    .flat_map(|c| c.and_then(Value::as_i64).unwrap_or(0).to_le_bytes())
+                    .collect();
+                self.push_buffer(&values);
+            }
+            ColumnType::Float64 => {
+                let values: Vec<u8> = cells
+                    .iter()
+                    .flat_map(|c| c.and_then(Value::as_f64).unwrap_or(0.0).to_le_bytes())
+                    .collect();
+                self.push_buffer(&values);
+            }
+            ColumnType::Utf8 => {
+                let mut offsets = Vec::with_capacity((cells.len() + 1) * 4);
+                let mut data = Vec::new();
+                offsets.extend_from_slice(&0i32.to_le_bytes());
+                for cell in cells {
+                    if let Some(value) = cell {
+                        data.extend_from_slice(value_key(value).as_bytes());
+                    }
+                    let end = i32::try_from(data.len())
+                        .map_err(|_| "String column exceeds 2 GiB".to_string())?;
+                    offsets.extend_from_slice(&end.to_le_bytes());
+                }
+                self.push_buffer(&offsets);
+                self.push_buffer(&data);
+            }
+            ColumnType::Null => {}
+        }
+        Ok(())
+    }
+}
+
+// LSB-first bit packing, as used for validity and boolean buffers
+fn bitmap(bits: impl ExactSizeIterator<Item = bool>) -> Vec<u8> {
+    let mut bytes = vec![0u8; bits.len().div_ceil(8)];
+    for (i, bit) in bits.enumerate() {
+        if bit {
+            bytes[i / 8] |= 1 << (i % 8);
+        }
+    }
+    bytes
+}
+
+// Minimal flatbuffer builder. Objects are written parent-first so every
+// offset points forward, and each table's vtable sits just before it.
+enum Fb {
+    /// (field id, value) pairs; unlisted ids are absent.
+    Table(Vec<(u16, Slot)>),
+    Str(String),
+    Tables(Vec<Fb>),
+    /// Vector of 16-byte structs (FieldNode / Buffer).
+    Structs(Vec<[i64; 2]>),
+}
+
+enum Slot {
+    U8(u8),
+    Bool(bool),
+    I16(i16),
+    I32(i32),
+    I64(i64),
+    Ref(Fb),
+}
+
+impl Slot {
+    fn size(&self) -> usize {
+        match self {
+            Slot::U8(_) | Slot::Bool(_) => 1,
+            Slot::I16(_) => 2,
+            Slot::I32(_) | Slot::Ref(_) => 4,
+            Slot::I64(_) => 8,
+        }
+    }
+}
+
+fn finish(root: &Fb) -> Vec<u8> {
+    let mut buf = vec![0u8; 4];
+    let root_pos = write_object(&mut buf, root);
+    patch_offset(&mut buf, 0, root_pos);
+    buf
+}
+
+fn align(buf: &mut Vec<u8>, alignment: usize) {
+    buf.resize(buf.len().next_multiple_of(alignment), 0);
+}
+
+fn patch_offset(buf: &mut [u8], at: usize, target: usize) {
+    buf[at..at + 4].copy_from_slice(&((target - at) as u32).to_le_bytes());
+}
+
+fn write_object(buf: &mut Vec<u8>, object: &Fb) -> usize {
+    match object {
+        Fb::Str(s) => {
+            align(buf, 4);
+            let pos = buf.len();
+            buf.extend_from_slice(&(s.len() as u32).to_le_bytes());
+            buf.extend_from_slice(s.as_bytes());
+            buf.push(0);
+            pos
+        }
+        Fb::Structs(items) => {
+            // Elements follow the length prefix and need 8-byte alignment
+            buf.resize((buf.len() + 4).next_multiple_of(8) - 4, 0);
+            let pos = buf.len();
+            buf.extend_from_slice(&(items.len() as u32).to_le_bytes());
+            for [a, b] in items {
+                buf.extend_from_slice(&a.to_le_bytes());
+                buf.extend_from_slice(&b.to_le_bytes());
+            }
+            pos
+        }
+        Fb::Tables(items) => {
+            align(buf, 4);
+            let pos = buf.len();
+            buf.extend_from_slice(&(items.len() as u32).to_le_bytes());
+            buf.resize(pos + 4 + 4 * items.len(), 0);
+            for (i, item) in items.iter().enumerate() {
+                let child = write_object(buf, item);
+                patch_offset(buf, pos + 4 + 4 * i, child);
+            }
+            pos
+        }
+        Fb::Table(fields) => write_table(buf, fields),
+    }
+}
+
+fn write_table(buf: &mut Vec<u8>, fields: &[(u16, Slot)]) -> usize {
+    // Inline layout after the 4-byte vtable offset, each field aligned to
+    // its own size (the table itself starts 8-byte aligned).
+    let mut layout = Vec::with_capacity(fields.len());
+    let mut table_size = 4usize;
+    for (_, slot) in fields {
+        table_size = table_size.next_multiple_of(slot.size());
+        layout.push(table_size);
+        table_size += slot.size();
+    }
+    let table_size = table_size.next_multiple_of(4);
+
+    let num_ids = fields
+        .iter()
+        .map(|(id, _)| *id as usize + 1)
+        .max()
+        .unwrap_or(0);
+    let mut vtable = vec![0u16; num_ids];
+    for ((id, _), offset) in fields.iter().zip(&layout) {
+        vtable[*id as usize] = *offset as u16;
+    }
+
+    align(buf, 2);
+    let vtable_pos = buf.len();
+    buf.extend_from_slice(&((4 + 2 * num_ids) as u16).to_le_bytes());
+    buf.extend_from_slice(&(table_size as u16).to_le_bytes());
+    for offset in vtable {
+        buf.extend_from_slice(&offset.to_le_bytes());
+    }
+
+    align(buf, 8);
+    let table_pos = buf.len();
+    buf.resize(table_pos + table_size, 0);
+    buf[table_pos..table_pos + 4].copy_from_slice(&((table_pos - vtable_pos) as i32).to_le_bytes());
+
+    for ((_, slot), offset) in fields.iter().zip(&layout) {
+        let at = table_pos + offset;
+        match slot {
+            Slot::U8(v) => buf[at] = *v,
+            Slot::Bool(v) => buf[at] = *v as u8,
+            Slot::I16(v) => buf[at..at + 2].copy_from_slice(&v.to_le_bytes()),
+            Slot::I32(v) => buf[at..at + 4].copy_from_slice(&v.to_le_bytes()),
+            Slot::I64(v) => buf[at..at + 8].copy_from_slice(&v.to_le_bytes()),
+            Slot::Ref(_) => {}
+        }
+    }
+
+    for ((_, slot), offset) in fields.iter().zip(&layout) {
+        if let Slot::Ref(child) = slot {
+            let child_pos = write_object(buf, child);
+            patch_offset(buf, table_pos + offset, child_pos);
+        }
+    }
+
+    table_pos
+}
@@ -1,4 +1,6 @@
+use std::cell::{Cell, RefCell};
 use std::collections::HashMap;
+use std::rc::Rc;
 use wasm_bindgen::prelude::*;
 
 #[wasm_bindgen]
@@ -69,10 +71,197 @@ impl CloudDataResponse {
     }
 }
 
+// A cached response body, gzipped when cache compression is on and it helps
+struct CacheEntry {
+    data: Vec<u8>,
+    status: u16,
+    compressed: bool,
+    logical_len: usize,
+    validators: Validators,
+}
+
+// Headers identifying a version of a response body, for revalidation
+#[derive(Debug, Default, PartialEq)]
+pub(crate) struct Validators {
+    pub etag: Option<String>,
+    pub last_modified: Option<String>,
+}
+
+impl Validators {
+    fn from_headers(headers: &JsValue) -> Validators {
+        Validators {
+            etag: read_header(headers, "etag"),
+            last_modified: read_header(headers, "last-modified"),
+        }
+    }
+
+    /// Whether `current` identifies the same body. ETags are compared when
+    /// both sides have one (ignoring a weak `W/` prefix), otherwise
+    /// Last-Modified dates; with nothing to compare the answer is no.
+    pub(crate) fn matches(&self, current: &Validators) -> bool {
+        let strip = |etag: &str| etag.trim().trim_start_matches("W/").to_string();
+        if let (Some(cached), Some(current)) = (&self.etag, &current.etag) {
+            return strip(cached) == strip(current);
+        }
+        match (&self.last_modified, &current.last_modified) {
+            (Some(cached), Some(current)) => cached.trim() == current.trim(),
+            _ => false,
+        }
+    }
+}
+
+// Bodies smaller than this aren't worth compressing
+const MIN_COMPRESS_BYTES: usize = 1024;
+
 #[wasm_bindgen]
 pub struct CloudStorageBridge {
     js_http_client: js_sys::Function,
-    request_cache: HashMap<String, CloudDataResponse>,
+    request_cache: HashMap<String, CacheEntry>,
+    cache_order: Vec<String>,
+    max_retries: u32,
+    retry_base_delay_ms: f64,
+    retry_budget: Option<u32>,
+    max_retry_after_ms: f64,
+    trace_id: Option<String>,
+    default_headers: Vec<(String, String)>,
+    // Options object holding the headers sent on every request, built on
+    // first use and dropped whenever those change; see `request_options`
+    options_template: RefCell<Option<js_sys::Object>>,
+    min_chunk_size: usize,
+    persistent_store: Option<PersistentStore>,
+    metadata_cache: HashMap<String, JsValue>,
+    allowed_providers: Vec<String>,
+    cache_compression: bool,
+    cache_max_bytes: Option<usize>,
+    url_allow: Vec<String>,
+    url_deny: Vec<String>,
+    rate_limiter: Option<RateLimiter>,
+    normalize_urls: bool,
+    allow_insecure: bool,
+    batch_dedupe: bool,
+    decompress_gzip: bool,
+    inflate_limits: crate::compression::InflateLimits,
+    max_upload_bytes: Option<usize>,
+    in_flight: Rc<RefCell<InFlight>>,
+}
+
+// JS callbacks for a durable cache tier behind the in-memory cache
+struct PersistentStore {
+    get_fn: js_sys::Function,
+    put_fn: js_sys::Function,
+}
+
+const DEFAULT_MIN_CHUNK_SIZE: usize = 1024;
+const DEFAULT_MAX_RETRY_AFTER_MS: f64 = 30_000.0;
+// Close to deflate's ~1032:1 ceiling, so only near-pure padding trips it
+const DEFAULT_MAX_COMPRESSION_RATIO: f64 = 1000.0;
+
+// Retries remaining/consumed within one logical operation (e.g. a batch).
+struct RetryBudget {
+    remaining: Option<u32>,
+    used: u32,
+}
+
+impl RetryBudget {
+    fn new(limit: Option<u32>) -> RetryBudget {
+        RetryBudget {
+            remaining: limit,
+            used: 0,
+        }
+    }
+
+    fn try_consume(&mut self) -> bool {
+        match self.remaining.as_mut() {
+            Some(0) => false,
+            Some(remaining) => {
+                *remaining -= 1;
+                self.used += 1;
+                true
+            }
+            None => {
+                self.used += 1;
+                true
+            }
+        }
+    }
+
+    fn is_exhausted(&self) -> bool {
+        self.remaining == Some(0)
+    }
+}
+
+// Requests currently awaiting the HTTP client. `abort_all` bumps the
+// generation, so a request (or retry loop) that started under an older one
+// knows it was aborted even if the client ignored the abort signal.
+#[derive(Default)]
+struct InFlight {
+    generation: u64,
+    next_id: u64,
+    controllers: HashMap<u64, web_sys::AbortController>,
+}
+
+impl InFlight {
+    fn abort_all(&mut self) -> u32 {
+        self.generation += 1;
+        let aborted = self.controllers.len() as u32;
+        for (_, controller) in self.controllers.drain() {
+            controller.abort();
+        }
+        aborted
+    }
+}
+
+/// Aborts a `CloudStorageBridge`'s requests from outside the bridge, which
+/// wasm-bindgen keeps borrowed while one of its async methods is running.
+#[wasm_bindgen]
+pub struct CloudAbortHandle {
+    in_flight: Rc<RefCell<InFlight>>,
+}
+
+#[wasm_bindgen]
+impl CloudAbortHandle {
+    /// See `CloudStorageBridge.abort_all`.
+    #[wasm_bindgen]
+    pub fn abort_all(&self) -> u32 {
+        self.in_flight.borrow_mut().abort_all()
+    }
+}
+
+fn aborted_error(url: &str) -> JsValue {
+    crate::utils::js_error("aborted", 499, &format!("Request to {url} was aborted"))
+}
+
+// Token bucket shared by every request. Tokens are reserved up front (the
+// balance may go negative), so concurrent callers queue behind each other
+// instead of all waking at once.
+pub(crate) struct RateLimiter {
+    per_sec: f64,
+    tokens: Cell<f64>,
+    last_ms: Cell<f64>,
+}
+
+impl RateLimiter {
+    pub(crate) fn new(per_sec: f64, now_ms: f64) -> RateLimiter {
+        RateLimiter {
+            per_sec,
+            tokens: Cell::new(per_sec.max(1.0)),
+            last_ms: Cell::new(now_ms),
+        }
+    }
+
+    /// Take a token, returning how long to wait (ms) before using it.
+    pub(crate) fn reserve(&self, now_ms: f64) -> f64 {
+        let elapsed_secs = (now_ms - self.last_ms.get()).max(0.0) / 1000.0;
+        let capacity = self.per_sec.max(1.0);
+        let tokens = (self.tokens.get() + elapsed_secs * self.per_sec).min(capacity) - 1.0;
+        self.tokens.set(tokens);
+        self.last_ms.set(now_ms);
+        if tokens >= 0.0 {
+            0.0
+        } else {
+            -tokens / self.per_sec * 1000.0
+        }
+    }
 }
 
 #[wasm_bindgen]
@@ -83,93 +272,585 @@ impl CloudStorageBridge {
         CloudStorageBridge {
             js_http_client: http_client_fn,
             request_cache: HashMap::new(),
+            cache_order: Vec::new(),
+            max_retries: 0,
+            retry_base_delay_ms: 100.0,
+            retry_budget: None,
+            max_retry_after_ms: DEFAULT_MAX_RETRY_AFTER_MS,
+            trace_id: None,
+            default_headers: Vec::new(),
+            options_template: RefCell::new(None),
+            min_chunk_size: DEFAULT_MIN_CHUNK_SIZE,
+            persistent_store: None,
+            metadata_cache: HashMap::new(),
+            allowed_providers: Vec::new(),
+            cache_compression: false,
+            cache_max_bytes: None,
+            url_allow: Vec::new(),
+            url_deny: Vec::new(),
+            rate_limiter: None,
+            normalize_urls: false,
+            allow_insecure: false,
+            batch_dedupe: true,
+            decompress_gzip: false,
+            inflate_limits: crate::compression::InflateLimits {
+                max_output: crate::utils::MAX_INPUT_BYTES,
+                max_ratio: Some(DEFAULT_MAX_COMPRESSION_RATIO),
+            },
+            max_upload_bytes: None,
+            in_flight: Rc::default(),
         }
     }
 
+    /// Abort every request in progress; their callers fail with an `aborted`
+    /// `DataPrismError` and pending retries are dropped. Returns how many
+    /// requests were aborted. The bridge stays usable for new requests.
+    /// While an async bridge method is running the bridge itself is borrowed,
+    /// so call this through `abort_handle()` from that point on.
     #[wasm_bindgen]
-    pub async fn fetch_cloud_data(&mut self, url: &str) -> Result<js_sys::Uint8Array, JsValue> {
-        console_log!("Fetching cloud data from: {}", url);
+    pub fn abort_all(&self) -> u32 {
+        self.in_flight.borrow_mut().abort_all()
+    }
 
-        // Check cache first
-        if let Some(cached) = self.request_cache.get(url) {
-            console_log!("Using cached data for: {}", url);
-            return Ok(cached.data());
+    /// A handle whose `abort_all()` can be called while bridge methods are
+    /// still awaiting, e.g. on an SPA route change.
+    #[wasm_bindgen]
+    pub fn abort_handle(&self) -> CloudAbortHandle {
+        CloudAbortHandle {
+            in_flight: self.in_flight.clone(),
         }
+    }
 
-        // Call JavaScript HTTP client from WASM
-        let options = js_sys::Object::new();
-        js_sys::Reflect::set(
-            &options,
-            &JsValue::from_str("method"),
-            &JsValue::from_str("GET"),
-        )?;
+    /// Clean up a user-supplied URL: trims whitespace, percent-encodes
+    /// spaces, adds `https://` when no scheme is given and collapses repeated
+    /// slashes in the path. Fails with an `invalid_url` `DataPrismError` for
+    /// schemes other than https (http only after `set_allow_insecure(true)`)
+    /// or a missing host.
+    #[wasm_bindgen]
+    pub fn normalize_url(&self, url: &str) -> Result<String, JsValue> {
+        normalize_url(url, self.allow_insecure)
+            .map_err(|e| crate::utils::js_error("invalid_url", 400, &e))
+    }
 
-        let promise =
-            self.js_http_client
-                .call2(&JsValue::NULL, &JsValue::from_str(url), &options)?;
+    /// Run `normalize_url` on URLs passed to `fetch_cloud_data` first.
+    #[wasm_bindgen]
+    pub fn set_normalize_urls(&mut self, enabled: bool) {
+        self.normalize_urls = enabled;
+    }
 
-        let response = wasm_bindgen_futures::JsFuture::from(js_sys::Promise::from(promise)).await?;
+    /// Accept plain `http://` URLs in `normalize_url`.
+    #[wasm_bindgen]
+    pub fn set_allow_insecure(&mut self, enabled: bool) {
+        self.allow_insecure = enabled;
+    }
 
-        // Extract response data
-        let array_buffer = js_sys::Reflect::get(&response, &JsValue::from_str("arrayBuffer"))?;
-        let array_buffer_fn = js_sys::Function::from(array_buffer);
-        let buffer_promise = array_buffer_fn.call0(&response)?;
-        let buffer =
-            wasm_bindgen_futures::JsFuture::from(js_sys::Promise::from(buffer_promise)).await?;
+    /// Restrict which URLs may be requested. Patterns use `*` as a wildcard
+    /// for any run of characters. A pattern with a scheme
+    /// (`https://*.example.com/data/*`) is compared part by part: scheme and
+    /// host case-insensitively, the host in full, the port (the scheme's
+    /// default unless the pattern gives one, which may be `*`), then the
+    /// path, as a prefix unless it contains `*`. User info and a trailing dot
+    /// on the host are ignored. Other patterns match the whole URL with the
+    /// scheme and host lowercased and a default port left out, as a prefix
+    /// when they have no `*`.
+    /// A URL matching any `deny` pattern is blocked; if `allow` is non-empty a
+    /// URL must also match one of its patterns. Blocked URLs fail with a
+    /// `url_blocked` `DataPrismError` before any request is made.
+    #[wasm_bindgen]
+    pub fn set_url_policy(&mut self, allow: Vec<String>, deny: Vec<String>) {
+        self.url_allow = allow;
+        self.url_deny = deny;
+    }
 
-        let uint8_array = js_sys::Uint8Array::new(&buffer);
-        let data: Vec<u8> = uint8_array.to_vec();
+    /// Only send requests to URLs whose detected provider (e.g. `"aws-s3"`,
+    /// `"unknown"`) is in `list`; others fail with a `provider_not_allowed`
+    /// `DataPrismError`. An empty list allows every provider.
+    #[wasm_bindgen]
+    pub fn set_allowed_providers(&mut self, list: Vec<String>) {
+        self.allowed_providers = list;
+    }
 
-        // Cache the response
-        let status = js_sys::Reflect::get(&response, &JsValue::from_str("status"))?
-            .as_f64()
-            .unwrap_or(200.0) as u16;
-        let provider = self.detect_provider(url);
+    /// Back the in-memory cache with a durable store (e.g. IndexedDB) in JS.
+    /// On a cache miss `get_fn(url)` is consulted before the network; it may
+    /// return bytes (or a Promise of bytes) or null. Successful network
+    /// responses are written back with `put_fn(url, bytes)`. Store errors are
+    /// logged and otherwise ignored.
+    #[wasm_bindgen]
+    pub fn set_persistent_store(&mut self, get_fn: js_sys::Function, put_fn: js_sys::Function) {
+        self.persistent_store = Some(PersistentStore { get_fn, put_fn });
+    }
+
+    #[wasm_bindgen]
+    pub fn clear_persistent_store(&mut self) {
+        self.persistent_store = None;
+    }
+
+    /// Send `id` as an `x-trace-id` header on every request and include it in
+    /// fetch log output.
+    #[wasm_bindgen]
+    pub fn set_trace_id(&mut self, id: String) {
+        self.trace_id = Some(id);
+        self.options_template.replace(None);
+    }
+
+    #[wasm_bindgen]
+    pub fn clear_trace_id(&mut self) {
+        self.trace_id = None;
+        self.options_template.replace(None);
+    }
 
-        let cached_response = CloudDataResponse::new(data.clone(), status, provider);
+    /// Send header `name: value` on every request, replacing any earlier
+    /// value for `name` (compared case-insensitively). Headers a request sets
+    /// itself, such as `Range`, take precedence.
+    #[wasm_bindgen]
+    pub fn set_default_header(&mut self, name: String, value: String) {
+        self.default_headers
+            .retain(|(existing, _)| !existing.eq_ignore_ascii_case(&name));
+        self.default_headers.push((name, value));
+        self.options_template.replace(None);
+    }
 
-        self.request_cache.insert(url.to_string(), cached_response);
+    #[wasm_bindgen]
+    pub fn clear_default_headers(&mut self) {
+        self.default_headers.clear();
+        self.options_template.replace(None);
+    }
+
+    /// Retry each request up to `max_retries` times on network errors and
+    /// 429/5xx responses, backing off exponentially from `base_delay_ms`.
+    /// A `Retry-After` header on a 429 or 503 is waited out instead when it
+    /// asks for longer (see `set_max_retry_after_ms`).
+    #[wasm_bindgen]
+    pub fn set_retry_policy(&mut self, max_retries: u32, base_delay_ms: f64) {
+        self.max_retries = max_retries;
+        self.retry_base_delay_ms = base_delay_ms.max(0.0);
+    }
+
+    /// Longest `Retry-After` wait honored before a retry; longer values are
+    /// capped to this. Defaults to 30 seconds.
+    #[wasm_bindgen]
+    pub fn set_max_retry_after_ms(&mut self, ms: f64) {
+        self.max_retry_after_ms = ms.max(0.0);
+    }
+
+    /// Space out HTTP requests (including retries) to at most
+    /// `requests_per_sec`, allowing bursts of up to one second's worth.
+    /// Requests wait for a token before calling the HTTP client. Cache hits
+    /// don't count.
+    #[wasm_bindgen]
+    pub fn set_rate_limit(&mut self, requests_per_sec: f64) -> Result<(), JsValue> {
+        if !(requests_per_sec.is_finite() && requests_per_sec > 0.0) {
+            return Err(crate::utils::js_error(
+                "invalid_rate_limit",
+                400,
+                "requests_per_sec must be a positive number",
+            ));
+        }
+        self.rate_limiter = Some(RateLimiter::new(requests_per_sec, js_sys::Date::now()));
+        Ok(())
+    }
+
+    #[wasm_bindgen]
+    pub fn clear_rate_limit(&mut self) {
+        self.rate_limiter = None;
+    }
+
+    /// Cap the total number of retries a single `fetch_cloud_data_batch` call
+    /// may spend across all of its requests. Once exhausted, remaining
+    /// failures fail fast.
+    #[wasm_bindgen]
+    pub fn set_retry_budget(&mut self, max_total_retries: u32) {
+        self.retry_budget = Some(max_total_retries);
+    }
 
+    #[wasm_bindgen]
+    pub fn clear_retry_budget(&mut self) {
+        self.retry_budget = None;
+    }
+
+    #[wasm_bindgen]
+    pub async fn fetch_cloud_data(&mut self, url: &str) -> Result<js_sys::Uint8Array, JsValue> {
+        let url = if self.normalize_urls {
+            self.normalize_url(url)?
+        } else {
+            url.to_string()
+        };
+        let mut budget = RetryBudget::new(None);
+        let (data, _) = self.fetch_bytes(&url, &mut budget).await?;
         Ok(js_sys::Uint8Array::from(&data[..]))
     }
 
+    /// Fetch `url` and decode the body from `encoding` (a WHATWG label such
+    /// as `utf-8`, `latin1` or `windows-1252`) into a string. Unknown labels
+    /// fail with an `unsupported_encoding` `DataPrismError`.
     #[wasm_bindgen]
-    pub async fn fetch_cloud_metadata(&self, url: &str) -> Result<JsValue, JsValue> {
-        console_log!("Fetching cloud metadata from: {}", url);
+    pub async fn fetch_cloud_text(&mut self, url: &str, encoding: &str) -> Result<String, JsValue> {
+        // Check the label before spending a request on it
+        decode_text(&[], encoding)?;
+        let mut budget = RetryBudget::new(None);
+        let (data, _) = self.fetch_bytes(url, &mut budget).await?;
+        decode_text(&data, encoding)
+    }
+
+    /// Whether `fetch_cloud_data_batch` fetches a URL listed more than once
+    /// only once (the default), sharing its result between those positions.
+    #[wasm_bindgen]
+    pub fn set_batch_dedupe(&mut self, enabled: bool) {
+        self.batch_dedupe = enabled;
+    }
+
+    /// Inflate gzipped response bodies (detected by their magic bytes) as
+    /// they are read, for files stored as `.gz` rather than sent with
+    /// `Content-Encoding`. The body is fed through the decoder chunk by
+    /// chunk, so peak memory is about the decompressed size rather than
+    /// both copies. Applies to `fetch_cloud_data` and the other cached GETs;
+    /// the cache then holds the decompressed body. Off by default. Output is
+    /// capped by `set_max_decompressed_bytes` and
+    /// `set_max_compression_ratio`.
+    #[wasm_bindgen]
+    pub fn set_decompress_gzip(&mut self, enabled: bool) {
+        self.decompress_gzip = enabled;
+    }
+
+    /// Largest body `set_decompress_gzip` may inflate to, in bytes; beyond it
+    /// the fetch fails with a `decompression_bomb` `DataPrismError` and the
+    /// partial output is freed. Defaults to the engine's 100 MB input limit.
+    /// A `max` of 0 fails with `invalid_limit`.
+    #[wasm_bindgen]
+    pub fn set_max_decompressed_bytes(&mut self, max: usize) -> Result<(), JsValue> {
+        if max == 0 {
+            return Err(crate::utils::js_error(
+                "invalid_limit",
+                400,
+                "max must be greater than zero",
+            ));
+        }
+        self.inflate_limits.max_output = max;
+        Ok(())
+    }
+
+    /// Largest decompressed / compressed size ratio `set_decompress_gzip`
+    /// accepts (default 1000), checked once a body has inflated past 1 MiB;
+    /// beyond it the fetch fails with `decompression_bomb`. A ratio below 1
+    /// fails with `invalid_limit`.
+    #[wasm_bindgen]
+    pub fn set_max_compression_ratio(&mut self, ratio: f64) -> Result<(), JsValue> {
+        if ratio.is_nan() || ratio < 1.0 {
+            return Err(crate::utils::js_error(
+                "invalid_limit",
+                400,
+                "ratio must be at least 1",
+            ));
+        }
+        self.inflate_limits.max_ratio = Some(ratio);
+        Ok(())
+    }
+
+    /// Largest body `put_cloud_data` and `QueryEngine::process_and_put` will
+    /// send, in bytes; a larger upload fails before any request with an
+    /// `upload_too_large` `DataPrismError` giving its size. Unlimited by
+    /// default. A `max` of 0 fails with `invalid_limit`.
+    #[wasm_bindgen]
+    pub fn set_max_upload_bytes(&mut self, max: usize) -> Result<(), JsValue> {
+        if max == 0 {
+            return Err(crate::utils::js_error(
+                "invalid_limit",
+                400,
+                "max must be greater than zero",
+            ));
+        }
+        self.max_upload_bytes = Some(max);
+        Ok(())
+    }
+
+    #[wasm_bindgen]
+    pub fn clear_max_upload_bytes(&mut self) {
+        self.max_upload_bytes = None;
+    }
+
+    /// Fetch several URLs, sharing the configured retry budget across them.
+    /// Resolves to `{results: [{url, ok, status, data | error}], summary}`,
+    /// with one result per input position. Repeated URLs are fetched once
+    /// (see `set_batch_dedupe`) and their results share the same `data`
+    /// array; the summary reports `unique_fetched` against `total_requested`.
+    /// `on_progress(completed, total)`, if given, is called as each position
+    /// resolves, successful or not; exceptions it throws are logged and
+    /// otherwise ignored.
+    #[wasm_bindgen]
+    pub async fn fetch_cloud_data_batch(
+        &mut self,
+        urls: Vec<String>,
+        on_progress: Option<js_sys::Function>,
+    ) -> Result<JsValue, JsValue> {
+        console_log!("Fetching batch of {} URLs", urls.len());
+
+        let mut budget = RetryBudget::new(self.retry_budget);
+        let results = js_sys::Array::new();
+        let mut succeeded = 0u32;
+        let mut fetched: HashMap<&str, Result<(js_sys::Uint8Array, u16), JsValue>> = HashMap::new();
+        let mut unique_fetched = 0u32;
+
+        for url in &urls {
+            let entry = js_sys::Object::new();
+            js_sys::Reflect::set(&entry, &JsValue::from_str("url"), &JsValue::from_str(url))?;
+
+            let outcome = match fetched.get(url.as_str()) {
+                Some(outcome) if self.batch_dedupe => outcome.clone(),
+                _ => {
+                    unique_fetched += 1;
+                    let outcome = self
+                        .fetch_bytes(url, &mut budget)
+                        .await
+                        .map(|(data, status)| (js_sys::Uint8Array::from(&data[..]), status));
+                    fetched.insert(url, outcome.clone());
+                    outcome
+                }
+            };
 
-        let options = js_sys::Object::new();
+            match outcome {
+                Ok((data, status)) => {
+                    let ok = status < 400;
+                    if ok {
+                        succeeded += 1;
+                    }
+                    js_sys::Reflect::set(
+                        &entry,
+                        &JsValue::from_str("ok"),
+                        &JsValue::from_bool(ok),
+                    )?;
+                    js_sys::Reflect::set(
+                        &entry,
+                        &JsValue::from_str("status"),
+                        &JsValue::from(status),
+                    )?;
+                    js_sys::Reflect::set(&entry, &JsValue::from_str("data"), &data)?;
+                }
+                Err(error) => {
+                    js_sys::Reflect::set(&entry, &JsValue::from_str("ok"), &JsValue::FALSE)?;
+                    js_sys::Reflect::set(&entry, &JsValue::from_str("error"), &error)?;
+                }
+            }
+
+            results.push(&entry);
+            if let Some(on_progress) = &on_progress {
+                let completed = JsValue::from(results.length());
+                let total = JsValue::from(urls.len() as u32);
+                if let Err(error) = on_progress.call2(&JsValue::NULL, &completed, &total) {
+                    console_log!(
+                        "Batch progress callback failed: {}",
+                        crate::utils::error_message(&error)
+                    );
+                }
+            }
+        }
+
+        let summary = serde_json::json!({
+            "total": urls.len(),
+            "total_requested": urls.len(),
+            "unique_fetched": unique_fetched,
+            "succeeded": succeeded,
+            "failed": urls.len() as u32 - succeeded,
+            "retries_used": budget.used,
+            "retry_budget": self.retry_budget,
+            "retry_budget_exhausted": budget.is_exhausted()
+        });
+
+        let batch = js_sys::Object::new();
+        js_sys::Reflect::set(&batch, &JsValue::from_str("results"), &results)?;
         js_sys::Reflect::set(
-            &options,
-            &JsValue::from_str("method"),
-            &JsValue::from_str("HEAD"),
+            &batch,
+            &JsValue::from_str("summary"),
+            &serde_wasm_bindgen::to_value(&summary)?,
         )?;
 
-        let promise =
-            self.js_http_client
-                .call2(&JsValue::NULL, &JsValue::from_str(url), &options)?;
+        Ok(batch.into())
+    }
 
-        let response = wasm_bindgen_futures::JsFuture::from(js_sys::Promise::from(promise)).await?;
+    /// Status and headers for `url`, from a HEAD request unless a previous
+    /// successful GET or HEAD already captured them. `clear_cache` and a
+    /// successful `put_cloud_data` drop cached metadata.
+    #[wasm_bindgen]
+    pub async fn fetch_cloud_metadata(&mut self, url: &str) -> Result<JsValue, JsValue> {
+        console_log!("Fetching cloud metadata from: {}", url);
+        self.check_request_allowed(url)?;
 
-        // Extract metadata
-        let metadata = js_sys::Object::new();
-        let status = js_sys::Reflect::get(&response, &JsValue::from_str("status"))?;
+        // A GET through `fetch_cloud_data` records metadata as a side effect
+        if let Some(metadata) = self.metadata_cache.get(url) {
+            console_log!("Using cached metadata for: {}", url);
+            return Ok(metadata.clone());
+        }
+
+        let response = self
+            .send_with_retries(url, "HEAD", None, &[], &mut RetryBudget::new(None))
+            .await?;
+
+        let metadata = self.build_metadata(url, &response, None)?;
+        if response_status(&response) < 400 {
+            self.metadata_cache
+                .insert(url.to_string(), metadata.clone());
+        }
+        Ok(metadata)
+    }
+
+    /// Whether the cached body for `url` is still current, checked with a
+    /// HEAD request whose `ETag` (or, failing that, `Last-Modified`) is
+    /// compared with the one the body was cached with. Nothing is
+    /// downloaded. Resolves to false when `url` isn't cached, when the cached
+    /// response had neither header, or when the HEAD fails with an error
+    /// status; a stale entry and its cached metadata are dropped, so the
+    /// next fetch goes to the network.
+    #[wasm_bindgen]
+    pub async fn is_cache_fresh(&mut self, url: &str) -> Result<bool, JsValue> {
+        self.check_request_allowed(url)?;
+        let Some(entry) = self.request_cache.get(url) else {
+            return Ok(false);
+        };
+        if entry.validators == Validators::default() {
+            return Ok(false);
+        }
+
+        let response = self
+            .send_with_retries(url, "HEAD", None, &[], &mut RetryBudget::new(None))
+            .await?;
         let headers = js_sys::Reflect::get(&response, &JsValue::from_str("headers"))?;
+        let fresh = response_status(&response) < 400
+            && self.request_cache.get(url).is_some_and(|entry| {
+                entry
+                    .validators
+                    .matches(&Validators::from_headers(&headers))
+            });
 
-        js_sys::Reflect::set(&metadata, &JsValue::from_str("status"), &status)?;
-        js_sys::Reflect::set(&metadata, &JsValue::from_str("headers"), &headers)?;
-        js_sys::Reflect::set(
-            &metadata,
-            &JsValue::from_str("provider"),
-            &JsValue::from_str(&self.detect_provider(url)),
-        )?;
+        if !fresh {
+            console_log!("Cached data for {} is stale", url);
+            self.remove_cached(url);
+            self.metadata_cache.remove(url);
+        }
+        Ok(fresh)
+    }
 
-        Ok(metadata.into())
+    /// Response headers for `url` as a plain `{name: value}` object with
+    /// lowercased names; repeated headers are joined with `", "`. Uses the
+    /// same cached metadata or HEAD request as `fetch_cloud_metadata`.
+    #[wasm_bindgen]
+    pub async fn fetch_headers(&mut self, url: &str) -> Result<JsValue, JsValue> {
+        let metadata = self.fetch_cloud_metadata(url).await?;
+        let headers = js_sys::Reflect::get(&metadata, &JsValue::from_str("headers"))?;
+        Ok(serde_wasm_bindgen::to_value(&header_map(&headers)?)?)
+    }
+
+    /// The first `bytes` bytes of `url`, for previewing an object before
+    /// downloading it. Sends a `Range: bytes=0-{bytes-1}` request; servers
+    /// that ignore ranges return the whole body, which is truncated here.
+    /// A fully cached response is served from the cache; partial responses
+    /// are never cached.
+    #[wasm_bindgen]
+    pub async fn peek(&mut self, url: &str, bytes: usize) -> Result<CloudDataBuffer, JsValue> {
+        if bytes == 0 {
+            return Err(crate::utils::js_error(
+                "invalid_range",
+                400,
+                "bytes must be greater than zero",
+            ));
+        }
+        console_log!("Peeking {} bytes of: {}", bytes, url);
+        self.check_request_allowed(url)?;
+
+        let provider = self.detect_provider(url);
+        if let Some((mut data, status)) = self.cache_lookup(url) {
+            if status < 400 {
+                data.truncate(bytes);
+                return Ok(CloudDataBuffer::new(data, url.to_string(), provider));
+            }
+        }
+
+        let range = format!("bytes=0-{}", bytes - 1);
+        let response = self
+            .send_with_retries(
+                url,
+                "GET",
+                None,
+                &[("Range", &range)],
+                &mut RetryBudget::new(None),
+            )
+            .await?;
+
+        let status = response_status(&response);
+        if status >= 400 {
+            return Err(crate::utils::js_error(
+                "http_error",
+                status.into(),
+                &format!("Peek of {url} failed with status {status}"),
+            ));
+        }
+
+        let mut data = read_body(&response).await?;
+        data.truncate(bytes);
+        Ok(CloudDataBuffer::new(data, url.to_string(), provider))
+    }
+
+    /// Continue a download that stopped after `existing` bytes: requests
+    /// `Range: bytes={existing.len()}-` and returns `existing` with the rest
+    /// appended. A 200 response (range ignored) replaces `existing` with the
+    /// full body; a 416 means there was nothing left to fetch.
+    #[wasm_bindgen]
+    pub async fn fetch_resumable(
+        &mut self,
+        url: &str,
+        existing: &[u8],
+    ) -> Result<CloudDataBuffer, JsValue> {
+        console_log!("Resuming {} from byte {}", url, existing.len());
+        let provider = self.detect_provider(url);
+
+        let range = format!("bytes={}-", existing.len());
+        let response = self
+            .send_with_retries(
+                url,
+                "GET",
+                None,
+                &[("Range", &range)],
+                &mut RetryBudget::new(None),
+            )
+            .await?;
+
+        let status = response_status(&response);
+        let data = match status {
+            206 => {
+                let mut data = existing.to_vec();
+                data.extend(read_body(&response).await?);
+                data
+            }
+            416 if !existing.is_empty() => existing.to_vec(),
+            _ if status >= 400 => {
+                return Err(crate::utils::js_error(
+                    "http_error",
+                    status.into(),
+                    &format!("Resumed download of {url} failed with status {status}"),
+                ))
+            }
+            _ => read_body(&response).await?,
+        };
+        Ok(CloudDataBuffer::new(data, url.to_string(), provider))
+    }
+
+    /// Upload `data` with a PUT request and return the response status. Any
+    /// cached response for `url` is dropped once the upload succeeds.
+    #[wasm_bindgen]
+    pub async fn put_cloud_data(
+        &mut self,
+        url: &str,
+        data: &[u8],
+        content_type: &str,
+    ) -> Result<u16, JsValue> {
+        let parts = js_sys::Array::of1(&js_sys::Uint8Array::from(data));
+        self.put_parts(url, &parts, content_type).await
     }
 
     #[wasm_bindgen]
     pub fn clear_cache(&mut self) {
         console_log!("Clearing cloud storage cache");
         self.request_cache.clear();
+        self.cache_order.clear();
+        self.metadata_cache.clear();
     }
 
     #[wasm_bindgen]
@@ -177,21 +858,85 @@ impl CloudStorageBridge {
         self.request_cache.len()
     }
 
+    /// Store cached bodies gzipped, decompressing on each hit. Bodies that are
+    /// small or already compressed (images, archives, parquet) are stored
+    /// as-is. Applies to responses cached from now on.
+    #[wasm_bindgen]
+    pub fn set_cache_compression(&mut self, enabled: bool) {
+        self.cache_compression = enabled;
+    }
+
+    /// Cap the bytes held by the response cache (compressed size for
+    /// compressed entries), evicting the oldest entries first.
+    #[wasm_bindgen]
+    pub fn set_cache_max_bytes(&mut self, bytes: usize) {
+        self.cache_max_bytes = Some(bytes);
+        self.evict_over_budget();
+    }
+
+    #[wasm_bindgen]
+    pub fn clear_cache_max_bytes(&mut self) {
+        self.cache_max_bytes = None;
+    }
+
+    /// `{entries, compressed_entries, stored_bytes, logical_bytes,
+    /// compression_ratio, max_bytes}`; the ratio is logical / stored bytes.
+    #[wasm_bindgen]
+    pub fn get_cache_stats(&self) -> JsValue {
+        let stored_bytes: usize = self.request_cache.values().map(|e| e.data.len()).sum();
+        let logical_bytes: usize = self.request_cache.values().map(|e| e.logical_len).sum();
+        let compression_ratio = if stored_bytes == 0 {
+            1.0
+        } else {
+            logical_bytes as f64 / stored_bytes as f64
+        };
+
+        let stats = serde_json::json!({
+            "entries": self.request_cache.len(),
+            "compressed_entries": self.request_cache.values().filter(|e| e.compressed).count(),
+            "stored_bytes": stored_bytes,
+            "logical_bytes": logical_bytes,
+            "compression_ratio": compression_ratio,
+            "max_bytes": self.cache_max_bytes,
+        });
+        serde_wasm_bindgen::to_value(&stats).unwrap_or(JsValue::NULL)
+    }
+
+    /// Cached URLs in the order they were first cached.
     #[wasm_bindgen]
     pub fn get_cached_urls(&self) -> js_sys::Array {
         let array = js_sys::Array::new();
-        for url in self.request_cache.keys() {
+        for url in &self.cache_order {
             array.push(&JsValue::from_str(url));
         }
         array
     }
 
+    /// Smallest chunk `stream_cloud_data` will produce; smaller requested
+    /// sizes are raised to this. Defaults to 1 KiB.
+    #[wasm_bindgen]
+    pub fn set_min_chunk_size(&mut self, bytes: usize) {
+        self.min_chunk_size = bytes.max(1);
+    }
+
+    /// Fetch `url` and split the body into chunks of `chunk_size` bytes
+    /// (at least the configured minimum). A `chunk_size` of 0 is rejected
+    /// with an `invalid_chunk_size` `DataPrismError`.
     #[wasm_bindgen]
     pub async fn stream_cloud_data(
         &self,
         url: &str,
         chunk_size: usize,
     ) -> Result<js_sys::Array, JsValue> {
+        if chunk_size == 0 {
+            return Err(crate::utils::js_error(
+                "invalid_chunk_size",
+                400,
+                "chunk_size must be greater than zero",
+            ));
+        }
+        let chunk_size = chunk_size.max(self.min_chunk_size);
+
         console_log!(
             "Streaming cloud data from: {} with chunk size: {}",
             url,
@@ -200,39 +945,500 @@ impl CloudStorageBridge {
 
         // For streaming, we'll fetch the data and split it into chunks
         // In a real implementation, this would use HTTP range requests
-        let options = js_sys::Object::new();
+        let response = self
+            .send_with_retries(url, "GET", None, &[], &mut RetryBudget::new(None))
+            .await?;
+        let data = read_body(&response).await?;
+
+        // Split data into chunks
+        let chunks = js_sys::Array::new();
+        for chunk in data.chunks(chunk_size) {
+            let chunk_array = js_sys::Uint8Array::from(chunk);
+            chunks.push(&chunk_array);
+        }
+
+        Ok(chunks)
+    }
+}
+
+impl CloudStorageBridge {
+    // PUT a body assembled from `parts` (Uint8Arrays). The parts are joined
+    // into a Blob on the JS side, so the upload never needs one contiguous
+    // copy in WASM memory; the Blob's type becomes the Content-Type.
+    pub(crate) async fn put_parts(
+        &mut self,
+        url: &str,
+        parts: &js_sys::Array,
+        content_type: &str,
+    ) -> Result<u16, JsValue> {
+        let size: usize = parts
+            .iter()
+            .map(|part| part.unchecked_into::<js_sys::Uint8Array>().length() as usize)
+            .sum();
+        if let Some(max) = self.max_upload_bytes.filter(|max| size > *max) {
+            return Err(crate::utils::js_error(
+                "upload_too_large",
+                413,
+                &format!("Upload to {url} is {size} bytes, over the limit of {max} (see set_max_upload_bytes)"),
+            ));
+        }
+        console_log!("Uploading {} parts to: {}", parts.length(), url);
+
+        let blob_options = web_sys::BlobPropertyBag::new();
+        blob_options.set_type(content_type);
+        let body = web_sys::Blob::new_with_u8_array_sequence_and_options(parts, &blob_options)?;
+
+        let response = self
+            .send_with_retries(url, "PUT", Some(&body), &[], &mut RetryBudget::new(None))
+            .await?;
+        let status = response_status(&response);
+
+        if status < 400 {
+            self.metadata_cache.remove(url);
+            self.remove_cached(url);
+        }
+
+        Ok(status)
+    }
+
+    pub(crate) async fn fetch_with_status(&mut self, url: &str) -> Result<(Vec<u8>, u16), JsValue> {
+        self.fetch_bytes(url, &mut RetryBudget::new(None)).await
+    }
+
+    // Uncached GET of bytes `start..start + len` of `url` with a `Range`
+    // header, returning the body, the status and the object's total size
+    // when a 206 response's `Content-Range` gives it. A 200 means the server
+    // ignored the range and sent everything.
+    pub(crate) async fn fetch_range(
+        &self,
+        url: &str,
+        start: usize,
+        len: usize,
+    ) -> Result<(Vec<u8>, u16, Option<usize>), JsValue> {
+        let range = format!("bytes={start}-{}", start + len - 1);
+        let response = self
+            .send_with_retries(
+                url,
+                "GET",
+                None,
+                &[("Range", &range)],
+                &mut RetryBudget::new(None),
+            )
+            .await?;
+        let status = response_status(&response);
+        let headers = js_sys::Reflect::get(&response, &JsValue::from_str("headers"))?;
+        let total = read_header(&headers, "content-range")
+            .as_deref()
+            .and_then(content_range_total);
+        let data = if status < 400 {
+            read_body(&response).await?
+        } else {
+            Vec::new()
+        };
+        Ok((data, status, total))
+    }
+
+    // Cached GET returning the body and status.
+    async fn fetch_bytes(
+        &mut self,
+        url: &str,
+        budget: &mut RetryBudget,
+    ) -> Result<(Vec<u8>, u16), JsValue> {
+        console_log!("Fetching cloud data from: {}", url);
+        self.check_request_allowed(url)?;
+
+        // Check cache first
+        if let Some(hit) = self.cache_lookup(url) {
+            console_log!("Using cached data for: {}", url);
+            return Ok(hit);
+        }
+
+        if let Some(data) = self.persistent_get(url).await {
+            console_log!("Using persisted data for: {}", url);
+            self.cache_response(url, data.clone(), 200, &JsValue::UNDEFINED);
+            return Ok((data, 200));
+        }
+
+        let response = self
+            .send_with_retries(url, "GET", None, &[], budget)
+            .await?;
+        let data = if self.decompress_gzip {
+            read_body_gunzipped(&response, url, self.inflate_limits).await?
+        } else {
+            read_body(&response).await?
+        };
+
+        // Error responses aren't cached, so a later fetch retries them
+        let status = response_status(&response);
+        if status < 400 {
+            self.persistent_put(url, &data).await;
+            if let Ok(metadata) = self.build_metadata(url, &response, Some(data.len())) {
+                self.metadata_cache.insert(url.to_string(), metadata);
+            }
+            let headers = js_sys::Reflect::get(&response, &JsValue::from_str("headers"))?;
+            self.cache_response(url, data.clone(), status, &headers);
+        }
+        Ok((data, status))
+    }
+
+    // Metadata object as returned by `fetch_cloud_metadata`. For GET
+    // responses `body_len` fills in `content_length` when the header is absent.
+    fn build_metadata(
+        &self,
+        url: &str,
+        response: &JsValue,
+        body_len: Option<usize>,
+    ) -> Result<JsValue, JsValue> {
+        let metadata = js_sys::Object::new();
+        let status = js_sys::Reflect::get(response, &JsValue::from_str("status"))?;
+        let headers = js_sys::Reflect::get(response, &JsValue::from_str("headers"))?;
+
+        let mut parsed_headers = parse_common_headers(&headers);
+        if let (Some(len), Some(parsed)) = (body_len, parsed_headers.as_object_mut()) {
+            if parsed["content_length"].is_null() {
+                parsed.insert("content_length".to_string(), len.into());
+            }
+        }
+
+        js_sys::Reflect::set(&metadata, &JsValue::from_str("status"), &status)?;
+        js_sys::Reflect::set(&metadata, &JsValue::from_str("headers"), &headers)?;
+        js_sys::Reflect::set(
+            &metadata,
+            &JsValue::from_str("parsed_headers"),
+            &serde_wasm_bindgen::to_value(&parsed_headers)?,
+        )?;
+        js_sys::Reflect::set(
+            &metadata,
+            &JsValue::from_str("provider"),
+            &JsValue::from_str(&self.detect_provider(url)),
+        )?;
+
+        Ok(metadata.into())
+    }
+
+    // Cached body and status for `url`. An entry that fails to decompress is
+    // dropped and reported as a miss.
+    fn cache_lookup(&mut self, url: &str) -> Option<(Vec<u8>, u16)> {
+        let entry = self.request_cache.get(url)?;
+        if !entry.compressed {
+            return Some((entry.data.clone(), entry.status));
+        }
+
+        let status = entry.status;
+        match crate::compression::gunzip(&entry.data) {
+            Ok(data) => Some((data, status)),
+            Err(_) => {
+                self.remove_cached(url);
+                None
+            }
+        }
+    }
+
+    // Cache a response body along with the headers needed to revalidate it
+    // (`headers` may be undefined when there were none)
+    fn cache_response(&mut self, url: &str, data: Vec<u8>, status: u16, headers: &JsValue) {
+        let content_type = read_header(headers, "content-type");
+        let logical_len = data.len();
+        let compressed = (self.cache_compression
+            && logical_len >= MIN_COMPRESS_BYTES
+            && !crate::compression::is_precompressed(content_type.as_deref(), url))
+        .then(|| crate::compression::gzip(&data))
+        .filter(|gz| gz.len() < logical_len);
+
+        let (data, compressed) = match compressed {
+            Some(gz) => (gz, true),
+            None => (data, false),
+        };
+        let entry = CacheEntry {
+            data,
+            status,
+            compressed,
+            logical_len,
+            validators: Validators::from_headers(headers),
+        };
+
+        if self.request_cache.insert(url.to_string(), entry).is_none() {
+            self.cache_order.push(url.to_string());
+        }
+        self.evict_over_budget();
+    }
+
+    fn remove_cached(&mut self, url: &str) {
+        if self.request_cache.remove(url).is_some() {
+            self.cache_order.retain(|cached| cached != url);
+        }
+    }
+
+    fn evict_over_budget(&mut self) {
+        let Some(max_bytes) = self.cache_max_bytes else {
+            return;
+        };
+        let mut stored: usize = self.request_cache.values().map(|e| e.data.len()).sum();
+        while stored > max_bytes && !self.cache_order.is_empty() {
+            let oldest = self.cache_order.remove(0);
+            if let Some(entry) = self.request_cache.remove(&oldest) {
+                stored -= entry.data.len();
+            }
+        }
+    }
+
+    // Look `url` up in the persistent store. Store failures are treated as a
+    // miss so a broken store never blocks fetching from the network.
+    async fn persistent_get(&self, url: &str) -> Option<Vec<u8>> {
+        let store = self.persistent_store.as_ref()?;
+        let result = store
+            .get_fn
+            .call1(&JsValue::NULL, &JsValue::from_str(url))
+            .ok()?;
+        let value = wasm_bindgen_futures::JsFuture::from(js_sys::Promise::resolve(&result))
+            .await
+            .ok()?;
+
+        if value.is_null() || value.is_undefined() {
+            return None;
+        }
+        Some(js_sys::Uint8Array::new(&value).to_vec())
+    }
+
+    async fn persistent_put(&self, url: &str, data: &[u8]) {
+        let Some(store) = &self.persistent_store else {
+            return;
+        };
+        let bytes = js_sys::Uint8Array::from(data);
+        match store
+            .put_fn
+            .call2(&JsValue::NULL, &JsValue::from_str(url), &bytes)
+        {
+            Ok(result) => {
+                let written =
+                    wasm_bindgen_futures::JsFuture::from(js_sys::Promise::resolve(&result)).await;
+                if written.is_err() {
+                    console_log!("Persistent store write failed for: {}", url);
+                }
+            }
+            Err(_) => console_log!("Persistent store write failed for: {}", url),
+        }
+    }
+
+    // Call the JavaScript HTTP client, retrying network errors and retryable
+    // statuses per the retry policy. When retries run out on a retryable
+    // status, the last response is returned as-is.
+    async fn send_with_retries(
+        &self,
+        url: &str,
+        method: &str,
+        body: Option<&JsValue>,
+        headers: &[(&str, &str)],
+        budget: &mut RetryBudget,
+    ) -> Result<JsValue, JsValue> {
+        self.check_request_allowed(url)?;
+
+        let generation = self.in_flight.borrow().generation;
+        let mut attempt = 0u32;
+        loop {
+            let outcome = self.send_request(url, method, body, headers).await;
+            if self.in_flight.borrow().generation != generation {
+                return Err(aborted_error(url));
+            }
+            let retryable = match &outcome {
+                Ok(response) => is_retryable_status(response_status(response)),
+                Err(_) => true,
+            };
+
+            if !retryable || attempt >= self.max_retries || !budget.try_consume() {
+                return outcome;
+            }
+
+            let mut delay = self.retry_base_delay_ms * 2f64.powi(attempt as i32);
+            if let Ok(response) = &outcome {
+                if let Some(wait) = self.retry_after_ms(response) {
+                    delay = delay.max(wait);
+                }
+            }
+            attempt += 1;
+            console_log!("Retrying {} {} (attempt {})", method, url, attempt);
+            crate::utils::sleep_ms(delay).await?;
+            if self.in_flight.borrow().generation != generation {
+                return Err(aborted_error(url));
+            }
+        }
+    }
+
+    // `Retry-After` of a 429/503 response in ms, capped to the configured
+    // maximum.
+    fn retry_after_ms(&self, response: &JsValue) -> Option<f64> {
+        if !matches!(response_status(response), 429 | 503) {
+            return None;
+        }
+        let headers = js_sys::Reflect::get(response, &JsValue::from_str("headers")).ok()?;
+        let value = read_header(&headers, "retry-after")?;
+        parse_retry_after(&value, js_sys::Date::now()).map(|wait| wait.min(self.max_retry_after_ms))
+    }
+
+    async fn send_request(
+        &self,
+        url: &str,
+        method: &str,
+        body: Option<&JsValue>,
+        headers: &[(&str, &str)],
+    ) -> Result<JsValue, JsValue> {
+        let generation = self.in_flight.borrow().generation;
+        if let Some(limiter) = &self.rate_limiter {
+            let delay = limiter.reserve(js_sys::Date::now());
+            if delay > 0.0 {
+                crate::utils::sleep_ms(delay).await?;
+            }
+        }
+        if self.in_flight.borrow().generation != generation {
+            return Err(aborted_error(url));
+        }
+
+        // Call JavaScript HTTP client from WASM
+        if let Some(trace_id) = &self.trace_id {
+            console_log!("[trace {}] {} {}", trace_id, method, url);
+        }
+        let options = self.request_options(method, body, headers)?;
+
+        // The client should pass `signal` on to `fetch`; if it doesn't, the
+        // request still runs but its result is discarded once aborted
+        let controller = web_sys::AbortController::new().ok();
+        if let Some(controller) = &controller {
+            js_sys::Reflect::set(&options, &JsValue::from_str("signal"), &controller.signal())?;
+        }
+        let id = {
+            let mut in_flight = self.in_flight.borrow_mut();
+            in_flight.next_id += 1;
+            let id = in_flight.next_id;
+            if let Some(controller) = controller {
+                in_flight.controllers.insert(id, controller);
+            }
+            id
+        };
+
+        let outcome =
+            match self
+                .js_http_client
+                .call2(&JsValue::NULL, &JsValue::from_str(url), &options)
+            {
+                Ok(promise) => {
+                    wasm_bindgen_futures::JsFuture::from(js_sys::Promise::from(promise)).await
+                }
+                Err(e) => Err(e),
+            };
+
+        let mut in_flight = self.in_flight.borrow_mut();
+        in_flight.controllers.remove(&id);
+        if in_flight.generation != generation {
+            return Err(aborted_error(url));
+        }
+        outcome
+    }
+
+    // Options for one call to the JavaScript HTTP client: a shallow copy of
+    // the cached template with the method, body and any per-request headers
+    // set on it. Under heavy fetch load this beats building the object and
+    // its default headers from scratch every time.
+    fn request_options(
+        &self,
+        method: &str,
+        body: Option<&JsValue>,
+        headers: &[(&str, &str)],
+    ) -> Result<js_sys::Object, JsValue> {
+        let template = self.options_template()?;
+        let options = js_sys::Object::assign(&js_sys::Object::new(), &template);
         js_sys::Reflect::set(
             &options,
             &JsValue::from_str("method"),
-            &JsValue::from_str("GET"),
+            &JsValue::from_str(method),
         )?;
+        if let Some(body) = body {
+            js_sys::Reflect::set(&options, &JsValue::from_str("body"), body)?;
+        }
 
-        let promise =
-            self.js_http_client
-                .call2(&JsValue::NULL, &JsValue::from_str(url), &options)?;
-
-        let response = wasm_bindgen_futures::JsFuture::from(js_sys::Promise::from(promise)).await?;
+        if !headers.is_empty() {
+            // Copy the shared headers object rather than adding to it
+            let header_object = js_sys::Object::new();
+            let defaults = js_sys::Reflect::get(&template, &JsValue::from_str("headers"))?;
+            if let Some(defaults) = defaults.dyn_ref::<js_sys::Object>() {
+                js_sys::Object::assign(&header_object, defaults);
+            }
+            for (name, value) in headers {
+                // Drop a default spelled with different case
+                for (default, _) in &self.default_headers {
+                    if default.eq_ignore_ascii_case(name) && default != name {
+                        js_sys::Reflect::delete_property(
+                            &header_object,
+                            &JsValue::from_str(default),
+                        )?;
+                    }
+                }
+                js_sys::Reflect::set(
+                    &header_object,
+                    &JsValue::from_str(name),
+                    &JsValue::from_str(value),
+                )?;
+            }
+            js_sys::Reflect::set(&options, &JsValue::from_str("headers"), &header_object)?;
+        }
+        Ok(options)
+    }
 
-        let array_buffer = js_sys::Reflect::get(&response, &JsValue::from_str("arrayBuffer"))?;
-        let array_buffer_fn = js_sys::Function::from(array_buffer);
-        let buffer_promise = array_buffer_fn.call0(&response)?;
-        let buffer =
-            wasm_bindgen_futures::JsFuture::from(js_sys::Promise::from(buffer_promise)).await?;
+    fn options_template(&self) -> Result<js_sys::Object, JsValue> {
+        if let Some(template) = &*self.options_template.borrow() {
+            return Ok(template.clone());
+        }
 
-        let uint8_array = js_sys::Uint8Array::new(&buffer);
-        let data: Vec<u8> = uint8_array.to_vec();
+        let template = js_sys::Object::new();
+        let mut defaults: Vec<(&str, &str)> = self
+            .default_headers
+            .iter()
+            .map(|(name, value)| (name.as_str(), value.as_str()))
+            .collect();
+        if let Some(trace_id) = &self.trace_id {
+            defaults.push(("x-trace-id", trace_id));
+        }
+        if !defaults.is_empty() {
+            let header_object = js_sys::Object::new();
+            for (name, value) in defaults {
+                js_sys::Reflect::set(
+                    &header_object,
+                    &JsValue::from_str(name),
+                    &JsValue::from_str(value),
+                )?;
+            }
+            js_sys::Reflect::set(&template, &JsValue::from_str("headers"), &header_object)?;
+        }
+        self.options_template.replace(Some(template.clone()));
+        Ok(template)
+    }
 
-        // Split data into chunks
-        let chunks = js_sys::Array::new();
-        for chunk in data.chunks(chunk_size) {
-            let chunk_array = js_sys::Uint8Array::from(chunk);
-            chunks.push(&chunk_array);
+    // URL policy and provider allowlist, checked before any cache lookup or
+    // call to the JavaScript HTTP client.
+    fn check_request_allowed(&self, url: &str) -> Result<(), JsValue> {
+        let matches = |patterns: &[String]| patterns.iter().any(|p| url_pattern_matches(p, url));
+        if matches(&self.url_deny) || (!self.url_allow.is_empty() && !matches(&self.url_allow)) {
+            return Err(crate::utils::js_error(
+                "url_blocked",
+                403,
+                &format!("URL {url} is blocked by the URL policy"),
+            ));
         }
 
-        Ok(chunks)
+        if self.allowed_providers.is_empty() {
+            return Ok(());
+        }
+        let provider = self.detect_provider(url);
+        if self.allowed_providers.contains(&provider) {
+            return Ok(());
+        }
+        Err(crate::utils::js_error(
+            "provider_not_allowed",
+            403,
+            &format!("Provider '{provider}' for {url} is not in the allowed providers list"),
+        ))
     }
 
-    fn detect_provider(&self, url: &str) -> String {
+    pub(crate) fn detect_provider(&self, url: &str) -> String {
         let url_lower = url.to_lowercase();
 
         if url_lower.contains("amazonaws.com") || url_lower.contains("s3.") {
@@ -251,6 +1457,373 @@ impl CloudStorageBridge {
     }
 }
 
+// Patterns with a scheme are compared with the URL part by part, so case,
+// an explicit default port, user info or a lookalike host can't slip past
+// them; others are matched against the URL's canonical form.
+pub(crate) fn url_pattern_matches(pattern: &str, url: &str) -> bool {
+    let Some(target) = UrlParts::parse(url) else {
+        return text_pattern_matches(pattern, url);
+    };
+    let Some(rule) = UrlParts::parse(pattern).filter(|_| pattern.contains("://")) else {
+        return text_pattern_matches(pattern, &target.canonical());
+    };
+
+    let port_matches = match rule.port {
+        Some(port) => target
+            .port()
+            .is_some_and(|actual| glob_matches(port, actual)),
+        None => target.port() == target.default_port(),
+    };
+    let rest_matches = if rule.rest.contains('*') {
+        glob_matches(rule.rest, target.rest)
+    } else {
+        target.rest.starts_with(rule.rest)
+    };
+    glob_matches(&rule.scheme, &target.scheme)
+        && glob_matches(&rule.host, &target.host)
+        && port_matches
+        && rest_matches
+}
+
+// A URL as policy patterns see it: scheme and host lowercased, with any
+// user info and a trailing dot on the host dropped
+struct UrlParts<'a> {
+    scheme: String,
+    host: String,
+    port: Option<&'a str>,
+    // Path, query and fragment
+    rest: &'a str,
+}
+
+impl<'a> UrlParts<'a> {
+    fn parse(url: &'a str) -> Option<UrlParts<'a>> {
+        let (scheme, after) = url.trim().split_once("://")?;
+        let end = after.find(['/', '\\', '?', '#']).unwrap_or(after.len());
+        let (authority, rest) = after.split_at(end);
+        let authority = authority
+            .rsplit_once('@')
+            .map_or(authority, |(_, host)| host);
+        // A colon inside brackets is part of an IPv6 address
+        let (host, port) = match authority.rfind(':') {
+            Some(i) if !authority[i..].contains(']') => {
+                (&authority[..i], Some(&authority[i + 1..]))
+            }
+            _ => (authority, None),
+        };
+        Some(UrlParts {
+            scheme: scheme.to_ascii_lowercase(),
+            host: host.trim_end_matches('.').to_ascii_lowercase(),
+            port: port.filter(|p| !p.is_empty()),
+            rest,
+        })
+    }
+
+    fn default_port(&self) -> Option<&'static str> {
+        match self.scheme.as_str() {
+            "https" => Some("443"),
+            "http" => Some("80"),
+            _ => None,
+        }
+    }
+
+    // The explicit port, else the scheme's default
+    fn port(&self) -> Option<&str> {
+        self.port.or(self.default_port())
+    }
+
+    // The URL with the parts normalized and a default port left out
+    fn canonical(&self) -> String {
+        let port = match self.port {
+            Some(port) if Some(port) != self.default_port() => format!(":{port}"),
+            _ => String::new(),
+        };
+        format!("{}://{}{port}{}", self.scheme, self.host, self.rest)
+    }
+}
+
+// `*` matches any run of characters; a pattern without one matches as a
+// prefix
+fn text_pattern_matches(pattern: &str, text: &str) -> bool {
+    if pattern.contains('*') {
+        glob_matches(pattern, text)
+    } else {
+        text.starts_with(pattern)
+    }
+}
+
+// Whether `pattern`, with `*` matching any run of characters, matches all
+// of `text`
+fn glob_matches(pattern: &str, text: &str) -> bool {
+    let parts: Vec<&str> = pattern.split('*').collect();
+    let Some((first, rest)) = parts.split_first() else {
+        return false;
+    };
+    let Some(mut remaining) = text.strip_prefix(first) else {
+        return false;
+    };
+    let Some((last, middle)) = rest.split_last() else {
+        return remaining.is_empty();
+    };
+
+    // The last part must end the text; the ones between match leftmost-first
+    for part in middle {
+        match remaining.find(part) {
+            Some(i) => remaining = &remaining[i + part.len()..],
+            None => return false,
+        }
+    }
+    remaining.len() >= last.len() && remaining.ends_with(last)
+}
+
+pub(crate) fn normalize_url(url: &str, allow_insecure: bool) -> Result<String, String> {
+    let url = url.trim();
+    let (scheme, rest) = match url.split_once("://") {
+        Some((scheme, rest)) => (scheme.to_ascii_lowercase(), rest),
+        None => ("https".to_string(), url.trim_start_matches('/')),
+    };
+    match scheme.as_str() {
+        "https" => {}
+        "http" if allow_insecure => {}
+        "http" => {
+            return Err(format!(
+                "Insecure URL {url}: use https or allow insecure URLs"
+            ))
+        }
+        _ => return Err(format!("Unsupported URL scheme '{scheme}' in {url}")),
+    }
+
+    let host_end = rest.find(['/', '?', '#']).unwrap_or(rest.len());
+    let (host, tail) = rest.split_at(host_end);
+    if host.is_empty() || host.contains(char::is_whitespace) {
+        return Err(format!("Invalid host in URL {url}"));
+    }
+
+    let path_end = tail.find(['?', '#']).unwrap_or(tail.len());
+    let (path, suffix) = tail.split_at(path_end);
+    let mut collapsed = String::with_capacity(path.len());
+    for c in path.chars() {
+        if !(c == '/' && collapsed.ends_with('/')) {
+            collapsed.push(c);
+        }
+    }
+
+    Ok(format!("{scheme}://{host}{collapsed}{suffix}").replace(' ', "%20"))
+}
+
+// `encoding_rs` follows the WHATWG Encoding Standard, so `latin1` and
+// `iso-8859-1` decode as windows-1252 like they do in browsers.
+pub(crate) fn decode_text(bytes: &[u8], label: &str) -> Result<String, JsValue> {
+    let encoding = encoding_rs::Encoding::for_label(label.trim().as_bytes()).ok_or_else(|| {
+        crate::utils::js_error(
+            "unsupported_encoding",
+            400,
+            &format!("Unsupported text encoding '{label}'"),
+        )
+    })?;
+    let (text, _) = encoding.decode_with_bom_removal(bytes);
+    Ok(text.into_owned())
+}
+
+fn response_status(response: &JsValue) -> u16 {
+    js_sys::Reflect::get(response, &JsValue::from_str("status"))
+        .ok()
+        .and_then(|v| v.as_f64())
+        .unwrap_or(200.0) as u16
+}
+
+/// Delay in ms requested by a `Retry-After` value: delta seconds, or an
+/// IMF-fixdate such as `Wed, 21 Oct 2015 07:28:00 GMT` relative to `now_ms`.
+/// A date in the past means no wait.
+pub(crate) fn parse_retry_after(value: &str, now_ms: f64) -> Option<f64> {
+    let value = value.trim();
+    if let Ok(seconds) = value.parse::<u64>() {
+        return Some(seconds as f64 * 1000.0);
+    }
+    Some((parse_http_date(value)? - now_ms).max(0.0))
+}
+
+// Epoch ms of an IMF-fixdate (`Wed, 21 Oct 2015 07:28:00 GMT`)
+fn parse_http_date(value: &str) -> Option<f64> {
+    const MONTHS: [&str; 12] = [
+        "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+    ];
+    let (_, rest) = value.split_once(", ")?;
+    let parts: Vec<&str> = rest.split(' ').collect();
+    let [day, month, year, time, "GMT"] = parts.as_slice() else {
+        return None;
+    };
+    let day: i64 = day.parse().ok()?;
+    let month = MONTHS.iter().position(|m| m == month)? as i64 + 1;
+    let year: i64 = year.parse().ok()?;
+    let mut clock = time.split(':').map(|p| p.parse::<i64>().ok());
+    let (hour, minute, second) = (clock.next()??, clock.next()??, clock.next()??);
+    if clock.next().is_some() || !(1..=31).contains(&day) {
+        return None;
+    }
+
+    let days = crate::timestamp::days_from_civil(year, month, day);
+
+    Some(((days * 86_400 + hour * 3600 + minute * 60 + second) * 1000) as f64)
+}
+
+/// Complete length from a `Content-Range` value such as `bytes 0-99/1234`;
+/// `None` when it is unknown (`*`) or the value is malformed.
+pub(crate) fn content_range_total(value: &str) -> Option<usize> {
+    let (unit, range) = value.trim().split_once(' ')?;
+    if !unit.eq_ignore_ascii_case("bytes") {
+        return None;
+    }
+    range.split_once('/')?.1.trim().parse().ok()
+}
+
+fn is_retryable_status(status: u16) -> bool {
+    status == 429 || (500..600).contains(&status)
+}
+
+async fn read_body(response: &JsValue) -> Result<Vec<u8>, JsValue> {
+    let array_buffer = js_sys::Reflect::get(response, &JsValue::from_str("arrayBuffer"))?;
+    let array_buffer_fn = js_sys::Function::from(array_buffer);
+    let buffer_promise = array_buffer_fn.call0(response)?;
+    let buffer =
+        wasm_bindgen_futures::JsFuture::from(js_sys::Promise::from(buffer_promise)).await?;
+
+    Ok(js_sys::Uint8Array::new(&buffer).to_vec())
+}
+
+// The body inflated chunk by chunk as it arrives when it is gzipped. Clients
+// whose responses have no `body` stream are read in one piece first.
+async fn read_body_gunzipped(
+    response: &JsValue,
+    url: &str,
+    limits: crate::compression::InflateLimits,
+) -> Result<Vec<u8>, JsValue> {
+    let invalid = |e: crate::utils::ParseError| {
+        let code = if e.kind == "decompression_bomb" {
+            413
+        } else {
+            422
+        };
+        crate::utils::js_error(e.kind, code, &format!("Response from {url}: {}", e.message))
+    };
+    let mut inflater = crate::compression::GunzipStream::new(limits);
+
+    let body = js_sys::Reflect::get(response, &JsValue::from_str("body"))?;
+    let Ok(stream) = body.dyn_into::<web_sys::ReadableStream>() else {
+        inflater
+            .push(&read_body(response).await?)
+            .map_err(invalid)?;
+        return inflater.finish().map_err(invalid);
+    };
+
+    let reader: web_sys::ReadableStreamDefaultReader = stream.get_reader().unchecked_into();
+    loop {
+        let next = wasm_bindgen_futures::JsFuture::from(reader.read()).await?;
+        if js_sys::Reflect::get(&next, &JsValue::from_str("done"))?
+            .as_bool()
+            .unwrap_or(false)
+        {
+            break;
+        }
+        let value = js_sys::Reflect::get(&next, &JsValue::from_str("value"))?;
+        inflater
+            .push(&js_sys::Uint8Array::new(&value).to_vec())
+            .map_err(invalid)?;
+    }
+    if inflater.is_gzip() {
+        console_log!("Decompressed gzipped response from {}", url);
+    }
+    inflater.finish().map_err(invalid)
+}
+
+// Reads a header from either a fetch `Headers` instance (via `get`, which is
+// case-insensitive) or a plain object, matching the name case-insensitively.
+fn read_header(headers: &JsValue, name: &str) -> Option<String> {
+    if headers.is_undefined() || headers.is_null() {
+        return None;
+    }
+
+    if let Ok(get) = js_sys::Reflect::get(headers, &JsValue::from_str("get")) {
+        if let Some(get_fn) = get.dyn_ref::<js_sys::Function>() {
+            return get_fn
+                .call1(headers, &JsValue::from_str(name))
+                .ok()
+                .and_then(|v| v.as_string());
+        }
+    }
+
+    let object = headers.dyn_ref::<js_sys::Object>()?;
+    js_sys::Object::keys(object)
+        .iter()
+        .filter_map(|key| key.as_string())
+        .find(|key| key.eq_ignore_ascii_case(name))
+        .and_then(|key| js_sys::Reflect::get(headers, &JsValue::from_str(&key)).ok())
+        .and_then(|v| v.as_string())
+}
+
+// All headers of a fetch `Headers` instance or a plain
+// object, keyed by lowercased name.
+fn header_map(headers: &JsValue) -> Result<std::collections::BTreeMap<String, String>, JsValue> {
+    let mut map = std::collections::BTreeMap::new();
+    if headers.is_undefined() || headers.is_null() {
+        return Ok(map);
+    }
+
+    // `Headers` iterates as [name, value] pairs; plain objects aren't iterable
+    if let Some(pairs) = js_sys::try_iter(headers)? {
+        for pair in pairs {
+            let pair = js_sys::Array::from(&pair?);
+            if let (Some(name), Some(value)) = (pair.get(0).as_string(), pair.get(1).as_string()) {
+                merge_header(&mut map, &name, &value);
+            }
+        }
+        return Ok(map);
+    }
+
+    if let Some(object) = headers.dyn_ref::<js_sys::Object>() {
+        for entry in js_sys::Object::entries(object).iter() {
+            let entry = js_sys::Array::from(&entry);
+            let Some(name) = entry.get(0).as_string() else {
+                continue;
+            };
+            let value = entry.get(1);
+            let values = if js_sys::Array::is_array(&value) {
+                js_sys::Array::from(&value).iter().collect()
+            } else {
+                vec![value]
+            };
+            for value in values.iter().filter_map(JsValue::as_string) {
+                merge_header(&mut map, &name, &value);
+            }
+        }
+    }
+    Ok(map)
+}
+
+pub(crate) fn merge_header(
+    map: &mut std::collections::BTreeMap<String, String>,
+    name: &str,
+    value: &str,
+) {
+    map.entry(name.to_ascii_lowercase())
+        .and_modify(|existing| {
+            existing.push_str(", ");
+            existing.push_str(value);
+        })
+        .or_insert_with(|| value.to_string());
+}
+
+fn parse_common_headers(headers: &JsValue) -> serde_json::Value {
+    serde_json::json!({
+        "content_length": read_header(headers, "content-length")
+            .and_then(|v| v.trim().parse::<u64>().ok()),
+        "content_type": read_header(headers, "content-type"),
+        "etag": read_header(headers, "etag"),
+        "last_modified": read_header(headers, "last-modified"),
+        "accept_ranges": read_header(headers, "accept-ranges"),
+        "content_encoding": read_header(headers, "content-encoding"),
+    })
+}
+
 // Helper struct for managing cloud data buffers
 #[wasm_bindgen]
 pub struct CloudDataBuffer {
@@ -298,6 +1871,12 @@ impl CloudDataBuffer {
     }
 }
 
+impl CloudDataBuffer {
+    pub(crate) fn bytes(&self) -> &[u8] {
+        &self.data
+    }
+}
+
 // Automatic cleanup when buffer is dropped
 impl Drop for CloudDataBuffer {
     fn drop(&mut self) {
@@ -0,0 +1,264 @@
+use crate::utils::{ParseError, MAX_INPUT_BYTES};
+use flate2::read::GzDecoder;
+use flate2::write::{GzDecoder as GzWriteDecoder, GzEncoder};
+use flate2::Compression;
+use std::borrow::Cow;
+use std::io::{Read, Write};
+
+// Gzip helpers shared by the cache and input decoding
+
+pub(crate) fn gzip(data: &[u8]) -> Vec<u8> {
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::fast());
+    // Writing into a Vec can't fail
+    let _ = encoder.write_all(data);
+    encoder.finish().unwrap_or_default()
+}
+
+pub(crate) fn gunzip(data: &[u8]) -> Result<Vec<u8>, String> {
+    let mut out = Vec::new();
+    GzDecoder::new(data)
+        .read_to_end(&mut out)
+        .map_err(|e| format!("Invalid gzip data: {e}"))?;
+    Ok(out)
+}
+
+/// Caps on how far compressed data may inflate, against decompression bombs.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) struct InflateLimits {
+    pub max_output: usize,
+    /// Largest output / input size ratio, checked once the output passes
+    /// `RATIO_CHECK_MIN_BYTES` so small, very repetitive bodies aren't caught.
+    pub max_ratio: Option<f64>,
+}
+
+pub(crate) const RATIO_CHECK_MIN_BYTES: usize = 1 << 20;
+// Compressed bytes inflated between limit checks; deflate expands at most
+// ~1032x, which bounds the overshoot
+const INFLATE_STEP_BYTES: usize = 1024;
+
+/// Inflates a body that arrives in chunks, so only the output and the
+/// decoder's window are held rather than the whole compressed body as well.
+/// Bodies that don't start with the gzip magic bytes pass through unchanged.
+pub(crate) struct GunzipStream {
+    state: InflateState,
+    limits: InflateLimits,
+    consumed: usize,
+    // Output already handed over by `take_output`
+    taken: usize,
+}
+
+enum InflateState {
+    // Fewer bytes than the magic seen so far
+    Undecided(Vec<u8>),
+    Gzip(GzWriteDecoder<Vec<u8>>),
+    Plain(Vec<u8>),
+}
+
+impl GunzipStream {
+    pub(crate) fn new(limits: InflateLimits) -> GunzipStream {
+        GunzipStream {
+            state: InflateState::Undecided(Vec::new()),
+            limits,
+            consumed: 0,
+            taken: 0,
+        }
+    }
+
+    /// Feed the next chunk. Exceeding a limit fails with
+    /// `decompression_bomb` and releases everything decoded so far.
+    pub(crate) fn push(&mut self, chunk: &[u8]) -> Result<(), ParseError> {
+        match &mut self.state {
+            InflateState::Undecided(head) => {
+                head.extend_from_slice(chunk);
+                if head.len() < 2 {
+                    return Ok(());
+                }
+                let head = std::mem::take(head);
+                self.state = if is_gzip(&head) {
+                    InflateState::Gzip(GzWriteDecoder::new(Vec::new()))
+                } else {
+                    InflateState::Plain(Vec::new())
+                };
+                self.push(&head)
+            }
+            InflateState::Gzip(decoder) => {
+                for step in chunk.chunks(INFLATE_STEP_BYTES) {
+                    decoder.write_all(step).map_err(invalid_gzip)?;
+                    self.consumed += step.len();
+                    let output = self.taken + decoder.get_ref().len();
+                    if let Err(e) = check_inflated(output, self.consumed, &self.limits) {
+                        self.state = InflateState::Plain(Vec::new());
+                        return Err(e);
+                    }
+                }
+                Ok(())
+            }
+            InflateState::Plain(out) => {
+                out.extend_from_slice(chunk);
+                Ok(())
+            }
+        }
+    }
+
+    /// Whether the body turned out to be gzipped.
+    pub(crate) fn is_gzip(&self) -> bool {
+        matches!(self.state, InflateState::Gzip(_))
+    }
+
+    /// Whether the body turned out not to be gzipped.
+    pub(crate) fn is_plain(&self) -> bool {
+        matches!(self.state, InflateState::Plain(_))
+    }
+
+    /// Bytes held that `take_output` hasn't returned: the first bytes while
+    /// it is undecided whether the body is gzipped, else decoded output.
+    pub(crate) fn pending_len(&self) -> usize {
+        match &self.state {
+            InflateState::Undecided(head) => head.len(),
+            InflateState::Gzip(decoder) => decoder.get_ref().len(),
+            InflateState::Plain(out) => out.len(),
+        }
+    }
+
+    /// The output decoded since the last call, for a caller that consumes it
+    /// as it arrives. It still counts towards the limits.
+    pub(crate) fn take_output(&mut self) -> Vec<u8> {
+        let out = match &mut self.state {
+            InflateState::Undecided(_) => return Vec::new(),
+            InflateState::Gzip(decoder) => std::mem::take(decoder.get_mut()),
+            InflateState::Plain(out) => std::mem::take(out),
+        };
+        self.taken += out.len();
+        out
+    }
+
+    pub(crate) fn finish(self) -> Result<Vec<u8>, ParseError> {
+        match self.state {
+            InflateState::Undecided(out) | InflateState::Plain(out) => Ok(out),
+            InflateState::Gzip(decoder) => {
+                let out = decoder.finish().map_err(invalid_gzip)?;
+                check_inflated(self.taken + out.len(), self.consumed, &self.limits)?;
+                Ok(out)
+            }
+        }
+    }
+}
+
+fn invalid_gzip(e: std::io::Error) -> ParseError {
+    ParseError {
+        kind: "invalid_gzip",
+        message: format!("Invalid gzip data: {e}"),
+    }
+}
+
+fn check_inflated(output: usize, input: usize, limits: &InflateLimits) -> Result<(), ParseError> {
+    let bomb = |message: String| ParseError {
+        kind: "decompression_bomb",
+        message,
+    };
+    if output > limits.max_output {
+        return Err(bomb(format!(
+            "Decompressed data exceeds the limit of {} bytes",
+            limits.max_output
+        )));
+    }
+    match limits.max_ratio {
+        Some(max_ratio) if output >= RATIO_CHECK_MIN_BYTES && output as f64 > input as f64 * max_ratio => {
+            Err(bomb(format!(
+                "Compression ratio exceeds {max_ratio}:1 ({input} bytes inflated to at least {output})"
+            )))
+        }
+        _ => Ok(()),
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum InputCompression {
+    /// Inflate input that starts with the gzip magic bytes.
+    Auto,
+    Gzip,
+    None,
+}
+
+impl InputCompression {
+    pub(crate) fn parse(name: &str) -> Option<InputCompression> {
+        match name {
+            "auto" => Some(InputCompression::Auto),
+            "gzip" => Some(InputCompression::Gzip),
+            "none" => Some(InputCompression::None),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn name(&self) -> &'static str {
+        match self {
+            InputCompression::Auto => "auto",
+            InputCompression::Gzip => "gzip",
+            InputCompression::None => "none",
+        }
+    }
+}
+
+pub(crate) fn is_gzip(data: &[u8]) -> bool {
+    data.starts_with(&[0x1f, 0x8b])
+}
+
+/// Input bytes after applying `compression`, borrowed when nothing needed
+/// inflating. Inflating stops with `decompression_bomb` past the engine's
+/// input size limit.
+pub(crate) fn decode_input(
+    data: &[u8],
+    compression: InputCompression,
+) -> Result<Cow<'_, [u8]>, ParseError> {
+    match compression {
+        InputCompression::Gzip => gunzip_limited(data, MAX_INPUT_BYTES).map(Cow::Owned),
+        InputCompression::Auto if is_gzip(data) => {
+            gunzip_limited(data, MAX_INPUT_BYTES).map(Cow::Owned)
+        }
+        _ => Ok(Cow::Borrowed(data)),
+    }
+}
+
+fn gunzip_limited(data: &[u8], max_output: usize) -> Result<Vec<u8>, ParseError> {
+    let mut out = Vec::new();
+    GzDecoder::new(data)
+        .take(max_output as u64 + 1)
+        .read_to_end(&mut out)
+        .map_err(invalid_gzip)?;
+    check_inflated(
+        out.len(),
+        data.len(),
+        &InflateLimits {
+            max_output,
+            max_ratio: None,
+        },
+    )?;
+    Ok(out)
+}
+
+/// Whether a body is likely already compressed, so compressing it again
+/// would cost time without saving space.
+pub(crate) fn is_precompressed(content_type: Option<&str>, url: &str) -> bool {
+    const TYPES: [&str; 8] = [
+        "image/",
+        "video/",
+        "audio/",
+        "application/zip",
+        "application/gzip",
+        "application/x-gzip",
+        "application/zstd",
+        "parquet",
+    ];
+    const EXTENSIONS: [&str; 6] = [".gz", ".zip", ".zst", ".parquet", ".png", ".jpg"];
+
+    let content_type = content_type.unwrap_or_default().to_ascii_lowercase();
+    if TYPES.iter().any(|t| content_type.contains(t)) {
+        return true;
+    }
+    let path = url
+        .split(['?', '#'])
+        .next()
+        .unwrap_or(url)
+        .to_ascii_lowercase();
+    EXTENSIONS.iter().any(|ext| path.ends_with(ext))
+}
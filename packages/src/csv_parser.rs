@@ -0,0 +1,277 @@
+use crate::utils::ParseError;
+use serde_json::{Map, Number, Value};
+
+// CSV input (RFC 4180) with a header row. Quoted fields may contain
+// delimiters, doubled quotes, and line breaks.
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum RaggedPolicy {
+    /// Reject rows whose field count differs from the header.
+    Error,
+    /// Fill missing trailing fields with null.
+    Pad,
+    /// Drop fields beyond the header's width.
+    Truncate,
+}
+
+impl RaggedPolicy {
+    pub(crate) fn parse(policy: &str) -> Option<RaggedPolicy> {
+        match policy {
+            "error" => Some(RaggedPolicy::Error),
+            "pad" => Some(RaggedPolicy::Pad),
+            "truncate" => Some(RaggedPolicy::Truncate),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn name(&self) -> &'static str {
+        match self {
+            RaggedPolicy::Error => "error",
+            RaggedPolicy::Pad => "pad",
+            RaggedPolicy::Truncate => "truncate",
+        }
+    }
+}
+
+/// Separators for locale-formatted numbers such as `1.234,56`.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct NumberLocale {
+    pub decimal: char,
+    pub thousands: Option<char>,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct CsvOptions {
+    pub ragged: RaggedPolicy,
+    pub number_locale: Option<NumberLocale>,
+}
+
+impl Default for CsvOptions {
+    fn default() -> CsvOptions {
+        CsvOptions {
+            ragged: RaggedPolicy::Error,
+            number_locale: None,
+        }
+    }
+}
+
+pub(crate) struct CsvRecord {
+    /// 1-based line on which the record starts.
+    pub line: usize,
+    pub fields: Vec<String>,
+}
+
+pub(crate) fn parse_records(text: &str) -> Result<Vec<CsvRecord>, ParseError> {
+    parse_records_at(text, 1).map(|(records, _)| records)
+}
+
+/// `parse_records` for text starting on line `first_line`, also returning
+/// the line after the last one read.
+pub(crate) fn parse_records_at(
+    text: &str,
+    first_line: usize,
+) -> Result<(Vec<CsvRecord>, usize), ParseError> {
+    let mut records = Vec::new();
+    let mut fields = Vec::new();
+    let mut field = String::new();
+    let mut in_quotes = false;
+    let mut line = first_line;
+    let mut record_line = first_line;
+    let mut chars = text.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        if in_quotes {
+            match c {
+                '"' if chars.peek() == Some(&'"') => {
+                    chars.next();
+                    field.push('"');
+                }
+                '"' => in_quotes = false,
+                '\n' => {
+                    line += 1;
+                    field.push(c);
+                }
+                _ => field.push(c),
+            }
+            continue;
+        }
+
+        match c {
+            '"' if field.is_empty() => in_quotes = true,
+            ',' => fields.push(std::mem::take(&mut field)),
+            '\r' | '\n' => {
+                if c == '\r' && chars.peek() == Some(&'\n') {
+                    chars.next();
+                }
+                fields.push(std::mem::take(&mut field));
+                push_record(&mut records, std::mem::take(&mut fields), record_line);
+                line += 1;
+                record_line = line;
+            }
+            _ => field.push(c),
+        }
+    }
+
+    if in_quotes {
+        return Err(ParseError {
+            kind: "invalid_csv",
+            message: format!("Unterminated quoted field starting on line {record_line}"),
+        });
+    }
+
+    if !field.is_empty() || !fields.is_empty() {
+        fields.push(field);
+        push_record(&mut records, fields, record_line);
+    }
+
+    Ok((records, line))
+}
+
+// Blank lines don't produce records
+fn push_record(records: &mut Vec<CsvRecord>, fields: Vec<String>, line: usize) {
+    if fields.len() == 1 && fields[0].is_empty() {
+        return;
+    }
+    records.push(CsvRecord { line, fields });
+}
+
+/// Coerce a CSV field to a JSON value: empty -> null, true/false -> bool,
+/// numeric text -> number, anything else stays a string.
+pub(crate) fn coerce_value(field: &str, locale: Option<&NumberLocale>) -> Value {
+    if field.is_empty() {
+        return Value::Null;
+    }
+    if let Some(locale) = locale {
+        if field.contains(locale.decimal) || locale.thousands.is_some_and(|t| field.contains(t)) {
+            return parse_localized(field, locale)
+                .unwrap_or_else(|| Value::String(field.to_string()));
+        }
+    }
+    match field {
+        "true" => return Value::Bool(true),
+        "false" => return Value::Bool(false),
+        _ => {}
+    }
+    if let Ok(i) = field.parse::<i64>() {
+        return Value::Number(i.into());
+    }
+    match field.parse::<f64>().ok().and_then(Number::from_f64) {
+        Some(n) if !field.contains(|c: char| c.is_ascii_alphabetic()) => Value::Number(n),
+        _ => Value::String(field.to_string()),
+    }
+}
+
+// A number written with `locale`'s separators: optional sign, digits grouped
+// in threes if thousands separators are used, and an optional decimal part.
+// Anything else (e.g. `1.5` when `.` groups thousands) is ambiguous -> None.
+fn parse_localized(field: &str, locale: &NumberLocale) -> Option<Value> {
+    let all_digits = |s: &str| !s.is_empty() && s.bytes().all(|b| b.is_ascii_digit());
+
+    let (sign, rest) = match field.strip_prefix('-') {
+        Some(rest) => ("-", rest),
+        None => ("", field.strip_prefix('+').unwrap_or(field)),
+    };
+    let (integer, fraction) = match rest.split_once(locale.decimal) {
+        Some((integer, fraction)) => (integer, Some(fraction)),
+        None => (rest, None),
+    };
+
+    let mut digits = String::from(sign);
+    match locale.thousands.filter(|&t| integer.contains(t)) {
+        Some(separator) => {
+            for (i, group) in integer.split(separator).enumerate() {
+                let valid = if i == 0 {
+                    group.len() <= 3
+                } else {
+                    group.len() == 3
+                };
+                if !valid || !all_digits(group) {
+                    return None;
+                }
+                digits.push_str(group);
+            }
+        }
+        None if all_digits(integer) => digits.push_str(integer),
+        None => return None,
+    }
+
+    let Some(fraction) = fraction else {
+        return digits.parse::<i64>().ok().map(|i| Value::Number(i.into()));
+    };
+    if !all_digits(fraction) {
+        return None;
+    }
+    digits.push('.');
+    digits.push_str(fraction);
+    digits
+        .parse::<f64>()
+        .ok()
+        .and_then(Number::from_f64)
+        .map(Value::Number)
+}
+
+fn check_width(record: &CsvRecord, width: usize, ragged: RaggedPolicy) -> Result<(), ParseError> {
+    let found = record.fields.len();
+    let allowed = match ragged {
+        _ if found == width => true,
+        RaggedPolicy::Pad => found < width,
+        RaggedPolicy::Truncate => found > width,
+        RaggedPolicy::Error => false,
+    };
+    if allowed {
+        return Ok(());
+    }
+    Err(ParseError {
+        kind: "ragged_row",
+        message: format!(
+            "Line {}: expected {width} fields, found {found}",
+            record.line
+        ),
+    })
+}
+
+/// Number of data rows `parse_csv` would return, without building them.
+pub(crate) fn count_csv(text: &str, options: &CsvOptions) -> Result<usize, ParseError> {
+    let mut records = parse_records(text)?.into_iter();
+    let Some(header) = records.next() else {
+        return Ok(0);
+    };
+    let width = header.fields.len();
+    records.try_fold(0, |count, record| {
+        check_width(&record, width, options.ragged).map(|_| count + 1)
+    })
+}
+
+/// Parse CSV text into one object per data row, keyed by the header row.
+pub(crate) fn parse_csv(text: &str, options: &CsvOptions) -> Result<Vec<Value>, ParseError> {
+    let mut records = parse_records(text)?.into_iter();
+    let Some(header) = records.next() else {
+        return Ok(Vec::new());
+    };
+    let columns = header.fields;
+
+    records
+        .map(|record| record_to_row(record, &columns, options))
+        .collect()
+}
+
+/// One data record as an object keyed by `columns`, the header's fields.
+pub(crate) fn record_to_row(
+    mut record: CsvRecord,
+    columns: &[String],
+    options: &CsvOptions,
+) -> Result<Value, ParseError> {
+    check_width(&record, columns.len(), options.ragged)?;
+    record.fields.truncate(columns.len());
+
+    let mut obj = Map::new();
+    for (i, column) in columns.iter().enumerate() {
+        let value = record
+            .fields
+            .get(i)
+            .map(|f| coerce_value(f, options.number_locale.as_ref()))
+            .unwrap_or(Value::Null);
+        obj.insert(column.clone(), value);
+    }
+    Ok(Value::Object(obj))
+}
@@ -0,0 +1,500 @@
+use serde::{Deserialize, Serialize, Serializer};
+use serde_json::{Number, Value};
+
+// Helpers for serializing result rows to text formats (JSON, CSV)
+
+pub(crate) fn format_number(number: &Number, float_precision: Option<usize>) -> String {
+    match (number.as_f64(), float_precision) {
+        (Some(value), Some(digits)) if number.is_f64() && value.is_finite() => {
+            format!("{value:.digits$}")
+        }
+        _ => number.to_string(),
+    }
+}
+
+pub(crate) fn write_json(out: &mut String, value: &Value, float_precision: Option<usize>) {
+    match value {
+        Value::Number(number) => out.push_str(&format_number(number, float_precision)),
+        Value::Array(items) => {
+            out.push('[');
+            for (i, item) in items.iter().enumerate() {
+                if i > 0 {
+                    out.push(',');
+                }
+                write_json(out, item, float_precision);
+            }
+            out.push(']');
+        }
+        Value::Object(map) => {
+            out.push('{');
+            for (i, (key, item)) in map.iter().enumerate() {
+                if i > 0 {
+                    out.push(',');
+                }
+                out.push_str(&Value::String(key.clone()).to_string());
+                out.push(':');
+                write_json(out, item, float_precision);
+            }
+            out.push('}');
+        }
+        other => out.push_str(&other.to_string()),
+    }
+}
+
+pub(crate) fn rows_to_json(rows: &[Value], float_precision: Option<usize>) -> String {
+    let mut out = String::new();
+    write_rows_chunked(
+        rows,
+        ExportFormat::Json,
+        float_precision,
+        &NullRepresentation::default(),
+        usize::MAX,
+        |chunk| out.push_str(&chunk),
+    );
+    out
+}
+
+/// RFC 8785 (JCS) canonical JSON: keys sorted by UTF-16 code units, numbers
+/// in ECMAScript shortest form, no whitespace.
+pub(crate) fn rows_to_canonical_json(rows: &[Value]) -> String {
+    let mut out = String::new();
+    out.push('[');
+    for (i, row) in rows.iter().enumerate() {
+        if i > 0 {
+            out.push(',');
+        }
+        write_canonical_json(&mut out, row);
+    }
+    out.push(']');
+    out
+}
+
+/// Hex SHA-256 of the rows' canonical JSON (as `rows_to_canonical_json`),
+/// leaving `skip_key` out of object rows. Hashed a row at a time, so the full
+/// document is never built.
+pub(crate) fn content_hash(rows: &[Value], skip_key: Option<&str>) -> String {
+    use sha2::{Digest, Sha256};
+
+    let mut hasher = Sha256::new();
+    let mut out = String::new();
+    hasher.update(b"[");
+    for (i, row) in rows.iter().enumerate() {
+        out.clear();
+        if i > 0 {
+            out.push(',');
+        }
+        match (row, skip_key) {
+            (Value::Object(obj), Some(key)) if obj.contains_key(key) => {
+                let mut trimmed = obj.clone();
+                trimmed.remove(key);
+                write_canonical_json(&mut out, &Value::Object(trimmed));
+            }
+            _ => write_canonical_json(&mut out, row),
+        }
+        hasher.update(out.as_bytes());
+    }
+    hasher.update(b"]");
+
+    hasher
+        .finalize()
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect()
+}
+
+fn write_canonical_json(out: &mut String, value: &Value) {
+    match value {
+        Value::Number(number) => out.push_str(&es_number(number.as_f64().unwrap_or_default())),
+        Value::Array(items) => {
+            out.push('[');
+            for (i, item) in items.iter().enumerate() {
+                if i > 0 {
+                    out.push(',');
+                }
+                write_canonical_json(out, item);
+            }
+            out.push(']');
+        }
+        Value::Object(map) => {
+            let mut entries: Vec<(&String, &Value)> = map.iter().collect();
+            entries.sort_by(|(a, _), (b, _)| a.encode_utf16().cmp(b.encode_utf16()));
+            out.push('{');
+            for (i, (key, item)) in entries.into_iter().enumerate() {
+                if i > 0 {
+                    out.push(',');
+                }
+                out.push_str(&Value::String(key.clone()).to_string());
+                out.push(':');
+                write_canonical_json(out, item);
+            }
+            out.push('}');
+        }
+        other => out.push_str(&other.to_string()),
+    }
+}
+
+// ECMAScript Number::toString, which JCS uses for all numbers. Rust's `{:e}`
+// gives the shortest round-tripping digits; only the layout differs.
+pub(crate) fn es_number(value: f64) -> String {
+    if value == 0.0 {
+        return "0".to_string();
+    }
+
+    let scientific = format!("{:e}", value.abs());
+    let (mantissa, exponent) = scientific.split_once('e').unwrap_or((&scientific, "0"));
+    let digits: String = mantissa.chars().filter(|c| *c != '.').collect();
+    let k = digits.len() as i32;
+    let n = exponent.parse::<i32>().unwrap_or(0) + 1;
+
+    let body = if k <= n && n <= 21 {
+        format!("{digits}{}", "0".repeat((n - k) as usize))
+    } else if 0 < n && n <= 21 {
+        format!("{}.{}", &digits[..n as usize], &digits[n as usize..])
+    } else if -6 < n && n <= 0 {
+        format!("0.{}{digits}", "0".repeat(-n as usize))
+    } else {
+        let sign = if n < 1 { '-' } else { '+' };
+        let fraction = if k > 1 {
+            format!(".{}", &digits[1..])
+        } else {
+            String::new()
+        };
+        format!("{}{fraction}e{sign}{}", &digits[..1], (n - 1).abs())
+    };
+
+    if value < 0.0 {
+        format!("-{body}")
+    } else {
+        body
+    }
+}
+
+// Column order is the order in which keys are first seen across rows.
+// Rows that aren't objects are exported under a single "value" column.
+pub(crate) fn row_cell<'a>(row: &'a Value, column: &str) -> Option<&'a Value> {
+    match row.as_object() {
+        Some(obj) => obj.get(column),
+        None if column == "value" => Some(row),
+        None => None,
+    }
+}
+
+/// Number of distinct columns `collect_columns` would return, without the
+/// quadratic lookups, for checking limits before collecting.
+pub(crate) fn count_columns(rows: &[Value]) -> usize {
+    let mut columns = std::collections::HashSet::new();
+    for row in rows {
+        match row.as_object() {
+            Some(obj) => columns.extend(obj.keys().map(String::as_str)),
+            None => {
+                columns.insert("value");
+            }
+        }
+    }
+    columns.len()
+}
+
+pub(crate) fn collect_columns(rows: &[Value]) -> Vec<String> {
+    let mut columns: Vec<String> = Vec::new();
+    for row in rows {
+        match row.as_object() {
+            Some(obj) => {
+                for key in obj.keys() {
+                    if !columns.contains(key) {
+                        columns.push(key.clone());
+                    }
+                }
+            }
+            None => {
+                if !columns.iter().any(|c| c == "value") {
+                    columns.push("value".to_string());
+                }
+            }
+        }
+    }
+    columns
+}
+
+/// CSV text written for a null value and for a key the row doesn't have.
+/// Both are empty by default.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub(crate) struct NullRepresentation {
+    pub null: String,
+    pub missing: String,
+}
+
+fn csv_cell(
+    value: Option<&Value>,
+    float_precision: Option<usize>,
+    nulls: &NullRepresentation,
+) -> String {
+    match value {
+        None => nulls.missing.clone(),
+        Some(Value::Null) => nulls.null.clone(),
+        Some(Value::String(s)) => s.clone(),
+        Some(Value::Number(n)) => format_number(n, float_precision),
+        Some(Value::Bool(b)) => b.to_string(),
+        Some(nested) => {
+            let mut out = String::new();
+            write_json(&mut out, nested, float_precision);
+            out
+        }
+    }
+}
+
+// RFC 4180: fields containing a delimiter, quote, or line break are quoted,
+// with embedded quotes doubled. Nested objects/arrays arrive here as compact
+// JSON and are quoted by the same rule.
+fn escape_csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+pub(crate) fn rows_to_csv(
+    rows: &[Value],
+    float_precision: Option<usize>,
+    nulls: &NullRepresentation,
+) -> String {
+    let mut out = String::new();
+    write_rows_chunked(
+        rows,
+        ExportFormat::Csv,
+        float_precision,
+        nulls,
+        usize::MAX,
+        |chunk| out.push_str(&chunk),
+    );
+    out
+}
+
+fn write_csv_row(
+    out: &mut String,
+    row: &Value,
+    columns: &[String],
+    float_precision: Option<usize>,
+    nulls: &NullRepresentation,
+) {
+    let fields: Vec<String> = columns
+        .iter()
+        .map(|column| escape_csv_field(&csv_cell(row_cell(row, column), float_precision, nulls)))
+        .collect();
+    out.push_str(&fields.join(","));
+    out.push('\n');
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub(crate) enum ExportFormat {
+    Json,
+    Csv,
+}
+
+impl ExportFormat {
+    pub(crate) fn parse(format: &str) -> Option<ExportFormat> {
+        match format {
+            "json" => Some(ExportFormat::Json),
+            "csv" => Some(ExportFormat::Csv),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn content_type(&self) -> &'static str {
+        match self {
+            ExportFormat::Json => "application/json",
+            ExportFormat::Csv => "text/csv",
+        }
+    }
+}
+
+/// Serialize `rows` in `format`, handing the output to `emit` in pieces of
+/// roughly `chunk_rows` rows so the full document is never held at once.
+/// Concatenating the pieces gives the same text as `rows_to_json`/`rows_to_csv`.
+/// `nulls` only applies to CSV.
+pub(crate) fn write_rows_chunked(
+    rows: &[Value],
+    format: ExportFormat,
+    float_precision: Option<usize>,
+    nulls: &NullRepresentation,
+    chunk_rows: usize,
+    mut emit: impl FnMut(String),
+) {
+    let chunk_rows = chunk_rows.max(1);
+    let mut out = String::new();
+
+    match format {
+        ExportFormat::Json => {
+            out.push('[');
+            for (i, row) in rows.iter().enumerate() {
+                if i > 0 {
+                    out.push(',');
+                }
+                write_json(&mut out, row, float_precision);
+                if (i + 1) % chunk_rows == 0 {
+                    emit(std::mem::take(&mut out));
+                }
+            }
+            out.push(']');
+        }
+        ExportFormat::Csv => {
+            let columns = collect_columns(rows);
+            let header: Vec<String> = columns.iter().map(|c| escape_csv_field(c)).collect();
+            out.push_str(&header.join(","));
+            out.push('\n');
+
+            for (i, row) in rows.iter().enumerate() {
+                write_csv_row(&mut out, row, &columns, float_precision, nulls);
+                if (i + 1) % chunk_rows == 0 {
+                    emit(std::mem::take(&mut out));
+                }
+            }
+        }
+    }
+
+    if !out.is_empty() {
+        emit(out);
+    }
+}
+
+pub(crate) const MAX_SAFE_INTEGER: u64 = 9_007_199_254_740_991;
+
+pub(crate) fn is_unsafe_integer(number: &Number) -> bool {
+    match (number.as_u64(), number.as_i64()) {
+        (Some(u), _) => u > MAX_SAFE_INTEGER,
+        (None, Some(i)) => i < -(MAX_SAFE_INTEGER as i64),
+        _ => false,
+    }
+}
+
+pub(crate) fn count_unsafe_integers(value: &Value) -> u32 {
+    match value {
+        Value::Number(number) => is_unsafe_integer(number) as u32,
+        Value::Array(items) => items.iter().map(count_unsafe_integers).sum(),
+        Value::Object(map) => map.values().map(count_unsafe_integers).sum(),
+        _ => 0,
+    }
+}
+
+// Serializes integers outside the JS safe range through `serialize_u64`/
+// `serialize_i64` (BigInt when the serializer is configured for it) and every
+// other number as a plain f64.
+pub(crate) struct BigIntSafe<'a>(pub &'a Value);
+
+impl Serialize for BigIntSafe<'_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self.0 {
+            Value::Number(number) if is_unsafe_integer(number) => match number.as_u64() {
+                Some(u) => serializer.serialize_u64(u),
+                None => serializer.serialize_i64(number.as_i64().unwrap_or_default()),
+            },
+            Value::Number(number) => serializer.serialize_f64(number.as_f64().unwrap_or_default()),
+            Value::Array(items) => serializer.collect_seq(items.iter().map(BigIntSafe)),
+            Value::Object(map) => {
+                serializer.collect_map(map.iter().map(|(key, item)| (key, BigIntSafe(item))))
+            }
+            other => other.serialize(serializer),
+        }
+    }
+}
+
+pub(crate) const MISSING_KEY_BUCKET: &str = "__missing__";
+
+// Key for a row under `set_output_key`; rows without the field (or with null)
+// have none and go to the `__missing__` bucket.
+pub(crate) fn output_key(row: &Value, field: &str) -> Option<String> {
+    row.get(field)
+        .filter(|v| !v.is_null())
+        .map(crate::transforms::value_key)
+}
+
+/// Number of entries `KeyedRows` produces: distinct keys, plus the
+/// `__missing__` bucket if any row lacks the key.
+pub(crate) fn keyed_entry_count(rows: &[Value], field: &str) -> usize {
+    let mut keys = std::collections::HashSet::new();
+    let mut missing = false;
+    for row in rows {
+        match output_key(row, field) {
+            Some(key) => {
+                keys.insert(key);
+            }
+            None => missing = true,
+        }
+    }
+    keys.len() + missing as usize
+}
+
+// Rows serialized as an object keyed by `field`, in first-seen key order with
+// the last row winning for repeated keys.
+pub(crate) struct KeyedRows<'a> {
+    pub rows: &'a [Value],
+    pub field: &'a str,
+    pub bigint: bool,
+}
+
+struct RowRef<'a>(&'a Value, bool);
+
+impl Serialize for RowRef<'_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        if self.1 {
+            BigIntSafe(self.0).serialize(serializer)
+        } else {
+            self.0.serialize(serializer)
+        }
+    }
+}
+
+// A row with `field` serialized as its first key; objects otherwise keep
+// their sorted key order.
+pub(crate) struct IndexFirst<'a> {
+    pub row: &'a Value,
+    pub field: &'a str,
+    pub bigint: bool,
+}
+
+impl Serialize for IndexFirst<'_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        use serde::ser::SerializeMap;
+
+        let Value::Object(obj) = self.row else {
+            return RowRef(self.row, self.bigint).serialize(serializer);
+        };
+        let mut map = serializer.serialize_map(Some(obj.len()))?;
+        if let Some(index) = obj.get(self.field) {
+            map.serialize_entry(self.field, &RowRef(index, self.bigint))?;
+        }
+        for (key, value) in obj.iter().filter(|(key, _)| *key != self.field) {
+            map.serialize_entry(key, &RowRef(value, self.bigint))?;
+        }
+        map.end()
+    }
+}
+
+impl Serialize for KeyedRows<'_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        use serde::ser::SerializeMap;
+
+        let mut order: Vec<String> = Vec::new();
+        let mut latest: std::collections::HashMap<String, &Value> = Default::default();
+        let mut missing: Vec<RowRef> = Vec::new();
+        for row in self.rows {
+            match output_key(row, self.field) {
+                Some(key) => {
+                    if latest.insert(key.clone(), row).is_none() {
+                        order.push(key);
+                    }
+                }
+                None => missing.push(RowRef(row, self.bigint)),
+            }
+        }
+
+        let mut map = serializer.serialize_map(Some(order.len() + !missing.is_empty() as usize))?;
+        for key in &order {
+            map.serialize_entry(key, &RowRef(latest[key], self.bigint))?;
+        }
+        if !missing.is_empty() {
+            map.serialize_entry(MISSING_KEY_BUCKET, &missing)?;
+        }
+        map.end()
+    }
+}
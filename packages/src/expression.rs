@@ -0,0 +1,158 @@
+use serde_json::Value;
+
+// Arithmetic expressions over numeric row fields, used for computed columns:
+// `+ - * /`, unary minus, parentheses, numeric literals and field references.
+
+#[derive(Debug, PartialEq)]
+pub(crate) enum Expr {
+    Number(f64),
+    Field(String),
+    Neg(Box<Expr>),
+    Binary(Box<Expr>, char, Box<Expr>),
+}
+
+impl Expr {
+    /// Field names referenced by the expression, in order of appearance.
+    pub(crate) fn fields(&self) -> Vec<&str> {
+        let mut fields = Vec::new();
+        self.collect_fields(&mut fields);
+        fields
+    }
+
+    fn collect_fields<'a>(&'a self, fields: &mut Vec<&'a str>) {
+        match self {
+            Expr::Number(_) => {}
+            Expr::Field(name) => fields.push(name),
+            Expr::Neg(inner) => inner.collect_fields(fields),
+            Expr::Binary(lhs, _, rhs) => {
+                lhs.collect_fields(fields);
+                rhs.collect_fields(fields);
+            }
+        }
+    }
+
+    /// Evaluate against a row. Missing or non-numeric fields yield `None`, as
+    /// does division by zero, which also sets `divided_by_zero`.
+    pub(crate) fn eval(&self, row: &Value, divided_by_zero: &mut bool) -> Option<f64> {
+        match self {
+            Expr::Number(n) => Some(*n),
+            Expr::Field(name) => row.get(name).and_then(Value::as_f64),
+            Expr::Neg(inner) => inner.eval(row, divided_by_zero).map(|n| -n),
+            Expr::Binary(lhs, op, rhs) => {
+                let lhs = lhs.eval(row, divided_by_zero);
+                let rhs = rhs.eval(row, divided_by_zero);
+                let (lhs, rhs) = (lhs?, rhs?);
+                match op {
+                    '+' => Some(lhs + rhs),
+                    '-' => Some(lhs - rhs),
+                    '*' => Some(lhs * rhs),
+                    _ if rhs == 0.0 => {
+                        *divided_by_zero = true;
+                        None
+                    }
+                    _ => Some(lhs / rhs),
+                }
+            }
+        }
+    }
+}
+
+pub(crate) fn parse_expression(source: &str) -> Result<Expr, String> {
+    let mut parser = Parser {
+        chars: source.chars().collect(),
+        pos: 0,
+    };
+    let expr = parser.expr()?;
+    parser.skip_whitespace();
+    match parser.peek() {
+        None => Ok(expr),
+        Some(c) => Err(format!("Unexpected '{c}' at position {}", parser.pos)),
+    }
+}
+
+struct Parser {
+    chars: Vec<char>,
+    pos: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.pos).copied()
+    }
+
+    fn skip_whitespace(&mut self) {
+        while self.peek().is_some_and(char::is_whitespace) {
+            self.pos += 1;
+        }
+    }
+
+    // expr := term (('+' | '-') term)*
+    fn expr(&mut self) -> Result<Expr, String> {
+        let mut lhs = self.term()?;
+        loop {
+            self.skip_whitespace();
+            match self.peek() {
+                Some(op @ ('+' | '-')) => {
+                    self.pos += 1;
+                    lhs = Expr::Binary(Box::new(lhs), op, Box::new(self.term()?));
+                }
+                _ => return Ok(lhs),
+            }
+        }
+    }
+
+    // term := factor (('*' | '/') factor)*
+    fn term(&mut self) -> Result<Expr, String> {
+        let mut lhs = self.factor()?;
+        loop {
+            self.skip_whitespace();
+            match self.peek() {
+                Some(op @ ('*' | '/')) => {
+                    self.pos += 1;
+                    lhs = Expr::Binary(Box::new(lhs), op, Box::new(self.factor()?));
+                }
+                _ => return Ok(lhs),
+            }
+        }
+    }
+
+    // factor := '-' factor | '(' expr ')' | number | field
+    fn factor(&mut self) -> Result<Expr, String> {
+        self.skip_whitespace();
+        let start = self.pos;
+        match self.peek() {
+            Some('-') => {
+                self.pos += 1;
+                Ok(Expr::Neg(Box::new(self.factor()?)))
+            }
+            Some('(') => {
+                self.pos += 1;
+                let inner = self.expr()?;
+                self.skip_whitespace();
+                if self.peek() != Some(')') {
+                    return Err(format!("Expected ')' at position {}", self.pos));
+                }
+                self.pos += 1;
+                Ok(inner)
+            }
+            Some(c) if c.is_ascii_digit() || c == '.' => {
+                while self.peek().is_some_and(|c| c.is_ascii_digit() || c == '.') {
+                    self.pos += 1;
+                }
+                let literal: String = self.chars[start..self.pos].iter().collect();
+                literal
+                    .parse()
+                    .map(Expr::Number)
+                    .map_err(|_| format!("Invalid number '{literal}' at position {start}"))
+            }
+            Some(c) if c.is_alphabetic() || c == '_' => {
+                while self.peek().is_some_and(|c| c.is_alphanumeric() || c == '_') {
+                    self.pos += 1;
+                }
+                Ok(Expr::Field(self.chars[start..self.pos].iter().collect()))
+            }
+            Some(c) => Err(format!("Unexpected '{c}' at position {start}")),
+            None => Err("Unexpected end of expression".to_string()),
+        }
+    }
+}
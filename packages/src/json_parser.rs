@@ -0,0 +1,269 @@
+use crate::utils::ParseError;
+use serde::de::{self, DeserializeSeed, MapAccess, SeqAccess, Visitor};
+use serde_json::{Map, Number, Value};
+use std::cell::Cell;
+use std::fmt;
+
+// JSON parsing with checks applied while the document is being read, rather
+// than in a walk over the parsed value afterwards.
+
+#[derive(Clone, Debug, Default)]
+pub(crate) struct JsonParseOptions {
+    pub reject_duplicate_keys: bool,
+    /// Deepest allowed array/object nesting; the top-level value is depth 1.
+    pub max_depth: Option<usize>,
+    /// Keys kept in each row object (empty keeps all); nested objects are
+    /// not filtered.
+    pub include_columns: Vec<String>,
+    /// Keys dropped from each row object.
+    pub exclude_columns: Vec<String>,
+}
+
+impl JsonParseOptions {
+    fn is_default(&self) -> bool {
+        !self.reject_duplicate_keys
+            && self.max_depth.is_none()
+            && self.include_columns.is_empty()
+            && self.exclude_columns.is_empty()
+    }
+
+    fn keeps_column(&self, key: &str) -> bool {
+        (self.include_columns.is_empty() || self.include_columns.iter().any(|c| c == key))
+            && !self.exclude_columns.iter().any(|c| c == key)
+    }
+}
+
+pub(crate) fn parse_json(bytes: &[u8], options: &JsonParseOptions) -> Result<Value, ParseError> {
+    if options.is_default() {
+        return serde_json::from_slice(bytes).map_err(|e| ParseError {
+            kind: "invalid_json",
+            message: format!("Invalid JSON data: {e}"),
+        });
+    }
+
+    let failure = Cell::new(None);
+    let seed = ValueSeed {
+        options,
+        failure: &failure,
+        depth: 0,
+        row: None,
+        is_row: true,
+    };
+
+    let mut deserializer = serde_json::Deserializer::from_slice(bytes);
+    seed.deserialize(&mut deserializer)
+        .and_then(|value| deserializer.end().map(|_| value))
+        .map_err(|e| match failure.get() {
+            Some(kind) => ParseError {
+                kind,
+                message: e.to_string(),
+            },
+            None => ParseError {
+                kind: "invalid_json",
+                message: format!("Invalid JSON data: {e}"),
+            },
+        })
+}
+
+/// Element count of a top-level JSON array (`None` for any other value),
+/// validated like `parse_json` but, with default options, without building
+/// the elements.
+pub(crate) fn count_json_array(
+    bytes: &[u8],
+    options: &JsonParseOptions,
+) -> Result<Option<usize>, ParseError> {
+    if !options.is_default() {
+        return parse_json(bytes, options).map(|v| v.as_array().map(Vec::len));
+    }
+
+    let mut deserializer = serde_json::Deserializer::from_slice(bytes);
+    de::Deserializer::deserialize_any(&mut deserializer, ElementCounter)
+        .and_then(|count| deserializer.end().map(|_| count))
+        .map_err(|e| ParseError {
+            kind: "invalid_json",
+            message: format!("Invalid JSON data: {e}"),
+        })
+}
+
+/// Check that `bytes` is one valid JSON value without keeping it.
+pub(crate) fn validate_json(bytes: &[u8], options: &JsonParseOptions) -> Result<(), ParseError> {
+    if !options.is_default() {
+        return parse_json(bytes, options).map(|_| ());
+    }
+    serde_json::from_slice::<de::IgnoredAny>(bytes)
+        .map(|_| ())
+        .map_err(|e| ParseError {
+            kind: "invalid_json",
+            message: format!("Invalid JSON data: {e}"),
+        })
+}
+
+struct ElementCounter;
+
+impl<'de> Visitor<'de> for ElementCounter {
+    type Value = Option<usize>;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("any JSON value")
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Option<usize>, A::Error> {
+        let mut count = 0;
+        while seq.next_element::<de::IgnoredAny>()?.is_some() {
+            count += 1;
+        }
+        Ok(Some(count))
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Option<usize>, A::Error> {
+        while map
+            .next_entry::<de::IgnoredAny, de::IgnoredAny>()?
+            .is_some()
+        {}
+        Ok(None)
+    }
+
+    fn visit_bool<E>(self, _: bool) -> Result<Option<usize>, E> {
+        Ok(None)
+    }
+
+    fn visit_i64<E>(self, _: i64) -> Result<Option<usize>, E> {
+        Ok(None)
+    }
+
+    fn visit_u64<E>(self, _: u64) -> Result<Option<usize>, E> {
+        Ok(None)
+    }
+
+    fn visit_f64<E>(self, _: f64) -> Result<Option<usize>, E> {
+        Ok(None)
+    }
+
+    fn visit_str<E>(self, _: &str) -> Result<Option<usize>, E> {
+        Ok(None)
+    }
+
+    fn visit_unit<E>(self) -> Result<Option<usize>, E> {
+        Ok(None)
+    }
+}
+
+#[derive(Clone, Copy)]
+struct ValueSeed<'a> {
+    options: &'a JsonParseOptions,
+    failure: &'a Cell<Option<&'static str>>,
+    depth: usize,
+    // Index of the enclosing element of a top-level array, for error messages.
+    row: Option<usize>,
+    // Whether this value is a row (the top-level value or an element of a
+    // top-level array), whose keys are subject to column filtering.
+    is_row: bool,
+}
+
+impl ValueSeed<'_> {
+    // Checked on entering a container, before any of its elements are read,
+    // so deep input is rejected without recursing further.
+    fn check_depth<E: de::Error>(&self) -> Result<(), E> {
+        match self.options.max_depth {
+            Some(max) if self.depth + 1 > max => Err(self.fail(
+                "max_depth_exceeded",
+                format!("JSON nesting exceeds maximum depth of {max}"),
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    fn fail<E: de::Error>(&self, kind: &'static str, message: String) -> E {
+        self.failure.set(Some(kind));
+        E::custom(message)
+    }
+
+    fn child(&self, row: Option<usize>) -> Self {
+        ValueSeed {
+            depth: self.depth + 1,
+            row: row.or(self.row),
+            is_row: row.is_some(),
+            ..*self
+        }
+    }
+}
+
+impl<'de> DeserializeSeed<'de> for ValueSeed<'_> {
+    type Value = Value;
+
+    fn deserialize<D: de::Deserializer<'de>>(self, deserializer: D) -> Result<Value, D::Error> {
+        deserializer.deserialize_any(self)
+    }
+}
+
+impl<'de> Visitor<'de> for ValueSeed<'_> {
+    type Value = Value;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("any JSON value")
+    }
+
+    fn visit_bool<E>(self, v: bool) -> Result<Value, E> {
+        Ok(Value::Bool(v))
+    }
+
+    fn visit_i64<E>(self, v: i64) -> Result<Value, E> {
+        Ok(Value::Number(v.into()))
+    }
+
+    fn visit_u64<E>(self, v: u64) -> Result<Value, E> {
+        Ok(Value::Number(v.into()))
+    }
+
+    fn visit_f64<E>(self, v: f64) -> Result<Value, E> {
+        Ok(Number::from_f64(v).map_or(Value::Null, Value::Number))
+    }
+
+    fn visit_str<E>(self, v: &str) -> Result<Value, E> {
+        Ok(Value::String(v.to_string()))
+    }
+
+    fn visit_string<E>(self, v: String) -> Result<Value, E> {
+        Ok(Value::String(v))
+    }
+
+    fn visit_unit<E>(self) -> Result<Value, E> {
+        Ok(Value::Null)
+    }
+
+    fn visit_none<E>(self) -> Result<Value, E> {
+        Ok(Value::Null)
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Value, A::Error> {
+        self.check_depth()?;
+        let mut items = Vec::new();
+        loop {
+            let row = (self.depth == 0).then_some(items.len());
+            match seq.next_element_seed(self.child(row))? {
+                Some(item) => items.push(item),
+                None => break,
+            }
+        }
+        Ok(Value::Array(items))
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Value, A::Error> {
+        self.check_depth()?;
+        let mut obj = Map::new();
+        while let Some(key) = map.next_key::<String>()? {
+            if self.options.reject_duplicate_keys && obj.contains_key(&key) {
+                let location = self.row.map(|r| format!(" in row {r}")).unwrap_or_default();
+                return Err(self.fail("duplicate_key", format!("Duplicate key '{key}'{location}")));
+            }
+            if self.is_row && !self.options.keeps_column(&key) {
+                // Skipped without building the value
+                map.next_value::<de::IgnoredAny>()?;
+                continue;
+            }
+            let value = map.next_value_seed(self.child(None))?;
+            obj.insert(key, value);
+        }
+        Ok(Value::Object(obj))
+    }
+}
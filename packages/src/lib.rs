@@ -1,13 +1,26 @@
+mod arrow_ipc;
 mod cloud_storage_bridge;
+mod compression;
+mod csv_parser;
+mod export;
+mod expression;
+mod json_parser;
+mod line_endings;
+mod log_parser;
 mod memory_manager;
 mod query_engine;
+mod schema;
+mod self_test;
+mod stream_parser;
+mod timestamp;
+mod transforms;
 mod utils;
 
 pub use cloud_storage_bridge::{
-    CloudDataBuffer, CloudDataRequest, CloudDataResponse, CloudStorageBridge,
+    CloudAbortHandle, CloudDataBuffer, CloudDataRequest, CloudDataResponse, CloudStorageBridge,
 };
 pub use memory_manager::MemoryManager;
-pub use query_engine::{QueryEngine, QueryResult};
+pub use query_engine::{QueryEngine, QueryResult, StreamAggregator};
 pub use utils::*;
 
 use wasm_bindgen::prelude::*;
@@ -20,7 +33,8 @@ extern "C" {
 
 #[wasm_bindgen]
 pub fn init_panic_hook() {
-    console_error_panic_hook::set_once();
+    static SET_HOOK: std::sync::Once = std::sync::Once::new();
+    SET_HOOK.call_once(|| std::panic::set_hook(Box::new(utils::record_panic)));
 }
 
 #[wasm_bindgen(start)]
@@ -35,6 +49,40 @@ pub fn get_version() -> String {
     env!("CARGO_PKG_VERSION").to_string()
 }
 
+/// Compare this build against the version a JS wrapper was written for:
+/// `{compatible, wasm_version, expected_version}`. Compatible means the WASM
+/// version satisfies `^expected_version` (same major, or same minor below
+/// 1.0, and not older). An unparseable version is reported as incompatible
+/// with an `error` message.
+#[wasm_bindgen]
+pub fn check_compatibility(expected_version: &str) -> JsValue {
+    let wasm_version = env!("CARGO_PKG_VERSION");
+    let mut report = serde_json::json!({
+        "compatible": false,
+        "wasm_version": wasm_version,
+        "expected_version": expected_version,
+    });
+    match (parse_version(wasm_version), parse_version(expected_version)) {
+        (Some(actual), Some(expected)) => {
+            report["compatible"] = is_version_compatible(actual, expected).into();
+        }
+        _ => {
+            report["error"] = format!("Invalid version '{expected_version}'").into();
+        }
+    }
+
+    serde_wasm_bindgen::to_value(&report).unwrap_or(JsValue::NULL)
+}
+
+/// Run a small canned dataset through parsing (JSON and CSV), a filter, a
+/// sort and serialization, and allocate and free a buffer, to confirm the
+/// module works independently of any caller's data. Returns
+/// `{ok, checks: [{name, passed, detail}]}`.
+#[wasm_bindgen]
+pub fn self_test() -> JsValue {
+    serde_wasm_bindgen::to_value(&self_test::run()).unwrap_or(JsValue::NULL)
+}
+
 #[wasm_bindgen]
 pub fn get_build_info() -> JsValue {
     let info = serde_json::json!({
@@ -49,7 +97,6 @@ pub fn get_build_info() -> JsValue {
 
 #[cfg(test)]
 mod tests {
-    #[cfg(target_arch = "wasm32")]
     use super::*;
     use wasm_bindgen_test::*;
 
@@ -95,4 +142,1698 @@ mod tests {
         let version = env!("CARGO_PKG_VERSION");
         assert!(!version.is_empty());
     }
+
+    #[test]
+    fn test_export_float_precision() {
+        let mut result = crate::QueryResult {
+            data: vec![serde_json::json!({"id": 1, "name": "a", "ratio": 0.1 + 0.2})],
+            row_count: 1,
+            execution_time_ms: 0,
+            memory_used_bytes: 0,
+            ..Default::default()
+        };
+        assert_eq!(result.to_csv(), "id,name,ratio\n1,a,0.30000000000000004\n");
+
+        result.float_precision = Some(2);
+        assert_eq!(result.to_csv(), "id,name,ratio\n1,a,0.30\n");
+        assert_eq!(
+            result.data_to_string(),
+            r#"[{"id":1,"name":"a","ratio":0.30}]"#
+        );
+    }
+
+    #[test]
+    fn test_pivot_sum_with_column_cap() {
+        let rows = vec![
+            serde_json::json!({"region": "east", "quarter": "q1", "sales": 10}),
+            serde_json::json!({"region": "east", "quarter": "q1", "sales": 5}),
+            serde_json::json!({"region": "west", "quarter": "q2", "sales": 7}),
+            serde_json::json!({"region": "west", "quarter": "q3", "sales": 1}),
+        ];
+
+        let (pivoted, warnings) =
+            crate::transforms::pivot(&rows, "region", "quarter", "sales", "sum", 2).unwrap();
+        assert_eq!(
+            pivoted,
+            vec![
+                serde_json::json!({"region": "east", "q1": 15.0, "q2": null}),
+                serde_json::json!({"region": "west", "q1": null, "q2": 7.0}),
+            ]
+        );
+        assert_eq!(warnings.len(), 1);
+
+        assert!(
+            crate::transforms::pivot(&rows, "region", "quarter", "sales", "median", 2).is_err()
+        );
+    }
+
+    #[test]
+    fn test_ndjson_stream_parser_split_records() {
+        let mut parser = crate::stream_parser::NdjsonStreamParser::new(Default::default());
+
+        assert_eq!(parser.push(b"{\"a\":1}\r\n{\"a\"").unwrap().len(), 1);
+        assert_eq!(
+            parser.push(b":2}\n\n").unwrap(),
+            vec![serde_json::json!({"a": 2})]
+        );
+        assert_eq!(parser.push(b"{\"a\":3}").unwrap().len(), 0);
+        assert_eq!(parser.finish().unwrap(), vec![serde_json::json!({"a": 3})]);
+
+        let mut parser = crate::stream_parser::NdjsonStreamParser::new(Default::default());
+        parser.push(b"{\"a\":1}\n").unwrap();
+        let error = parser.push(b"not json\n").unwrap_err();
+        assert_eq!(error.kind, "invalid_json");
+        assert!(error.message.starts_with("Line 2:"));
+    }
+
+    #[test]
+    fn test_reject_duplicate_keys() {
+        use crate::json_parser::{parse_json, JsonParseOptions};
+
+        let input = br#"[{"a": 1}, {"a": 1, "b": {"c": 1, "c": 2}}]"#;
+        assert!(parse_json(input, &JsonParseOptions::default()).is_ok());
+
+        let strict = JsonParseOptions {
+            reject_duplicate_keys: true,
+            ..Default::default()
+        };
+        let error = parse_json(input, &strict).unwrap_err();
+        assert_eq!(error.kind, "duplicate_key");
+        assert!(error.message.starts_with("Duplicate key 'c' in row 1"));
+    }
+
+    #[test]
+    fn test_stale_buffer_ids_are_rejected() {
+        let mut manager = MemoryManager::new();
+        let first = manager.allocate_buffer(8);
+        assert!(manager.write_buffer(first, b"abc"));
+        assert!(manager.deallocate_buffer(first));
+
+        // The freed slot is reused under a new generation
+        let second = manager.allocate_buffer(8);
+        assert_ne!(first, second);
+        assert!(!manager.is_valid_buffer(first));
+        assert!(!manager.write_buffer(first, b"stale"));
+        assert!(!manager.deallocate_buffer(first));
+        assert!(manager.is_valid_buffer(second));
+
+        manager.reset();
+        assert_eq!(manager.get_buffer_count(), 0);
+        assert!(!manager.is_valid_buffer(second));
+    }
+
+    #[test]
+    fn test_csv_quoting_round_trip() {
+        let result = crate::QueryResult {
+            data: vec![
+                serde_json::json!({"note": "he said \"hi\",\nbye", "tags": ["a", "b"]}),
+                serde_json::json!({"note": "line\r\nbreak", "tags": {"k": 1}}),
+            ],
+            ..Default::default()
+        };
+
+        let csv_text = result.to_csv();
+        let mut reader = csv::Reader::from_reader(csv_text.as_bytes());
+        assert_eq!(reader.headers().unwrap(), vec!["note", "tags"]);
+
+        let records: Vec<csv::StringRecord> = reader.records().map(|r| r.unwrap()).collect();
+        assert_eq!(records.len(), 2);
+        assert_eq!(&records[0][0], "he said \"hi\",\nbye");
+        assert_eq!(&records[0][1], r#"["a","b"]"#);
+        assert_eq!(&records[1][0], "line\r\nbreak");
+        assert_eq!(&records[1][1], r#"{"k":1}"#);
+    }
+
+    #[test]
+    fn test_csv_ragged_policy() {
+        use crate::csv_parser::{parse_csv, CsvOptions, RaggedPolicy};
+
+        let text = "a,b,c\n1,\"x,y\",true\n2,z\n3,w,false,extra\n";
+
+        let err = parse_csv(
+            text,
+            &CsvOptions {
+                ragged: RaggedPolicy::Error,
+                ..Default::default()
+            },
+        )
+        .unwrap_err();
+        assert_eq!(err.kind, "ragged_row");
+        assert_eq!(err.message, "Line 3: expected 3 fields, found 2");
+
+        let err = parse_csv(
+            text,
+            &CsvOptions {
+                ragged: RaggedPolicy::Pad,
+                ..Default::default()
+            },
+        )
+        .unwrap_err();
+        assert_eq!(err.message, "Line 4: expected 3 fields, found 4");
+
+        let rows = parse_csv(
+            "a,b,c\n1,\"x,y\",true\n2,z\n",
+            &CsvOptions {
+                ragged: RaggedPolicy::Pad,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        assert_eq!(rows[0], serde_json::json!({"a": 1, "b": "x,y", "c": true}));
+        assert_eq!(rows[1], serde_json::json!({"a": 2, "b": "z", "c": null}));
+
+        let rows = parse_csv(
+            "a,b\r\n3,w,false\r\n",
+            &CsvOptions {
+                ragged: RaggedPolicy::Truncate,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        assert_eq!(rows, vec![serde_json::json!({"a": 3, "b": "w"})]);
+    }
+
+    #[test]
+    fn test_computed_column_expression() {
+        use crate::expression::parse_expression;
+
+        let expr = parse_expression("(price - discount) * quantity / 2").unwrap();
+        assert_eq!(expr.fields(), vec!["price", "discount", "quantity"]);
+
+        let mut divided_by_zero = false;
+        let row = serde_json::json!({"price": 10, "discount": 2.5, "quantity": 4});
+        assert_eq!(expr.eval(&row, &mut divided_by_zero), Some(15.0));
+        assert!(!divided_by_zero);
+
+        let ratio = parse_expression("price / -quantity").unwrap();
+        let row = serde_json::json!({"price": 10, "quantity": 0});
+        assert_eq!(ratio.eval(&row, &mut divided_by_zero), None);
+        assert!(divided_by_zero);
+
+        assert!(parse_expression("price * (quantity").is_err());
+        assert!(parse_expression("price quantity").is_err());
+    }
+
+    #[test]
+    fn test_chunked_export_matches_whole() {
+        use crate::export::{
+            rows_to_csv, rows_to_json, write_rows_chunked, ExportFormat, NullRepresentation,
+        };
+
+        let rows: Vec<serde_json::Value> = (0..5)
+            .map(|i| serde_json::json!({"id": i, "name": format!("row,{i}")}))
+            .collect();
+
+        let mut chunks = Vec::new();
+        let nulls = NullRepresentation::default();
+        write_rows_chunked(&rows, ExportFormat::Json, None, &nulls, 2, |c| {
+            chunks.push(c)
+        });
+        assert_eq!(chunks.len(), 3);
+        assert_eq!(chunks.concat(), rows_to_json(&rows, None));
+
+        let mut chunks = Vec::new();
+        write_rows_chunked(&rows, ExportFormat::Csv, None, &nulls, 5, |c| {
+            chunks.push(c)
+        });
+        assert_eq!(chunks.len(), 1);
+        assert_eq!(chunks.concat(), rows_to_csv(&rows, None, &nulls));
+    }
+
+    #[test]
+    fn test_buffer_expected_len() {
+        let mut manager = MemoryManager::new();
+        let id = manager.allocate_buffer(8);
+        assert!(manager.is_buffer_complete(id));
+
+        assert!(manager.set_expected_len(id, 6));
+        manager.append_bytes(id, b"abc");
+        assert!(!manager.is_buffer_complete(id));
+        manager.append_bytes(id, b"def");
+        assert!(manager.is_buffer_complete(id));
+
+        manager.deallocate_buffer(id);
+        assert!(!manager.is_buffer_complete(id));
+        assert!(!manager.set_expected_len(id, 6));
+    }
+
+    #[test]
+    fn test_max_json_depth() {
+        use crate::json_parser::{parse_json, JsonParseOptions};
+
+        let limited = JsonParseOptions {
+            max_depth: Some(3),
+            ..Default::default()
+        };
+        assert!(parse_json(br#"[{"a": [1]}]"#, &limited).is_ok());
+
+        let error = parse_json(br#"[{"a": [[1]]}]"#, &limited).unwrap_err();
+        assert_eq!(error.kind, "max_depth_exceeded");
+
+        let deep = "[".repeat(100_000);
+        let error = parse_json(deep.as_bytes(), &limited).unwrap_err();
+        assert_eq!(error.kind, "max_depth_exceeded");
+    }
+
+    #[test]
+    fn test_arrow_ipc_framing() {
+        use crate::arrow_ipc::{infer_column_type, rows_to_arrow_ipc, ColumnType};
+
+        let rows = vec![
+            serde_json::json!({"id": 1, "price": 2, "name": "a", "flag": true, "mixed": 1}),
+            serde_json::json!({"id": 2, "price": 2.5, "name": null, "flag": false, "mixed": "x"}),
+        ];
+        assert_eq!(infer_column_type(&rows, "id"), ColumnType::Int64);
+        assert_eq!(infer_column_type(&rows, "price"), ColumnType::Float64);
+        assert_eq!(infer_column_type(&rows, "name"), ColumnType::Utf8);
+        assert_eq!(infer_column_type(&rows, "flag"), ColumnType::Bool);
+        assert_eq!(infer_column_type(&rows, "mixed"), ColumnType::Utf8);
+        assert_eq!(infer_column_type(&rows, "missing"), ColumnType::Null);
+
+        let bytes = rows_to_arrow_ipc(&rows).unwrap();
+        let read_u32 = |at: usize| u32::from_le_bytes(bytes[at..at + 4].try_into().unwrap());
+
+        // Walk the messages using each Message's bodyLength (field 3)
+        let mut pos = 0;
+        let mut body_lengths = Vec::new();
+        loop {
+            assert_eq!(read_u32(pos), 0xFFFF_FFFF);
+            let metadata_len = read_u32(pos + 4) as usize;
+            if metadata_len == 0 {
+                break;
+            }
+            assert_eq!(metadata_len % 8, 0);
+            let message = pos + 8;
+            let table = message + read_u32(message) as usize;
+            let vtable = table - read_u32(table) as usize;
+            let field = u16::from_le_bytes(bytes[vtable + 10..vtable + 12].try_into().unwrap());
+            let at = table + field as usize;
+            let body_len = i64::from_le_bytes(bytes[at..at + 8].try_into().unwrap()) as usize;
+            body_lengths.push(body_len);
+            pos = message + metadata_len + body_len;
+        }
+        assert_eq!(pos + 8, bytes.len());
+        assert_eq!(body_lengths.len(), 2);
+        assert_eq!(body_lengths[0], 0);
+        assert_eq!(body_lengths[1] % 8, 0);
+    }
+
+    #[test]
+    fn test_count_without_materializing() {
+        use crate::stream_parser::count_ndjson;
+
+        let engine = QueryEngine::new();
+        assert_eq!(engine.count(br#"[{"a": 1}, {"a": [2, 3]}, 4]"#).unwrap(), 3);
+
+        let ndjson = b"{\"a\": 1}\r\n\n{\"a\": 2}\n{\"a\": 3}";
+        assert_eq!(count_ndjson(ndjson, &Default::default()).unwrap(), 3);
+
+        let error = count_ndjson(b"{\"a\": 1}\n{oops}\n", &Default::default()).unwrap_err();
+        assert!(error.message.starts_with("Line 2:"));
+    }
+
+    #[test]
+    fn test_url_pattern_matching() {
+        use crate::cloud_storage_bridge::url_pattern_matches;
+
+        assert!(url_pattern_matches(
+            "https://data.example.com/",
+            "https://data.example.com/a.json"
+        ));
+        assert!(!url_pattern_matches(
+            "https://data.example.com/",
+            "https://evil.com/data.example.com/"
+        ));
+        assert!(url_pattern_matches(
+            "https://*.amazonaws.com/*",
+            "https://bucket.s3.amazonaws.com/key"
+        ));
+        assert!(!url_pattern_matches(
+            "https://*.amazonaws.com/*",
+            "https://amazonaws.com.evil.io/key"
+        ));
+        assert!(url_pattern_matches(
+            "*169.254.169.254*",
+            "http://169.254.169.254/latest"
+        ));
+        assert!(url_pattern_matches("*.json", "https://x.io/a.json"));
+        assert!(!url_pattern_matches("*.json", "https://x.io/a.json.gz"));
+
+        // Spellings of a denied URL that must still match its rule
+        let deny = "https://internal.corp/*";
+        for url in [
+            "HTTPS://INTERNAL.corp/x",
+            "https://internal.corp:443/x",
+            "https://user@internal.corp/x",
+            "https://internal.corp./x",
+        ] {
+            assert!(url_pattern_matches(deny, url), "{url}");
+        }
+        assert!(!url_pattern_matches(deny, "https://internal.corp:8443/x"));
+        assert!(url_pattern_matches(
+            "https://internal.corp:*/*",
+            "https://internal.corp:8443/x"
+        ));
+        assert!(url_pattern_matches(
+            "*169.254.169.254*",
+            "HTTP://169.254.169.254:80/latest"
+        ));
+
+        // The host compares whole, not as a string prefix
+        assert!(url_pattern_matches(
+            "https://example.com",
+            "https://example.com/a"
+        ));
+        assert!(!url_pattern_matches(
+            "https://example.com",
+            "https://example.com.evil.com/a"
+        ));
+        assert!(!url_pattern_matches(
+            "https://example.com",
+            "https://example.com@evil.com/a"
+        ));
+    }
+
+    #[test]
+    fn test_running_stats() {
+        use crate::transforms::RunningStats;
+
+        let mut stats = RunningStats::default();
+        for value in [2.0, 4.0, 4.0, 4.0, 5.0, 5.0, 7.0, 9.0] {
+            stats.push(value);
+        }
+        assert_eq!(stats.get("count"), serde_json::json!(8));
+        assert_eq!(stats.get("mean"), serde_json::json!(5.0));
+        assert_eq!(stats.get("min"), serde_json::json!(2.0));
+        assert_eq!(stats.get("max"), serde_json::json!(9.0));
+        let variance = stats.get("variance").as_f64().unwrap();
+        assert!((variance - 32.0 / 7.0).abs() < 1e-12);
+
+        assert_eq!(RunningStats::default().get("mean"), serde_json::Value::Null);
+    }
+
+    #[test]
+    fn test_gzip_round_trip() {
+        use crate::compression::{gunzip, gzip, is_precompressed};
+
+        let body = br#"{"id": 1, "value": "processed"}"#.repeat(100);
+        let compressed = gzip(&body);
+        assert!(compressed.len() < body.len());
+        assert_eq!(gunzip(&compressed).unwrap(), body);
+        assert!(gunzip(b"not gzip").is_err());
+
+        assert!(is_precompressed(Some("image/png"), "https://x.io/a"));
+        assert!(is_precompressed(None, "https://x.io/data.parquet?v=2"));
+        assert!(!is_precompressed(
+            Some("application/json"),
+            "https://x.io/a.json"
+        ));
+    }
+
+    #[test]
+    fn test_canonical_json() {
+        use crate::export::{es_number, rows_to_canonical_json};
+
+        for (value, expected) in [
+            (1.0, "1"),
+            (-0.0, "0"),
+            (0.1, "0.1"),
+            (123.456, "123.456"),
+            (1e21, "1e+21"),
+            (1e20, "100000000000000000000"),
+            (0.000001, "0.000001"),
+            (1e-7, "1e-7"),
+            (-1.5e-9, "-1.5e-9"),
+            (4.5e300, "4.5e+300"),
+        ] {
+            assert_eq!(es_number(value), expected);
+        }
+
+        let rows = vec![serde_json::json!({"b": [1.0, "x"], "a": {"é": 2, "z": null}, "A": true})];
+        assert_eq!(
+            rows_to_canonical_json(&rows),
+            r#"[{"A":true,"a":{"z":null,"é":2},"b":[1,"x"]}]"#
+        );
+    }
+
+    #[test]
+    fn test_declared_type_coercion() {
+        use crate::transforms::DeclaredType;
+        use serde_json::json;
+
+        let integer = DeclaredType::parse("integer").unwrap();
+        assert_eq!(integer.coerce(&json!(" 42 ")), Some(json!(42)));
+        assert_eq!(integer.coerce(&json!(3.0)), Some(json!(3)));
+        assert_eq!(integer.coerce(&json!(3.5)), None);
+        assert_eq!(integer.coerce(&json!(null)), Some(json!(null)));
+
+        let boolean = DeclaredType::parse("boolean").unwrap();
+        assert_eq!(boolean.coerce(&json!("TRUE")), Some(json!(true)));
+        assert_eq!(boolean.coerce(&json!(0)), Some(json!(false)));
+        assert_eq!(boolean.coerce(&json!("yes")), None);
+
+        let string = DeclaredType::parse("string").unwrap();
+        assert_eq!(string.coerce(&json!(1.5)), Some(json!("1.5")));
+        assert!(DeclaredType::parse("date").is_none());
+    }
+
+    #[test]
+    fn test_json_merge_patch() {
+        use crate::utils::apply_merge_patch;
+        use serde_json::json;
+
+        // Examples from RFC 7386 Appendix A
+        let cases = [
+            (json!({"a": "b"}), json!({"a": "c"}), json!({"a": "c"})),
+            (json!({"a": "b"}), json!({"a": null}), json!({})),
+            (
+                json!({"a": {"b": "c"}}),
+                json!({"a": {"b": "d", "c": null}}),
+                json!({"a": {"b": "d"}}),
+            ),
+            (
+                json!({"a": [{"b": "c"}]}),
+                json!({"a": [1]}),
+                json!({"a": [1]}),
+            ),
+            (json!(["a", "b"]), json!({"a": "b"}), json!({"a": "b"})),
+            (
+                json!({"e": null}),
+                json!({"a": 1}),
+                json!({"e": null, "a": 1}),
+            ),
+            (
+                json!({}),
+                json!({"a": {"bb": {"ccc": null}}}),
+                json!({"a": {"bb": {}}}),
+            ),
+            (json!({"a": "foo"}), json!("bar"), json!("bar")),
+        ];
+        for (mut target, patch, expected) in cases {
+            apply_merge_patch(&mut target, patch);
+            assert_eq!(target, expected);
+        }
+    }
+
+    #[test]
+    fn test_keyed_output() {
+        let rows = vec![
+            serde_json::json!({"id": 1, "v": "a"}),
+            serde_json::json!({"v": "none"}),
+            serde_json::json!({"id": 1, "v": "b"}),
+            serde_json::json!({"id": "x", "v": "c"}),
+        ];
+        let keyed = export::KeyedRows {
+            rows: &rows,
+            field: "id",
+            bigint: false,
+        };
+        let value = serde_json::to_value(&keyed).unwrap();
+        assert_eq!(value["1"]["v"], "b");
+        assert_eq!(value["x"]["v"], "c");
+        assert_eq!(value["__missing__"][0]["v"], "none");
+    }
+
+    #[test]
+    fn test_ndjson_resync_on_error() {
+        let mut parser = crate::stream_parser::NdjsonStreamParser::default();
+        parser.resync_on_error = true;
+        let mut rows = parser.push(b"{\"a\":1}\n{\"a\":").unwrap();
+        rows.extend(parser.push(b"\n{\"a\":3}\n{bad").unwrap());
+        rows.extend(parser.finish().unwrap());
+
+        assert_eq!(rows.len(), 2);
+        assert_eq!(rows[1]["a"], 3);
+        assert_eq!(parser.skipped.len(), 2);
+        assert!(parser.skipped[0].starts_with("Line 2: skipped bytes 8..13"));
+        assert!(parser.skipped[1].starts_with("Line 4: skipped bytes 22..26"));
+    }
+
+    #[test]
+    fn test_csv_number_locale() {
+        use crate::csv_parser::{coerce_value, NumberLocale};
+
+        let eu = NumberLocale {
+            decimal: ',',
+            thousands: Some('.'),
+        };
+        assert_eq!(
+            coerce_value("1.234,56", Some(&eu)),
+            serde_json::json!(1234.56)
+        );
+        assert_eq!(
+            coerce_value("-1.234.567", Some(&eu)),
+            serde_json::json!(-1234567)
+        );
+        assert_eq!(coerce_value("0,5", Some(&eu)), serde_json::json!(0.5));
+        assert_eq!(coerce_value("1.5", Some(&eu)), serde_json::json!("1.5"));
+        assert_eq!(
+            coerce_value("12.34,5,6", Some(&eu)),
+            serde_json::json!("12.34,5,6")
+        );
+        assert_eq!(coerce_value("42", Some(&eu)), serde_json::json!(42));
+        assert_eq!(coerce_value("1,5", None), serde_json::json!("1,5"));
+    }
+
+    #[test]
+    fn test_unflatten() {
+        let mut row = serde_json::json!({"user.id": 1, "user.name": "a", "x": true, "a..b": 2});
+        let mut conflicts = Vec::new();
+        transforms::unflatten(&mut row, ".", &mut conflicts);
+        assert_eq!(
+            row,
+            serde_json::json!({"user": {"id": 1, "name": "a"}, "x": true, "a..b": 2})
+        );
+        assert!(conflicts.is_empty());
+
+        let mut row = serde_json::json!({"user": 5, "user.id": 1});
+        transforms::unflatten(&mut row, ".", &mut conflicts);
+        assert_eq!(row, serde_json::json!({"user": {"id": 1}}));
+        assert_eq!(conflicts, vec!["user"]);
+    }
+
+    #[test]
+    fn test_rate_limiter_spacing() {
+        let limiter = crate::cloud_storage_bridge::RateLimiter::new(2.0, 0.0);
+        assert_eq!(limiter.reserve(0.0), 0.0);
+        assert_eq!(limiter.reserve(0.0), 0.0);
+        assert_eq!(limiter.reserve(0.0), 500.0);
+        assert_eq!(limiter.reserve(0.0), 1000.0);
+        // Reserved tokens are paid back before new ones accrue
+        assert_eq!(limiter.reserve(2000.0), 0.0);
+    }
+
+    #[test]
+    fn test_parse_columns() {
+        use crate::json_parser::{parse_json, JsonParseOptions};
+
+        let options = JsonParseOptions {
+            include_columns: vec!["a".into(), "b".into(), "nested".into()],
+            exclude_columns: vec!["b".into()],
+            ..Default::default()
+        };
+        let input = br#"[{"a": 1, "b": 2, "c": [3], "nested": {"c": 4}}, {"c": 5}]"#;
+        let rows = parse_json(input, &options).unwrap();
+        assert_eq!(rows, serde_json::json!([{"a": 1, "nested": {"c": 4}}, {}]));
+
+        let row = parse_json(br#"{"a": 1, "c": {"deep": true}}"#, &options).unwrap();
+        assert_eq!(row, serde_json::json!({"a": 1}));
+    }
+
+    #[test]
+    fn test_version_compatibility() {
+        assert_eq!(parse_version("v1.2.3-beta.1"), Some((1, 2, 3)));
+        assert_eq!(parse_version("2"), Some((2, 0, 0)));
+        assert_eq!(parse_version("1.2.3.4"), None);
+        assert_eq!(parse_version("one"), None);
+
+        assert!(is_version_compatible((1, 4, 0), (1, 2, 5)));
+        assert!(!is_version_compatible((1, 2, 4), (1, 2, 5)));
+        assert!(!is_version_compatible((2, 0, 0), (1, 2, 5)));
+        assert!(is_version_compatible((0, 1, 3), (0, 1, 0)));
+        assert!(!is_version_compatible((0, 2, 0), (0, 1, 0)));
+        assert!(!is_version_compatible((0, 0, 4), (0, 0, 3)));
+    }
+
+    #[test]
+    fn test_gzip_input_detection() {
+        use crate::compression::{decode_input, gzip, InputCompression};
+
+        let raw = br#"[{"a": 1}]"#;
+        let packed = gzip(raw);
+        assert_eq!(
+            &*decode_input(&packed, InputCompression::Auto).unwrap(),
+            raw
+        );
+        assert_eq!(&*decode_input(raw, InputCompression::Auto).unwrap(), raw);
+        assert_eq!(
+            &*decode_input(&packed, InputCompression::None).unwrap(),
+            &packed[..]
+        );
+        assert!(decode_input(raw, InputCompression::Gzip).is_err());
+    }
+
+    #[test]
+    fn test_csv_null_representation() {
+        use crate::export::{rows_to_csv, NullRepresentation};
+
+        let rows = vec![
+            serde_json::json!({"a": 1, "b": null}),
+            serde_json::json!({"a": 2}),
+        ];
+        assert_eq!(
+            rows_to_csv(&rows, None, &NullRepresentation::default()),
+            "a,b\n1,\n2,\n"
+        );
+        let nulls = NullRepresentation {
+            null: String::new(),
+            missing: "\\N".to_string(),
+        };
+        assert_eq!(rows_to_csv(&rows, None, &nulls), "a,b\n1,\n2,\\N\n");
+    }
+
+    #[test]
+    fn test_schema_inference_cache() {
+        use crate::schema::{cache_key, infer_schema, SchemaCache};
+
+        let rows = vec![
+            serde_json::json!({"id": 1, "score": 2, "name": "a"}),
+            serde_json::json!({"id": 2, "score": 2.5, "name": null}),
+        ];
+        let schema = infer_schema(&rows);
+        assert_eq!(schema["sampled_rows"], 2);
+        assert_eq!(
+            schema["columns"][1],
+            serde_json::json!({"name": "name", "type": "string", "nullable": true})
+        );
+        assert_eq!(schema["columns"][2]["type"], "number");
+
+        let mut cache = SchemaCache::default();
+        let key = cache_key(b"[1]", "json");
+        assert_ne!(key, cache_key(b"[1]", "ndjson"));
+        cache.insert(key, schema.clone());
+        assert_eq!(cache.get(key), Some(schema));
+        cache.clear();
+        assert_eq!(cache.get(key), None);
+    }
+
+    #[test]
+    fn test_ndjson_shard_ranges() {
+        let data = b"{\"a\":1}\n\n{\"a\":2}\n{\"a\":3}\n{\"a\":4}";
+        let shards: Vec<&[u8]> = ndjson_shard_ranges(data, 2)
+            .into_iter()
+            .map(|range| &data[range])
+            .collect();
+        assert_eq!(
+            shards,
+            vec![
+                &b"{\"a\":1}\n\n{\"a\":2}\n"[..],
+                &b"{\"a\":3}\n{\"a\":4}"[..]
+            ]
+        );
+        assert_eq!(ndjson_shard_ranges(b"{}\n{}\n\n", 2), vec![0..7]);
+        assert!(ndjson_shard_ranges(b"", 2).is_empty());
+    }
+
+    #[test]
+    fn test_content_hash() {
+        use crate::export::content_hash;
+
+        assert_eq!(
+            content_hash(&[], None),
+            "4f53cda18c2baa0c0354bb5f9a3ecbe5ed12ab4d8e11ba873c2f11161202b945"
+        );
+        let a = vec![serde_json::json!({"id": 1, "timestamp": 100})];
+        let b = vec![serde_json::json!({"timestamp": 200, "id": 1.0})];
+        assert_ne!(content_hash(&a, None), content_hash(&b, None));
+        assert_eq!(
+            content_hash(&a, Some("timestamp")),
+            content_hash(&b, Some("timestamp"))
+        );
+    }
+
+    #[test]
+    fn test_decode_text() {
+        use crate::cloud_storage_bridge::decode_text;
+
+        let latin1 = b"caf\xe9,\x80";
+        assert_eq!(decode_text(latin1, "latin1").unwrap(), "café,€");
+        assert_eq!(decode_text(latin1, "windows-1252").unwrap(), "café,€");
+        assert_eq!(
+            decode_text(b"\xef\xbb\xbfna\xc3\xafve", "utf-8").unwrap(),
+            "naïve"
+        );
+    }
+
+    #[test]
+    fn test_normalize_url() {
+        use crate::cloud_storage_bridge::normalize_url;
+
+        assert_eq!(
+            normalize_url(
+                "  bucket.s3.amazonaws.com//data//my file.csv?x=a//b ",
+                false
+            )
+            .unwrap(),
+            "https://bucket.s3.amazonaws.com/data/my%20file.csv?x=a//b"
+        );
+        assert_eq!(
+            normalize_url("HTTPS://host/a", false).unwrap(),
+            "https://host/a"
+        );
+        assert!(normalize_url("http://host/a", false).is_err());
+        assert_eq!(
+            normalize_url("http://host/a", true).unwrap(),
+            "http://host/a"
+        );
+        assert!(normalize_url("ftp://host/a", true).is_err());
+        assert!(normalize_url("https:///a", false).is_err());
+    }
+
+    #[test]
+    fn test_parsers_never_panic_on_fuzzed_input() {
+        use crate::compression::{decode_input, gzip, InputCompression};
+        use crate::csv_parser::{count_csv, parse_csv, CsvOptions, NumberLocale, RaggedPolicy};
+        use crate::json_parser::{parse_json, JsonParseOptions};
+        use crate::stream_parser::{count_ndjson, NdjsonStreamParser};
+
+        // xorshift64, so failures reproduce from the seed
+        let mut state = 0x9e37_79b9_7f4a_7c15u64;
+        let mut next = move || {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state
+        };
+
+        let seeds: [&[u8]; 4] = [
+            b"[{\"a\": 1, \"b\": {\"c\": [1, 2.5e3, null]}}, {\"a\": \"x\"}]",
+            b"{\"a\": 1}\n{\"a\": true}\n\n{\"b\": \"\\u00e9\"}\n",
+            b"a,b,c\n1,\"x,\"\"y\"\"\",3\n4,5\n\"open",
+            b"(price * qty) >= 10 && name != \"x\" || -a / 2",
+        ];
+        let json_options = [
+            JsonParseOptions::default(),
+            JsonParseOptions {
+                reject_duplicate_keys: true,
+                max_depth: Some(3),
+                include_columns: vec!["a".into()],
+                exclude_columns: vec!["b".into()],
+            },
+        ];
+        let csv_options = CsvOptions {
+            ragged: RaggedPolicy::Pad,
+            number_locale: Some(NumberLocale {
+                decimal: ',',
+                thousands: Some('.'),
+            }),
+        };
+
+        for round in 0..2000 {
+            let mut input = seeds[round % seeds.len()].to_vec();
+            if round % 5 == 0 {
+                input = (0..next() % 64).map(|_| next() as u8).collect();
+            } else {
+                for _ in 0..1 + next() % 4 {
+                    let at = (next() as usize) % (input.len() + 1);
+                    match next() % 3 {
+                        0 if at < input.len() => input[at] = next() as u8,
+                        1 if at < input.len() => {
+                            input.remove(at);
+                        }
+                        _ => input.insert(at, next() as u8),
+                    }
+                }
+            }
+            let text = String::from_utf8_lossy(&input);
+
+            for options in &json_options {
+                let _ = parse_json(&input, options);
+                let _ = count_ndjson(&input, options);
+                let mut parser = NdjsonStreamParser::new(options.clone());
+                parser.resync_on_error = round % 2 == 0;
+                let split = (next() as usize) % (input.len() + 1);
+                let _ = parser.push(&input[..split]);
+                let _ = parser.push(&input[split..]);
+                let _ = parser.finish();
+            }
+            let _ = parse_csv(&text, &CsvOptions::default());
+            let _ = parse_csv(&text, &csv_options);
+            let _ = count_csv(&text, &csv_options);
+            let _ = crate::expression::parse_expression(&text);
+
+            let mut compressed = gzip(&input);
+            let at = (next() as usize) % compressed.len();
+            compressed[at] ^= next() as u8;
+            compressed.truncate(compressed.len() - (next() as usize) % 8);
+            let _ = decode_input(&compressed, InputCompression::Auto);
+            let _ = decode_input(&input, InputCompression::Gzip);
+        }
+    }
+
+    #[test]
+    fn test_config_round_trip() {
+        let mut engine = QueryEngine::new();
+        engine.set_input_format("csv").unwrap();
+        engine.set_number_locale(",".into(), ".".into()).unwrap();
+        engine.set_parse_columns(vec!["a".into()], vec![]);
+        engine.set_unflatten(true, "__".into()).unwrap();
+        engine
+            .add_computed_column("total".into(), "a * 2".into())
+            .unwrap();
+        engine.set_output_key("id".into());
+        engine.set_deadline_ms(250.0);
+        engine.set_deterministic(true);
+
+        let config = engine.config();
+        let json = serde_json::to_value(&config).unwrap();
+        assert_eq!(json["input_format"], "csv");
+        assert_eq!(json["number_locale"]["thousands"], ".");
+        assert_eq!(json["computed_columns"][0]["expr"], "a * 2");
+
+        let mut restored = QueryEngine::new();
+        restored
+            .apply_config(serde_json::from_value(json).unwrap())
+            .unwrap();
+        assert_eq!(restored.config(), config);
+
+        // Missing fields fall back to the defaults
+        restored
+            .apply_config(serde_json::from_str("{}").unwrap())
+            .unwrap();
+        assert_eq!(restored.config(), QueryEngine::new().config());
+        assert!(serde_json::from_str::<query_engine::EngineConfig>("{\"format\": 1}").is_err());
+    }
+
+    #[test]
+    fn test_merge_header() {
+        let mut map = std::collections::BTreeMap::new();
+        cloud_storage_bridge::merge_header(&mut map, "Cache-Control", "no-cache");
+        cloud_storage_bridge::merge_header(&mut map, "cache-control", "no-store");
+        cloud_storage_bridge::merge_header(&mut map, "ETag", "\"abc\"");
+        assert_eq!(map["cache-control"], "no-cache, no-store");
+        assert_eq!(map["etag"], "\"abc\"");
+    }
+
+    #[test]
+    fn test_count_columns() {
+        let rows = vec![
+            serde_json::json!({"a": 1, "b": 2}),
+            serde_json::json!({"b": 3, "c": 4}),
+            serde_json::json!(5),
+        ];
+        assert_eq!(export::count_columns(&rows), 4);
+        assert_eq!(
+            export::count_columns(&rows),
+            export::collect_columns(&rows).len()
+        );
+    }
+
+    #[test]
+    fn test_lookup_table_enrich() {
+        let table = transforms::LookupTable::new(
+            vec![
+                serde_json::json!({"code": "NZ", "name": "New Zealand", "region": "Oceania"}),
+                serde_json::json!({"code": "FR", "name": "France", "region": "Europe"}),
+                serde_json::json!({"name": "no key"}),
+            ],
+            "code",
+        );
+        assert_eq!(table.len(), 2);
+
+        let mut row = serde_json::json!({"id": 1, "country": "NZ"});
+        assert!(table.enrich(&mut row, "country", "country_", false));
+        assert_eq!(
+            row,
+            serde_json::json!({"id": 1, "country": "NZ", "country_name": "New Zealand", "country_region": "Oceania"})
+        );
+
+        let mut row = serde_json::json!({"id": 2, "country": "JP"});
+        assert!(!table.enrich(&mut row, "country", "", false));
+        assert_eq!(row, serde_json::json!({"id": 2, "country": "JP"}));
+        assert!(!table.enrich(&mut row, "country", "", true));
+        assert_eq!(row["region"], serde_json::Value::Null);
+    }
+
+    #[test]
+    fn test_schema_sampling() {
+        use crate::schema::Sampling;
+
+        let data: Vec<u8> = (0..1000)
+            .flat_map(|i| format!("{{\"n\":{i}}}\n").into_bytes())
+            .collect();
+        let line = |range: std::ops::Range<usize>| -> serde_json::Value {
+            serde_json::from_slice(&data[range]).unwrap()
+        };
+
+        let head = Sampling::Head.select_lines(&data, 3, 7);
+        assert_eq!(head.len(), 3);
+        assert_eq!(line(head[2].clone())["n"], 2);
+
+        let stratified = Sampling::Stratified.select_lines(&data, 4, 7);
+        let values: Vec<_> = stratified
+            .into_iter()
+            .map(|r| line(r)["n"].clone())
+            .collect();
+        assert_eq!(values.len(), 4);
+        assert!(values[0].as_u64().unwrap() < 250 && values[3].as_u64().unwrap() >= 750);
+
+        let random = Sampling::Random.select_lines(&data, 10, 7);
+        assert!(!random.is_empty() && random.len() <= 10);
+        assert_eq!(random, Sampling::Random.select_lines(&data, 10, 7));
+        assert!(random
+            .iter()
+            .any(|r| line(r.clone())["n"].as_u64().unwrap() >= 100));
+
+        let rows: Vec<_> = (0..10).map(|i| serde_json::json!(i)).collect();
+        assert_eq!(
+            Sampling::Stratified.select_rows(rows, 2, 0),
+            vec![serde_json::json!(2), serde_json::json!(7)]
+        );
+    }
+
+    #[test]
+    fn test_binary_encoding() {
+        use crate::transforms::{base64_encode, encode_binary, DeclaredType};
+
+        assert_eq!(base64_encode(b""), "");
+        assert_eq!(base64_encode(b"f"), "Zg==");
+        assert_eq!(base64_encode(b"fo"), "Zm8=");
+        assert_eq!(base64_encode(b"foobar"), "Zm9vYmFy");
+
+        let blob: Vec<u8> = (0..16).collect();
+        let mut row =
+            serde_json::json!({"blob": blob, "tiny": [1, 2], "ids": [1, 2], "big": vec![256; 16]});
+        encode_binary(&mut row, &["tiny"]);
+        assert_eq!(row["blob"], "AAECAwQFBgcICQoLDA0ODw==");
+        assert_eq!(row["tiny"], "AQI=");
+        assert_eq!(row["ids"], serde_json::json!([1, 2]));
+        assert!(row["big"].is_array());
+
+        let binary = DeclaredType::parse("binary").unwrap();
+        assert!(binary.coerce(&serde_json::json!([0, 255])).is_some());
+        assert!(binary.coerce(&serde_json::json!([-1])).is_none());
+    }
+
+    #[test]
+    fn test_parse_retry_after() {
+        use crate::cloud_storage_bridge::parse_retry_after;
+
+        assert_eq!(parse_retry_after("120", 0.0), Some(120_000.0));
+        // 2015-10-21T07:28:00Z
+        let date_ms = 1_445_412_480_000.0;
+        assert_eq!(
+            parse_retry_after("Wed, 21 Oct 2015 07:28:00 GMT", date_ms - 5000.0),
+            Some(5000.0)
+        );
+        assert_eq!(
+            parse_retry_after("Wed, 21 Oct 2015 07:28:00 GMT", date_ms + 1.0),
+            Some(0.0)
+        );
+        assert_eq!(parse_retry_after("soon", 0.0), None);
+        assert_eq!(parse_retry_after("-5", 0.0), None);
+    }
+
+    #[test]
+    fn test_read_zip_members() {
+        use std::io::Write;
+
+        let mut writer = zip::ZipWriter::new(std::io::Cursor::new(Vec::new()));
+        let options = zip::write::SimpleFileOptions::default();
+        writer.add_directory("tables/", options).unwrap();
+        writer.start_file("tables/users.csv", options).unwrap();
+        writer.write_all(b"id,name\n1,a\n").unwrap();
+        writer.start_file("logo.png", options).unwrap();
+        writer.write_all(&[0x89, b'P', b'N', b'G']).unwrap();
+        let archive = writer.finish().unwrap().into_inner();
+
+        let mut archive = zip::ZipArchive::new(std::io::Cursor::new(&archive[..])).unwrap();
+        let members: Vec<_> = (0..archive.len())
+            .filter_map(|i| query_engine::read_zip_member(&mut archive, i).unwrap())
+            .map(|(name, contents)| (name, contents.unwrap()))
+            .collect();
+        assert_eq!(members.len(), 2);
+        assert_eq!(
+            members[0],
+            ("tables/users.csv".to_string(), b"id,name\n1,a\n".to_vec())
+        );
+        assert_eq!(members[1].0, "logo.png");
+    }
+
+    #[test]
+    fn test_parse_logs() {
+        let regex = log_parser::compile_pattern(
+            r#"^(?P<ip>\S+) "(?P<method>[A-Z]+) (?P<path>\S+)" (?P<status>\d{3}) (?P<bytes>\d+|-)(?: (?P<ms>\d+))?$"#,
+        )
+        .unwrap();
+        let text = "10.0.0.1 \"GET /a\" 200 512 12\n\ngarbage\n10.0.0.2 \"POST /b\" 404 -\n";
+        let (rows, unmatched) = log_parser::parse_logs(text, &regex);
+
+        assert_eq!(unmatched, vec![3]);
+        assert_eq!(
+            rows[0],
+            serde_json::json!({"ip": "10.0.0.1", "method": "GET", "path": "/a", "status": 200, "bytes": 512, "ms": 12})
+        );
+        assert_eq!(rows[1]["bytes"], "-");
+        assert_eq!(rows[1]["ms"], serde_json::Value::Null);
+
+        assert_eq!(
+            log_parser::compile_pattern(r"\d+").unwrap_err().kind,
+            "invalid_pattern"
+        );
+        assert!(log_parser::compile_pattern("(?P<a>").is_err());
+    }
+
+    #[test]
+    fn test_sorted_runs_merge() {
+        use crate::transforms::{merge_runs, sorted_run};
+
+        let rows: Vec<serde_json::Value> = [5, 1, 4, 2, 3, 1]
+            .iter()
+            .enumerate()
+            .map(|(i, n)| serde_json::json!({"n": n, "i": i}))
+            .chain([
+                serde_json::json!({"i": 6}),
+                serde_json::json!({"n": "x", "i": 7}),
+            ])
+            .collect();
+        let sorted_field = |ndjson: &[u8], field: &str| -> Vec<serde_json::Value> {
+            ndjson
+                .split(|&b| b == b'\n')
+                .filter(|line| !line.is_empty())
+                .map(|line| {
+                    serde_json::from_slice::<serde_json::Value>(line).unwrap()[field].clone()
+                })
+                .collect()
+        };
+
+        for descending in [false, true] {
+            let runs: Vec<Vec<u8>> = rows
+                .chunks(3)
+                .map(|chunk| sorted_run(chunk.to_vec(), "n", descending))
+                .collect();
+            let run_slices: Vec<&[u8]> = runs.iter().map(Vec::as_slice).collect();
+            let mut merged = Vec::new();
+            merge_runs(&run_slices, "n", descending, &mut merged).unwrap();
+
+            let expected = if descending {
+                serde_json::json!(["x", 5, 4, 3, 2, 1, 1, null])
+            } else {
+                serde_json::json!([1, 1, 2, 3, 4, 5, "x", null])
+            };
+            assert_eq!(
+                serde_json::Value::from(sorted_field(&merged, "n")),
+                expected
+            );
+            // Equal keys keep input order
+            let ids = sorted_field(&merged, "i");
+            let ones: Vec<_> = ids.iter().filter(|i| *i == 1 || *i == 5).collect();
+            assert_eq!(ones, vec![&serde_json::json!(1), &serde_json::json!(5)]);
+        }
+    }
+
+    #[test]
+    fn test_schema_rule_matching() {
+        let mut engine = QueryEngine::new();
+        engine.set_input_format("ndjson").unwrap();
+        let mut then_config = serde_json::to_value(engine.config()).unwrap();
+        then_config["annotate_rows"] = false.into();
+        let mut config = serde_json::to_value(engine.config()).unwrap();
+        config["schema_rules"] = serde_json::json!([{
+            "required_columns": ["timestamp"],
+            "config": then_config.clone(),
+        }]);
+        engine
+            .apply_config(serde_json::from_value(config).unwrap())
+            .unwrap();
+
+        let matched = engine.matching_schema_rule(b"{\"v\": 2}\n{\"timestamp\": 1}\n");
+        assert_eq!(matched, Some(0));
+        let exported = serde_json::to_value(engine.config()).unwrap();
+        assert_eq!(exported["schema_rules"][0]["config"], then_config);
+        assert_eq!(engine.matching_schema_rule(b"{\"v\": 2}\n"), None);
+    }
+
+    #[test]
+    fn test_timestamp_formats() {
+        use timestamp::{format_iso8601, parse_iso8601, TimestampFormat};
+
+        assert_eq!(format_iso8601(0), "1970-01-01T00:00:00.000Z");
+        assert_eq!(
+            format_iso8601(1_709_294_400_123),
+            "2024-03-01T12:00:00.123Z"
+        );
+        assert_eq!(format_iso8601(-1), "1969-12-31T23:59:59.999Z");
+        assert_eq!(
+            parse_iso8601("2024-03-01T12:00:00.123Z"),
+            Some(1_709_294_400_123)
+        );
+        assert_eq!(
+            parse_iso8601("2024-03-01T14:00:00+02:00"),
+            Some(1_709_294_400_000)
+        );
+        assert_eq!(parse_iso8601("2024-02-29"), Some(1_709_164_800_000));
+        assert_eq!(parse_iso8601("2023-02-29"), None);
+        assert_eq!(parse_iso8601("2024-03-01T25:00"), None);
+        assert_eq!(TimestampFormat::EpochS.render(-1), serde_json::json!(-1));
+
+        let declared = transforms::DeclaredType::parse("timestamp").unwrap();
+        assert_eq!(
+            declared.coerce(&serde_json::json!("1970-01-01T00:00:01Z")),
+            Some(serde_json::json!(1000))
+        );
+        assert_eq!(declared.coerce(&serde_json::json!("soon")), None);
+    }
+
+    #[test]
+    fn test_mixed_line_endings() {
+        use line_endings::LineEndingCounts;
+
+        let data = b"{\"a\": 1}\r\n{\"a\": 2}\r{\"a\": 3}\n{\"a\": 4}";
+        let counts = LineEndingCounts::scan(data);
+        assert_eq!((counts.lf, counts.crlf, counts.cr), (1, 1, 1));
+        assert_eq!(counts.style(), "mixed");
+        assert_eq!(LineEndingCounts::scan(b"a\r\nb\r\n").style(), "crlf");
+
+        let options = json_parser::JsonParseOptions::default();
+        assert_eq!(stream_parser::count_ndjson(data, &options).unwrap(), 4);
+
+        // A `\r\n` split across chunks is still one line break
+        let mut parser = stream_parser::NdjsonStreamParser::new(options);
+        let mut rows = parser.push(b"{\"a\": 1}\r").unwrap();
+        rows.extend(parser.push(b"\n{\"a\": 2}\r{\"a\": 3}\r\n").unwrap());
+        rows.extend(parser.finish().unwrap());
+        assert_eq!(rows.len(), 3);
+        assert_eq!(rows[2], serde_json::json!({"a": 3}));
+
+        let regex = log_parser::compile_pattern(r"(?P<n>\d+)").unwrap();
+        let (rows, unmatched) = log_parser::parse_logs("1\r2\r\nx\n3", &regex);
+        assert_eq!(rows.len(), 3);
+        assert_eq!(unmatched, vec![3]);
+    }
+
+    #[test]
+    fn test_streaming_gunzip() {
+        use crate::compression::{gzip, GunzipStream, InflateLimits};
+
+        let limits = InflateLimits {
+            max_output: 1 << 30,
+            max_ratio: None,
+        };
+
+        let body: Vec<u8> = (0..20_000).map(|i| (i % 251) as u8).collect();
+        let compressed = gzip(&body);
+        let mut inflater = GunzipStream::new(limits);
+        for chunk in compressed.chunks(1) {
+            inflater.push(chunk).unwrap();
+        }
+        assert!(inflater.is_gzip());
+        assert_eq!(inflater.finish().unwrap(), body);
+
+        let mut plain = GunzipStream::new(limits);
+        plain.push(b"{").unwrap();
+        plain.push(b"\"a\": 1}").unwrap();
+        assert!(!plain.is_gzip());
+        assert_eq!(plain.finish().unwrap(), b"{\"a\": 1}");
+
+        let mut truncated = GunzipStream::new(limits);
+        truncated.push(&compressed[..compressed.len() / 2]).unwrap();
+        assert!(truncated.finish().is_err());
+    }
+
+    #[test]
+    fn test_mapping_paths() {
+        use transforms::value_at_path;
+
+        let row = serde_json::json!({
+            "user": {"id": "7", "tags": ["a", "b"]},
+            "user.name": "flat",
+        });
+        assert_eq!(
+            value_at_path(&row, "user.id"),
+            Some(&serde_json::json!("7"))
+        );
+        assert_eq!(
+            value_at_path(&row, "user.tags.1"),
+            Some(&serde_json::json!("b"))
+        );
+        assert_eq!(
+            value_at_path(&row, "user.name"),
+            Some(&serde_json::json!("flat"))
+        );
+        assert_eq!(value_at_path(&row, "user.tags.x"), None);
+        assert_eq!(value_at_path(&row, "user.id.more"), None);
+
+        let mut engine = QueryEngine::new();
+        let mut config = serde_json::to_value(engine.config()).unwrap();
+        config["mapping"] = serde_json::json!([
+            {"from": "user.id", "to": "uid", "type": "integer"},
+            {"from": "score"},
+        ]);
+        engine
+            .apply_config(serde_json::from_value(config).unwrap())
+            .unwrap();
+        let exported = serde_json::to_value(engine.config()).unwrap();
+        assert_eq!(
+            exported["mapping"],
+            serde_json::json!([
+                {"from": "user.id", "to": "uid", "type": "integer"},
+                {"from": "score", "to": "score"},
+            ])
+        );
+    }
+
+    #[test]
+    fn test_cache_validators() {
+        use cloud_storage_bridge::Validators;
+
+        let validators = |etag: Option<&str>, last_modified: Option<&str>| Validators {
+            etag: etag.map(String::from),
+            last_modified: last_modified.map(String::from),
+        };
+        let cached = validators(Some("\"abc\""), Some("Wed, 21 Oct 2015 07:28:00 GMT"));
+        assert!(cached.matches(&validators(Some("W/\"abc\""), None)));
+        // ETags win over dates when both sides have one
+        assert!(!cached.matches(&validators(
+            Some("\"def\""),
+            Some("Wed, 21 Oct 2015 07:28:00 GMT")
+        )));
+        assert!(cached.matches(&validators(None, Some("Wed, 21 Oct 2015 07:28:00 GMT"))));
+        assert!(!cached.matches(&validators(None, None)));
+        assert!(!Validators::default().matches(&Validators::default()));
+    }
+
+    #[test]
+    fn test_parse_json_fields() {
+        let mut rows = vec![
+            serde_json::json!({"payload": "{\"a\": 1}", "n": "2"}),
+            serde_json::json!({"payload": "not json", "n": 3}),
+            serde_json::json!({"payload": 7}),
+        ];
+        let fields = vec!["payload".to_string(), "n".to_string()];
+        let failures = transforms::parse_json_fields(
+            &mut rows,
+            &fields,
+            &json_parser::JsonParseOptions::default(),
+        );
+        assert_eq!(failures, vec![1, 0]);
+        assert_eq!(rows[0], serde_json::json!({"payload": {"a": 1}, "n": 2}));
+        assert_eq!(rows[1]["payload"], "not json");
+        assert_eq!(rows[2]["payload"], 7);
+    }
+
+    #[test]
+    fn test_decompression_bomb_limits() {
+        use crate::compression::{gzip, GunzipStream, InflateLimits};
+
+        let bomb = gzip(&vec![0u8; 4 << 20]);
+        let capped = InflateLimits {
+            max_output: 1 << 20,
+            max_ratio: None,
+        };
+        let mut inflater = GunzipStream::new(capped);
+        let error = bomb
+            .chunks(256)
+            .find_map(|chunk| inflater.push(chunk).err())
+            .unwrap();
+        assert_eq!(error.kind, "decompression_bomb");
+
+        let by_ratio = InflateLimits {
+            max_output: usize::MAX,
+            max_ratio: Some(100.0),
+        };
+        let mut inflater = GunzipStream::new(by_ratio);
+        let pushed = inflater.push(&bomb);
+        assert_eq!(pushed.unwrap_err().kind, "decompression_bomb");
+
+        // Ordinary data passes both checks
+        let text: Vec<u8> = (0..200_000u32).flat_map(|i| i.to_le_bytes()).collect();
+        let mut inflater = GunzipStream::new(by_ratio);
+        inflater.push(&gzip(&text)).unwrap();
+        assert_eq!(inflater.finish().unwrap(), text);
+    }
+
+    #[test]
+    fn test_row_index() {
+        use serde_json::json;
+
+        let rows = vec![
+            json!({"id": 1, "v": "a"}),
+            json!({"id": "1", "v": "b"}),
+            json!({"id": 1, "v": "c"}),
+            json!({"id": null, "v": "d"}),
+            json!({"v": "e"}),
+        ];
+        let index = transforms::RowIndex::new(rows, "id");
+        assert_eq!(index.len(), 2);
+        assert_eq!(index.get(&json!(1)).len(), 2);
+        assert_eq!(index.get(&json!(1))[1]["v"], "c");
+        assert_eq!(index.get(&json!("1"))[0]["v"], "b");
+        assert!(index.get(&json!(null)).is_empty());
+        assert!(index.get(&json!(2)).is_empty());
+    }
+
+    #[test]
+    fn test_row_ids() {
+        use crate::transforms::{add_row_ids, RowIdMode};
+        use serde_json::json;
+
+        let mut rows = vec![json!({"a": 1}), json!({"a": 2}), json!({"a": 1})];
+        add_row_ids(&mut rows, "id", RowIdMode::Ordinal, 10, None);
+        assert_eq!(rows[0]["id"], json!(10));
+        assert_eq!(rows[2]["id"], json!(12));
+
+        // Hashes ignore the id field itself and the skipped key
+        rows[2]["timestamp"] = json!(123);
+        add_row_ids(&mut rows, "id", RowIdMode::Hash, 0, Some("timestamp"));
+        let id = rows[0]["id"].as_str().unwrap();
+        assert_eq!(id.len(), 16);
+        assert_eq!(rows[2]["id"], rows[0]["id"]);
+        assert_ne!(rows[1]["id"], rows[0]["id"]);
+        assert_eq!(RowIdMode::parse("uuid"), None);
+    }
+
+    #[test]
+    fn test_upsert() {
+        use crate::transforms::{upsert, UpsertCounts};
+        use serde_json::json;
+
+        let base = vec![
+            json!({"id": 1, "name": "a", "score": 1}),
+            json!({"id": 2, "name": "b", "score": 2}),
+            json!({"id": "1", "name": "typed"}),
+        ];
+        let updates = vec![
+            json!({"id": 1, "score": 10}),
+            json!({"id": 2, "deleted": true}),
+            json!({"id": 3, "name": "c", "deleted": false}),
+            json!({"name": "no key"}),
+        ];
+
+        let (rows, counts) = upsert(base.clone(), updates.clone(), "id", true, Some("deleted"));
+        assert_eq!(
+            rows,
+            vec![
+                json!({"id": 1, "name": "a", "score": 10}),
+                json!({"id": "1", "name": "typed"}),
+                json!({"id": 3, "name": "c"}),
+            ]
+        );
+        assert_eq!(
+            counts,
+            UpsertCounts {
+                updated: 1,
+                inserted: 1,
+                deleted: 1,
+                skipped: 1
+            }
+        );
+
+        // Without merging the update replaces the row; without a tombstone
+        // field the flag is just data
+        let (rows, _) = upsert(base, updates, "id", false, None);
+        assert_eq!(rows[0], json!({"id": 1, "score": 10}));
+        assert_eq!(rows[1], json!({"id": 2, "deleted": true}));
+    }
+
+    #[test]
+    fn test_self_test() {
+        let report = self_test::run();
+        assert_eq!(report["ok"], serde_json::json!(true), "{report}");
+        assert_eq!(report["checks"].as_array().unwrap().len(), 6);
+    }
+
+    #[test]
+    fn test_top_k() {
+        use crate::transforms::top_k;
+        use serde_json::json;
+
+        let rows: Vec<_> = ["b", "a", "c", "a", "b", "d", "a"]
+            .iter()
+            .map(|v| json!({"cat": v}))
+            .chain([json!({"cat": 1}), json!({"other": "x"})])
+            .collect();
+        assert_eq!(
+            top_k(&rows, "cat", 2, false),
+            vec![(json!("a"), 3), (json!("b"), 2)]
+        );
+        // Ties among the singletons order numbers before strings
+        assert_eq!(
+            top_k(&rows, "cat", 3, true),
+            vec![(json!(1), 1), (json!("c"), 1), (json!("d"), 1)]
+        );
+        assert!(top_k(&rows, "missing", 5, false).is_empty());
+    }
+
+    #[test]
+    fn test_csv_stream_parser() {
+        use crate::csv_parser::{parse_csv, CsvOptions};
+        use crate::stream_parser::CsvStreamParser;
+
+        let input = "name,note,n\r\n\
+            a,\"split\nacross \"\"lines\"\"\",1\r\n\
+            \"\",\"x,y\",2\r\
+            b,\"\"\"quoted\"\"\",3\n\
+            c,é,4";
+        let expected = parse_csv(input, &CsvOptions::default()).unwrap();
+        assert_eq!(expected.len(), 4);
+
+        // Every split point, including inside quotes and between \r and \n
+        let bytes = input.as_bytes();
+        for at in 0..=bytes.len() {
+            let mut parser = CsvStreamParser::new(CsvOptions::default());
+            let mut rows = parser.push(&bytes[..at]).unwrap();
+            rows.extend(parser.push(&bytes[at..]).unwrap());
+            rows.extend(parser.finish().unwrap());
+            assert_eq!(rows, expected, "split at {at}");
+        }
+
+        // Rows come out as soon as they are complete
+        let mut parser = CsvStreamParser::new(CsvOptions::default());
+        assert!(parser.push(b"a,b\n1,\"2\n").unwrap().is_empty());
+        assert_eq!(parser.push(b"\"\n3,").unwrap().len(), 1);
+        assert_eq!(parser.pending_len(), 2);
+
+        // Errors keep their line numbers across chunks
+        let mut parser = CsvStreamParser::new(CsvOptions::default());
+        parser.push(b"a,b\n1,2\n").unwrap();
+        let error = parser.push(b"3\n").unwrap_err();
+        assert!(error.message.starts_with("Line 3:"), "{}", error.message);
+    }
+
+    #[test]
+    fn test_index_first_serialization() {
+        use crate::export::IndexFirst;
+        use serde_json::json;
+
+        let row = json!({"a": 1, "date": "2024-01-01", "z": [2]});
+        let text = serde_json::to_string(&IndexFirst {
+            row: &row,
+            field: "date",
+            bigint: false,
+        })
+        .unwrap();
+        assert_eq!(text, r#"{"date":"2024-01-01","a":1,"z":[2]}"#);
+
+        // Rows without the column are written as usual
+        let text = serde_json::to_string(&IndexFirst {
+            row: &json!({"b": 1, "a": 2}),
+            field: "date",
+            bigint: false,
+        })
+        .unwrap();
+        assert_eq!(text, r#"{"a":2,"b":1}"#);
+    }
+
+    #[test]
+    fn test_set_operations() {
+        use crate::transforms::{set_operation, SetOp};
+        use serde_json::json;
+
+        let a = vec![
+            json!({"id": 1, "from": "a"}),
+            json!({"id": 2, "from": "a"}),
+            json!({"id": "3", "from": "a"}),
+            json!({"from": "a, no key"}),
+        ];
+        let b = vec![
+            json!({"id": 2, "from": "b"}),
+            json!({"id": 3, "from": "b"}),
+            json!({"id": 4, "from": "b"}),
+            json!({"id": 4, "from": "b again"}),
+        ];
+        let ids = |rows: Vec<serde_json::Value>| -> Vec<String> {
+            rows.iter()
+                .map(|row| format!("{}:{}", row["id"], row["from"].as_str().unwrap()))
+                .collect()
+        };
+
+        assert_eq!(
+            ids(set_operation(a.clone(), b.clone(), "id", SetOp::Intersect)),
+            ["2:a"]
+        );
+        assert_eq!(
+            ids(set_operation(a.clone(), b.clone(), "id", SetOp::Except)),
+            ["1:a", "\"3\":a", "null:a, no key"]
+        );
+        assert_eq!(
+            ids(set_operation(a, b, "id", SetOp::Union)),
+            ["1:a", "2:a", "\"3\":a", "null:a, no key", "3:b", "4:b"]
+        );
+        assert_eq!(SetOp::parse("minus"), None);
+    }
+
+    #[test]
+    fn test_content_range_total() {
+        use crate::cloud_storage_bridge::content_range_total;
+
+        assert_eq!(content_range_total("bytes 0-99/1234"), Some(1234));
+        assert_eq!(content_range_total("Bytes 100-199/200"), Some(200));
+        assert_eq!(content_range_total("bytes 0-99/*"), None);
+        assert_eq!(content_range_total("bytes */1234"), Some(1234));
+        assert_eq!(content_range_total("items 0-9/10"), None);
+        assert_eq!(content_range_total("garbage"), None);
+    }
+
+    #[test]
+    fn test_panic_recorded_after_memory_manager() {
+        // Building a manager (as every engine does) must keep the hook that
+        // records panics for `take_last_panic`
+        let _manager = MemoryManager::new();
+        let _ = std::panic::catch_unwind(|| panic!("boom"));
+        let error = take_last_panic().expect("panic was not recorded");
+        assert_eq!(error.error_type(), "internal_panic");
+        assert!(error.message().contains("boom"), "{}", error.message());
+        assert!(take_last_panic().is_none());
+    }
+
+    #[test]
+    fn test_columns_seen_across_slices() {
+        let mut engine = QueryEngine::new();
+        let mut config = serde_json::to_value(engine.config()).unwrap();
+        config["schema"] = serde_json::json!({"score": "number", "absent": "string"});
+        config["unknown_columns"] = "warn".into();
+        config["annotate_rows"] = false.into();
+        engine
+            .apply_config(serde_json::from_value(config).unwrap())
+            .unwrap();
+
+        // `score` only appears in the second slice, `absent` in neither
+        let slices = [
+            vec![serde_json::json!({"id": 1}), serde_json::json!({"id": 2})],
+            vec![serde_json::json!({"id": 3, "score": "4.5"})],
+        ];
+        let mut seen = query_engine::SeenColumns::default();
+        let mut warnings = Vec::new();
+        let mut first_row = 0;
+        for slice in slices {
+            let rows = engine
+                .transform_parsed(slice, &mut warnings, first_row, &mut seen)
+                .unwrap();
+            first_row += rows.len();
+        }
+        assert!(warnings.is_empty());
+        engine.check_seen_columns(&seen, &mut warnings).unwrap();
+        assert_eq!(warnings.len(), 1);
+        assert!(warnings[0].contains("'absent'"));
+        assert!(!warnings[0].contains("'score'"));
+    }
+
+    #[test]
+    fn test_partial_emit_result_matches_process_data() {
+        use crate::json_parser::JsonParseOptions;
+        use crate::query_engine::SliceRun;
+        use crate::stream_parser::NdjsonStreamParser;
+
+        let mut engine = QueryEngine::new();
+        let mut config = serde_json::to_value(engine.config()).unwrap();
+        config["input_format"] = "ndjson".into();
+        config["annotate_rows"] = false.into();
+        config["deterministic"] = true.into();
+        config["schema"] = serde_json::json!({"score": "number"});
+        config["output_key"] = "id".into();
+        engine
+            .apply_config(serde_json::from_value(config).unwrap())
+            .unwrap();
+
+        let data = b"{\"id\": 1, \"score\": \"2\"}\n{\"id\": 2, \"score\": \"1.5\"}\n{\"id\": 3}\n";
+        let expected = engine.process_bytes(data).unwrap();
+
+        // The partial-emit path of process_data, minus the callback, with
+        // slices that split rows
+        let mut run = SliceRun::default();
+        let mut parser = NdjsonStreamParser::new(JsonParseOptions::default());
+        for slice in data.chunks(7) {
+            engine
+                .transform_slice(&mut run, &mut parser, Some(slice))
+                .unwrap();
+        }
+        engine.transform_slice(&mut run, &mut parser, None).unwrap();
+        let partial = engine.finish_slices(run, 0.0, data.len()).unwrap();
+        assert_eq!(
+            serde_json::to_value(&partial).unwrap(),
+            serde_json::to_value(&expected).unwrap()
+        );
+        assert_eq!(partial.data[1]["score"], serde_json::json!(1.5));
+    }
+
+    #[test]
+    fn test_schema_rule_settings_restored() {
+        let mut engine = QueryEngine::new();
+        engine.set_input_format("ndjson").unwrap();
+        engine.set_deterministic(true);
+        let mut then_config = serde_json::to_value(engine.config()).unwrap();
+        then_config["annotate_rows"] = false.into();
+        then_config["output_key"] = "id".into();
+        let mut config = serde_json::to_value(engine.config()).unwrap();
+        config["schema_rules"] = serde_json::json!([{
+            "required_columns": ["id"],
+            "config": then_config,
+        }]);
+        engine
+            .apply_config(serde_json::from_value(config).unwrap())
+            .unwrap();
+        let before = engine.config();
+
+        let matched = engine.process_bytes(b"{\"id\": 7}\n").unwrap();
+        assert!(!matched.annotated);
+        assert_eq!(matched.output_key.as_deref(), Some("id"));
+        assert_eq!(engine.config(), before);
+
+        let unmatched = engine.process_bytes(b"{\"v\": 1}\n").unwrap();
+        assert!(unmatched.annotated);
+        assert_eq!(unmatched.output_key, None);
+    }
+
+    #[test]
+    fn test_gzipped_stream_chunks() {
+        use crate::compression::{gzip, GunzipStream, InflateLimits};
+
+        let body = b"{\"id\": 1}\n{\"id\": 2}\n{\"id\": 3}\n";
+        let compressed = gzip(body);
+
+        // Output taken as it arrives still adds up to the whole body
+        let mut inflater = GunzipStream::new(InflateLimits {
+            max_output: 1 << 20,
+            max_ratio: None,
+        });
+        let mut inflated = Vec::new();
+        for chunk in compressed.chunks(5) {
+            inflater.push(chunk).unwrap();
+            inflated.extend(inflater.take_output());
+        }
+        inflated.extend(inflater.finish().unwrap());
+        assert_eq!(inflated, body);
+
+        // A lone first byte is held until gzip or not can be told
+        let mut undecided = GunzipStream::new(InflateLimits {
+            max_output: 1 << 20,
+            max_ratio: None,
+        });
+        undecided.push(&compressed[..1]).unwrap();
+        assert_eq!(undecided.take_output(), b"");
+        assert_eq!(undecided.pending_len(), 1);
+
+        let mut engine = QueryEngine::new();
+        engine.set_input_format("ndjson").unwrap();
+        engine.set_deterministic(true);
+        engine.set_annotate_rows(false);
+        engine.begin_stream().unwrap();
+        for chunk in compressed.chunks(7) {
+            engine.push_chunk(chunk).unwrap();
+        }
+        let result = engine.finish_stream().unwrap();
+        let ids: Vec<_> = result.data.iter().map(|row| row["id"].clone()).collect();
+        assert_eq!(ids, [1, 2, 3]);
+    }
+
+    #[test]
+    fn test_processing_stage() {
+        use utils::processing_stage;
+
+        assert_eq!(processing_stage(Some("invalid_gzip")), "decompress");
+        assert_eq!(processing_stage(Some("decompression_bomb")), "decompress");
+        assert_eq!(processing_stage(Some("invalid_json")), "parse");
+        assert_eq!(processing_stage(None), "parse");
+    }
 }
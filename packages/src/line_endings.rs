@@ -0,0 +1,111 @@
+use serde_json::{json, Value};
+use std::ops::Range;
+
+// Line splitting for the line-oriented inputs (NDJSON, logs). `\n`, `\r\n`
+// and a lone `\r` all end a line, as they already do for CSV records, so
+// files with Windows or classic Mac line endings parse the same as Unix ones.
+
+fn is_break(b: &u8) -> bool {
+    *b == b'\n' || *b == b'\r'
+}
+
+/// Byte ranges of lines (without their line breaks) from `start` onwards.
+/// A break at the very end doesn't begin another, empty line.
+pub(crate) struct LineRanges<'a> {
+    data: &'a [u8],
+    start: usize,
+}
+
+impl LineRanges<'_> {
+    pub(crate) fn new(data: &[u8], start: usize) -> LineRanges<'_> {
+        LineRanges { data, start }
+    }
+}
+
+impl Iterator for LineRanges<'_> {
+    type Item = Range<usize>;
+
+    fn next(&mut self) -> Option<Range<usize>> {
+        if self.start >= self.data.len() {
+            return None;
+        }
+        let start = self.start;
+        let end = self.data[start..]
+            .iter()
+            .position(is_break)
+            .map_or(self.data.len(), |i| start + i);
+        self.start = match self.data.get(end..end + 2) {
+            Some(b"\r\n") => end + 2,
+            _ => end + 1,
+        };
+        Some(start..end)
+    }
+}
+
+/// Offset just past the last complete line break in `data`, or `None` if
+/// there isn't one. A trailing `\r` doesn't count, since the `\n` of a
+/// `\r\n` may not have arrived yet.
+pub(crate) fn last_line_break_end(data: &[u8]) -> Option<usize> {
+    let searched = data.strip_suffix(b"\r").unwrap_or(data);
+    let at = searched.iter().rposition(is_break)?;
+    Some(match data.get(at..at + 2) {
+        Some(b"\r\n") => at + 2,
+        _ => at + 1,
+    })
+}
+
+/// Start of the line holding `offset`.
+pub(crate) fn line_start(data: &[u8], offset: usize) -> usize {
+    match data[..offset].iter().rposition(is_break) {
+        // Inside a `\r\n`: the line starts after the `\n`
+        Some(at) if data.get(at..at + 2) == Some(b"\r\n") && at + 1 == offset => at + 2,
+        Some(at) => at + 1,
+        None => 0,
+    }
+}
+
+/// How many of each kind of line break a text input contained.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub(crate) struct LineEndingCounts {
+    pub lf: usize,
+    pub crlf: usize,
+    pub cr: usize,
+}
+
+impl LineEndingCounts {
+    pub(crate) fn scan(data: &[u8]) -> LineEndingCounts {
+        let mut counts = LineEndingCounts::default();
+        let mut bytes = data.iter().peekable();
+        while let Some(&b) = bytes.next() {
+            match b {
+                b'\r' if bytes.next_if_eq(&&b'\n').is_some() => counts.crlf += 1,
+                b'\r' => counts.cr += 1,
+                b'\n' => counts.lf += 1,
+                _ => {}
+            }
+        }
+        counts
+    }
+
+    /// `"lf"`, `"crlf"` or `"cr"` when one kind was used throughout,
+    /// `"mixed"` for more than one, or `"none"` for single-line input.
+    pub(crate) fn style(&self) -> &'static str {
+        match (self.lf > 0, self.crlf > 0, self.cr > 0) {
+            (false, false, false) => "none",
+            (true, false, false) => "lf",
+            (false, true, false) => "crlf",
+            (false, false, true) => "cr",
+            _ => "mixed",
+        }
+    }
+
+    pub(crate) fn to_json(self) -> Value {
+        json!({
+            "line_endings": self.style(),
+            "mixed": self.style() == "mixed",
+            "lf": self.lf,
+            "crlf": self.crlf,
+            "cr": self.cr,
+        })
+    }
+}
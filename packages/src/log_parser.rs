@@ -0,0 +1,55 @@
+use crate::csv_parser::coerce_value;
+use crate::line_endings::LineRanges;
+use crate::utils::ParseError;
+use regex::Regex;
+use serde_json::{Map, Value};
+
+// Line-oriented text logs turned into rows with a regex: each named capture
+// group becomes a column.
+
+/// Compile `pattern`, which must have at least one named capture group.
+pub(crate) fn compile_pattern(pattern: &str) -> Result<Regex, ParseError> {
+    let regex = Regex::new(pattern).map_err(|e| ParseError {
+        kind: "invalid_pattern",
+        message: format!("Invalid log pattern: {e}"),
+    })?;
+    if regex.capture_names().flatten().next().is_none() {
+        return Err(ParseError {
+            kind: "invalid_pattern",
+            message: "Log pattern has no named capture groups, e.g. (?P<status>\\d+)".to_string(),
+        });
+    }
+    Ok(regex)
+}
+
+/// Rows for the lines of `text` that `regex` matches, plus the 1-based
+/// numbers of non-blank lines it didn't. Captured text is coerced like a CSV
+/// field (numbers, booleans); groups that didn't participate are null.
+pub(crate) fn parse_logs(text: &str, regex: &Regex) -> (Vec<Value>, Vec<usize>) {
+    let names: Vec<&str> = regex.capture_names().flatten().collect();
+    let mut rows = Vec::new();
+    let mut unmatched = Vec::new();
+
+    for (index, line) in LineRanges::new(text.as_bytes(), 0).enumerate() {
+        // Lines are split on ASCII line breaks, so stay on char boundaries
+        let line = &text[line];
+        if line.trim().is_empty() {
+            continue;
+        }
+        let Some(captures) = regex.captures(line) else {
+            unmatched.push(index + 1);
+            continue;
+        };
+        let row: Map<String, Value> = names
+            .iter()
+            .map(|name| {
+                let value = captures
+                    .name(name)
+                    .map_or(Value::Null, |m| coerce_value(m.as_str(), None));
+                (name.to_string(), value)
+            })
+            .collect();
+        rows.push(Value::Object(row));
+    }
+    (rows, unmatched)
+}
@@ -1,10 +1,34 @@
-use std::collections::HashMap;
+use std::collections::BTreeMap;
 use wasm_bindgen::prelude::*;
 
+// Buffer ids pack a slot index in the low bits and that slot's generation in
+// the high bits. Freed slots are reused with a bumped generation, so an id
+// cached after its buffer was freed no longer resolves. Generations wrap after
+// 256 reuses of the same slot.
+const SLOT_BITS: u32 = 24;
+const SLOT_MASK: u32 = (1 << SLOT_BITS) - 1;
+const GENERATION_MASK: u32 = (1 << (32 - SLOT_BITS)) - 1;
+
+fn pack_id(slot: u32, generation: u32) -> u32 {
+    (generation << SLOT_BITS) | slot
+}
+
+fn unpack_id(id: u32) -> (u32, u32) {
+    (id & SLOT_MASK, id >> SLOT_BITS)
+}
+
+struct Slot {
+    generation: u32,
+    data: Vec<u8>,
+    expected_len: Option<usize>,
+}
+
 #[wasm_bindgen]
 pub struct MemoryManager {
-    buffers: HashMap<u32, Vec<u8>>,
-    next_id: u32,
+    buffers: BTreeMap<u32, Slot>,
+    generations: Vec<u32>,
+    free_slots: Vec<u32>,
+    event_callback: Option<js_sys::Function>,
 }
 
 impl Default for MemoryManager {
@@ -17,47 +41,205 @@ impl Default for MemoryManager {
 impl MemoryManager {
     #[wasm_bindgen(constructor)]
     pub fn new() -> MemoryManager {
-        console_error_panic_hook::set_once();
+        crate::init_panic_hook();
         MemoryManager {
-            buffers: HashMap::new(),
-            next_id: 0,
+            buffers: BTreeMap::new(),
+            generations: Vec::new(),
+            free_slots: Vec::new(),
+            event_callback: None,
         }
     }
 
+    /// Call `cb({event: "alloc" | "free", id, size, total_after})` whenever a
+    /// buffer is allocated or freed. Exceptions thrown by `cb` are ignored.
+    #[wasm_bindgen]
+    pub fn set_event_callback(&mut self, cb: js_sys::Function) {
+        self.event_callback = Some(cb);
+    }
+
+    #[wasm_bindgen]
+    pub fn clear_event_callback(&mut self) {
+        self.event_callback = None;
+    }
+
     #[wasm_bindgen]
     pub fn allocate_buffer(&mut self, size: usize) -> u32 {
-        let buffer = Vec::with_capacity(size);
-        let id = self.next_id;
-        self.buffers.insert(id, buffer);
-        self.next_id += 1;
+        let slot = self.free_slots.pop().unwrap_or_else(|| {
+            self.generations.push(0);
+            (self.generations.len() - 1) as u32
+        });
+        let generation = self.generations[slot as usize];
+        // `size` is only a capacity hint; an unsatisfiable one (e.g. from a
+        // bad length on the JS side) leaves the buffer to grow on write
+        // instead of aborting the instance.
+        let mut data = Vec::new();
+        let _ = data.try_reserve_exact(size);
+        self.buffers.insert(
+            slot,
+            Slot {
+                generation,
+                data,
+                expected_len: None,
+            },
+        );
+
+        let id = pack_id(slot, generation);
+        self.emit_event("alloc", id, size);
         id
     }
 
     #[wasm_bindgen]
     pub fn get_buffer_ptr(&self, id: u32) -> *const u8 {
-        self.buffers
-            .get(&id)
+        self.get_buffer(id)
             .map(|b| b.as_ptr())
             .unwrap_or(std::ptr::null())
     }
 
     #[wasm_bindgen]
     pub fn get_buffer_len(&self, id: u32) -> usize {
-        self.buffers.get(&id).map(|b| b.len()).unwrap_or(0)
+        self.get_buffer(id).map(|b| b.len()).unwrap_or(0)
+    }
+
+    /// Whether `id` refers to a live buffer (false for stale or unknown ids).
+    #[wasm_bindgen]
+    pub fn is_valid_buffer(&self, id: u32) -> bool {
+        self.get_buffer(id).is_some()
+    }
+
+    /// Replace the contents of a buffer. Returns false for unknown ids.
+    #[wasm_bindgen]
+    pub fn write_buffer(&mut self, id: u32, data: &[u8]) -> bool {
+        match self.get_buffer_mut(id) {
+            Some(buffer) => {
+                buffer.clear();
+                buffer.extend_from_slice(data);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Append bytes to a buffer. Returns false for unknown ids.
+    #[wasm_bindgen]
+    pub fn append_bytes(&mut self, id: u32, data: &[u8]) -> bool {
+        match self.get_buffer_mut(id) {
+            Some(buffer) => {
+                buffer.extend_from_slice(data);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Record the length a buffer should reach once fully written, for
+    /// `is_buffer_complete`. Returns false for unknown ids.
+    #[wasm_bindgen]
+    pub fn set_expected_len(&mut self, id: u32, len: usize) -> bool {
+        match self.get_slot_mut(id) {
+            Some(slot) => {
+                slot.expected_len = Some(len);
+                true
+            }
+            None => false,
+        }
+    }
+
+    #[wasm_bindgen]
+    pub fn get_expected_len(&self, id: u32) -> Option<usize> {
+        self.get_slot(id).and_then(|s| s.expected_len)
+    }
+
+    /// Whether a buffer has reached its expected length. Buffers without an
+    /// expected length are always complete; unknown ids never are.
+    #[wasm_bindgen]
+    pub fn is_buffer_complete(&self, id: u32) -> bool {
+        self.get_slot(id)
+            .is_some_and(|s| s.expected_len.is_none_or(|len| s.data.len() == len))
     }
 
     #[wasm_bindgen]
     pub fn deallocate_buffer(&mut self, id: u32) -> bool {
-        self.buffers.remove(&id).is_some()
+        self.take_buffer(id).is_some()
+    }
+
+    /// Free every buffer. Ids handed out before the reset become stale.
+    #[wasm_bindgen]
+    pub fn reset(&mut self) {
+        let ids = self.get_buffer_ids();
+        for id in ids {
+            self.take_buffer(id);
+        }
     }
 
     #[wasm_bindgen]
     pub fn get_total_allocated(&self) -> usize {
-        self.buffers.values().map(|b| b.capacity()).sum()
+        self.buffers.values().map(|s| s.data.capacity()).sum()
     }
 
     #[wasm_bindgen]
     pub fn get_buffer_count(&self) -> u32 {
         self.buffers.len() as u32
     }
+
+    /// Ids of live buffers in ascending order.
+    #[wasm_bindgen]
+    pub fn get_buffer_ids(&self) -> Vec<u32> {
+        self.buffers
+            .iter()
+            .map(|(slot, s)| pack_id(*slot, s.generation))
+            .collect()
+    }
+}
+
+impl MemoryManager {
+    fn get_slot(&self, id: u32) -> Option<&Slot> {
+        let (slot, generation) = unpack_id(id);
+        self.buffers
+            .get(&slot)
+            .filter(|s| s.generation == generation)
+    }
+
+    fn get_slot_mut(&mut self, id: u32) -> Option<&mut Slot> {
+        let (slot, generation) = unpack_id(id);
+        self.buffers
+            .get_mut(&slot)
+            .filter(|s| s.generation == generation)
+    }
+
+    pub(crate) fn get_buffer(&self, id: u32) -> Option<&Vec<u8>> {
+        self.get_slot(id).map(|s| &s.data)
+    }
+
+    pub(crate) fn get_buffer_mut(&mut self, id: u32) -> Option<&mut Vec<u8>> {
+        self.get_slot_mut(id).map(|s| &mut s.data)
+    }
+
+    pub(crate) fn take_buffer(&mut self, id: u32) -> Option<Vec<u8>> {
+        self.get_buffer(id)?;
+        let (slot, generation) = unpack_id(id);
+        let buffer = self.buffers.remove(&slot)?.data;
+
+        self.generations[slot as usize] = (generation + 1) & GENERATION_MASK;
+        self.free_slots.push(slot);
+
+        self.emit_event("free", id, buffer.capacity());
+        Some(buffer)
+    }
+
+    fn emit_event(&self, event: &str, id: u32, size: usize) {
+        let Some(cb) = &self.event_callback else {
+            return;
+        };
+
+        let payload = js_sys::Object::new();
+        let _ = js_sys::Reflect::set(&payload, &"event".into(), &event.into());
+        let _ = js_sys::Reflect::set(&payload, &"id".into(), &id.into());
+        let _ = js_sys::Reflect::set(&payload, &"size".into(), &(size as f64).into());
+        let _ = js_sys::Reflect::set(
+            &payload,
+            &"total_after".into(),
+            &(self.get_total_allocated() as f64).into(),
+        );
+        let _ = cb.call1(&JsValue::NULL, &payload);
+    }
 }
@@ -1,8 +1,43 @@
+use crate::arrow_ipc;
+use crate::cloud_storage_bridge::{CloudDataBuffer, CloudStorageBridge};
+use crate::compression::{self, GunzipStream, InflateLimits, InputCompression};
+use crate::csv_parser::{count_csv, parse_csv, CsvOptions, NumberLocale, RaggedPolicy};
+use crate::export;
+use crate::expression::{parse_expression, Expr};
+use crate::json_parser::{count_json_array, parse_json, JsonParseOptions};
+use crate::line_endings::LineEndingCounts;
+use crate::log_parser;
 use crate::memory_manager::MemoryManager;
+use crate::schema::{self, SchemaCache};
+use crate::stream_parser::{count_ndjson, CsvStreamParser, NdjsonStreamParser, StreamParser};
+use crate::timestamp::TimestampFormat;
+use crate::transforms;
+use crate::utils::{
+    error_message, js_error, pipeline_error, processing_error, yield_to_event_loop, ParseError,
+    MAX_INPUT_BYTES,
+};
 use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+use std::cell::Cell;
+use std::rc::Rc;
 use wasm_bindgen::prelude::*;
 
-#[derive(Serialize, Deserialize)]
+// Rows serialized per uploaded part in `process_and_put`
+const UPLOAD_CHUNK_ROWS: usize = 10_000;
+// NDJSON bytes parsed between deadline checks
+const DEADLINE_CHECK_BYTES: usize = 1 << 20;
+// Bytes requested per range in `process_from_cloud`
+const CLOUD_RANGE_BYTES: usize = 4 << 20;
+// Rows serialized between clock checks in `QueryResult::data_chunked`
+const DATA_SLICE_CHECK_ROWS: usize = 256;
+
+#[wasm_bindgen]
+extern "C" {
+    #[wasm_bindgen(js_namespace = console)]
+    fn log(s: &str);
+}
+
+#[derive(Serialize, Deserialize, Default)]
 #[wasm_bindgen]
 pub struct QueryResult {
     #[wasm_bindgen(skip)]
@@ -10,19 +45,550 @@ pub struct QueryResult {
     pub row_count: u32,
     pub execution_time_ms: u32,
     pub memory_used_bytes: u32,
+    pub bytes_per_sec: f64,
+    pub rows_per_sec: f64,
+    pub(crate) float_precision: Option<usize>,
+    pub(crate) trace_id: Option<String>,
+    pub(crate) bigint_passthrough: bool,
+    pub(crate) unsafe_integer_count: u32,
+    pub(crate) warnings: Vec<String>,
+    pub(crate) input_buffer_id: Option<u32>,
+    pub(crate) output_key: Option<String>,
+    pub(crate) parse_ms: f64,
+    pub(crate) transform_ms: f64,
+    pub(crate) total_ms: f64,
+    pub(crate) stage_counts: Vec<(String, usize)>,
+    pub(crate) null_representation: export::NullRepresentation,
+    pub(crate) annotated: bool,
+    pub(crate) index_first: Option<String>,
 }
 
 #[wasm_bindgen]
 impl QueryResult {
+    /// The rows as an array, or, when the engine has an output key set, as an
+    /// object keyed by that field's value with unkeyed rows under
+    /// `__missing__`. With `set_index_first`, array rows list the index
+    /// column first.
     #[wasm_bindgen(getter)]
     pub fn data(&self) -> JsValue {
-        serde_wasm_bindgen::to_value(&self.data).unwrap()
+        if let Some(field) = &self.output_key {
+            let keyed = export::KeyedRows {
+                rows: &self.data,
+                field,
+                bigint: self.bigint_passthrough,
+            };
+            let serializer = serde_wasm_bindgen::Serializer::new()
+                .serialize_large_number_types_as_bigints(self.bigint_passthrough);
+            return keyed.serialize(&serializer).unwrap_or(JsValue::NULL);
+        }
+        if let Some(field) = &self.index_first {
+            let serializer = serde_wasm_bindgen::Serializer::new()
+                .serialize_large_number_types_as_bigints(self.bigint_passthrough);
+            let rows: Vec<_> = self
+                .data
+                .iter()
+                .map(|row| export::IndexFirst {
+                    row,
+                    field,
+                    bigint: self.bigint_passthrough,
+                })
+                .collect();
+            return rows.serialize(&serializer).unwrap_or(JsValue::NULL);
+        }
+        if self.bigint_passthrough {
+            let serializer =
+                serde_wasm_bindgen::Serializer::new().serialize_large_number_types_as_bigints(true);
+            let rows: Vec<_> = self.data.iter().map(export::BigIntSafe).collect();
+            return rows.serialize(&serializer).unwrap_or(JsValue::NULL);
+        }
+        serde_wasm_bindgen::to_value(&self.data).unwrap_or(JsValue::NULL)
+    }
+
+    /// `data` together with how long building it took:
+    /// `{data, serialize_ms}`.
+    #[wasm_bindgen]
+    pub fn data_timed(&self) -> JsValue {
+        let start = js_sys::Date::now();
+        let data = self.data();
+        let timed = js_sys::Object::new();
+        let _ = js_sys::Reflect::set(&timed, &JsValue::from_str("data"), &data);
+        let _ = js_sys::Reflect::set(
+            &timed,
+            &JsValue::from_str("serialize_ms"),
+            &JsValue::from_f64(js_sys::Date::now() - start),
+        );
+        timed.into()
+    }
+
+    /// Resolves to the same rows as `data`, serialized in slices of at most
+    /// about `max_ms` with a yield to the event loop between them, so large
+    /// results don't block the thread in one go. Keyed output (see
+    /// `QueryEngine::set_output_key`) is built in a single slice. Fails with
+    /// an `invalid_time_slice` `DataPrismError` unless `max_ms` is positive.
+    #[wasm_bindgen]
+    pub async fn data_chunked(&self, max_ms: f64) -> Result<JsValue, JsValue> {
+        if max_ms.is_nan() || max_ms <= 0.0 {
+            return Err(js_error(
+                "invalid_time_slice",
+                400,
+                "max_ms must be greater than zero",
+            ));
+        }
+        if self.output_key.is_some() {
+            return Ok(self.data());
+        }
+
+        let serializer = serde_wasm_bindgen::Serializer::new()
+            .serialize_large_number_types_as_bigints(self.bigint_passthrough);
+        let rows = js_sys::Array::new_with_length(self.data.len() as u32);
+        let mut slice_start = js_sys::Date::now();
+        for (i, row) in self.data.iter().enumerate() {
+            let value = if self.bigint_passthrough {
+                export::BigIntSafe(row).serialize(&serializer)?
+            } else {
+                row.serialize(&serializer)?
+            };
+            rows.set(i as u32, value);
+
+            if (i + 1) % DATA_SLICE_CHECK_ROWS == 0 && js_sys::Date::now() - slice_start >= max_ms {
+                yield_to_event_loop().await?;
+                slice_start = js_sys::Date::now();
+            }
+        }
+        Ok(rows.into())
+    }
+
+    /// Number of integers outside the JS safe range (|n| > 2^53 - 1) found
+    /// while processing. Only counted when BigInt passthrough is enabled.
+    #[wasm_bindgen(getter)]
+    pub fn unsafe_integer_count(&self) -> u32 {
+        self.unsafe_integer_count
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn trace_id(&self) -> Option<String> {
+        self.trace_id.clone()
+    }
+
+    /// Id of the staged copy of the input when the engine retains input;
+    /// pass it to `QueryEngine::process_buffer` to re-run without re-sending.
+    #[wasm_bindgen(getter)]
+    pub fn input_buffer_id(&self) -> Option<u32> {
+        self.input_buffer_id
+    }
+
+    /// Where the time went: `{parse_ms, transform_ms, total_ms}`. Parsing
+    /// covers decoding the input into rows; transforming covers schema
+    /// coercion, computed columns and output key checks (or the whole of a
+    /// `pivot`). Serializing `data` happens later in the getter and is not
+    /// included; `data_timed` measures it.
+    #[wasm_bindgen(getter)]
+    pub fn timing(&self) -> JsValue {
+        let timing = serde_json::json!({
+            "parse_ms": self.parse_ms,
+            "transform_ms": self.transform_ms,
+            "total_ms": self.total_ms,
+        });
+        serde_wasm_bindgen::to_value(&timing).unwrap_or(JsValue::NULL)
+    }
+
+    /// Row counts through the pipeline, in stage order, e.g.
+    /// `{parsed: 1000, after_output_key: 750, output: 750}`. `output` is the
+    /// number of entries in `data`. Empty for results not built from parsed
+    /// input (such as `pivot`).
+    #[wasm_bindgen(getter)]
+    pub fn stage_counts(&self) -> JsValue {
+        let counts = self
+            .stage_counts
+            .iter()
+            .map(|(stage, count)| (stage, count));
+        serde::Serializer::collect_map(&serde_wasm_bindgen::Serializer::new(), counts)
+            .unwrap_or(JsValue::NULL)
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn warnings(&self) -> Vec<String> {
+        self.warnings.clone()
+    }
+
+    #[wasm_bindgen]
+    pub fn data_to_string(&self) -> String {
+        export::rows_to_json(&self.data, self.float_precision)
+    }
+
+    #[wasm_bindgen]
+    pub fn to_csv(&self) -> String {
+        export::rows_to_csv(&self.data, self.float_precision, &self.null_representation)
+    }
+
+    /// The rows as RFC 8785 canonical JSON (sorted keys, shortest number
+    /// form, no whitespace), stable for hashing and diffing across runs.
+    /// Float precision settings don't apply.
+    #[wasm_bindgen]
+    pub fn to_canonical_json(&self) -> String {
+        export::rows_to_canonical_json(&self.data)
+    }
+
+    /// Hex SHA-256 of the rows as canonical JSON (see `to_canonical_json`),
+    /// for keying caches on result content. The injected `timestamp` of
+    /// annotated rows is left out, so re-running the same query over the
+    /// same input gives the same hash.
+    #[wasm_bindgen]
+    pub fn content_hash(&self) -> String {
+        export::content_hash(&self.data, self.annotated.then_some("timestamp"))
+    }
+
+    /// The rows as an Arrow IPC stream (one record batch), for Arrow JS or
+    /// DuckDB-WASM. Column types are inferred: integer, float, boolean or
+    /// string, with mixed columns promoted to string (integers mixed with
+    /// floats become float). Nested values are stored as JSON text.
+    #[wasm_bindgen]
+    pub fn to_arrow_ipc(&self) -> Result<js_sys::Uint8Array, JsValue> {
+        let bytes = arrow_ipc::rows_to_arrow_ipc(&self.data)
+            .map_err(|e| js_error("arrow_export_failed", 500, &e))?;
+        Ok(js_sys::Uint8Array::from(&bytes[..]))
+    }
+}
+
+/// Running statistics over one numeric field of an NDJSON stream, from
+/// `QueryEngine::stream_aggregate`. Rows are parsed, folded into the stats and
+/// dropped; only the stats are kept.
+#[wasm_bindgen]
+pub struct StreamAggregator {
+    parser: NdjsonStreamParser,
+    field: String,
+    ops: Vec<String>,
+    stats: transforms::RunningStats,
+    rows_seen: u64,
+}
+
+#[wasm_bindgen]
+impl StreamAggregator {
+    /// Feed the next chunk of NDJSON. Returns the number of rows seen so far.
+    #[wasm_bindgen]
+    pub fn push_chunk(&mut self, chunk: &[u8]) -> Result<u32, JsValue> {
+        let rows = self.parser.push(chunk)?;
+        self.fold(rows);
+        Ok(self.rows_seen as u32)
+    }
+
+    /// Parse any buffered trailing input and return
+    /// `{field, rows, <op>: value, ...}` for the requested ops. `count` is the
+    /// number of numeric values of `field`; other rows are skipped.
+    #[wasm_bindgen]
+    pub fn finish(&mut self) -> Result<JsValue, JsValue> {
+        let rows = self.parser.finish()?;
+        self.fold(rows);
+
+        let mut stats = serde_json::Map::new();
+        stats.insert("field".to_string(), self.field.clone().into());
+        stats.insert("rows".to_string(), self.rows_seen.into());
+        for op in &self.ops {
+            stats.insert(op.clone(), self.stats.get(op));
+        }
+        Ok(serde_wasm_bindgen::to_value(&stats)?)
+    }
+
+    fn fold(&mut self, rows: Vec<serde_json::Value>) {
+        for row in rows {
+            self.rows_seen += 1;
+            if let Some(value) = row.get(&self.field).and_then(|v| v.as_f64()) {
+                self.stats.push(value);
+            }
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+enum InputFormat {
+    Json,
+    Ndjson,
+    Csv,
+}
+
+impl InputFormat {
+    /// Format of an archive member from its extension (ignoring a trailing
+    /// `.gz`), else from its first bytes. `None` for non-data members.
+    fn detect(name: &str, data: &[u8]) -> Option<InputFormat> {
+        let name = name.to_ascii_lowercase();
+        let name = name.strip_suffix(".gz").unwrap_or(&name);
+        match name.rsplit_once('.').map(|(_, ext)| ext) {
+            Some("csv") => return Some(InputFormat::Csv),
+            Some("json") => return Some(InputFormat::Json),
+            Some("ndjson" | "jsonl") => return Some(InputFormat::Ndjson),
+            _ => {}
+        }
+
+        let text = data.trim_ascii_start();
+        match text.first() {
+            Some(b'[') => Some(InputFormat::Json),
+            // More than one line of objects is NDJSON
+            Some(b'{')
+                if text
+                    .trim_ascii_end()
+                    .iter()
+                    .any(|b| matches!(b, b'\n' | b'\r')) =>
+            {
+                Some(InputFormat::Ndjson)
+            }
+            Some(b'{') => Some(InputFormat::Json),
+            _ => None,
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            InputFormat::Json => "json",
+            InputFormat::Ndjson => "ndjson",
+            InputFormat::Csv => "csv",
+        }
+    }
+}
+
+// Snapshot of every engine setting, as exchanged by `export_config` and
+// `import_config`. Missing fields take the engine defaults.
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct EngineConfig {
+    /// Crate version that exported the config; ignored on import.
+    version: String,
+    input_format: String,
+    input_compression: String,
+    csv_ragged_policy: String,
+    number_locale: Option<NumberLocaleConfig>,
+    include_columns: Vec<String>,
+    exclude_columns: Vec<String>,
+    max_json_depth: Option<usize>,
+    reject_duplicate_keys: bool,
+    resync_on_error: bool,
+    schema: std::collections::BTreeMap<String, String>,
+    schema_on_fail: String,
+    unknown_columns: String,
+    unflatten_separator: Option<String>,
+    computed_columns: Vec<ComputedColumnConfig>,
+    enrichments: Vec<Enrichment>,
+    output_key: Option<String>,
+    output_key_unique: bool,
+    null_representation: export::NullRepresentation,
+    binary_encoding: String,
+    float_precision: Option<usize>,
+    bigint_passthrough: bool,
+    annotate_rows: bool,
+    deterministic: bool,
+    retain_input: bool,
+    trace_id: Option<String>,
+    deadline_ms: Option<f64>,
+    max_pivot_columns: usize,
+    max_columns: usize,
+    error_on_empty: bool,
+    schema_rules: Vec<SchemaRule>,
+    timestamp_format: String,
+    mapping: Vec<MappingConfig>,
+    json_columns: Vec<String>,
+    row_id: Option<RowIdConfig>,
+    upsert_merge_fields: bool,
+    tombstone_field: Option<String>,
+    index_column: Option<String>,
+    index_first: bool,
+}
+
+impl Default for EngineConfig {
+    fn default() -> Self {
+        QueryEngine::new().config()
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
+#[serde(deny_unknown_fields)]
+struct NumberLocaleConfig {
+    decimal: String,
+    #[serde(default)]
+    thousands: String,
+}
+
+// A lookup join applied to each processed row; see `enrich_with`.
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
+#[serde(deny_unknown_fields)]
+struct Enrichment {
+    table: String,
+    on_field: String,
+    prefix: String,
+    null_fill: bool,
+}
+
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
+#[serde(deny_unknown_fields)]
+struct ComputedColumnConfig {
+    name: String,
+    expr: String,
+}
+
+// One output column of `set_mapping`
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
+#[serde(deny_unknown_fields)]
+struct MappingConfig {
+    from: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    to: Option<String>,
+    #[serde(default, rename = "type", skip_serializing_if = "Option::is_none")]
+    type_name: Option<String>,
+}
+
+// The id column added by `set_add_row_id`
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
+#[serde(deny_unknown_fields)]
+struct RowIdConfig {
+    field: String,
+    mode: String,
+}
+
+// A config used in place of the engine's own for inputs whose columns
+// include all of `required_columns`; see `when_schema`.
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
+#[serde(deny_unknown_fields)]
+struct SchemaRule {
+    required_columns: Vec<String>,
+    config: EngineConfig,
+}
+
+// A `when_schema` rule with its config already applied to a scratch engine,
+// so a match swaps those settings in rather than validating them again.
+struct StagedRule {
+    rule: SchemaRule,
+    settings: QueryEngine,
+}
+
+// State for an incremental parse driven by `push_chunk`.
+struct StreamState {
+    parser: StreamParser,
+    // Unless the input compression is `none`; chunks are inflated on the way
+    // to the parser
+    inflater: Option<GunzipStream>,
+    require_gzip: bool,
+    rows: Vec<serde_json::Value>,
+    bytes_received: usize,
+    start_time: f64,
+    parse_ms: f64,
+}
+
+impl StreamState {
+    fn push(&mut self, chunk: &[u8]) -> Result<Vec<serde_json::Value>, ParseError> {
+        let Some(inflater) = &mut self.inflater else {
+            return self.parser.push(chunk);
+        };
+        inflater.push(chunk)?;
+        if self.require_gzip && inflater.is_plain() {
+            return Err(not_gzip());
+        }
+        self.parser.push(&inflater.take_output())
+    }
+
+    // Input bytes received but not yet parsed into rows, in the parser or
+    // still in the inflater
+    fn pending_len(&self) -> usize {
+        let inflating = self.inflater.as_ref().map_or(0, GunzipStream::pending_len);
+        self.parser.pending_len() + inflating
+    }
+
+    fn finish(&mut self) -> Result<Vec<serde_json::Value>, ParseError> {
+        let mut rows = match self.inflater.take() {
+            Some(inflater) if self.require_gzip && !inflater.is_gzip() => return Err(not_gzip()),
+            Some(inflater) => self.parser.push(&inflater.finish()?)?,
+            None => Vec::new(),
+        };
+        rows.append(&mut self.parser.finish()?);
+        Ok(rows)
+    }
+}
+
+fn not_gzip() -> ParseError {
+    ParseError {
+        kind: "invalid_gzip",
+        message: "Input is not gzip data (see set_input_compression)".to_string(),
+    }
+}
+
+// Columns seen by the slices of an incremental run so far, so the column
+// limit and the unknown-column checks judge the whole input rather than
+// whichever slice is being transformed.
+#[derive(Default)]
+pub(crate) struct SeenColumns {
+    // After unflattening, for the column limit
+    input: Vec<String>,
+    // After enrichment, for the unknown-column checks made once at the end
+    known: Vec<String>,
+}
+
+impl SeenColumns {
+    fn extend(columns: &mut Vec<String>, rows: &[serde_json::Value]) {
+        for column in export::collect_columns(rows) {
+            if !columns.contains(&column) {
+                columns.push(column);
+            }
+        }
     }
 }
 
+// An NDJSON `process_data` run with partial emit, transformed a slice at a
+// time; the rows are all kept for the final result
+#[derive(Default)]
+pub(crate) struct SliceRun {
+    rows: Vec<serde_json::Value>,
+    warnings: Vec<String>,
+    seen: SeenColumns,
+    parse_ms: f64,
+    transform_ms: f64,
+}
+
 #[wasm_bindgen]
 pub struct QueryEngine {
     memory_manager: MemoryManager,
+    float_precision: Option<usize>,
+    last_dataset: Vec<serde_json::Value>,
+    input_diagnostics: Option<LineEndingCounts>,
+    trace_id: Option<String>,
+    bigint_passthrough: bool,
+    max_pivot_columns: usize,
+    max_columns: usize,
+    error_on_empty: bool,
+    schema_rules: Vec<StagedRule>,
+    annotate_rows: bool,
+    stream: Option<StreamState>,
+    json_options: JsonParseOptions,
+    input_buffer: Vec<u8>,
+    retain_input: bool,
+    partial_emit: Option<(usize, js_sys::Function)>,
+    input_format: InputFormat,
+    csv_options: CsvOptions,
+    // (name, source expression, parsed expression)
+    computed_columns: Vec<(String, String, Expr)>,
+    json_columns: Vec<String>,
+    row_id: Option<(String, transforms::RowIdMode)>,
+    upsert_merge_fields: bool,
+    tombstone_field: Option<String>,
+    index_column: Option<String>,
+    index_first: bool,
+    // (source path, output column, declared type)
+    mapping: Vec<(String, String, Option<transforms::DeclaredType>)>,
+    lookup_tables: std::collections::HashMap<String, transforms::LookupTable>,
+    indexes: std::collections::HashMap<u32, transforms::RowIndex>,
+    next_index_id: u32,
+    enrichments: Vec<Enrichment>,
+    schema: Vec<(String, transforms::DeclaredType)>,
+    schema_errors: bool,
+    output_key: Option<String>,
+    output_key_unique: bool,
+    resync_on_error: bool,
+    unflatten_separator: Option<String>,
+    input_compression: InputCompression,
+    null_representation: export::NullRepresentation,
+    binary_base64: bool,
+    schema_cache: SchemaCache,
+    lenient_columns: bool,
+    deadline_ms: Option<f64>,
+    partial_result: Option<QueryResult>,
+    clock: Clock,
+    processing: bool,
 }
 
 impl Default for QueryEngine {
@@ -37,99 +603,3403 @@ impl QueryEngine {
     pub fn new() -> QueryEngine {
         QueryEngine {
             memory_manager: MemoryManager::new(),
+            float_precision: None,
+            last_dataset: Vec::new(),
+            input_diagnostics: None,
+            trace_id: None,
+            bigint_passthrough: false,
+            max_pivot_columns: 1000,
+            max_columns: 4096,
+            error_on_empty: false,
+            schema_rules: Vec::new(),
+            annotate_rows: true,
+            stream: None,
+            json_options: JsonParseOptions::default(),
+            input_buffer: Vec::new(),
+            retain_input: false,
+            partial_emit: None,
+            input_format: InputFormat::Json,
+            csv_options: CsvOptions::default(),
+            computed_columns: Vec::new(),
+            mapping: Vec::new(),
+            json_columns: Vec::new(),
+            row_id: None,
+            upsert_merge_fields: false,
+            tombstone_field: None,
+            index_column: None,
+            index_first: false,
+            lookup_tables: std::collections::HashMap::new(),
+            indexes: std::collections::HashMap::new(),
+            next_index_id: 1,
+            enrichments: Vec::new(),
+            schema: Vec::new(),
+            schema_errors: false,
+            output_key: None,
+            output_key_unique: false,
+            resync_on_error: false,
+            unflatten_separator: None,
+            input_compression: InputCompression::Auto,
+            null_representation: export::NullRepresentation::default(),
+            binary_base64: false,
+            schema_cache: SchemaCache::default(),
+            lenient_columns: false,
+            deadline_ms: None,
+            partial_result: None,
+            clock: Clock::default(),
+            processing: false,
         }
     }
 
+    /// Keep a staged copy of each `process_data` input in the engine's memory
+    /// manager, reported as `QueryResult.input_buffer_id`. Retained inputs are
+    /// held until `release_buffer` or `reset`.
     #[wasm_bindgen]
-    pub async fn process_data(&mut self, data: &[u8]) -> Result<QueryResult, JsValue> {
-        let start_time = js_sys::Date::now();
+    pub fn set_retain_input(&mut self, enabled: bool) {
+        self.retain_input = enabled;
+    }
 
-        // Validate input data
-        if data.is_empty() {
-            return Err(JsValue::from_str("Input data cannot be empty"));
-        }
+    /// Drop retained inputs, staged buffers, the last dataset and any
+    /// in-progress stream or accumulated input. Configuration is kept.
+    #[wasm_bindgen]
+    pub fn reset(&mut self) {
+        self.memory_manager.reset();
+        self.last_dataset = Vec::new();
+        self.input_diagnostics = None;
+        self.stream = None;
+        self.input_buffer = Vec::new();
+        self.clock.counter.set(0);
+    }
 
-        if data.len() > 100_000_000 {
-            // 100MB limit
-            return Err(JsValue::from_str("Input data exceeds maximum size limit"));
+    /// Fail with a `duplicate_key` `DataPrismError` when a JSON object repeats
+    /// a key, instead of silently keeping the last value.
+    #[wasm_bindgen]
+    pub fn set_reject_duplicate_keys(&mut self, enabled: bool) {
+        self.json_options.reject_duplicate_keys = enabled;
+    }
+
+    /// Input format for `process_data` and friends: `"json"` (default),
+    /// `"ndjson"` or `"csv"`. CSV input must start with a header row.
+    #[wasm_bindgen]
+    pub fn set_input_format(&mut self, format: &str) -> Result<(), JsValue> {
+        self.input_format = match format {
+            "json" => InputFormat::Json,
+            "ndjson" => InputFormat::Ndjson,
+            "csv" => InputFormat::Csv,
+            _ => {
+                return Err(js_error(
+                    "invalid_input_format",
+                    400,
+                    &format!("Unsupported input format '{format}' (expected json, ndjson or csv)"),
+                ))
+            }
+        };
+        Ok(())
+    }
+
+    /// Compression of input passed to `process_data`, `count` and the buffer
+    /// methods: `"auto"` (default) inflates input starting with the gzip
+    /// magic bytes, `"gzip"` always inflates, `"none"` never does. Streams fed
+    /// by `push_chunk` follow it too, inflating chunks as they arrive.
+    #[wasm_bindgen]
+    pub fn set_input_compression(&mut self, compression: &str) -> Result<(), JsValue> {
+        self.input_compression = InputCompression::parse(compression).ok_or_else(|| {
+            js_error(
+                "invalid_input_compression",
+                400,
+                &format!(
+                    "Unsupported input compression '{compression}' (expected auto, gzip or none)"
+                ),
+            )
+        })?;
+        Ok(())
+    }
+
+    /// How CSV rows with a different field count than the header are handled:
+    /// `"error"` (default) fails with a `ragged_row` `DataPrismError`, `"pad"`
+    /// fills missing trailing fields with null, `"truncate"` drops extra fields.
+    #[wasm_bindgen]
+    pub fn set_csv_ragged_policy(&mut self, policy: &str) -> Result<(), JsValue> {
+        self.csv_options.ragged = RaggedPolicy::parse(policy).ok_or_else(|| {
+            js_error(
+                "invalid_ragged_policy",
+                400,
+                &format!(
+                    "Unsupported CSV ragged policy '{policy}' (expected error, pad or truncate)"
+                ),
+            )
+        })?;
+        Ok(())
+    }
+
+    /// Parse CSV numbers written with these separators, e.g. `(",", ".")` for
+    /// `1.234,56`. `thousands_sep` may be empty for none. Values that don't
+    /// fit the locale (such as `1.5` when `.` groups thousands) stay strings.
+    #[wasm_bindgen]
+    pub fn set_number_locale(
+        &mut self,
+        decimal_sep: String,
+        thousands_sep: String,
+    ) -> Result<(), JsValue> {
+        let single = |s: &str| {
+            let mut chars = s.chars();
+            chars.next().filter(|_| chars.next().is_none())
+        };
+        let decimal = single(&decimal_sep);
+        let thousands = single(&thousands_sep);
+        match (decimal, thousands_sep.is_empty() || thousands.is_some()) {
+            (Some(decimal), true) if Some(decimal) != thousands && !decimal.is_ascii_digit() => {
+                self.csv_options.number_locale = Some(NumberLocale { decimal, thousands });
+                Ok(())
+            }
+            _ => Err(js_error(
+                "invalid_number_locale",
+                400,
+                &format!(
+                    "Invalid number locale (decimal '{decimal_sep}', thousands '{thousands_sep}'): separators must be distinct single characters"
+                ),
+            )),
         }
+    }
 
-        // Allocate buffer for processing
-        let buffer_id = self.memory_manager.allocate_buffer(data.len());
+    /// Go back to plain `1234.56` CSV number parsing.
+    #[wasm_bindgen]
+    pub fn clear_number_locale(&mut self) {
+        self.csv_options.number_locale = None;
+    }
 
-        // Simulate data processing - in real implementation this would contain
-        // optimized algorithms for data transformation and analysis
-        let processed_data = self.process_internal(data)?;
+    /// Keep only `include` columns (all when empty) minus `exclude` columns
+    /// of each JSON/NDJSON row while it is parsed, so dropped values are never
+    /// built. Applies to the keys of top-level rows only, not nested objects.
+    #[wasm_bindgen]
+    pub fn set_parse_columns(&mut self, include: Vec<String>, exclude: Vec<String>) {
+        self.json_options.include_columns = include;
+        self.json_options.exclude_columns = exclude;
+    }
 
-        // Clean up buffer
-        self.memory_manager.deallocate_buffer(buffer_id);
+    #[wasm_bindgen]
+    pub fn clear_parse_columns(&mut self) {
+        self.set_parse_columns(Vec::new(), Vec::new());
+    }
 
-        let end_time = js_sys::Date::now();
+    /// Unpack double-encoded JSON: the string value of each of `fields` is
+    /// parsed as JSON and replaced with the result (e.g. `"{\"a\":1}"`
+    /// becomes `{a: 1}`), before schema coercion, computed columns and
+    /// mapping see it. Strings that aren't valid JSON are left as they are
+    /// and counted per field in `QueryResult.warnings`. Replaces any
+    /// earlier list; an empty list turns this off.
+    #[wasm_bindgen]
+    pub fn parse_json_columns(&mut self, fields: Vec<String>) {
+        self.json_columns = fields;
+    }
 
-        Ok(QueryResult {
-            data: processed_data,
-            row_count: 2, // This would be computed based on actual data
-            execution_time_ms: (end_time - start_time) as u32,
-            memory_used_bytes: data.len() as u32,
-        })
+    /// Fail with a `max_depth_exceeded` `DataPrismError` when JSON input nests
+    /// arrays/objects deeper than `depth` (the top-level value is depth 1).
+    /// The check runs while parsing, so deep input never recurses past it.
+    /// serde_json's own recursion limit of 128 still applies above that.
+    #[wasm_bindgen]
+    pub fn set_max_json_depth(&mut self, depth: usize) {
+        self.json_options.max_depth = Some(depth);
     }
 
-    fn process_internal(&self, data: &[u8]) -> Result<Vec<serde_json::Value>, JsValue> {
-        // Parse input data and perform transformations
-        let data_str =
-            std::str::from_utf8(data).map_err(|_| JsValue::from_str("Invalid UTF-8 data"))?;
+    #[wasm_bindgen]
+    pub fn clear_max_json_depth(&mut self) {
+        self.json_options.max_depth = None;
+    }
 
-        // For demonstration, parse as JSON and perform simple transformations
-        if let Ok(json_data) = serde_json::from_str::<serde_json::Value>(data_str) {
-            if let Some(array) = json_data.as_array() {
-                let processed: Vec<serde_json::Value> = array
-                    .iter()
-                    .map(|item| {
-                        let mut processed_item = item.clone();
-                        if let Some(obj) = processed_item.as_object_mut() {
-                            obj.insert("processed".to_string(), serde_json::Value::Bool(true));
-                            obj.insert(
-                                "timestamp".to_string(),
-                                serde_json::Value::Number(serde_json::Number::from(
-                                    js_sys::Date::now() as i64,
-                                )),
-                            );
-                        }
-                        processed_item
+    /// Declare column types as `{column: "integer" | "number" | "string" |
+    /// "boolean" | "binary" | "timestamp"}`. Listed columns are coerced to
+    /// their type in every processed row (e.g. `"42"` to 42); other columns
+    /// pass through. Timestamps are read from epoch milliseconds or ISO-8601
+    /// text and written in the `set_timestamp_format` format. Values
+    /// that can't be converted become null, or fail the run with a
+    /// `schema_violation` `DataPrismError` after `set_schema_on_fail("error")`.
+    /// A declared column missing from every row is an `unknown_column` error
+    /// (see `set_unknown_columns`).
+    #[wasm_bindgen]
+    pub fn set_schema(&mut self, schema: JsValue) -> Result<(), JsValue> {
+        self.set_declared_schema(serde_wasm_bindgen::from_value(schema)?)
+    }
+
+    #[wasm_bindgen]
+    pub fn clear_schema(&mut self) {
+        self.schema.clear();
+    }
+
+    fn set_declared_schema(
+        &mut self,
+        declared: std::collections::BTreeMap<String, String>,
+    ) -> Result<(), JsValue> {
+        self.schema = declared
+            .into_iter()
+            .map(|(column, type_name)| {
+                transforms::DeclaredType::parse(&type_name)
+                    .map(|t| (column.clone(), t))
+                    .ok_or_else(|| {
+                        js_error(
+                            "invalid_schema",
+                            400,
+                            &format!("Unsupported type '{type_name}' for column '{column}'"),
+                        )
                     })
-                    .collect();
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(())
+    }
+
+    fn set_mapping_columns(&mut self, spec: Vec<MappingConfig>) -> Result<(), JsValue> {
+        let invalid = |message: String| js_error("invalid_mapping", 400, &message);
+        if spec.is_empty() {
+            return Err(invalid(
+                "A mapping needs at least one column (use clear_mapping to remove it)".into(),
+            ));
+        }
 
-                return Ok(processed);
+        let mut mapping = Vec::with_capacity(spec.len());
+        let mut outputs = std::collections::HashSet::new();
+        for column in spec {
+            let to = column.to.unwrap_or_else(|| column.from.clone());
+            if !outputs.insert(to.clone()) {
+                return Err(invalid(format!(
+                    "Output column '{to}' is mapped more than once"
+                )));
             }
+            let declared = match column.type_name {
+                Some(type_name) => {
+                    Some(transforms::DeclaredType::parse(&type_name).ok_or_else(|| {
+                        invalid(format!("Unsupported type '{type_name}' for column '{to}'"))
+                    })?)
+                }
+                None => None,
+            };
+            mapping.push((column.from, to, declared));
         }
+        self.mapping = mapping;
+        Ok(())
+    }
 
-        // Fallback: create simple processed data structure
-        Ok(vec![
-            serde_json::json!({"id": 1, "value": "processed", "source": "wasm"}),
-            serde_json::json!({"id": 2, "value": "data", "source": "wasm"}),
-        ])
+    /// What happens when a value can't be coerced to its declared type:
+    /// `"null"` (default) replaces it with null, `"error"` fails the run.
+    #[wasm_bindgen]
+    pub fn set_schema_on_fail(&mut self, behavior: &str) -> Result<(), JsValue> {
+        self.schema_errors = match behavior {
+            "null" => false,
+            "error" => true,
+            _ => {
+                return Err(js_error(
+                    "invalid_schema",
+                    400,
+                    &format!("Unsupported on-fail behavior '{behavior}' (expected null or error)"),
+                ))
+            }
+        };
+        Ok(())
     }
 
+    /// What happens when a configured column (schema, computed column
+    /// fields, output key, pivot arguments) appears in none of the rows:
+    /// `"error"` (default) fails with an `unknown_column` `DataPrismError`
+    /// listing the available columns; `"warn"` adds the same message to
+    /// `QueryResult.warnings` and carries on.
     #[wasm_bindgen]
-    pub fn get_memory_usage(&self) -> u32 {
-        self.memory_manager.get_total_allocated() as u32
+    pub fn set_unknown_columns(&mut self, behavior: &str) -> Result<(), JsValue> {
+        self.lenient_columns = match behavior {
+            "error" => false,
+            "warn" => true,
+            _ => {
+                return Err(js_error(
+                    "invalid_column_behavior",
+                    400,
+                    &format!(
+                        "Unsupported unknown-column behavior '{behavior}' (expected error or warn)"
+                    ),
+                ))
+            }
+        };
+        Ok(())
     }
 
+    /// Expand flattened keys such as `user.id` into nested objects while
+    /// processing, splitting on `separator`. When a key is both a value and a
+    /// prefix of other keys (`user` and `user.id`), the nested object is kept
+    /// and a warning is added to `QueryResult.warnings`.
     #[wasm_bindgen]
-    pub fn optimize_memory(&mut self) -> u32 {
-        // In a real implementation, this would perform memory optimization
-        // For now, return current memory usage
-        self.get_memory_usage()
+    pub fn set_unflatten(&mut self, enabled: bool, separator: String) -> Result<(), JsValue> {
+        if !enabled {
+            self.unflatten_separator = None;
+            return Ok(());
+        }
+        if separator.is_empty() {
+            return Err(js_error(
+                "invalid_separator",
+                400,
+                "Unflatten separator must not be empty",
+            ));
+        }
+        self.unflatten_separator = Some(separator);
+        Ok(())
     }
 
+    /// Text written to CSV exports (`to_csv`, `process_and_put`) for null
+    /// values and for keys a row doesn't have, e.g. `("", "\\N")` for
+    /// loaders such as Postgres `COPY` that distinguish the two. Both default
+    /// to empty.
     #[wasm_bindgen]
-    pub fn get_stats(&self) -> JsValue {
-        let stats = serde_json::json!({
-            "memory_usage": self.get_memory_usage(),
-            "buffer_count": self.memory_manager.get_buffer_count(),
-            "version": "0.1.0"
-        });
+    pub fn set_null_representation(&mut self, null_token: String, missing_token: String) {
+        self.null_representation = export::NullRepresentation {
+            null: null_token,
+            missing: missing_token,
+        };
+    }
+
+    /// How byte arrays appear in processed rows: `"array"` (default) keeps
+    /// JSON arrays of numbers, `"base64"` turns them into base64 strings.
+    /// Applies to columns declared `"binary"` in `set_schema`, and to any
+    /// other top-level array of at least 16 integers in 0-255.
+    #[wasm_bindgen]
+    pub fn set_binary_encoding(&mut self, encoding: &str) -> Result<(), JsValue> {
+        self.binary_base64 = match encoding {
+            "array" => false,
+            "base64" => true,
+            _ => {
+                return Err(js_error(
+                    "invalid_binary_encoding",
+                    400,
+                    &format!("Unsupported binary encoding '{encoding}' (expected base64 or array)"),
+                ))
+            }
+        };
+        Ok(())
+    }
+
+    /// Make `QueryResult.data` an object keyed by each row's `field` value
+    /// instead of an array. Rows without the field go in a `__missing__`
+    /// array. Repeated keys keep the last row unless
+    /// `set_output_key_unique(true)`.
+    #[wasm_bindgen]
+    pub fn set_output_key(&mut self, field: String) {
+        self.output_key = Some(field);
+    }
+
+    #[wasm_bindgen]
+    pub fn clear_output_key(&mut self) {
+        self.output_key = None;
+    }
+
+    /// Give each output row an id under `field`: with `"ordinal"` its 0-based
+    /// position in the output, after every other transform; with `"hash"` a
+    /// 16-hex-digit hash of the row's content (not counting `field` or the
+    /// annotation timestamp), so ids stay the same across runs and identical
+    /// rows share one. An existing `field` is overwritten. The id can be used
+    /// as the `set_output_key` field. Fails with an `invalid_row_id`
+    /// `DataPrismError` for an empty field or any other mode.
+    #[wasm_bindgen]
+    pub fn set_add_row_id(&mut self, field: String, mode: &str) -> Result<(), JsValue> {
+        let Some(mode) = transforms::RowIdMode::parse(mode) else {
+            return Err(js_error(
+                "invalid_row_id",
+                400,
+                &format!("Unsupported row id mode '{mode}' (expected ordinal or hash)"),
+            ));
+        };
+        if field.is_empty() {
+            return Err(js_error(
+                "invalid_row_id",
+                400,
+                "Row id field name cannot be empty",
+            ));
+        }
+        self.row_id = Some((field, mode));
+        Ok(())
+    }
+
+    #[wasm_bindgen]
+    pub fn clear_row_id(&mut self) {
+        self.row_id = None;
+    }
+
+    /// Fail processing with a `duplicate_output_key` `DataPrismError` when
+    /// two rows share an output key, instead of keeping the last one.
+    #[wasm_bindgen]
+    pub fn set_output_key_unique(&mut self, enabled: bool) {
+        self.output_key_unique = enabled;
+    }
+
+    /// Treat `field` as the rows' key or index column: it becomes the
+    /// default key for `pivot`, `sort_streaming`, `upsert` and `set_op` when
+    /// theirs is left undefined, and `set_mapping` keeps it even when
+    /// unmapped.
+    #[wasm_bindgen]
+    pub fn set_index_column(&mut self, field: String) {
+        self.index_column = Some(field);
+    }
+
+    #[wasm_bindgen]
+    pub fn clear_index_column(&mut self) {
+        self.index_column = None;
+    }
+
+    /// List the `set_index_column` column first in each row of
+    /// `QueryResult.data` (array output only); other columns keep their
+    /// sorted order.
+    #[wasm_bindgen]
+    pub fn set_index_first(&mut self, enabled: bool) {
+        self.index_first = enabled;
+    }
+
+    /// Add a column computed from numeric fields of each output row, e.g.
+    /// `add_computed_column("total", "price * quantity")`. Expressions support
+    /// `+ - * /`, parentheses, numeric literals and field references, including
+    /// earlier computed columns. Rows where a field is missing or non-numeric,
+    /// or that divide by zero, get null; divisions by zero are counted in
+    /// `QueryResult.warnings`.
+    #[wasm_bindgen]
+    pub fn add_computed_column(&mut self, name: String, expr: String) -> Result<(), JsValue> {
+        let parsed = parse_expression(&expr).map_err(|e| {
+            js_error(
+                "invalid_expression",
+                400,
+                &format!("Invalid expression for '{name}': {e}"),
+            )
+        })?;
+
+        if !self.last_dataset.is_empty() {
+            let mut known = export::collect_columns(&self.last_dataset);
+            known.extend(self.computed_columns.iter().map(|(n, _, _)| n.clone()));
+            let context = format!("Computed column '{name}'");
+            for field in parsed.fields() {
+                // Lenient mode reports these when the column is evaluated
+                self.check_column(&context, field, &known, &mut Vec::new())?;
+            }
+        }
+
+        self.computed_columns.push((name, expr, parsed));
+        Ok(())
+    }
+
+    #[wasm_bindgen]
+    pub fn clear_computed_columns(&mut self) {
+        self.computed_columns.clear();
+    }
+
+    /// Reshape every output row to exactly the columns in `spec`, an array of
+    /// `{from, to, type}`. `from` is a dotted path into the row (`user.id`,
+    /// `items.0.sku`; a flat key spelled the same way also matches), `to`
+    /// the output column name (defaults to `from`) and the optional `type`
+    /// one of the `set_schema` types, coerced the same way. Columns not
+    /// listed are dropped and missing paths give null; the `processed` and
+    /// `timestamp` annotations are kept while annotation is on. Runs after
+    /// computed columns, so they can be mapped too; the output key refers to
+    /// a mapped column. Fails with an `invalid_mapping` `DataPrismError` on
+    /// a malformed spec, an unknown type or a repeated output column.
+    #[wasm_bindgen]
+    pub fn set_mapping(&mut self, spec: JsValue) -> Result<(), JsValue> {
+        let spec: Vec<MappingConfig> = serde_wasm_bindgen::from_value(spec)
+            .map_err(|e| js_error("invalid_mapping", 400, &format!("Invalid mapping: {e}")))?;
+        self.set_mapping_columns(spec)
+    }
+
+    #[wasm_bindgen]
+    pub fn clear_mapping(&mut self) {
+        self.mapping.clear();
+    }
+
+    /// Load a dimension table for `enrich_with` under `name`, parsing `data`
+    /// with the current input settings and indexing rows by their `key`
+    /// value. Replaces any table of the same name. Rows without the key are
+    /// skipped and a repeated key keeps the last row. Returns the number of
+    /// keys loaded.
+    #[wasm_bindgen]
+    pub fn set_lookup_table(
+        &mut self,
+        name: String,
+        data: &[u8],
+        key: String,
+    ) -> Result<u32, JsValue> {
+        let rows = self.sample_rows(data, schema::Sampling::Head, usize::MAX)?;
+        let table = transforms::LookupTable::new(rows, &key);
+        let keys = table.len() as u32;
+        self.lookup_tables.insert(name, table);
+        Ok(keys)
+    }
+
+    /// Drop a lookup table and any enrichments using it.
+    #[wasm_bindgen]
+    pub fn remove_lookup_table(&mut self, name: &str) -> bool {
+        self.enrichments.retain(|e| e.table != name);
+        self.lookup_tables.remove(name).is_some()
+    }
+
+    /// Parse `data` with the current input settings (no transforms) and index
+    /// its rows by their `key` value for `lookup`. Rows without the key, or
+    /// with a null one, are left out. Returns the index's handle.
+    #[wasm_bindgen]
+    pub fn build_index(&mut self, data: &[u8], key: String) -> Result<u32, JsValue> {
+        let rows = self.sample_rows(data, schema::Sampling::Head, usize::MAX)?;
+        let id = self.next_index_id;
+        self.next_index_id += 1;
+        self.indexes
+            .insert(id, transforms::RowIndex::new(rows, &key));
+        Ok(id)
+    }
+
+    /// The rows of index `index_id` whose key equals `value`, as an array
+    /// (empty when none match). Keys match by type and value, so `1` doesn't
+    /// find `"1"`. An unknown or dropped handle fails with an
+    /// `unknown_index` `DataPrismError`.
+    #[wasm_bindgen]
+    pub fn lookup(&self, index_id: u32, value: JsValue) -> Result<JsValue, JsValue> {
+        let index = self.indexes.get(&index_id).ok_or_else(|| {
+            js_error(
+                "unknown_index",
+                404,
+                &format!("No index with id {index_id} (see build_index)"),
+            )
+        })?;
+        let key: serde_json::Value = serde_wasm_bindgen::from_value(value)?;
+        Ok(serde_wasm_bindgen::to_value(index.get(&key))?)
+    }
+
+    /// Number of distinct keys in index `index_id`, or undefined if there is
+    /// no such index.
+    #[wasm_bindgen]
+    pub fn get_index_size(&self, index_id: u32) -> Option<u32> {
+        self.indexes.get(&index_id).map(|index| index.len() as u32)
+    }
+
+    /// Free index `index_id`. Returns whether it existed.
+    #[wasm_bindgen]
+    pub fn drop_index(&mut self, index_id: u32) -> bool {
+        self.indexes.remove(&index_id).is_some()
+    }
+
+    /// Merge the rows of `updates` into those of `base` by their `key` value,
+    /// both parsed with the current input settings (no transforms). An
+    /// update replaces the base rows with its key, or is appended if the key
+    /// is new; see `set_upsert_options` for field merging and deletes. Keys
+    /// match by type and value, as in `lookup`. `stage_counts` gives
+    /// `{base, updated, inserted, deleted, output}`; updates without a key
+    /// are skipped and counted in `QueryResult.warnings`. `key` defaults to
+    /// the `set_index_column` column.
+    #[wasm_bindgen]
+    pub fn upsert(
+        &mut self,
+        base: &[u8],
+        updates: &[u8],
+        key: Option<String>,
+    ) -> Result<QueryResult, JsValue> {
+        let start_time = self.clock.now();
+        let key = self.key_or_index_column("upsert", key)?;
+        let base_rows = self.sample_rows(base, schema::Sampling::Head, usize::MAX)?;
+        let update_rows = self.sample_rows(updates, schema::Sampling::Head, usize::MAX)?;
+        let base_count = base_rows.len();
+
+        let mut warnings = Vec::new();
+        let mut known = export::collect_columns(&base_rows);
+        known.extend(export::collect_columns(&update_rows));
+        if !known.is_empty() {
+            self.check_column("Upsert", &key, &known, &mut warnings)?;
+        }
+
+        let (rows, counts) = transforms::upsert(
+            base_rows,
+            update_rows,
+            &key,
+            self.upsert_merge_fields,
+            self.tombstone_field.as_deref(),
+        );
+        if counts.skipped > 0 {
+            warnings.push(format!(
+                "Upsert: {} update rows had no '{key}' and were skipped",
+                counts.skipped
+            ));
+        }
+        self.check_not_empty(rows.len())?;
+        self.last_dataset = rows.clone();
+
+        let mut result = self.build_result(rows, start_time, base.len() + updates.len());
+        result.stage_counts = vec![
+            ("base".to_string(), base_count),
+            ("updated".to_string(), counts.updated),
+            ("inserted".to_string(), counts.inserted),
+            ("deleted".to_string(), counts.deleted),
+            ("output".to_string(), result.data.len()),
+        ];
+        result.warnings = warnings;
+        result.transform_ms = result.total_ms;
+        result.annotated = false;
+        result.output_key = None;
+        Ok(result)
+    }
+
+    /// Set operation `op` between the rows of `a` and `b` by their `key`
+    /// value, both parsed with the current input settings (no transforms):
+    /// `"intersect"` keeps the rows of `a` whose key is in `b`, `"except"`
+    /// those whose key isn't, and `"union"` the rows of both with the first
+    /// row kept for each key. Keys match by type and value, as in `lookup`;
+    /// rows without one never match. `key` defaults to the
+    /// `set_index_column` column. `stage_counts` gives `{a, b, output}`. An
+    /// unknown `op` fails with an `invalid_set_op` `DataPrismError`.
+    #[wasm_bindgen]
+    pub fn set_op(
+        &mut self,
+        a: &[u8],
+        b: &[u8],
+        key: Option<String>,
+        op: &str,
+    ) -> Result<QueryResult, JsValue> {
+        let start_time = self.clock.now();
+        let set_op = transforms::SetOp::parse(op).ok_or_else(|| {
+            js_error(
+                "invalid_set_op",
+                400,
+                &format!("Unsupported set operation '{op}' (expected intersect, except or union)"),
+            )
+        })?;
+        let key = self.key_or_index_column("set_op", key)?;
+        let a_rows = self.sample_rows(a, schema::Sampling::Head, usize::MAX)?;
+        let b_rows = self.sample_rows(b, schema::Sampling::Head, usize::MAX)?;
+        let (a_count, b_count) = (a_rows.len(), b_rows.len());
+
+        let mut warnings = Vec::new();
+        let mut known = export::collect_columns(&a_rows);
+        known.extend(export::collect_columns(&b_rows));
+        if !known.is_empty() {
+            self.check_column("Set operation", &key, &known, &mut warnings)?;
+        }
+
+        let rows = transforms::set_operation(a_rows, b_rows, &key, set_op);
+        self.check_not_empty(rows.len())?;
+        self.last_dataset = rows.clone();
+
+        let mut result = self.build_result(rows, start_time, a.len() + b.len());
+        result.stage_counts = vec![
+            ("a".to_string(), a_count),
+            ("b".to_string(), b_count),
+            ("output".to_string(), result.data.len()),
+        ];
+        result.warnings = warnings;
+        result.transform_ms = result.total_ms;
+        result.annotated = false;
+        result.output_key = None;
+        Ok(result)
+    }
+
+    /// How `upsert` applies updates: with `merge_fields` an update overwrites
+    /// only the fields it has, keeping the rest of the base row, instead of
+    /// replacing it. With a `tombstone_field`, an update where that field is
+    /// set to anything but null or false deletes its key; the field itself
+    /// never appears in the output.
+    #[wasm_bindgen]
+    pub fn set_upsert_options(&mut self, merge_fields: bool, tombstone_field: Option<String>) {
+        self.upsert_merge_fields = merge_fields;
+        self.tombstone_field = tombstone_field;
+    }
+
+    /// Join each processed row (including streamed rows) against lookup
+    /// table `name`: the table row whose key equals the row's `on_field`
+    /// value has its columns merged in, each named `prefix` + column.
+    /// Unmatched rows are left unchanged, or get those columns as null when
+    /// `null_fill`; their count is reported in `QueryResult.warnings`.
+    /// Enrichments run in the order added, before schema coercion and
+    /// computed columns.
+    #[wasm_bindgen]
+    pub fn enrich_with(
+        &mut self,
+        name: String,
+        on_field: String,
+        prefix: String,
+        null_fill: bool,
+    ) -> Result<(), JsValue> {
+        self.check_lookup_table(&name)?;
+        self.enrichments.push(Enrichment {
+            table: name,
+            on_field,
+            prefix,
+            null_fill,
+        });
+        Ok(())
+    }
+
+    #[wasm_bindgen]
+    pub fn clear_enrichments(&mut self) {
+        self.enrichments.clear();
+    }
+
+    /// Limit how long each `process_data` call may run. Elapsed time is
+    /// checked between processing stages and every 1 MiB of NDJSON input;
+    /// once past the deadline the call fails with a `deadline_exceeded`
+    /// `DataPrismError` and the rows processed so far are kept for
+    /// `take_partial_result`. Has no effect in deterministic mode.
+    #[wasm_bindgen]
+    pub fn set_deadline_ms(&mut self, ms: f64) {
+        self.deadline_ms = Some(ms.max(0.0));
+    }
+
+    #[wasm_bindgen]
+    pub fn clear_deadline(&mut self) {
+        self.deadline_ms = None;
+    }
+
+    /// The partial result left by the last `deadline_exceeded` failure, if
+    /// any. Taking it clears it.
+    #[wasm_bindgen]
+    pub fn take_partial_result(&mut self) -> Option<QueryResult> {
+        self.partial_result.take()
+    }
+
+    /// Fail with an `empty_result` `DataPrismError` when processing produces
+    /// no output rows, instead of succeeding with an empty result. Applies to
+    /// `process_data` and the other whole-input methods, `finish_stream` and
+    /// `process_to_sink`.
+    #[wasm_bindgen]
+    pub fn set_error_on_empty(&mut self, enabled: bool) {
+        self.error_on_empty = enabled;
+    }
+
+    /// Process inputs whose columns include all of `required_columns` with
+    /// `then_config` (an `export_config`-style object; missing fields take
+    /// the defaults, as with `import_config`) instead of the current
+    /// settings. Columns are detected from the first rows of the input, read
+    /// with the current settings. Rules are tried in the order they were
+    /// added and the first match wins; inputs matching none use the current
+    /// settings. Applies to `process_data` and the other whole-input methods,
+    /// `process_to_sink`, `process_data_stream`, `sort_streaming` and
+    /// `process_logs` (matching on the columns its pattern captures). A
+    /// stream from `begin_stream`, `process_readable_stream` or
+    /// `process_from_cloud` is matched on its parsed rows when it finishes,
+    /// and the rule governs its transforms and output; the rows themselves
+    /// were parsed with the settings the stream began with.
+    /// Fails with an `invalid_config` `DataPrismError` if `then_config` is
+    /// malformed or itself contains rules, or with the matching setter's
+    /// error on a bad value.
+    #[wasm_bindgen]
+    pub fn when_schema(
+        &mut self,
+        required_columns: Vec<String>,
+        then_config: JsValue,
+    ) -> Result<(), JsValue> {
+        let config: EngineConfig = serde_wasm_bindgen::from_value(then_config)
+            .map_err(|e| js_error("invalid_config", 400, &format!("Invalid config: {e}")))?;
+        let rule = self.stage_schema_rule(SchemaRule {
+            required_columns,
+            config,
+        })?;
+        self.schema_rules.push(rule);
+        Ok(())
+    }
+
+    /// Remove every rule added with `when_schema`.
+    #[wasm_bindgen]
+    pub fn clear_when_schema(&mut self) {
+        self.schema_rules.clear();
+    }
+
+    /// Make output and timings reproducible: no wall-clock reads, so
+    /// `execution_time_ms` is 0 and row `timestamp`s count up from 0 across
+    /// processing calls. Enabling (or `reset`) restarts the count.
+    #[wasm_bindgen]
+    pub fn set_deterministic(&mut self, enabled: bool) {
+        self.clock = Clock {
+            deterministic: enabled,
+            format: self.clock.format,
+            ..Clock::default()
+        };
+    }
+
+    /// How row `timestamp` annotations and columns declared `timestamp` in
+    /// `set_schema` are written: `"epoch_ms"` (the default), `"epoch_s"` or
+    /// `"iso8601"` (`2024-03-01T12:00:00.000Z`, always UTC). Fails with an
+    /// `invalid_timestamp_format` `DataPrismError` otherwise.
+    #[wasm_bindgen]
+    pub fn set_timestamp_format(&mut self, format: &str) -> Result<(), JsValue> {
+        self.clock.format = TimestampFormat::parse(format).ok_or_else(|| {
+            js_error(
+                "invalid_timestamp_format",
+                400,
+                &format!(
+                    "Unsupported timestamp format '{format}' (expected epoch_ms, epoch_s or iso8601)"
+                ),
+            )
+        })?;
+        Ok(())
+    }
+
+    /// Whether processed rows get the `processed`/`timestamp` annotation.
+    /// With annotation off, parsed rows are passed through untouched.
+    #[wasm_bindgen]
+    pub fn set_annotate_rows(&mut self, enabled: bool) {
+        self.annotate_rows = enabled;
+    }
+
+    /// Serialize integers outside the JS safe integer range as BigInt in
+    /// `QueryResult.data` instead of failing or losing precision.
+    #[wasm_bindgen]
+    pub fn set_bigint_passthrough(&mut self, enabled: bool) {
+        self.bigint_passthrough = enabled;
+    }
+
+    /// Tag subsequent processing with a correlation id. It prefixes engine
+    /// log output and is echoed back as `QueryResult.trace_id`.
+    #[wasm_bindgen]
+    pub fn set_trace_id(&mut self, id: String) {
+        self.trace_id = Some(id);
+    }
+
+    #[wasm_bindgen]
+    pub fn clear_trace_id(&mut self) {
+        self.trace_id = None;
+    }
+
+    /// Format floats to `digits` decimal places in `to_csv`/`data_to_string`.
+    /// Integers and strings are unaffected.
+    #[wasm_bindgen]
+    pub fn set_float_precision(&mut self, digits: usize) {
+        self.float_precision = Some(digits);
+    }
+
+    /// Restore full float precision on export.
+    #[wasm_bindgen]
+    pub fn clear_float_precision(&mut self) {
+        self.float_precision = None;
+    }
+
+    /// Every setting made through the `set_*`/`add_*` methods as one
+    /// JSON-compatible object, for bug reports or saved presets. Callbacks
+    /// and buffered data are not included.
+    #[wasm_bindgen]
+    pub fn export_config(&self) -> JsValue {
+        serde_wasm_bindgen::to_value(&self.config()).unwrap_or(JsValue::NULL)
+    }
+
+    /// Restore settings from an `export_config` object. Missing fields are
+    /// reset to their defaults. Fails with an `invalid_config`
+    /// `DataPrismError` on unknown fields, or with the matching setter's
+    /// error on a bad value; on failure the current settings are unchanged.
+    #[wasm_bindgen]
+    pub fn import_config(&mut self, config: JsValue) -> Result<(), JsValue> {
+        let config: EngineConfig = serde_wasm_bindgen::from_value(config)
+            .map_err(|e| js_error("invalid_config", 400, &format!("Invalid config: {e}")))?;
+        self.apply_config(config)
+    }
+
+    /// Have `process_data` call `callback(rows, index)` with batches of
+    /// `every_rows` processed rows as they are produced, yielding to the
+    /// event loop after each so a table can render them; `index` is the
+    /// position of the batch's first row. NDJSON is parsed and transformed
+    /// in 1 MiB slices, each slice's full batches handed over as soon as it
+    /// is done; JSON and CSV input is processed whole first. A failure after
+    /// some batches were handed over (a later slice, the output key or
+    /// unknown-column checks, a throw from `callback`) is still returned as
+    /// the error, and those batches stand. The final `QueryResult` is the
+    /// same as without partial emit and holds every row. Returned Promises
+    /// are not awaited. An `every_rows` of 0 fails with an
+    /// `invalid_batch_size` `DataPrismError`.
+    #[wasm_bindgen]
+    pub fn set_partial_emit(
+        &mut self,
+        every_rows: usize,
+        callback: js_sys::Function,
+    ) -> Result<(), JsValue> {
+        if every_rows == 0 {
+            return Err(js_error(
+                "invalid_batch_size",
+                400,
+                "every_rows must be greater than zero",
+            ));
+        }
+        self.partial_emit = Some((every_rows, callback));
+        Ok(())
+    }
+
+    #[wasm_bindgen]
+    pub fn clear_partial_emit(&mut self) {
+        self.partial_emit = None;
+    }
+
+    #[wasm_bindgen]
+    pub async fn process_data(&mut self, data: &[u8]) -> Result<QueryResult, JsValue> {
+        let mut result = match self.partial_emit.clone() {
+            Some((every_rows, callback)) => {
+                self.begin_processing()?;
+                let rule = self.matching_schema_rule(data);
+                let rules = rule.map(|index| self.enter_schema_rule(index));
+                let result = self.process_data_partial(data, every_rows, &callback).await;
+                if let (Some(index), Some(rules)) = (rule, rules) {
+                    self.leave_schema_rule(index, rules);
+                }
+                self.processing = false;
+                result?
+            }
+            None => self.process_bytes(data)?,
+        };
+        if self.retain_input {
+            result.input_buffer_id = Some(self.stage_data(data));
+        }
+        Ok(result)
+    }
+
+    /// Process each CSV/JSON/NDJSON member of a zip archive, detecting its
+    /// format from the extension or, failing that, its content. Returns an
+    /// array with one entry per file: `{name, format, result}` on success,
+    /// `{name, format, error}` when the member failed to process, or
+    /// `{name, skipped}` with the reason for non-data and oversized members.
+    /// The engine's input format is unchanged afterwards.
+    #[wasm_bindgen]
+    pub fn process_zip(&mut self, data: &[u8]) -> Result<js_sys::Array, JsValue> {
+        let mut archive = zip::ZipArchive::new(std::io::Cursor::new(data))
+            .map_err(|e| js_error("invalid_zip", 400, &format!("Invalid zip archive: {e}")))?;
+
+        let entries = js_sys::Array::new();
+        let input_format = self.input_format;
+        for index in 0..archive.len() {
+            let member = read_zip_member(&mut archive, index)
+                .map_err(|e| js_error("invalid_zip", 400, &format!("Invalid zip archive: {e}")))?;
+            let Some((name, contents)) = member else {
+                continue;
+            };
+
+            let entry = js_sys::Object::new();
+            js_sys::Reflect::set(&entry, &"name".into(), &name.as_str().into())?;
+            let contents = match contents {
+                Ok(contents) => contents,
+                Err(reason) => {
+                    js_sys::Reflect::set(&entry, &"skipped".into(), &reason.into())?;
+                    entries.push(&entry);
+                    continue;
+                }
+            };
+            let Some(format) = InputFormat::detect(&name, &contents) else {
+                js_sys::Reflect::set(&entry, &"skipped".into(), &"not a data file".into())?;
+                entries.push(&entry);
+                continue;
+            };
+
+            js_sys::Reflect::set(&entry, &"format".into(), &format.name().into())?;
+            self.input_format = format;
+            let outcome = self.process_bytes(&contents);
+            self.input_format = input_format;
+            match outcome {
+                Ok(result) => js_sys::Reflect::set(&entry, &"result".into(), &result.into())?,
+                Err(error) => js_sys::Reflect::set(&entry, &"error".into(), &error)?,
+            };
+            entries.push(&entry);
+        }
+        Ok(entries)
+    }
+
+    /// Fetch `url` through `bridge` and process the body. Errors are
+    /// `PipelineError`s whose `stage` says whether the fetch itself, the HTTP
+    /// status, inflating a gzipped body, or parsing failed, along with the
+    /// provider and status.
+    #[wasm_bindgen]
+    pub async fn fetch_and_process(
+        &mut self,
+        bridge: &mut CloudStorageBridge,
+        url: &str,
+    ) -> Result<QueryResult, JsValue> {
+        let provider = bridge.detect_provider(url);
+
+        let (data, status) = bridge.fetch_with_status(url).await.map_err(|e| {
+            pipeline_error("fetch", &error_message(&e), Some(provider.clone()), None)
+        })?;
+
+        if status >= 400 {
+            return Err(pipeline_error(
+                "http",
+                &format!("Request for {url} failed with HTTP {status}"),
+                Some(provider),
+                Some(status),
+            ));
+        }
+
+        self.process_bytes(&data)
+            .map_err(|e| processing_error(&e, Some(provider), Some(status)))
+    }
+
+    /// Fetch `url` through `bridge` in 4 MiB `Range` requests, feeding each
+    /// piece to the streaming parser (see `begin_stream`) as it arrives, so
+    /// parsing overlaps the download and the whole body is never held at
+    /// once. Stops at the object's size from `Content-Range`, or at a short
+    /// or 416 response; a server that ignores ranges sends the whole body in
+    /// one 200 response, which is processed the same way. Responses aren't
+    /// cached; a gzipped body is inflated as it arrives, following
+    /// `set_input_compression`. NDJSON and CSV are parsed incrementally, while
+    /// a JSON array is buffered until the end, as with `push_chunk`. Errors
+    /// are `PipelineError`s with stage `fetch`, `http`, `decompress` or
+    /// `parse`.
+    #[wasm_bindgen]
+    pub async fn process_from_cloud(
+        &mut self,
+        bridge: &mut CloudStorageBridge,
+        url: &str,
+    ) -> Result<QueryResult, JsValue> {
+        let provider = bridge.detect_provider(url);
+        self.begin_processing()?;
+        self.begin_stream_unguarded();
+        let outcome = self
+            .stream_from_cloud(bridge, url, &provider)
+            .await
+            .and_then(|()| {
+                self.finish_stream_unguarded()
+                    .map_err(|e| processing_error(&e, Some(provider.clone()), None))
+            });
+        if outcome.is_err() {
+            self.stream = None;
+        }
+        self.processing = false;
+        outcome
+    }
+
+    /// Process `data` and upload the result to `url` through `bridge` with a
+    /// PUT, serialized as `format` (`"json"` or `"csv"`). The output is
+    /// serialized in chunks of rows rather than as one string. Errors are
+    /// `PipelineError`s with stage `decompress`, `parse`, `upload` or `http`.
+    #[wasm_bindgen]
+    pub async fn process_and_put(
+        &mut self,
+        data: &[u8],
+        bridge: &mut CloudStorageBridge,
+        url: &str,
+        format: &str,
+    ) -> Result<QueryResult, JsValue> {
+        let format = export::ExportFormat::parse(format).ok_or_else(|| {
+            js_error(
+                "invalid_format",
+                400,
+                &format!("Unsupported output format '{format}' (expected json or csv)"),
+            )
+        })?;
+        let provider = bridge.detect_provider(url);
+
+        let result = self
+            .process_bytes(data)
+            .map_err(|e| processing_error(&e, Some(provider.clone()), None))?;
+
+        let parts = js_sys::Array::new();
+        export::write_rows_chunked(
+            &result.data,
+            format,
+            self.float_precision,
+            &self.null_representation,
+            UPLOAD_CHUNK_ROWS,
+            |chunk| {
+                parts.push(&js_sys::Uint8Array::from(chunk.as_bytes()));
+            },
+        );
+
+        let status = bridge
+            .put_parts(url, &parts, format.content_type())
+            .await
+            .map_err(|e| {
+                pipeline_error("upload", &error_message(&e), Some(provider.clone()), None)
+            })?;
+
+        if status >= 400 {
+            return Err(pipeline_error(
+                "http",
+                &format!("Upload to {url} failed with HTTP {status}"),
+                Some(provider),
+                Some(status),
+            ));
+        }
+
+        Ok(result)
+    }
+
+    /// Sort the rows of `data` on `field` and return them as NDJSON, without
+    /// holding every parsed row at once: rows are sorted in runs of
+    /// `run_size`, each run is staged as NDJSON in the memory manager, and
+    /// the runs are then k-way merged. Parsed rows peak at about one run plus
+    /// one row per run. NDJSON is parsed incrementally; JSON and CSV input is
+    /// parsed whole first. Rows are passed through without annotations or
+    /// transforms. Ascending, numbers sort before strings, then booleans,
+    /// then nested values; rows missing `field` (or null) come last in both
+    /// directions, and equal keys keep their input order. `field` defaults
+    /// to the `set_index_column` column.
+    #[wasm_bindgen]
+    pub fn sort_streaming(
+        &mut self,
+        data: &[u8],
+        field: Option<String>,
+        descending: bool,
+        run_size: usize,
+    ) -> Result<js_sys::Uint8Array, JsValue> {
+        if run_size == 0 {
+            return Err(js_error(
+                "invalid_run_size",
+                400,
+                "run_size must be greater than zero",
+            ));
+        }
+        self.guarded(|engine| {
+            let rule = engine.matching_schema_rule(data);
+            engine.with_schema_rule(rule, |engine| {
+                let field = engine.key_or_index_column("sort_streaming", field)?;
+                let mut run_ids = Vec::new();
+                let sorted = engine.sort_in_runs(data, &field, descending, run_size, &mut run_ids);
+                for id in run_ids {
+                    engine.memory_manager.deallocate_buffer(id);
+                }
+                Ok(js_sys::Uint8Array::from(&sorted?[..]))
+            })
+        })
+    }
+
+    /// Turn a line-oriented text log into rows: each line is matched against
+    /// `pattern`, a regex whose named groups (`(?P<status>\d+)`) become
+    /// columns. Captured numbers and booleans are converted as in CSV input.
+    /// Lines that don't match are counted in `stage_counts.unmatched` and
+    /// reported, with their line numbers, in `warnings`. The rows then go
+    /// through the same transforms as `process_data`.
+    #[wasm_bindgen]
+    pub fn process_logs(&mut self, data: &[u8], pattern: &str) -> Result<QueryResult, JsValue> {
+        let regex = log_parser::compile_pattern(pattern)?;
+        self.guarded(|engine| {
+            let rule = engine.matching_log_rule(data, &regex);
+            engine.with_schema_rule(rule, |engine| engine.process_logs_unguarded(data, &regex))
+        })
+    }
+
+    /// Process `data` and call `sink(row_json, index)` with each output row
+    /// instead of collecting them, for bounded-memory ETL into IndexedDB, a
+    /// file stream and the like. NDJSON is parsed, transformed and drained
+    /// in 1 MiB slices, so only one slice's rows are held at a time; JSON
+    /// and CSV input is parsed whole first. Returns a `QueryResult` with the
+    /// stats and warnings but no `data`, and doesn't replace the dataset
+    /// used by `pivot` and friends. An exception thrown by `sink` stops
+    /// processing and is returned as the error; returned Promises are not
+    /// awaited. Rows are emitted as an array whatever the output key, whose
+    /// uniqueness is only checked within a slice; the deadline doesn't apply.
+    /// Columns named by the schema, output key or lookups are checked
+    /// against the columns of all slices once the last is drained, so an
+    /// `unknown_column` error comes after the rows were emitted.
+    #[wasm_bindgen]
+    pub fn process_to_sink(
+        &mut self,
+        data: &[u8],
+        sink: js_sys::Function,
+    ) -> Result<QueryResult, JsValue> {
+        self.guarded(|engine| {
+            let rule = engine.matching_schema_rule(data);
+            engine.with_schema_rule(rule, |engine| engine.process_to_sink_unguarded(data, &sink))
+        })
+    }
+
+    /// Process `data` and hand the output rows to `consumer(rows, index)` in
+    /// arrays of at most `high_water_rows`, awaiting the Promise it returns
+    /// (if any) before producing the next batch, so a slow consumer such as
+    /// an upload throttles processing. `index` is the position of the
+    /// batch's first row. NDJSON is parsed and transformed in 1 MiB slices
+    /// as batches are taken, so at most a slice plus a batch of rows are
+    /// held; JSON and CSV input is parsed whole first. A rejected Promise or
+    /// a throw stops processing and becomes the error. Returns a
+    /// `QueryResult` with stats but no `data`, with the same caveats as
+    /// `process_to_sink`: the output key is only checked for uniqueness
+    /// within a slice, unknown columns are reported once the last slice is
+    /// transformed, and the deadline doesn't apply. A `high_water_rows` of 0
+    /// fails with an `invalid_high_water` `DataPrismError`.
+    #[wasm_bindgen]
+    pub async fn process_data_stream(
+        &mut self,
+        data: &[u8],
+        high_water_rows: usize,
+        consumer: js_sys::Function,
+    ) -> Result<QueryResult, JsValue> {
+        if high_water_rows == 0 {
+            return Err(js_error(
+                "invalid_high_water",
+                400,
+                "high_water_rows must be greater than zero",
+            ));
+        }
+        self.begin_processing()?;
+        let rule = self.matching_schema_rule(data);
+        let rules = rule.map(|index| self.enter_schema_rule(index));
+        let result = self
+            .process_data_stream_unguarded(data, high_water_rows, &consumer)
+            .await;
+        if let (Some(index), Some(rules)) = (rule, rules) {
+            self.leave_schema_rule(index, rules);
+        }
+        self.processing = false;
+        result
+    }
+
+    /// Process a staged buffer (e.g. a retained input) with the current
+    /// options. The buffer stays staged afterwards.
+    #[wasm_bindgen]
+    pub fn process_buffer(&mut self, buffer_id: u32) -> Result<QueryResult, JsValue> {
+        self.check_buffer_complete(buffer_id)?;
+        let data = self
+            .memory_manager
+            .get_buffer_mut(buffer_id)
+            .map(std::mem::take)
+            .ok_or_else(|| JsValue::from_str("Unknown buffer id"))?;
+
+        let result = self.process_bytes(&data);
+
+        if let Some(buffer) = self.memory_manager.get_buffer_mut(buffer_id) {
+            *buffer = data;
+        }
+
+        let mut result = result?;
+        result.input_buffer_id = Some(buffer_id);
+        Ok(result)
+    }
+
+    /// Append a chunk to the engine's input buffer for `process_accumulated`.
+    #[wasm_bindgen]
+    pub fn append_input(&mut self, chunk: &[u8]) {
+        self.input_buffer.extend_from_slice(chunk);
+    }
+
+    /// Process everything appended via `append_input` in one pass, then clear
+    /// the input buffer.
+    #[wasm_bindgen]
+    pub fn process_accumulated(&mut self) -> Result<QueryResult, JsValue> {
+        let input = std::mem::take(&mut self.input_buffer);
+        self.process_bytes(&input)
+    }
+
+    /// Discard any input appended via `append_input`.
+    #[wasm_bindgen]
+    pub fn reset_input(&mut self) {
+        self.input_buffer = Vec::new();
+    }
+
+    #[wasm_bindgen]
+    pub fn get_accumulated_len(&self) -> usize {
+        self.input_buffer.len()
+    }
+
+    /// Line breaks found in the last input processed by `process_data` or
+    /// another whole-input method (after decompression):
+    /// `{line_endings, mixed, lf, crlf, cr}`, where `line_endings` is
+    /// `"lf"`, `"crlf"`, `"cr"`, `"mixed"` or `"none"`. All three kinds end a
+    /// line in every format. Null before the first input.
+    #[wasm_bindgen]
+    pub fn get_input_diagnostics(&self) -> JsValue {
+        self.input_diagnostics
+            .map(|counts| serde_wasm_bindgen::to_value(&counts.to_json()).unwrap_or(JsValue::NULL))
+            .unwrap_or(JsValue::NULL)
+    }
+
+    /// When enabled, an NDJSON line in a stream (`begin_stream`,
+    /// `process_readable_stream`) that fails to parse is skipped instead of
+    /// aborting the stream. Parsing resumes at the next line break and each
+    /// skipped byte range is reported in `QueryResult.warnings`. Applies to
+    /// streams begun after the call.
+    #[wasm_bindgen]
+    pub fn set_resync_on_error(&mut self, enabled: bool) {
+        self.resync_on_error = enabled;
+    }
+
+    /// Start an incremental parse fed by `push_chunk`. Input is CSV when
+    /// that is the input format (see `set_input_format`), with the header in
+    /// the first record; rows are parsed as each one completes, even when a
+    /// quoted field or line is split across chunks. Otherwise it is NDJSON;
+    /// a JSON array is also accepted but is only parsed once the stream
+    /// ends. Gzipped input is inflated as it arrives, following
+    /// `set_input_compression`. Any stream already in progress is discarded.
+    /// Fails with `reentrant_call` while another call is processing.
+    #[wasm_bindgen]
+    pub fn begin_stream(&mut self) -> Result<(), JsValue> {
+        self.guarded(|engine| {
+            engine.begin_stream_unguarded();
+            Ok(())
+        })
+    }
+
+    /// Feed the next chunk of input. Returns the number of rows parsed so far.
+    #[wasm_bindgen]
+    pub fn push_chunk(&mut self, chunk: &[u8]) -> Result<u32, JsValue> {
+        self.guarded(|engine| engine.push_chunk_unguarded(chunk))
+    }
+
+    /// Parse any buffered trailing input and return the complete result.
+    #[wasm_bindgen]
+    pub fn finish_stream(&mut self) -> Result<QueryResult, JsValue> {
+        self.guarded(|engine| engine.finish_stream_unguarded())
+    }
+
+    /// Stop the stream in progress without parsing its buffered tail. The
+    /// partial line and parsed rows are discarded and the engine is ready for
+    /// a fresh `begin_stream`. Returns what had been collected:
+    /// `{rows_parsed, bytes_received, bytes_discarded, elapsed_ms}`.
+    #[wasm_bindgen]
+    pub fn abort_stream(&mut self) -> Result<JsValue, JsValue> {
+        self.guarded(|engine| {
+            let stream = engine.stream.take().ok_or_else(|| {
+                JsValue::from_str("No stream in progress; call begin_stream first")
+            })?;
+
+            let stats = serde_json::json!({
+                "rows_parsed": stream.rows.len(),
+                "bytes_received": stream.bytes_received,
+                "bytes_discarded": stream.pending_len(),
+                "elapsed_ms": engine.clock.now() - stream.start_time,
+            });
+            Ok(serde_wasm_bindgen::to_value(&stats)?)
+        })
+    }
+
+    /// Read a `ReadableStream` of bytes (e.g. a fetch body) chunk by chunk
+    /// through the streaming parser, without assembling the whole input
+    /// first. Any failure, including one reading the stream, ends the stream
+    /// begun for it.
+    #[wasm_bindgen]
+    pub async fn process_readable_stream(
+        &mut self,
+        stream: web_sys::ReadableStream,
+    ) -> Result<QueryResult, JsValue> {
+        self.begin_processing()?;
+        let reader: web_sys::ReadableStreamDefaultReader = stream.get_reader().unchecked_into();
+        self.begin_stream_unguarded();
+        let result = match self.read_into_stream(&reader).await {
+            Ok(()) => self.finish_stream_unguarded(),
+            Err(e) => {
+                self.stream = None;
+                Err(e)
+            }
+        };
+        self.processing = false;
+        result
+    }
+
+    /// Number of rows `process_data` would return for `data`, without building
+    /// them. Input is validated the same way, except that JSON input which is
+    /// not an array is an `invalid_json` error rather than a fallback result.
+    #[wasm_bindgen]
+    pub fn count(&self, data: &[u8]) -> Result<u32, JsValue> {
+        let data = &*self.decode_input(data)?;
+        if data.len() > MAX_INPUT_BYTES {
+            return Err(JsValue::from_str("Input data exceeds maximum size limit"));
+        }
+
+        let count = match self.input_format {
+            InputFormat::Csv => {
+                let text = std::str::from_utf8(data)
+                    .map_err(|_| JsValue::from_str("Invalid UTF-8 data"))?;
+                count_csv(text, &self.csv_options)?
+            }
+            InputFormat::Ndjson => count_ndjson(data, &self.json_options)?,
+            InputFormat::Json => count_json_array(data, &self.json_options)?
+                .ok_or_else(|| js_error("invalid_json", 400, "Expected a JSON array"))?,
+        };
+        Ok(count as u32)
+    }
+
+    /// Infer column names and types from a sample of `data`, read with the
+    /// current input settings. `sampling` picks the rows: `"head"` (default)
+    /// the first `sample_size` (default 100), `"random"` rows spread at
+    /// random through the input, `"stratified"` one row from each of
+    /// `sample_size` equal slices. For NDJSON the random and stratified
+    /// samples seek to line offsets, so only sampled lines are parsed. Returns
+    /// `{columns: [{name, type, nullable}], sampled_rows, cached}`; types are
+    /// named as `set_schema` expects. Results are cached by a hash of the
+    /// input's leading bytes and length, so re-inspecting the same input is
+    /// cheap; `cached` says whether this call was served from that cache.
+    #[wasm_bindgen]
+    pub fn infer_schema(
+        &mut self,
+        data: &[u8],
+        sampling: Option<String>,
+        sample_size: Option<usize>,
+    ) -> Result<JsValue, JsValue> {
+        let sampling_name = sampling.as_deref().unwrap_or("head");
+        let sampling = schema::Sampling::parse(sampling_name).ok_or_else(|| {
+            js_error(
+                "invalid_sampling",
+                400,
+                &format!(
+                    "Unsupported sampling '{sampling_name}' (expected head, random or stratified)"
+                ),
+            )
+        })?;
+        let sample_size = sample_size.unwrap_or(schema::SCHEMA_SAMPLE_ROWS);
+        if sample_size == 0 {
+            return Err(js_error(
+                "invalid_sample_size",
+                400,
+                "sample_size must be greater than zero",
+            ));
+        }
+
+        let key = schema::cache_key(
+            data,
+            &format!(
+                "{:?}|{:?}|{:?}|{:?}|{:?}|{sample_size}|{}",
+                self.input_format,
+                self.input_compression,
+                self.csv_options,
+                self.json_options,
+                sampling,
+                data.len()
+            ),
+        );
+
+        let (mut inferred, cached) = match self.schema_cache.get(key) {
+            Some(inferred) => (inferred, true),
+            None => {
+                let rows = self.sample_rows(data, sampling, sample_size)?;
+                self.check_column_limit("Schema inference", export::count_columns(&rows))?;
+                let inferred = schema::infer_schema(&rows);
+                self.schema_cache.insert(key, inferred.clone());
+                (inferred, false)
+            }
+        };
+        inferred["cached"] = cached.into();
+        Ok(serde_wasm_bindgen::to_value(&inferred)?)
+    }
+
+    #[wasm_bindgen]
+    pub fn clear_schema_cache(&mut self) {
+        self.schema_cache.clear();
+    }
+
+    // Up to `limit` rows of the input chosen by `sampling`, without
+    // annotations or transforms. NDJSON only parses the sampled lines.
+    fn sample_rows(
+        &self,
+        data: &[u8],
+        sampling: schema::Sampling,
+        limit: usize,
+    ) -> Result<Vec<serde_json::Value>, JsValue> {
+        let data = &*self.decode_input(data)?;
+        let seed = schema::cache_key(data, "sample");
+        let rows = match self.input_format {
+            InputFormat::Csv => {
+                let text = std::str::from_utf8(data)
+                    .map_err(|_| JsValue::from_str("Invalid UTF-8 data"))?;
+                parse_csv(text, &self.csv_options)?
+            }
+            // An NDJSON stream may also be a JSON array, as in `NdjsonStreamParser`
+            InputFormat::Ndjson
+                if data.iter().find(|b| !b.is_ascii_whitespace()) != Some(&b'[') =>
+            {
+                let mut rows = Vec::new();
+                for line in sampling.select_lines(data, limit, seed) {
+                    rows.push(parse_json(&data[line], &self.json_options)?);
+                }
+                return Ok(rows);
+            }
+            InputFormat::Json | InputFormat::Ndjson => {
+                match parse_json(data, &self.json_options)? {
+                    serde_json::Value::Array(rows) => rows,
+                    other => vec![other],
+                }
+            }
+        };
+        Ok(sampling.select_rows(rows, limit, seed))
+    }
+
+    // Parse `data` into rows. The flag is false when `deadline` passed part way
+    // through NDJSON input, which is parsed in slices so it can stop early.
+    fn process_internal(
+        &self,
+        data: &[u8],
+        deadline: Option<f64>,
+    ) -> Result<(Vec<serde_json::Value>, bool), JsValue> {
+        // Parse input data and perform transformations
+        let data_str =
+            std::str::from_utf8(data).map_err(|_| JsValue::from_str("Invalid UTF-8 data"))?;
+
+        let parsed = match self.input_format {
+            InputFormat::Csv => Some((parse_csv(data_str, &self.csv_options)?, true)),
+            InputFormat::Ndjson => {
+                let mut parser = NdjsonStreamParser::new(self.json_options.clone());
+                let mut rows = Vec::new();
+                let mut complete = true;
+                for slice in data.chunks(DEADLINE_CHECK_BYTES) {
+                    rows.append(&mut parser.push(slice)?);
+                    if self.past_deadline(deadline) {
+                        complete = false;
+                        break;
+                    }
+                }
+                if complete {
+                    rows.append(&mut parser.finish()?);
+                }
+                Some((rows, complete))
+            }
+            InputFormat::Json => None,
+        };
+        if let Some((mut rows, complete)) = parsed {
+            if self.annotate_rows {
+                rows.iter_mut()
+                    .for_each(|row| annotate_row(row, &self.clock));
+            }
+            return Ok((rows, complete));
+        }
+
+        // For demonstration, parse as JSON and perform simple transformations.
+        // The parsed rows are owned, so they're annotated in place (or passed
+        // straight through) rather than cloned.
+        match parse_json(data_str.as_bytes(), &self.json_options) {
+            Ok(serde_json::Value::Array(mut rows)) => {
+                if self.annotate_rows {
+                    rows.iter_mut()
+                        .for_each(|row| annotate_row(row, &self.clock));
+                }
+                return Ok((rows, true));
+            }
+            Err(e) if e.kind != "invalid_json" => return Err(e.into()),
+            _ => {}
+        }
+
+        // Fallback: create simple processed data structure
+        let rows = vec![
+            serde_json::json!({"id": 1, "value": "processed", "source": "wasm"}),
+            serde_json::json!({"id": 2, "value": "data", "source": "wasm"}),
+        ];
+        Ok((rows, true))
+    }
+
+    /// Up to `max` distinct values of `field` from the last processed dataset,
+    /// in first-seen order. `truncated` is set when more values exist.
+    #[wasm_bindgen]
+    pub fn distinct_values(&self, field: String, max: usize) -> JsValue {
+        let mut seen = std::collections::HashSet::new();
+        let mut values = Vec::new();
+        let mut truncated = false;
+
+        for value in self.last_dataset.iter().filter_map(|row| row.get(&field)) {
+            if !seen.insert(value.to_string()) {
+                continue;
+            }
+            if values.len() == max {
+                truncated = true;
+                break;
+            }
+            values.push(value.clone());
+        }
+
+        let result = serde_json::json!({
+            "field": field,
+            "values": values,
+            "truncated": truncated
+        });
+
+        serde_wasm_bindgen::to_value(&result).unwrap_or(JsValue::NULL)
+    }
+
+    /// The `k` most frequent values of `field` in the last processed dataset
+    /// as `[{value, count}]`, by descending count, or the `k` least frequent
+    /// by ascending count when `least`. Equal counts are ordered by value
+    /// (numbers, then strings, booleans, other values and null last). `1` and
+    /// `"1"` count separately.
+    #[wasm_bindgen]
+    pub fn top_k(&self, field: String, k: usize, least: bool) -> JsValue {
+        let entries: Vec<serde_json::Value> =
+            transforms::top_k(&self.last_dataset, &field, k, least)
+                .into_iter()
+                .map(|(value, count)| serde_json::json!({"value": value, "count": count}))
+                .collect();
+        serde_wasm_bindgen::to_value(&entries).unwrap_or(JsValue::NULL)
+    }
+
+    /// Cap on the number of output columns `pivot` generates.
+    #[wasm_bindgen]
+    pub fn set_max_pivot_columns(&mut self, max: usize) {
+        self.max_pivot_columns = max;
+    }
+
+    /// Fail with a `column_limit_exceeded` `DataPrismError` when processed
+    /// rows (after unflattening), an `infer_schema` sample or a pivot result
+    /// have more than `max` distinct columns. Defaults to 4096; `usize::MAX`
+    /// (`0xFFFFFFFF` from JS) turns the check off.
+    #[wasm_bindgen]
+    pub fn set_max_columns(&mut self, max: usize) {
+        self.max_columns = max;
+    }
+
+    /// Pivot the last processed dataset: one row per distinct `index` value,
+    /// with each distinct `columns` value as a key holding `agg` (sum, count,
+    /// mean/avg, min, max) over `values`. `index` defaults to the
+    /// `set_index_column` column.
+    #[wasm_bindgen]
+    pub fn pivot(
+        &self,
+        index: Option<String>,
+        columns: String,
+        values: String,
+        agg: String,
+    ) -> Result<QueryResult, JsValue> {
+        let start_time = self.clock.now();
+        let index = self.key_or_index_column("pivot", index)?;
+
+        let known = export::collect_columns(&self.last_dataset);
+        let mut column_warnings = Vec::new();
+        if !self.last_dataset.is_empty() {
+            for column in [&index, &columns, &values] {
+                self.check_column("Pivot", column, &known, &mut column_warnings)?;
+            }
+        }
+
+        let (rows, mut warnings) = transforms::pivot(
+            &self.last_dataset,
+            &index,
+            &columns,
+            &values,
+            &agg,
+            self.max_pivot_columns,
+        )
+        .map_err(|e| JsValue::from_str(&e))?;
+        self.check_column_limit("Pivot", export::count_columns(&rows))?;
+
+        column_warnings.append(&mut warnings);
+        let mut result = self.build_result(rows, start_time, 0);
+        result.warnings = column_warnings;
+        result.transform_ms = result.total_ms;
+        result.annotated = false;
+        Ok(result)
+    }
+
+    /// Start a streaming aggregation of `field` over NDJSON fed through the
+    /// returned handle. `ops` are any of count, sum, min, max, mean, variance
+    /// and stddev.
+    #[wasm_bindgen]
+    pub fn stream_aggregate(
+        &self,
+        field: String,
+        ops: Vec<String>,
+    ) -> Result<StreamAggregator, JsValue> {
+        if let Some(op) = ops
+            .iter()
+            .find(|op| !transforms::RUNNING_STATS.contains(&op.as_str()))
+        {
+            return Err(JsValue::from_str(&format!(
+                "Unsupported aggregate '{op}' (expected one of {})",
+                transforms::RUNNING_STATS.join(", ")
+            )));
+        }
+
+        Ok(StreamAggregator {
+            parser: NdjsonStreamParser::new(self.json_options.clone()),
+            field,
+            ops,
+            stats: transforms::RunningStats::default(),
+            rows_seen: 0,
+        })
+    }
+
+    /// Forward allocation events from the engine's memory manager to `cb`;
+    /// see `MemoryManager::set_event_callback`.
+    #[wasm_bindgen]
+    pub fn set_memory_event_callback(&mut self, cb: js_sys::Function) {
+        self.memory_manager.set_event_callback(cb);
+    }
+
+    /// Copy `data` into the engine's memory manager and return its buffer id.
+    #[wasm_bindgen]
+    pub fn stage_data(&mut self, data: &[u8]) -> u32 {
+        let buffer_id = self.memory_manager.allocate_buffer(data.len());
+        self.memory_manager.write_buffer(buffer_id, data);
+        buffer_id
+    }
+
+    /// Stage the bytes of a fetched `CloudDataBuffer` for `process_buffer`,
+    /// copying them within WASM memory rather than out through JS and back.
+    /// Returns the buffer id; `buf` stays usable.
+    #[wasm_bindgen]
+    pub fn stage_cloud_buffer(&mut self, buf: &CloudDataBuffer) -> u32 {
+        self.stage_data(buf.bytes())
+    }
+
+    /// Append a chunk to a staged buffer, for inputs staged in pieces.
+    /// Returns false for unknown ids.
+    #[wasm_bindgen]
+    pub fn append_to_buffer(&mut self, buffer_id: u32, chunk: &[u8]) -> bool {
+        self.memory_manager.append_bytes(buffer_id, chunk)
+    }
+
+    /// Declare the full length of a staged buffer. `process_buffer` and
+    /// `process_buffer_chunked` reject the buffer with `incomplete_buffer`
+    /// until it reaches this length.
+    #[wasm_bindgen]
+    pub fn set_buffer_expected_len(&mut self, buffer_id: u32, len: usize) -> bool {
+        self.memory_manager.set_expected_len(buffer_id, len)
+    }
+
+    #[wasm_bindgen]
+    pub fn release_buffer(&mut self, buffer_id: u32) -> bool {
+        self.memory_manager.deallocate_buffer(buffer_id)
+    }
+
+    /// Process a staged buffer like `process_data` and pass the output rows
+    /// to `on_batch(rows)` in arrays of `chunk_rows` as they are produced.
+    /// The buffer is read in 1 MiB slices by the streaming parser for the
+    /// input format, as in `begin_stream`, so only a slice plus a batch of
+    /// rows are held besides it. The buffer is released once every batch has
+    /// been handed over; if processing fails it stays staged, although
+    /// earlier batches will have been passed on. Unknown columns are checked
+    /// once the last slice is transformed, and warnings are not reported.
+    /// Returns the number of rows emitted. A `chunk_rows` of 0 fails with an
+    /// `invalid_batch_size` `DataPrismError`.
+    #[wasm_bindgen]
+    pub fn process_buffer_chunked(
+        &mut self,
+        buffer_id: u32,
+        chunk_rows: usize,
+        on_batch: js_sys::Function,
+    ) -> Result<u32, JsValue> {
+        if chunk_rows == 0 {
+            return Err(js_error(
+                "invalid_batch_size",
+                400,
+                "chunk_rows must be greater than zero",
+            ));
+        }
+        self.check_buffer_complete(buffer_id)?;
+        let data = self
+            .memory_manager
+            .get_buffer_mut(buffer_id)
+            .map(std::mem::take)
+            .ok_or_else(|| {
+                js_error(
+                    "unknown_buffer",
+                    404,
+                    &format!("No staged buffer with id {buffer_id}"),
+                )
+            })?;
+
+        let result = self.guarded(|engine| {
+            let rule = engine.matching_schema_rule(&data);
+            engine.with_schema_rule(rule, |engine| {
+                engine.process_buffer_chunked_unguarded(&data, chunk_rows, &on_batch)
+            })
+        });
+
+        if result.is_ok() {
+            self.memory_manager.deallocate_buffer(buffer_id);
+        } else if let Some(buffer) = self.memory_manager.get_buffer_mut(buffer_id) {
+            *buffer = data;
+        }
+        result
+    }
+
+    #[wasm_bindgen]
+    pub fn get_memory_usage(&self) -> u32 {
+        self.memory_manager.get_total_allocated() as u32
+    }
+
+    #[wasm_bindgen]
+    pub fn optimize_memory(&mut self) -> u32 {
+        // In a real implementation, this would perform memory optimization
+        // For now, return current memory usage
+        self.get_memory_usage()
+    }
+
+    /// All guard values the engine enforces, in one object.
+    #[wasm_bindgen]
+    pub fn get_limits(&self) -> JsValue {
+        let limits = serde_json::json!({
+            "max_input_bytes": MAX_INPUT_BYTES,
+            "max_pivot_columns": self.max_pivot_columns,
+            "max_columns": self.max_columns
+        });
+
+        serde_wasm_bindgen::to_value(&limits).unwrap_or(JsValue::NULL)
+    }
+
+    #[wasm_bindgen]
+    pub fn get_stats(&self) -> JsValue {
+        let stats = serde_json::json!({
+            "memory_usage": self.get_memory_usage(),
+            "buffer_count": self.memory_manager.get_buffer_count(),
+            "version": "0.1.0"
+        });
+
+        serde_wasm_bindgen::to_value(&stats).unwrap_or(JsValue::NULL)
+    }
+}
+
+impl QueryEngine {
+    pub(crate) fn config(&self) -> EngineConfig {
+        EngineConfig {
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            input_format: self.input_format.name().to_string(),
+            input_compression: self.input_compression.name().to_string(),
+            csv_ragged_policy: self.csv_options.ragged.name().to_string(),
+            number_locale: self
+                .csv_options
+                .number_locale
+                .map(|locale| NumberLocaleConfig {
+                    decimal: locale.decimal.to_string(),
+                    thousands: locale.thousands.map(String::from).unwrap_or_default(),
+                }),
+            include_columns: self.json_options.include_columns.clone(),
+            exclude_columns: self.json_options.exclude_columns.clone(),
+            max_json_depth: self.json_options.max_depth,
+            reject_duplicate_keys: self.json_options.reject_duplicate_keys,
+            resync_on_error: self.resync_on_error,
+            schema: self
+                .schema
+                .iter()
+                .map(|(column, declared)| (column.clone(), declared.name().to_string()))
+                .collect(),
+            schema_on_fail: if self.schema_errors { "error" } else { "null" }.to_string(),
+            unknown_columns: if self.lenient_columns {
+                "warn"
+            } else {
+                "error"
+            }
+            .to_string(),
+            unflatten_separator: self.unflatten_separator.clone(),
+            computed_columns: self
+                .computed_columns
+                .iter()
+                .map(|(name, expr, _)| ComputedColumnConfig {
+                    name: name.clone(),
+                    expr: expr.clone(),
+                })
+                .collect(),
+            enrichments: self.enrichments.clone(),
+            output_key: self.output_key.clone(),
+            output_key_unique: self.output_key_unique,
+            null_representation: self.null_representation.clone(),
+            binary_encoding: if self.binary_base64 {
+                "base64"
+            } else {
+                "array"
+            }
+            .to_string(),
+            float_precision: self.float_precision,
+            bigint_passthrough: self.bigint_passthrough,
+            annotate_rows: self.annotate_rows,
+            deterministic: self.clock.deterministic,
+            retain_input: self.retain_input,
+            trace_id: self.trace_id.clone(),
+            deadline_ms: self.deadline_ms,
+            max_pivot_columns: self.max_pivot_columns,
+            max_columns: self.max_columns,
+            error_on_empty: self.error_on_empty,
+            schema_rules: self
+                .schema_rules
+                .iter()
+                .map(|staged| staged.rule.clone())
+                .collect(),
+            timestamp_format: self.clock.format.name().to_string(),
+            mapping: self
+                .mapping
+                .iter()
+                .map(|(from, to, declared)| MappingConfig {
+                    from: from.clone(),
+                    to: Some(to.clone()),
+                    type_name: declared.map(|d| d.name().to_string()),
+                })
+                .collect(),
+            json_columns: self.json_columns.clone(),
+            row_id: self.row_id.as_ref().map(|(field, mode)| RowIdConfig {
+                field: field.clone(),
+                mode: mode.name().to_string(),
+            }),
+            upsert_merge_fields: self.upsert_merge_fields,
+            tombstone_field: self.tombstone_field.clone(),
+            index_column: self.index_column.clone(),
+            index_first: self.index_first,
+        }
+    }
+
+    fn stage_schema_rule(&self, rule: SchemaRule) -> Result<StagedRule, JsValue> {
+        if rule.required_columns.is_empty() {
+            return Err(js_error(
+                "invalid_config",
+                400,
+                "when_schema needs at least one required column",
+            ));
+        }
+        if !rule.config.schema_rules.is_empty() {
+            return Err(js_error(
+                "invalid_config",
+                400,
+                "A when_schema config cannot contain further schema rules",
+            ));
+        }
+        let settings = self.stage_config(rule.config.clone())?;
+        Ok(StagedRule { rule, settings })
+    }
+
+    // The first `when_schema` rule whose required columns all appear in the
+    // leading rows of `data`. Input that can't be sampled matches nothing,
+    // leaving the error to processing.
+    pub(crate) fn matching_schema_rule(&self, data: &[u8]) -> Option<usize> {
+        if self.schema_rules.is_empty() {
+            return None;
+        }
+        let rows = self
+            .sample_rows(data, schema::Sampling::Head, schema::SCHEMA_SAMPLE_ROWS)
+            .ok()?;
+        self.schema_rule_for_rows(&rows)
+    }
+
+    // As `matching_schema_rule`, for the rows `regex` makes of the leading
+    // lines of a log
+    fn matching_log_rule(&self, data: &[u8], regex: &regex::Regex) -> Option<usize> {
+        if self.schema_rules.is_empty() {
+            return None;
+        }
+        let data = self.decode_input(data).ok()?;
+        let text = std::str::from_utf8(&data).ok()?;
+        let head = match text.match_indices('\n').nth(schema::SCHEMA_SAMPLE_ROWS - 1) {
+            Some((end, _)) => &text[..end],
+            None => text,
+        };
+        let (rows, _) = log_parser::parse_logs(head, regex);
+        self.schema_rule_for_rows(&rows)
+    }
+
+    // The first `when_schema` rule whose required columns all appear in the
+    // leading rows of `rows`
+    fn schema_rule_for_rows(&self, rows: &[serde_json::Value]) -> Option<usize> {
+        if self.schema_rules.is_empty() {
+            return None;
+        }
+        let sample = &rows[..rows.len().min(schema::SCHEMA_SAMPLE_ROWS)];
+        let columns: std::collections::HashSet<String> =
+            export::collect_columns(sample).into_iter().collect();
+        self.schema_rules.iter().position(|staged| {
+            let required = &staged.rule.required_columns;
+            required.iter().all(|c| columns.contains(c))
+        })
+    }
+
+    pub(crate) fn apply_config(&mut self, config: EngineConfig) -> Result<(), JsValue> {
+        let rules = config
+            .schema_rules
+            .iter()
+            .map(|rule| self.stage_schema_rule(rule.clone()))
+            .collect::<Result<Vec<_>, _>>()?;
+        let mut staged = self.stage_config(config)?;
+        // Keep the deterministic counter running unless the mode changes
+        let restart_clock = self.clock.deterministic != staged.clock.deterministic;
+        self.swap_settings(&mut staged);
+        self.schema_rules = rules;
+        if restart_clock {
+            self.set_deterministic(self.clock.deterministic);
+        }
+        Ok(())
+    }
+
+    // Settings are applied to a fresh engine through the public setters, so
+    // they get the same validation, and only adopted by the caller once all
+    // succeed. Rules in `config` are left to the caller.
+    fn stage_config(&self, config: EngineConfig) -> Result<QueryEngine, JsValue> {
+        let mut staged = QueryEngine::new();
+        staged.set_input_format(&config.input_format)?;
+        staged.set_input_compression(&config.input_compression)?;
+        staged.set_csv_ragged_policy(&config.csv_ragged_policy)?;
+        if let Some(locale) = config.number_locale {
+            staged.set_number_locale(locale.decimal, locale.thousands)?;
+        }
+        staged.set_parse_columns(config.include_columns, config.exclude_columns);
+        staged.json_options.max_depth = config.max_json_depth;
+        staged.set_reject_duplicate_keys(config.reject_duplicate_keys);
+        staged.set_resync_on_error(config.resync_on_error);
+        staged.set_declared_schema(config.schema)?;
+        staged.set_schema_on_fail(&config.schema_on_fail)?;
+        staged.set_unknown_columns(&config.unknown_columns)?;
+        if let Some(separator) = config.unflatten_separator {
+            staged.set_unflatten(true, separator)?;
+        }
+        for column in config.computed_columns {
+            staged.add_computed_column(column.name, column.expr)?;
+        }
+        // Lookup tables are data rather than settings, so enrichments are
+        // checked against the tables already loaded here
+        for enrichment in &config.enrichments {
+            self.check_lookup_table(&enrichment.table)?;
+        }
+        staged.enrichments = config.enrichments;
+        staged.output_key = config.output_key;
+        staged.set_output_key_unique(config.output_key_unique);
+        staged.null_representation = config.null_representation;
+        staged.set_binary_encoding(&config.binary_encoding)?;
+        staged.float_precision = config.float_precision;
+        staged.set_bigint_passthrough(config.bigint_passthrough);
+        staged.set_annotate_rows(config.annotate_rows);
+        staged.set_retain_input(config.retain_input);
+        staged.trace_id = config.trace_id;
+        if let Some(ms) = config.deadline_ms {
+            staged.set_deadline_ms(ms);
+        }
+        staged.set_max_pivot_columns(config.max_pivot_columns);
+        staged.set_max_columns(config.max_columns);
+        staged.set_error_on_empty(config.error_on_empty);
+        staged.set_timestamp_format(&config.timestamp_format)?;
+        if !config.mapping.is_empty() {
+            staged.set_mapping_columns(config.mapping)?;
+        }
+        staged.parse_json_columns(config.json_columns);
+        if let Some(row_id) = config.row_id {
+            staged.set_add_row_id(row_id.field, &row_id.mode)?;
+        }
+        staged.set_upsert_options(config.upsert_merge_fields, config.tombstone_field);
+        staged.index_column = config.index_column;
+        staged.set_index_first(config.index_first);
+
+        staged.clock.deterministic = config.deterministic;
+        Ok(staged)
+    }
+
+    // Exchange the settings an `EngineConfig` covers, other than the schema
+    // rules, with those of `other`
+    fn swap_settings(&mut self, other: &mut QueryEngine) {
+        std::mem::swap(&mut self.input_format, &mut other.input_format);
+        std::mem::swap(&mut self.input_compression, &mut other.input_compression);
+        std::mem::swap(&mut self.csv_options, &mut other.csv_options);
+        std::mem::swap(&mut self.json_options, &mut other.json_options);
+        std::mem::swap(&mut self.resync_on_error, &mut other.resync_on_error);
+        std::mem::swap(&mut self.schema, &mut other.schema);
+        std::mem::swap(&mut self.schema_errors, &mut other.schema_errors);
+        std::mem::swap(&mut self.lenient_columns, &mut other.lenient_columns);
+        std::mem::swap(
+            &mut self.unflatten_separator,
+            &mut other.unflatten_separator,
+        );
+        std::mem::swap(&mut self.computed_columns, &mut other.computed_columns);
+        std::mem::swap(&mut self.enrichments, &mut other.enrichments);
+        std::mem::swap(&mut self.output_key, &mut other.output_key);
+        std::mem::swap(&mut self.output_key_unique, &mut other.output_key_unique);
+        std::mem::swap(
+            &mut self.null_representation,
+            &mut other.null_representation,
+        );
+        std::mem::swap(&mut self.binary_base64, &mut other.binary_base64);
+        std::mem::swap(&mut self.float_precision, &mut other.float_precision);
+        std::mem::swap(&mut self.bigint_passthrough, &mut other.bigint_passthrough);
+        std::mem::swap(&mut self.annotate_rows, &mut other.annotate_rows);
+        std::mem::swap(&mut self.retain_input, &mut other.retain_input);
+        std::mem::swap(&mut self.trace_id, &mut other.trace_id);
+        std::mem::swap(&mut self.deadline_ms, &mut other.deadline_ms);
+        std::mem::swap(&mut self.max_pivot_columns, &mut other.max_pivot_columns);
+        std::mem::swap(&mut self.max_columns, &mut other.max_columns);
+        std::mem::swap(&mut self.error_on_empty, &mut other.error_on_empty);
+        std::mem::swap(&mut self.clock.format, &mut other.clock.format);
+        std::mem::swap(&mut self.mapping, &mut other.mapping);
+        std::mem::swap(&mut self.json_columns, &mut other.json_columns);
+        std::mem::swap(&mut self.row_id, &mut other.row_id);
+        std::mem::swap(
+            &mut self.upsert_merge_fields,
+            &mut other.upsert_merge_fields,
+        );
+        std::mem::swap(&mut self.tombstone_field, &mut other.tombstone_field);
+        std::mem::swap(&mut self.index_column, &mut other.index_column);
+        std::mem::swap(&mut self.index_first, &mut other.index_first);
+        std::mem::swap(
+            &mut self.clock.deterministic,
+            &mut other.clock.deterministic,
+        );
+    }
+
+    // Put the settings of schema rule `index` in place of the current ones.
+    // The rules are set aside meanwhile and returned, for `leave_schema_rule`.
+    fn enter_schema_rule(&mut self, index: usize) -> Vec<StagedRule> {
+        let mut rules = std::mem::take(&mut self.schema_rules);
+        self.swap_settings(&mut rules[index].settings);
+        rules
+    }
+
+    fn leave_schema_rule(&mut self, index: usize, mut rules: Vec<StagedRule>) {
+        self.swap_settings(&mut rules[index].settings);
+        self.schema_rules = rules;
+    }
+
+    // Run with the settings of schema rule `rule`, if any, restoring the
+    // current ones afterwards
+    fn with_schema_rule<T>(
+        &mut self,
+        rule: Option<usize>,
+        run: impl FnOnce(&mut Self) -> Result<T, JsValue>,
+    ) -> Result<T, JsValue> {
+        let Some(index) = rule else {
+            return run(self);
+        };
+        let rules = self.enter_schema_rule(index);
+        let result = run(self);
+        self.leave_schema_rule(index, rules);
+        result
+    }
+
+    fn process_buffer_chunked_unguarded(
+        &mut self,
+        data: &[u8],
+        chunk_rows: usize,
+        on_batch: &js_sys::Function,
+    ) -> Result<u32, JsValue> {
+        let data = &*self.decode_input(data)?;
+        if data.len() > MAX_INPUT_BYTES {
+            return Err(JsValue::from_str("Input data exceeds maximum size limit"));
+        }
+
+        let mut parser = self.stream_parser();
+        let mut seen = SeenColumns::default();
+        let mut warnings = Vec::new();
+        let mut pending = Vec::new();
+        let mut emitted = 0usize;
+        for slice in data.chunks(DEADLINE_CHECK_BYTES) {
+            let rows = parser.push(slice)?;
+            let first_row = emitted + pending.len();
+            pending.extend(self.transform_parsed(rows, &mut warnings, first_row, &mut seen)?);
+            emit_batches(&mut pending, chunk_rows, on_batch, &mut emitted, false)?;
+        }
+        let rows = parser.finish()?;
+        let first_row = emitted + pending.len();
+        pending.extend(self.transform_parsed(rows, &mut warnings, first_row, &mut seen)?);
+        self.check_seen_columns(&seen, &mut warnings)?;
+        emit_batches(&mut pending, chunk_rows, on_batch, &mut emitted, true)?;
+        Ok(emitted as u32)
+    }
+
+    // A streaming parser for the input format
+    fn stream_parser(&self) -> StreamParser {
+        match self.input_format {
+            InputFormat::Csv => StreamParser::Csv(CsvStreamParser::new(self.csv_options)),
+            InputFormat::Json | InputFormat::Ndjson => {
+                let mut parser = NdjsonStreamParser::new(self.json_options.clone());
+                parser.resync_on_error = self.resync_on_error;
+                StreamParser::Ndjson(parser)
+            }
+        }
+    }
+
+    fn begin_stream_unguarded(&mut self) {
+        let inflater = match self.input_compression {
+            InputCompression::None => None,
+            InputCompression::Auto | InputCompression::Gzip => {
+                Some(GunzipStream::new(InflateLimits {
+                    max_output: MAX_INPUT_BYTES,
+                    max_ratio: None,
+                }))
+            }
+        };
+        self.stream = Some(StreamState {
+            parser: self.stream_parser(),
+            inflater,
+            require_gzip: matches!(self.input_compression, InputCompression::Gzip),
+            rows: Vec::new(),
+            bytes_received: 0,
+            start_time: self.clock.now(),
+            parse_ms: 0.0,
+        });
+    }
+
+    fn push_chunk_unguarded(&mut self, chunk: &[u8]) -> Result<u32, JsValue> {
+        let annotate = self.annotate_rows;
+        let clock = self.clock.clone();
+        let stream = self
+            .stream
+            .as_mut()
+            .ok_or_else(|| JsValue::from_str("No stream in progress; call begin_stream first"))?;
+
+        stream.bytes_received += chunk.len();
+        let parse_start = clock.now();
+        let parsed = stream.push(chunk);
+        stream.parse_ms += clock.now() - parse_start;
+        match parsed {
+            Ok(mut rows) => {
+                if annotate {
+                    rows.iter_mut().for_each(|row| annotate_row(row, &clock));
+                }
+                stream.rows.append(&mut rows);
+                Ok(stream.rows.len() as u32)
+            }
+            Err(e) => {
+                self.stream = None;
+                Err(e.into())
+            }
+        }
+    }
+
+    fn finish_stream_unguarded(&mut self) -> Result<QueryResult, JsValue> {
+        let mut stream = self
+            .stream
+            .take()
+            .ok_or_else(|| JsValue::from_str("No stream in progress; call begin_stream first"))?;
+
+        let parse_start = self.clock.now();
+        let mut rows = stream.finish()?;
+        if self.annotate_rows {
+            rows.iter_mut()
+                .for_each(|row| annotate_row(row, &self.clock));
+        }
+        stream.rows.append(&mut rows);
+        stream.parse_ms += self.clock.now() - parse_start;
+
+        let rule = self.schema_rule_for_rows(&stream.rows);
+        self.with_schema_rule(rule, |engine| engine.transform_stream(stream))
+    }
+
+    // Push each chunk `reader` yields into the stream in progress
+    async fn read_into_stream(
+        &mut self,
+        reader: &web_sys::ReadableStreamDefaultReader,
+    ) -> Result<(), JsValue> {
+        loop {
+            let next = wasm_bindgen_futures::JsFuture::from(reader.read()).await?;
+            if js_sys::Reflect::get(&next, &JsValue::from_str("done"))?
+                .as_bool()
+                .unwrap_or(false)
+            {
+                return Ok(());
+            }
+
+            let value = js_sys::Reflect::get(&next, &JsValue::from_str("value"))?;
+            self.push_chunk_unguarded(&js_sys::Uint8Array::new(&value).to_vec())?;
+        }
+    }
+
+    // Transform the rows of a finished stream into its result
+    fn transform_stream(&mut self, mut stream: StreamState) -> Result<QueryResult, JsValue> {
+        let transform_start = self.clock.now();
+        let mut warnings = stream.parser.take_skipped();
+        let stage_counts = self
+            .apply_transforms(&mut stream.rows, &mut warnings, None, 0, None)?
+            .unwrap_or_default();
+        let transform_ms = self.clock.now() - transform_start;
+        self.check_not_empty(stream.rows.len())?;
+        self.last_dataset = stream.rows.clone();
+
+        let mut result = self.build_result(stream.rows, stream.start_time, stream.bytes_received);
+        result.warnings = warnings;
+        result.stage_counts = stage_counts;
+        result.parse_ms = stream.parse_ms;
+        result.transform_ms = transform_ms;
+        Ok(result)
+    }
+
+    // Entry point for every whole-input processing call. A call made while
+    // another is in progress (e.g. from a callback the engine invoked) fails
+    // with `reentrant_call` instead of interleaving with it.
+    pub(crate) fn process_bytes(&mut self, data: &[u8]) -> Result<QueryResult, JsValue> {
+        self.guarded(|engine| {
+            let rule = engine.matching_schema_rule(data);
+            engine.with_schema_rule(rule, |engine| engine.process_bytes_unguarded(data))
+        })
+    }
+
+    fn guarded<T>(
+        &mut self,
+        run: impl FnOnce(&mut Self) -> Result<T, JsValue>,
+    ) -> Result<T, JsValue> {
+        self.begin_processing()?;
+        let result = run(self);
+        self.processing = false;
+        result
+    }
+
+    // Mark the engine busy; callers clear `processing` when done
+    fn begin_processing(&mut self) -> Result<(), JsValue> {
+        if self.processing {
+            return Err(js_error(
+                "reentrant_call",
+                409,
+                "The engine is already processing; nested calls are not allowed",
+            ));
+        }
+        self.processing = true;
+        Ok(())
+    }
+
+    // Stage sorted runs (recording their ids for the caller to release) and
+    // merge them
+    fn sort_in_runs(
+        &mut self,
+        data: &[u8],
+        field: &str,
+        descending: bool,
+        run_size: usize,
+        run_ids: &mut Vec<u32>,
+    ) -> Result<Vec<u8>, JsValue> {
+        let data = &*self.decode_input(data)?;
+        let mut stage_run = |engine: &mut Self, rows: Vec<serde_json::Value>| {
+            let run = transforms::sorted_run(rows, field, descending);
+            run_ids.push(engine.stage_data(&run));
+        };
+
+        let is_ndjson_lines = matches!(self.input_format, InputFormat::Ndjson)
+            && data.iter().find(|b| !b.is_ascii_whitespace()) != Some(&b'[');
+        if is_ndjson_lines {
+            let mut parser = NdjsonStreamParser::new(self.json_options.clone());
+            let mut pending = Vec::new();
+            for slice in data.chunks(DEADLINE_CHECK_BYTES) {
+                pending.append(&mut parser.push(slice)?);
+                while pending.len() >= run_size {
+                    let rest = pending.split_off(run_size);
+                    stage_run(self, std::mem::replace(&mut pending, rest));
+                }
+            }
+            pending.append(&mut parser.finish()?);
+            while !pending.is_empty() {
+                let rest = pending.split_off(run_size.min(pending.len()));
+                stage_run(self, std::mem::replace(&mut pending, rest));
+            }
+        } else {
+            let mut rows = self
+                .sample_rows(data, schema::Sampling::Head, usize::MAX)?
+                .into_iter();
+            loop {
+                let run: Vec<serde_json::Value> = rows.by_ref().take(run_size).collect();
+                if run.is_empty() {
+                    break;
+                }
+                stage_run(self, run);
+            }
+        }
+
+        let runs: Vec<&[u8]> = run_ids
+            .iter()
+            .filter_map(|id| self.memory_manager.get_buffer(*id).map(Vec::as_slice))
+            .collect();
+        let mut sorted = Vec::with_capacity(runs.iter().map(|run| run.len()).sum());
+        transforms::merge_runs(&runs, field, descending, &mut sorted)
+            .map_err(|e| js_error("invalid_json", 400, &format!("Invalid staged run: {e}")))?;
+        Ok(sorted)
+    }
+
+    fn process_logs_unguarded(
+        &mut self,
+        data: &[u8],
+        regex: &regex::Regex,
+    ) -> Result<QueryResult, JsValue> {
+        let start_time = self.clock.now();
+        let data = &*self.decode_input(data)?;
+        if data.len() > MAX_INPUT_BYTES {
+            return Err(JsValue::from_str("Input data exceeds maximum size limit"));
+        }
+        let text =
+            std::str::from_utf8(data).map_err(|_| JsValue::from_str("Invalid UTF-8 data"))?;
+
+        let (mut rows, unmatched) = log_parser::parse_logs(text, regex);
+        if self.annotate_rows {
+            rows.iter_mut()
+                .for_each(|row| annotate_row(row, &self.clock));
+        }
+        let transform_start = self.clock.now();
+
+        let mut warnings = Vec::new();
+        if !unmatched.is_empty() {
+            const LISTED: usize = 10;
+            let listed: Vec<String> = unmatched
+                .iter()
+                .take(LISTED)
+                .map(usize::to_string)
+                .collect();
+            let more = if unmatched.len() > LISTED {
+                ", ..."
+            } else {
+                ""
+            };
+            warnings.push(format!(
+                "{} lines did not match the log pattern (lines {}{more})",
+                unmatched.len(),
+                listed.join(", ")
+            ));
+        }
+        let mut stage_counts = self
+            .apply_transforms(&mut rows, &mut warnings, None, 0, None)?
+            .unwrap_or_default();
+        stage_counts.insert(1, ("unmatched".to_string(), unmatched.len()));
+        let transform_ms = self.clock.now() - transform_start;
+        self.check_not_empty(rows.len())?;
+        self.last_dataset = rows.clone();
+
+        let mut result = self.build_result(rows, start_time, data.len());
+        result.warnings = warnings;
+        result.stage_counts = stage_counts;
+        result.parse_ms = transform_start - start_time;
+        result.transform_ms = transform_ms;
+        Ok(result)
+    }
+
+    fn process_to_sink_unguarded(
+        &mut self,
+        data: &[u8],
+        sink: &js_sys::Function,
+    ) -> Result<QueryResult, JsValue> {
+        let start_time = self.clock.now();
+        if data.is_empty() {
+            return Err(JsValue::from_str("Input data cannot be empty"));
+        }
+        let data = &*self.decode_input(data)?;
+        if data.len() > MAX_INPUT_BYTES {
+            return Err(JsValue::from_str("Input data exceeds maximum size limit"));
+        }
+
+        let mut emitted = 0usize;
+        let mut warnings = Vec::new();
+        let is_ndjson_lines = matches!(self.input_format, InputFormat::Ndjson)
+            && data.iter().find(|b| !b.is_ascii_whitespace()) != Some(&b'[');
+        if is_ndjson_lines {
+            let mut parser = NdjsonStreamParser::new(self.json_options.clone());
+            let mut seen = SeenColumns::default();
+            for slice in data.chunks(DEADLINE_CHECK_BYTES) {
+                let rows = parser.push(slice)?;
+                self.drain_to_sink(rows, sink, &mut emitted, &mut warnings, &mut seen)?;
+            }
+            let rows = parser.finish()?;
+            self.drain_to_sink(rows, sink, &mut emitted, &mut warnings, &mut seen)?;
+            self.check_seen_columns(&seen, &mut warnings)?;
+        } else {
+            let (mut rows, _) = self.process_internal(data, None)?;
+            self.apply_transforms(&mut rows, &mut warnings, None, 0, None)?;
+            self.emit_rows(rows, sink, &mut emitted)?;
+        }
+
+        self.check_not_empty(emitted)?;
+        let mut result = self.build_result(Vec::new(), start_time, data.len());
+        result.row_count = emitted as u32;
+        result.output_key = None;
+        result.rows_per_sec = per_second(emitted, result.total_ms);
+        result.stage_counts = vec![("output".to_string(), emitted)];
+        result.warnings = warnings;
+        Ok(result)
+    }
+
+    async fn process_data_stream_unguarded(
+        &mut self,
+        data: &[u8],
+        high_water_rows: usize,
+        consumer: &js_sys::Function,
+    ) -> Result<QueryResult, JsValue> {
+        let start_time = self.clock.now();
+        if data.is_empty() {
+            return Err(JsValue::from_str("Input data cannot be empty"));
+        }
+        let data = &*self.decode_input(data)?;
+        if data.len() > MAX_INPUT_BYTES {
+            return Err(JsValue::from_str("Input data exceeds maximum size limit"));
+        }
+
+        let mut emitted = 0usize;
+        let mut warnings = Vec::new();
+        let mut pending = Vec::new();
+        let is_ndjson_lines = matches!(self.input_format, InputFormat::Ndjson)
+            && data.iter().find(|b| !b.is_ascii_whitespace()) != Some(&b'[');
+        if is_ndjson_lines {
+            let mut parser = NdjsonStreamParser::new(self.json_options.clone());
+            let mut seen = SeenColumns::default();
+            for slice in data.chunks(DEADLINE_CHECK_BYTES) {
+                let rows = parser.push(slice)?;
+                let first_row = emitted + pending.len();
+                let rows = self.transform_parsed(rows, &mut warnings, first_row, &mut seen)?;
+                pending.extend(rows);
+                self.feed_consumer(&mut pending, high_water_rows, consumer, &mut emitted, false)
+                    .await?;
+            }
+            let rows = parser.finish()?;
+            let first_row = emitted + pending.len();
+            let rows = self.transform_parsed(rows, &mut warnings, first_row, &mut seen)?;
+            pending.extend(rows);
+            self.check_seen_columns(&seen, &mut warnings)?;
+        } else {
+            let (mut rows, _) = self.process_internal(data, None)?;
+            self.apply_transforms(&mut rows, &mut warnings, None, 0, None)?;
+            pending = rows;
+        }
+        self.feed_consumer(&mut pending, high_water_rows, consumer, &mut emitted, true)
+            .await?;
+
+        self.check_not_empty(emitted)?;
+        let mut result = self.build_result(Vec::new(), start_time, data.len());
+        result.row_count = emitted as u32;
+        result.output_key = None;
+        result.rows_per_sec = per_second(emitted, result.total_ms);
+        result.stage_counts = vec![("output".to_string(), emitted)];
+        result.warnings = warnings;
+        Ok(result)
+    }
+
+    // `process_bytes_unguarded`, handing rows to `callback` as they are
+    // transformed
+    async fn process_data_partial(
+        &mut self,
+        data: &[u8],
+        every_rows: usize,
+        callback: &js_sys::Function,
+    ) -> Result<QueryResult, JsValue> {
+        if !matches!(self.input_format, InputFormat::Ndjson) {
+            let result = self.process_bytes_unguarded(data)?;
+            emit_partial(&result.data, every_rows, callback, &mut 0, true).await?;
+            return Ok(result);
+        }
+
+        let start_time = self.clock.now();
+        if data.is_empty() {
+            return Err(JsValue::from_str("Input data cannot be empty"));
+        }
+        let data = &*self.decode_input(data)?;
+        if data.len() > MAX_INPUT_BYTES {
+            return Err(JsValue::from_str("Input data exceeds maximum size limit"));
+        }
+        self.input_diagnostics = Some(LineEndingCounts::scan(data));
+
+        let deadline = self.deadline_ms.map(|ms| start_time + ms);
+        let mut parser = NdjsonStreamParser::new(self.json_options.clone());
+        let mut run = SliceRun::default();
+        let mut emitted = 0usize;
+        for slice in data.chunks(DEADLINE_CHECK_BYTES) {
+            self.transform_slice(&mut run, &mut parser, Some(slice))?;
+            emit_partial(&run.rows, every_rows, callback, &mut emitted, false).await?;
+            if self.past_deadline(deadline) {
+                return Err(self.deadline_exceeded(run.rows, run.warnings, start_time, data.len()));
+            }
+        }
+        self.transform_slice(&mut run, &mut parser, None)?;
+        let result = self.finish_slices(run, start_time, data.len())?;
+        emit_partial(&result.data, every_rows, callback, &mut emitted, true).await?;
+        Ok(result)
+    }
+
+    // Parse one slice of NDJSON into `run` and transform it; `None` finishes
+    // the parser
+    pub(crate) fn transform_slice(
+        &self,
+        run: &mut SliceRun,
+        parser: &mut NdjsonStreamParser,
+        slice: Option<&[u8]>,
+    ) -> Result<(), JsValue> {
+        let parse_start = self.clock.now();
+        let rows = match slice {
+            Some(slice) => parser.push(slice)?,
+            None => parser.finish()?,
+        };
+        let transform_start = self.clock.now();
+        let first_row = run.rows.len();
+        let rows = self.transform_parsed(rows, &mut run.warnings, first_row, &mut run.seen)?;
+        run.rows.extend(rows);
+        run.parse_ms += transform_start - parse_start;
+        run.transform_ms += self.clock.now() - transform_start;
+        Ok(())
+    }
+
+    // Make the checks that span slices and build the result of `run`
+    pub(crate) fn finish_slices(
+        &mut self,
+        run: SliceRun,
+        start_time: f64,
+        bytes: usize,
+    ) -> Result<QueryResult, JsValue> {
+        let SliceRun {
+            rows,
+            mut warnings,
+            seen,
+            parse_ms,
+            transform_ms,
+        } = run;
+        self.check_seen_columns(&seen, &mut warnings)?;
+        self.check_output_keys(&rows)?;
+        self.check_not_empty(rows.len())?;
+
+        let mut stage_counts = vec![("parsed".to_string(), rows.len())];
+        let mut output = rows.len();
+        if let Some(field) = &self.output_key {
+            output = export::keyed_entry_count(&rows, field);
+            stage_counts.push(("after_output_key".to_string(), output));
+        }
+        stage_counts.push(("output".to_string(), output));
+        self.last_dataset = rows.clone();
+
+        let mut result = self.build_result(rows, start_time, bytes);
+        result.warnings = warnings;
+        result.stage_counts = stage_counts;
+        result.parse_ms = parse_ms;
+        result.transform_ms = transform_ms;
+        Ok(result)
+    }
+
+    // Range-fetch `url` into the stream begun by `process_from_cloud`
+    async fn stream_from_cloud(
+        &mut self,
+        bridge: &CloudStorageBridge,
+        url: &str,
+        provider: &str,
+    ) -> Result<(), JsValue> {
+        let mut offset = 0;
+        loop {
+            let (data, status, total) = bridge
+                .fetch_range(url, offset, CLOUD_RANGE_BYTES)
+                .await
+                .map_err(|e| {
+                    pipeline_error(
+                        "fetch",
+                        &error_message(&e),
+                        Some(provider.to_string()),
+                        None,
+                    )
+                })?;
+            // Past the end of an object whose size came out even
+            if status == 416 && offset > 0 {
+                return Ok(());
+            }
+            if status >= 400 {
+                return Err(pipeline_error(
+                    "http",
+                    &format!("Request for {url} failed with HTTP {status}"),
+                    Some(provider.to_string()),
+                    Some(status),
+                ));
+            }
+
+            self.push_chunk_unguarded(&data)
+                .map_err(|e| processing_error(&e, Some(provider.to_string()), Some(status)))?;
+            offset += data.len();
+            let ended = status != 206
+                || data.len() < CLOUD_RANGE_BYTES
+                || total.is_some_and(|total| offset >= total);
+            if ended {
+                return Ok(());
+            }
+        }
+    }
+
+    // Pass full batches from `pending` to the consumer, awaiting each; with
+    // `flush`, a final partial batch too
+    async fn feed_consumer(
+        &self,
+        pending: &mut Vec<serde_json::Value>,
+        high_water_rows: usize,
+        consumer: &js_sys::Function,
+        emitted: &mut usize,
+        flush: bool,
+    ) -> Result<(), JsValue> {
+        while pending.len() >= high_water_rows || (flush && !pending.is_empty()) {
+            let batch: Vec<_> = pending
+                .drain(..high_water_rows.min(pending.len()))
+                .collect();
+            let batch_js = serde_wasm_bindgen::to_value(&batch)?;
+            let index = JsValue::from_f64(*emitted as f64);
+            *emitted += batch.len();
+            drop(batch);
+
+            let returned = consumer.call2(&JsValue::NULL, &batch_js, &index)?;
+            wasm_bindgen_futures::JsFuture::from(js_sys::Promise::resolve(&returned)).await?;
+        }
+        Ok(())
+    }
+
+    // Annotate and transform a batch of parsed NDJSON rows, then hand them
+    // to the sink
+    fn drain_to_sink(
+        &self,
+        rows: Vec<serde_json::Value>,
+        sink: &js_sys::Function,
+        emitted: &mut usize,
+        warnings: &mut Vec<String>,
+        seen: &mut SeenColumns,
+    ) -> Result<(), JsValue> {
+        let rows = self.transform_parsed(rows, warnings, *emitted, seen)?;
+        self.emit_rows(rows, sink, emitted)
+    }
+
+    // Annotate and transform one slice of parsed rows, adding its columns to
+    // `seen` for `check_seen_columns`
+    pub(crate) fn transform_parsed(
+        &self,
+        mut rows: Vec<serde_json::Value>,
+        warnings: &mut Vec<String>,
+        first_row: usize,
+        seen: &mut SeenColumns,
+    ) -> Result<Vec<serde_json::Value>, JsValue> {
+        if rows.is_empty() {
+            return Ok(rows);
+        }
+        if self.annotate_rows {
+            rows.iter_mut()
+                .for_each(|row| annotate_row(row, &self.clock));
+        }
+        self.apply_transforms(&mut rows, warnings, None, first_row, Some(seen))?;
+        Ok(rows)
+    }
+
+    fn emit_rows(
+        &self,
+        rows: Vec<serde_json::Value>,
+        sink: &js_sys::Function,
+        emitted: &mut usize,
+    ) -> Result<(), JsValue> {
+        for row in rows {
+            sink.call2(
+                &JsValue::NULL,
+                &JsValue::from_str(&row.to_string()),
+                &JsValue::from_f64(*emitted as f64),
+            )?;
+            *emitted += 1;
+        }
+        Ok(())
+    }
+
+    fn process_bytes_unguarded(&mut self, data: &[u8]) -> Result<QueryResult, JsValue> {
+        let start_time = self.clock.now();
+
+        if let Some(trace_id) = &self.trace_id {
+            log(&format!(
+                "[trace {trace_id}] Processing {} bytes",
+                data.len()
+            ));
+        }
+
+        // Validate input data
+        if data.is_empty() {
+            return Err(JsValue::from_str("Input data cannot be empty"));
+        }
+
+        let data = &*self.decode_input(data)?;
+        if data.len() > MAX_INPUT_BYTES {
+            return Err(JsValue::from_str("Input data exceeds maximum size limit"));
+        }
+        self.input_diagnostics = Some(LineEndingCounts::scan(data));
+
+        // Allocate buffer for processing
+        let buffer_id = self.memory_manager.allocate_buffer(data.len());
+
+        // Simulate data processing - in real implementation this would contain
+        // optimized algorithms for data transformation and analysis
+        let deadline = self.deadline_ms.map(|ms| start_time + ms);
+        let parse_start = self.clock.now();
+        let parsed = self.process_internal(data, deadline);
+        let transform_start = self.clock.now();
+
+        // Clean up buffer, before a parse error is returned too
+        self.memory_manager.deallocate_buffer(buffer_id);
+        let (mut processed_data, parsed_fully) = parsed?;
+
+        let mut warnings = Vec::new();
+        let stage_counts = if parsed_fully {
+            self.apply_transforms(&mut processed_data, &mut warnings, deadline, 0, None)?
+        } else {
+            None
+        };
+        let Some(stage_counts) = stage_counts else {
+            return Err(self.deadline_exceeded(processed_data, warnings, start_time, data.len()));
+        };
+        let transform_ms = self.clock.now() - transform_start;
+        self.check_not_empty(processed_data.len())?;
+        self.last_dataset = processed_data.clone();
+
+        let mut result = self.build_result(processed_data, start_time, data.len());
+        result.warnings = warnings;
+        result.stage_counts = stage_counts;
+        result.parse_ms = transform_start - parse_start;
+        result.transform_ms = transform_ms;
+        Ok(result)
+    }
+
+    fn decode_input<'a>(&self, data: &'a [u8]) -> Result<Cow<'a, [u8]>, JsValue> {
+        Ok(compression::decode_input(data, self.input_compression)?)
+    }
+
+    fn check_buffer_complete(&self, buffer_id: u32) -> Result<(), JsValue> {
+        if self.memory_manager.is_buffer_complete(buffer_id)
+            || !self.memory_manager.is_valid_buffer(buffer_id)
+        {
+            return Ok(());
+        }
+        Err(js_error(
+            "incomplete_buffer",
+            400,
+            &format!(
+                "Buffer {buffer_id} has {} of {} expected bytes",
+                self.memory_manager.get_buffer_len(buffer_id),
+                self.memory_manager
+                    .get_expected_len(buffer_id)
+                    .unwrap_or_default()
+            ),
+        ))
+    }
+
+    fn check_output_keys(&self, rows: &[serde_json::Value]) -> Result<(), JsValue> {
+        let (Some(field), true) = (&self.output_key, self.output_key_unique) else {
+            return Ok(());
+        };
+
+        let mut seen = std::collections::HashSet::new();
+        for (index, row) in rows.iter().enumerate() {
+            if let Some(key) = export::output_key(row, field) {
+                if !seen.insert(key.clone()) {
+                    return Err(js_error(
+                        "duplicate_output_key",
+                        400,
+                        &format!("Row {index}: duplicate value '{key}' for output key '{field}'"),
+                    ));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // Run the configured row transforms over freshly parsed rows, returning
+    // the row count after each stage that can change it (`parsed` first,
+    // `output` last), or None if `deadline` passed between stages.
+    // `first_row` is the output position of `rows[0]`, for ordinal row ids.
+    // With `seen`, `rows` is one slice of the input: its columns are added
+    // to `seen` and the unknown-column checks are left to `check_seen_columns`.
+    fn apply_transforms(
+        &self,
+        rows: &mut [serde_json::Value],
+        warnings: &mut Vec<String>,
+        deadline: Option<f64>,
+        first_row: usize,
+        mut seen: Option<&mut SeenColumns>,
+    ) -> Result<Option<Vec<(String, usize)>>, JsValue> {
+        let mut stage_counts = vec![("parsed".to_string(), rows.len())];
+
+        warnings.extend(self.apply_unflatten(rows));
+        match seen.as_deref_mut() {
+            Some(seen) => {
+                SeenColumns::extend(&mut seen.input, rows);
+                self.check_column_limit("Input", seen.input.len())?;
+            }
+            None => self.check_column_limit("Input", export::count_columns(rows))?,
+        }
+        warnings.extend(self.apply_json_columns(rows));
+        self.apply_enrichments(rows, warnings);
+        if self.past_deadline(deadline) {
+            return Ok(None);
+        }
+        match seen {
+            Some(seen) => SeenColumns::extend(&mut seen.known, rows),
+            None if !rows.is_empty() => {
+                self.check_known_columns(&export::collect_columns(rows), warnings)?;
+            }
+            None => {}
+        }
+        self.apply_schema(rows)?;
+        if self.past_deadline(deadline) {
+            return Ok(None);
+        }
+        warnings.extend(self.apply_computed_columns(rows)?);
+        self.apply_mapping(rows)?;
+        if self.past_deadline(deadline) {
+            return Ok(None);
+        }
+        self.apply_binary_encoding(rows);
+        if let Some((field, mode)) = &self.row_id {
+            let skip = self.annotate_rows.then_some("timestamp");
+            transforms::add_row_ids(rows, field, *mode, first_row, skip);
+        }
+        self.check_output_keys(rows)?;
+
+        let mut output = rows.len();
+        if let Some(field) = &self.output_key {
+            output = export::keyed_entry_count(rows, field);
+            stage_counts.push(("after_output_key".to_string(), output));
+        }
+        stage_counts.push(("output".to_string(), output));
+        Ok(Some(stage_counts))
+    }
+
+    // The key an operation was given, or else the index column
+    fn key_or_index_column(&self, operation: &str, key: Option<String>) -> Result<String, JsValue> {
+        key.or_else(|| self.index_column.clone()).ok_or_else(|| {
+            js_error(
+                "missing_key",
+                400,
+                &format!("{operation} needs a key column or set_index_column"),
+            )
+        })
+    }
+
+    fn check_not_empty(&self, output_rows: usize) -> Result<(), JsValue> {
+        if output_rows > 0 || !self.error_on_empty {
+            return Ok(());
+        }
+        Err(js_error(
+            "empty_result",
+            422,
+            "Processing produced no output rows (see set_error_on_empty)",
+        ))
+    }
+
+    fn check_lookup_table(&self, name: &str) -> Result<(), JsValue> {
+        if self.lookup_tables.contains_key(name) {
+            return Ok(());
+        }
+        Err(js_error(
+            "unknown_lookup_table",
+            404,
+            &format!("No lookup table named '{name}' (see set_lookup_table)"),
+        ))
+    }
+
+    // The checks `apply_transforms` makes against the dataset's `known`
+    // columns: lookup fields, declared schema columns and the output key
+    fn check_known_columns(
+        &self,
+        known: &[String],
+        warnings: &mut Vec<String>,
+    ) -> Result<(), JsValue> {
+        for enrichment in &self.enrichments {
+            if self.lookup_tables.contains_key(&enrichment.table) {
+                let context = format!("Lookup '{}'", enrichment.table);
+                self.check_column(&context, &enrichment.on_field, known, warnings)?;
+            }
+        }
+        for (column, _) in &self.schema {
+            self.check_column("Schema", column, known, warnings)?;
+        }
+        if let Some(field) = &self.output_key {
+            // With a mapping the key names one of its output columns
+            let outputs: Vec<String>;
+            let known = if self.mapping.is_empty() {
+                known
+            } else {
+                outputs = self.mapping.iter().map(|(_, to, _)| to.clone()).collect();
+                &outputs
+            };
+            // The row id is added after this check
+            if self.row_id.as_ref().is_none_or(|(id, _)| id != field) {
+                self.check_column("Output key", field, known, warnings)?;
+            }
+        }
+        Ok(())
+    }
+
+    // Once an incremental run has transformed its last slice, check the
+    // columns all of its slices had
+    pub(crate) fn check_seen_columns(
+        &self,
+        seen: &SeenColumns,
+        warnings: &mut Vec<String>,
+    ) -> Result<(), JsValue> {
+        if seen.known.is_empty() {
+            return Ok(());
+        }
+        self.check_known_columns(&seen.known, warnings)
+    }
+
+    fn apply_enrichments(&self, rows: &mut [serde_json::Value], warnings: &mut Vec<String>) {
+        if rows.is_empty() {
+            return;
+        }
+        for enrichment in &self.enrichments {
+            let Some(table) = self.lookup_tables.get(&enrichment.table) else {
+                continue;
+            };
+            let mut unmatched = 0usize;
+            for row in rows.iter_mut() {
+                let on = &enrichment.on_field;
+                if !table.enrich(row, on, &enrichment.prefix, enrichment.null_fill) {
+                    unmatched += 1;
+                }
+            }
+            if unmatched > 0 {
+                warnings.push(format!(
+                    "Lookup '{}': {unmatched} rows had no match on '{}'",
+                    enrichment.table, enrichment.on_field
+                ));
+            }
+        }
+    }
+
+    fn check_column_limit(&self, context: &str, count: usize) -> Result<(), JsValue> {
+        if count <= self.max_columns {
+            return Ok(());
+        }
+        Err(js_error(
+            "column_limit_exceeded",
+            400,
+            &format!(
+                "{context} has {count} columns, over the limit of {} (see set_max_columns)",
+                self.max_columns
+            ),
+        ))
+    }
+
+    fn past_deadline(&self, deadline: Option<f64>) -> bool {
+        deadline.is_some_and(|deadline| self.clock.now() > deadline)
+    }
+
+    // Keep what was processed before the deadline passed for
+    // `take_partial_result`, and build the error reporting it.
+    fn deadline_exceeded(
+        &mut self,
+        rows: Vec<serde_json::Value>,
+        warnings: Vec<String>,
+        start_time: f64,
+        bytes: usize,
+    ) -> JsValue {
+        let row_count = rows.len();
+        let mut partial = self.build_result(rows, start_time, bytes);
+        partial.warnings = warnings;
+        self.partial_result = Some(partial);
+        js_error(
+            "deadline_exceeded",
+            408,
+            &format!(
+                "Processing exceeded the {} ms deadline after {row_count} rows; the partial result is available from take_partial_result",
+                self.deadline_ms.unwrap_or_default()
+            ),
+        )
+    }
+
+    // `unknown_column` error, or warning in lenient mode, when `column` isn't
+    // among the dataset's `known` columns.
+    fn check_column(
+        &self,
+        context: &str,
+        column: &str,
+        known: &[String],
+        warnings: &mut Vec<String>,
+    ) -> Result<(), JsValue> {
+        if known.iter().any(|k| k == column) {
+            return Ok(());
+        }
+        let message = format!(
+            "{context} references unknown column '{column}' (available: {})",
+            known.join(", ")
+        );
+        if self.lenient_columns {
+            warnings.push(message);
+            return Ok(());
+        }
+        Err(js_error("unknown_column", 400, &message))
+    }
+
+    // Coerce declared columns in place. Runs before computed columns so
+    // expressions see the declared types.
+    fn apply_schema(&self, rows: &mut [serde_json::Value]) -> Result<(), JsValue> {
+        if self.schema.is_empty() {
+            return Ok(());
+        }
+
+        for (index, row) in rows.iter_mut().enumerate() {
+            let Some(obj) = row.as_object_mut() else {
+                continue;
+            };
+            for (column, declared) in &self.schema {
+                let Some(value) = obj.get_mut(column) else {
+                    continue;
+                };
+                *value = self.coerce_declared(*declared, value, index, column)?;
+            }
+        }
+        Ok(())
+    }
+
+    // `value` as `declared`, or null (or a `schema_violation` error, per
+    // `set_schema_on_fail`) when it doesn't convert
+    fn coerce_declared(
+        &self,
+        declared: transforms::DeclaredType,
+        value: &serde_json::Value,
+        index: usize,
+        column: &str,
+    ) -> Result<serde_json::Value, JsValue> {
+        match declared.coerce(value) {
+            Some(coerced) => Ok(match (declared, coerced.as_i64()) {
+                (transforms::DeclaredType::Timestamp, Some(epoch_ms)) => {
+                    self.clock.format.render(epoch_ms)
+                }
+                _ => coerced,
+            }),
+            None if self.schema_errors => Err(js_error(
+                "schema_violation",
+                400,
+                &format!(
+                    "Row {index}: column '{column}' value {value} is not a valid {}",
+                    declared.name()
+                ),
+            )),
+            None => Ok(serde_json::Value::Null),
+        }
+    }
+
+    fn apply_json_columns(&self, rows: &mut [serde_json::Value]) -> Vec<String> {
+        if self.json_columns.is_empty() {
+            return Vec::new();
+        }
+        let options = JsonParseOptions {
+            reject_duplicate_keys: self.json_options.reject_duplicate_keys,
+            max_depth: self.json_options.max_depth,
+            ..JsonParseOptions::default()
+        };
+        let failures = transforms::parse_json_fields(rows, &self.json_columns, &options);
+        self.json_columns
+            .iter()
+            .zip(failures)
+            .filter(|(_, failed)| *failed > 0)
+            .map(|(field, failed)| {
+                format!("Column '{field}': {failed} value(s) were not valid JSON and were left as strings")
+            })
+            .collect()
+    }
+
+    // Rebuild each row from the `set_mapping` columns
+    fn apply_mapping(&self, rows: &mut [serde_json::Value]) -> Result<(), JsValue> {
+        if self.mapping.is_empty() {
+            return Ok(());
+        }
+
+        for (index, row) in rows.iter_mut().enumerate() {
+            let mut mapped = serde_json::Map::new();
+            for (from, to, declared) in &self.mapping {
+                let value = transforms::value_at_path(row, from)
+                    .cloned()
+                    .unwrap_or(serde_json::Value::Null);
+                let value = match declared {
+                    Some(declared) => self.coerce_declared(*declared, &value, index, to)?,
+                    None => value,
+                };
+                mapped.insert(to.clone(), value);
+            }
+            let annotations: &[&str] = if self.annotate_rows {
+                &["processed", "timestamp"]
+            } else {
+                &[]
+            };
+            let kept = annotations
+                .iter()
+                .copied()
+                .chain(self.index_column.as_deref());
+            for key in kept {
+                if let (Some(value), false) = (row.get(key), mapped.contains_key(key)) {
+                    mapped.insert(key.to_string(), value.clone());
+                }
+            }
+            *row = serde_json::Value::Object(mapped);
+        }
+        Ok(())
+    }
+
+    fn apply_binary_encoding(&self, rows: &mut [serde_json::Value]) {
+        if !self.binary_base64 {
+            return;
+        }
+        let mapped = self
+            .mapping
+            .iter()
+            .filter_map(|(_, to, declared)| Some((to, (*declared)?)));
+        let declared: Vec<&str> = self
+            .schema
+            .iter()
+            .map(|(column, declared)| (column, *declared))
+            .chain(mapped)
+            .filter(|(_, declared)| *declared == transforms::DeclaredType::Binary)
+            .map(|(column, _)| column.as_str())
+            .collect();
+        for row in rows.iter_mut() {
+            transforms::encode_binary(row, &declared);
+        }
+    }
+
+    // Expand dotted keys when unflattening is on, with one warning per
+    // conflicting path.
+    fn apply_unflatten(&self, rows: &mut [serde_json::Value]) -> Vec<String> {
+        let Some(separator) = &self.unflatten_separator else {
+            return Vec::new();
+        };
+
+        let mut counts: std::collections::BTreeMap<String, usize> = Default::default();
+        let mut conflicts = Vec::new();
+        for row in rows.iter_mut() {
+            transforms::unflatten(row, separator, &mut conflicts);
+            for path in conflicts.drain(..) {
+                *counts.entry(path).or_default() += 1;
+            }
+        }
+        counts
+            .into_iter()
+            .map(|(path, rows)| {
+                format!("Unflatten: '{path}' is both a value and a prefix in {rows} rows; kept the nested object")
+            })
+            .collect()
+    }
+
+    // Evaluate computed columns into each row, returning any warnings. Field
+    // references are checked once against the dataset's columns up front.
+    fn apply_computed_columns(
+        &self,
+        rows: &mut [serde_json::Value],
+    ) -> Result<Vec<String>, JsValue> {
+        if self.computed_columns.is_empty() || rows.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        let mut warnings = Vec::new();
+        let mut known = export::collect_columns(rows);
+        for (name, _, expr) in &self.computed_columns {
+            let context = format!("Computed column '{name}'");
+            for field in expr.fields() {
+                self.check_column(&context, field, &known, &mut warnings)?;
+            }
+            known.push(name.clone());
+        }
+
+        for (name, _, expr) in &self.computed_columns {
+            let mut divisions_by_zero = 0usize;
+            for row in rows.iter_mut() {
+                let mut divided_by_zero = false;
+                let value = expr
+                    .eval(row, &mut divided_by_zero)
+                    .and_then(serde_json::Number::from_f64)
+                    .map_or(serde_json::Value::Null, serde_json::Value::Number);
+                if divided_by_zero {
+                    divisions_by_zero += 1;
+                }
+                if let Some(obj) = row.as_object_mut() {
+                    obj.insert(name.clone(), value);
+                }
+            }
+            if divisions_by_zero > 0 {
+                warnings.push(format!(
+                    "Computed column '{name}': division by zero in {divisions_by_zero} rows; set to null"
+                ));
+            }
+        }
+        Ok(warnings)
+    }
+
+    fn build_result(
+        &self,
+        data: Vec<serde_json::Value>,
+        start_time: f64,
+        memory_used_bytes: usize,
+    ) -> QueryResult {
+        let unsafe_integer_count = if self.bigint_passthrough {
+            data.iter().map(export::count_unsafe_integers).sum()
+        } else {
+            0
+        };
+
+        let elapsed_ms = self.clock.now() - start_time;
+
+        QueryResult {
+            row_count: data.len() as u32,
+            bytes_per_sec: per_second(memory_used_bytes, elapsed_ms),
+            rows_per_sec: per_second(data.len(), elapsed_ms),
+            data,
+            execution_time_ms: elapsed_ms as u32,
+            memory_used_bytes: memory_used_bytes as u32,
+            float_precision: self.float_precision,
+            trace_id: self.trace_id.clone(),
+            bigint_passthrough: self.bigint_passthrough,
+            unsafe_integer_count,
+            warnings: Vec::new(),
+            input_buffer_id: None,
+            output_key: self.output_key.clone(),
+            parse_ms: 0.0,
+            transform_ms: 0.0,
+            total_ms: elapsed_ms,
+            stage_counts: Vec::new(),
+            null_representation: self.null_representation.clone(),
+            annotated: self.annotate_rows,
+            index_first: self
+                .index_column
+                .clone()
+                .filter(|_| self.index_first && self.output_key.is_none()),
+        }
+    }
+}
+
+// Hand full batches of `every_rows` from `rows[*emitted..]` to `callback`,
+// yielding to the event loop after each; with `flush`, a final partial
+// batch too
+async fn emit_partial(
+    rows: &[serde_json::Value],
+    every_rows: usize,
+    callback: &js_sys::Function,
+    emitted: &mut usize,
+    flush: bool,
+) -> Result<(), JsValue> {
+    while rows.len() - *emitted >= every_rows || (flush && *emitted < rows.len()) {
+        let end = rows.len().min(*emitted + every_rows);
+        let batch = serde_wasm_bindgen::to_value(&rows[*emitted..end])?;
+        let first_row = JsValue::from_f64(*emitted as f64);
+        *emitted = end;
+        callback.call2(&JsValue::NULL, &batch, &first_row)?;
+        yield_to_event_loop().await?;
+    }
+    Ok(())
+}
+
+// Hand full batches from `pending` to `on_batch`; with `flush`, a final
+// partial batch too
+fn emit_batches(
+    pending: &mut Vec<serde_json::Value>,
+    chunk_rows: usize,
+    on_batch: &js_sys::Function,
+    emitted: &mut usize,
+    flush: bool,
+) -> Result<(), JsValue> {
+    while pending.len() >= chunk_rows || (flush && !pending.is_empty()) {
+        let batch: Vec<_> = pending.drain(..chunk_rows.min(pending.len())).collect();
+        let batch_js = serde_wasm_bindgen::to_value(&batch)?;
+        *emitted += batch.len();
+        drop(batch);
+        on_batch.call1(&JsValue::NULL, &batch_js)?;
+    }
+    Ok(())
+}
+
+// Date.now() has millisecond resolution, so small inputs often measure 0ms;
+// durations are floored at 1ms to keep the rate finite.
+fn per_second(amount: usize, elapsed_ms: f64) -> f64 {
+    amount as f64 * 1000.0 / elapsed_ms.max(1.0)
+}
+
+// File name and contents of an archive member, or why it wasn't read
+pub(crate) type ZipMember = (String, Result<Vec<u8>, String>);
+
+// Archive member `index`, or `None` for a directory. Members over
+// `MAX_INPUT_BYTES` uncompressed are not read.
+pub(crate) fn read_zip_member(
+    archive: &mut zip::ZipArchive<std::io::Cursor<&[u8]>>,
+    index: usize,
+) -> Result<Option<ZipMember>, zip::result::ZipError> {
+    use std::io::Read;
+
+    let file = archive.by_index(index)?;
+    if file.is_dir() {
+        return Ok(None);
+    }
+    let name = file.name().to_string();
+    if file.size() > MAX_INPUT_BYTES as u64 {
+        return Ok(Some((name, Err("exceeds maximum size limit".to_string()))));
+    }
+    let mut contents = Vec::with_capacity(file.size() as usize);
+    file.take(MAX_INPUT_BYTES as u64 + 1)
+        .read_to_end(&mut contents)?;
+    if contents.len() > MAX_INPUT_BYTES {
+        return Ok(Some((name, Err("exceeds maximum size limit".to_string()))));
+    }
+    Ok(Some((name, Ok(contents))))
+}
+
+// Source of the times the engine records. In deterministic mode there are no
+// wall-clock reads: elapsed time is always 0 and row timestamps come from a
+// counter shared by clones of the clock.
+#[derive(Clone, Default)]
+struct Clock {
+    deterministic: bool,
+    counter: Rc<Cell<i64>>,
+    format: TimestampFormat,
+}
+
+impl Clock {
+    fn now(&self) -> f64 {
+        if self.deterministic {
+            0.0
+        } else {
+            js_sys::Date::now()
+        }
+    }
+
+    fn timestamp(&self) -> serde_json::Value {
+        let epoch_ms = if self.deterministic {
+            let next = self.counter.get();
+            self.counter.set(next + 1);
+            next
+        } else {
+            js_sys::Date::now() as i64
+        };
+        self.format.render(epoch_ms)
+    }
+}
 
-        serde_wasm_bindgen::to_value(&stats).unwrap()
+fn annotate_row(item: &mut serde_json::Value, clock: &Clock) {
+    if let Some(obj) = item.as_object_mut() {
+        obj.insert("processed".to_string(), serde_json::Value::Bool(true));
+        obj.insert("timestamp".to_string(), clock.timestamp());
     }
 }
@@ -0,0 +1,168 @@
+use crate::arrow_ipc::{infer_column_type, ColumnType};
+use crate::export::{collect_columns, row_cell};
+use crate::line_endings::{line_start, LineRanges};
+use serde_json::{json, Value};
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::ops::Range;
+
+// Schema inference over a sample of input rows, and a small LRU of recent
+// results so profiling the same input repeatedly doesn't re-parse it.
+
+/// Rows `infer_schema` samples unless told otherwise.
+pub(crate) const SCHEMA_SAMPLE_ROWS: usize = 100;
+/// Leading input bytes hashed into the cache key.
+const CACHE_KEY_PREFIX_BYTES: usize = 64 * 1024;
+const CACHE_CAPACITY: usize = 16;
+
+/// Which rows of the input `infer_schema` reads.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum Sampling {
+    /// The first rows.
+    Head,
+    /// Rows at pseudo-random positions, seeded from the input so repeated
+    /// calls see the same sample.
+    Random,
+    /// One row from each of equal-sized slices of the input.
+    Stratified,
+}
+
+impl Sampling {
+    pub(crate) fn parse(name: &str) -> Option<Sampling> {
+        match name {
+            "head" => Some(Sampling::Head),
+            "random" => Some(Sampling::Random),
+            "stratified" => Some(Sampling::Stratified),
+            _ => None,
+        }
+    }
+
+    // Positions in `0..len` to sample, sorted and deduplicated
+    fn positions(&self, len: usize, size: usize, seed: u64) -> Vec<usize> {
+        if len == 0 {
+            return Vec::new();
+        }
+        let mut positions: Vec<usize> = match self {
+            Sampling::Head => (0..len.min(size)).collect(),
+            // The middle of each slice
+            Sampling::Stratified => {
+                let slices = size.min(len) as u64;
+                (0..slices)
+                    .map(|i| ((2 * i + 1) * len as u64 / (2 * slices)) as usize)
+                    .collect()
+            }
+            Sampling::Random => {
+                let mut state = seed | 1;
+                (0..size.min(len))
+                    .map(|_| {
+                        state ^= state << 13;
+                        state ^= state >> 7;
+                        state ^= state << 17;
+                        (state % len as u64) as usize
+                    })
+                    .collect()
+            }
+        };
+        positions.sort_unstable();
+        positions.dedup();
+        positions
+    }
+
+    /// Up to `size` of `rows`, chosen by this sampling.
+    pub(crate) fn select_rows(&self, rows: Vec<Value>, size: usize, seed: u64) -> Vec<Value> {
+        let positions = self.positions(rows.len(), size, seed);
+        let mut positions = positions.into_iter().peekable();
+        rows.into_iter()
+            .enumerate()
+            .filter(|(i, _)| positions.next_if_eq(i).is_some())
+            .map(|(_, row)| row)
+            .collect()
+    }
+
+    /// Byte ranges of up to `size` non-blank NDJSON lines. Random and
+    /// stratified sampling pick byte offsets and take the line holding each,
+    /// so nothing before them needs parsing.
+    pub(crate) fn select_lines(&self, data: &[u8], size: usize, seed: u64) -> Vec<Range<usize>> {
+        let is_blank =
+            |range: &Range<usize>| data[range.clone()].iter().all(u8::is_ascii_whitespace);
+        if *self == Sampling::Head {
+            return LineRanges::new(data, 0)
+                .filter(|range| !is_blank(range))
+                .take(size)
+                .collect();
+        }
+
+        let mut lines: Vec<Range<usize>> = self
+            .positions(data.len(), size, seed)
+            .into_iter()
+            .map(|offset| {
+                let start = line_start(data, offset);
+                LineRanges::new(data, start).next().unwrap_or(start..start)
+            })
+            .filter(|range| !is_blank(range))
+            .collect();
+        lines.dedup();
+        lines
+    }
+}
+
+/// `{columns: [{name, type, nullable}], sampled_rows}`. Types use the names
+/// `set_schema` accepts (`integer`, `number`, `string`, `boolean`), or `null`
+/// when a column only ever held nulls in the sample.
+pub(crate) fn infer_schema(rows: &[Value]) -> Value {
+    let columns: Vec<Value> = collect_columns(rows)
+        .into_iter()
+        .map(|name| {
+            let nullable = rows
+                .iter()
+                .any(|row| row_cell(row, &name).is_none_or(Value::is_null));
+            let column_type = match infer_column_type(rows, &name) {
+                ColumnType::Null => "null",
+                ColumnType::Bool => "boolean",
+                ColumnType::Int64 => "integer",
+                ColumnType::Float64 => "number",
+                ColumnType::Utf8 => "string",
+            };
+            json!({"name": name, "type": column_type, "nullable": nullable})
+        })
+        .collect();
+
+    json!({"columns": columns, "sampled_rows": rows.len()})
+}
+
+/// Cache key for `data` parsed under `settings` (anything that changes how
+/// the input is read, e.g. the input format).
+pub(crate) fn cache_key(data: &[u8], settings: &str) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    settings.hash(&mut hasher);
+    data[..data.len().min(CACHE_KEY_PREFIX_BYTES)].hash(&mut hasher);
+    hasher.finish()
+}
+
+// Most recently used entry last
+#[derive(Default)]
+pub(crate) struct SchemaCache {
+    entries: Vec<(u64, Value)>,
+}
+
+impl SchemaCache {
+    pub(crate) fn get(&mut self, key: u64) -> Option<Value> {
+        let index = self.entries.iter().position(|(k, _)| *k == key)?;
+        let entry = self.entries.remove(index);
+        let schema = entry.1.clone();
+        self.entries.push(entry);
+        Some(schema)
+    }
+
+    pub(crate) fn insert(&mut self, key: u64, schema: Value) {
+        self.entries.retain(|(k, _)| *k != key);
+        if self.entries.len() >= CACHE_CAPACITY {
+            self.entries.remove(0);
+        }
+        self.entries.push((key, schema));
+    }
+
+    pub(crate) fn clear(&mut self) {
+        self.entries.clear();
+    }
+}
@@ -0,0 +1,117 @@
+use crate::csv_parser::{parse_csv, CsvOptions};
+use crate::export;
+use crate::expression::parse_expression;
+use crate::json_parser::{parse_json, JsonParseOptions};
+use crate::memory_manager::MemoryManager;
+use crate::transforms::SortKey;
+use serde_json::{json, Value};
+
+// Canned end-to-end checks behind `self_test`: a small dataset is parsed,
+// filtered, sorted and serialized with the same code the engine uses, so a
+// failure points at the build rather than at the caller's data.
+
+const SAMPLE_JSON: &str = r#"[
+    {"name": "apple", "price": 1.5, "quantity": 10},
+    {"name": "banana", "price": 0.25, "quantity": 12},
+    {"name": "cherry", "price": 4, "quantity": 3},
+    {"name": "date", "price": 8, "quantity": 1}
+]"#;
+
+const SAMPLE_CSV: &str = "name,price,quantity\n\
+    apple,1.5,10\n\
+    banana,0.25,12\n\
+    cherry,4,3\n\
+    date,8,1\n";
+
+type Check = Result<String, String>;
+
+/// `{ok, checks: [{name, passed, detail}]}`; `ok` when every check passed.
+pub(crate) fn run() -> Value {
+    let mut checks = Vec::new();
+    let mut record = |name: &str, outcome: Check| {
+        let (passed, detail) = match outcome {
+            Ok(detail) => (true, detail),
+            Err(detail) => (false, detail),
+        };
+        checks.push(json!({"name": name, "passed": passed, "detail": detail}));
+        passed
+    };
+
+    let rows = check_parse_json();
+    let mut ok = record("parse_json", summarize(&rows));
+    ok &= record("parse_csv", check_parse_csv(rows.as_deref()));
+    let filtered = rows.and_then(check_filter);
+    ok &= record("filter", summarize(&filtered));
+    let sorted = filtered.and_then(check_sort);
+    ok &= record("sort", summarize(&sorted));
+    ok &= record("serialize", sorted.and_then(|rows| check_serialize(&rows)));
+    ok &= record("memory", check_memory());
+
+    json!({"ok": ok, "checks": checks})
+}
+
+fn summarize(rows: &Result<Vec<Value>, String>) -> Check {
+    rows.as_deref().map(describe).map_err(Clone::clone)
+}
+
+fn describe(rows: &[Value]) -> String {
+    format!("{} rows", rows.len())
+}
+
+fn check_parse_json() -> Result<Vec<Value>, String> {
+    match parse_json(SAMPLE_JSON.as_bytes(), &JsonParseOptions::default()) {
+        Ok(Value::Array(rows)) if rows.len() == 4 => Ok(rows),
+        Ok(other) => Err(format!("Unexpected parse result: {other}")),
+        Err(e) => Err(e.message),
+    }
+}
+
+fn check_parse_csv(expected: Result<&[Value], &String>) -> Check {
+    let rows = parse_csv(SAMPLE_CSV, &CsvOptions::default()).map_err(|e| e.message)?;
+    match expected {
+        Ok(expected) if rows == expected => Ok(describe(&rows)),
+        Ok(_) => Err("CSV rows differ from the JSON rows".to_string()),
+        Err(_) => Err("Skipped: JSON parsing failed".to_string()),
+    }
+}
+
+// Keep rows whose `price * quantity` is over 10
+fn check_filter(mut rows: Vec<Value>) -> Result<Vec<Value>, String> {
+    let expr = parse_expression("price * quantity")?;
+    rows.retain(|row| expr.eval(row, &mut false).is_some_and(|total| total > 10.0));
+    let names: Vec<&str> = rows.iter().filter_map(|row| row["name"].as_str()).collect();
+    if names != ["apple", "cherry"] {
+        return Err(format!("Expected apple and cherry, got {names:?}"));
+    }
+    Ok(rows)
+}
+
+fn check_sort(mut rows: Vec<Value>) -> Result<Vec<Value>, String> {
+    rows.sort_by(|a, b| SortKey::of(a, "price").cmp_directed(&SortKey::of(b, "price"), true));
+    let names: Vec<&str> = rows.iter().filter_map(|row| row["name"].as_str()).collect();
+    if names != ["cherry", "apple"] {
+        return Err(format!("Expected cherry, apple, got {names:?}"));
+    }
+    Ok(rows)
+}
+
+fn check_serialize(rows: &[Value]) -> Check {
+    let text = export::rows_to_json(rows, None);
+    let reparsed: Vec<Value> = serde_json::from_str(&text).map_err(|e| e.to_string())?;
+    if reparsed != rows {
+        return Err(format!("Round trip changed the rows: {text}"));
+    }
+    Ok(format!("{} bytes", text.len()))
+}
+
+fn check_memory() -> Check {
+    let mut manager = MemoryManager::new();
+    let id = manager.allocate_buffer(1024);
+    if !manager.write_buffer(id, &[7; 1024]) || manager.get_buffer_len(id) != 1024 {
+        return Err("Could not write a 1024-byte buffer".to_string());
+    }
+    if !manager.deallocate_buffer(id) || manager.is_valid_buffer(id) {
+        return Err("Buffer was still valid after being freed".to_string());
+    }
+    Ok("Allocated and freed 1024 bytes".to_string())
+}
@@ -0,0 +1,292 @@
+use crate::csv_parser::{parse_records_at, record_to_row, CsvOptions, CsvRecord};
+use crate::json_parser::{count_json_array, parse_json, validate_json, JsonParseOptions};
+use crate::line_endings::{last_line_break_end, LineRanges};
+use crate::utils::ParseError;
+use serde_json::Value;
+
+// Incremental NDJSON parser. Chunks may split records anywhere; the trailing
+// partial line is kept until the line break that completes it arrives. Lines
+// may end in `\n`, `\r\n` or `\r`.
+//
+// Input whose first non-whitespace byte is `[` is a JSON array, which can't be
+// split on newlines, so it is buffered and parsed in one go by `finish`.
+//
+// With `resync_on_error` set, a line that fails to parse is skipped and
+// parsing resumes after the next newline; the skipped byte range is recorded
+// in `skipped`.
+#[derive(Default)]
+pub(crate) struct NdjsonStreamParser {
+    partial: Vec<u8>,
+    // Stream offset of the first byte of `partial`
+    offset: usize,
+    line_number: usize,
+    json_array: Option<bool>,
+    options: JsonParseOptions,
+    pub(crate) resync_on_error: bool,
+    pub(crate) skipped: Vec<String>,
+}
+
+impl NdjsonStreamParser {
+    pub(crate) fn new(options: JsonParseOptions) -> NdjsonStreamParser {
+        NdjsonStreamParser {
+            options,
+            ..NdjsonStreamParser::default()
+        }
+    }
+
+    /// Feed a chunk and return the records it completed.
+    pub(crate) fn push(&mut self, chunk: &[u8]) -> Result<Vec<Value>, ParseError> {
+        if self.json_array.is_none() {
+            if let Some(first) = chunk.iter().find(|b| !b.is_ascii_whitespace()) {
+                self.json_array = Some(*first == b'[');
+            }
+        }
+
+        self.partial.extend_from_slice(chunk);
+        if self.json_array == Some(true) {
+            return Ok(Vec::new());
+        }
+
+        let Some(complete_len) = last_line_break_end(&self.partial) else {
+            return Ok(Vec::new());
+        };
+
+        let remainder = self.partial.split_off(complete_len);
+        let complete = std::mem::replace(&mut self.partial, remainder);
+
+        let mut rows = Vec::new();
+        for line in LineRanges::new(&complete, 0) {
+            let offset = self.offset + line.start;
+            if let Some(row) = self.parse_line(&complete[line], offset)? {
+                rows.push(row);
+            }
+        }
+        self.offset += complete.len();
+        Ok(rows)
+    }
+
+    /// Bytes buffered waiting for the rest of their line (or, for a JSON
+    /// array, the end of input).
+    pub(crate) fn pending_len(&self) -> usize {
+        self.partial.len()
+    }
+
+    /// Parse whatever remains buffered once the input has ended.
+    pub(crate) fn finish(&mut self) -> Result<Vec<Value>, ParseError> {
+        let remaining = std::mem::take(&mut self.partial);
+
+        if self.json_array == Some(true) {
+            return match parse_json(&remaining, &self.options)? {
+                Value::Array(rows) => Ok(rows),
+                _ => Err(ParseError {
+                    kind: "invalid_json",
+                    message: "Expected a JSON array".to_string(),
+                }),
+            };
+        }
+
+        let row = self.parse_line(&remaining, self.offset)?;
+        self.offset += remaining.len();
+        Ok(row.into_iter().collect())
+    }
+
+    fn parse_line(&mut self, line: &[u8], offset: usize) -> Result<Option<Value>, ParseError> {
+        self.line_number += 1;
+
+        let trimmed = line.strip_suffix(b"\r").unwrap_or(line);
+        if trimmed.iter().all(|b| b.is_ascii_whitespace()) {
+            return Ok(None);
+        }
+
+        match parse_json(trimmed, &self.options) {
+            Ok(row) => Ok(Some(row)),
+            Err(e) if self.resync_on_error => {
+                self.skipped.push(format!(
+                    "Line {}: skipped bytes {}..{}: {}",
+                    self.line_number,
+                    offset,
+                    offset + line.len(),
+                    e.message
+                ));
+                Ok(None)
+            }
+            Err(e) => Err(ParseError {
+                kind: e.kind,
+                message: format!("Line {}: {}", self.line_number, e.message),
+            }),
+        }
+    }
+}
+
+// Incremental CSV parser with a header row. A quoted field may hold line
+// breaks, so the end of the last complete record in the buffer is found by
+// scanning with the same quote rules as `parse_records`; the scan resumes
+// where the previous chunk left off. The first record is the header.
+#[derive(Default)]
+pub(crate) struct CsvStreamParser {
+    partial: Vec<u8>,
+    options: CsvOptions,
+    header: Option<Vec<String>>,
+    // Line on which `partial` starts
+    line: usize,
+    // Scan position in `partial` and the quote state there
+    scanned: usize,
+    in_quotes: bool,
+    field_empty: bool,
+}
+
+impl CsvStreamParser {
+    pub(crate) fn new(options: CsvOptions) -> CsvStreamParser {
+        CsvStreamParser {
+            options,
+            line: 1,
+            field_empty: true,
+            ..CsvStreamParser::default()
+        }
+    }
+
+    /// Feed a chunk and return the rows it completed.
+    pub(crate) fn push(&mut self, chunk: &[u8]) -> Result<Vec<Value>, ParseError> {
+        self.partial.extend_from_slice(chunk);
+        let Some(complete_len) = self.scan() else {
+            return Ok(Vec::new());
+        };
+
+        let remainder = self.partial.split_off(complete_len);
+        let complete = std::mem::replace(&mut self.partial, remainder);
+        self.scanned -= complete_len;
+        self.parse(&complete)
+    }
+
+    pub(crate) fn pending_len(&self) -> usize {
+        self.partial.len()
+    }
+
+    /// Parse whatever remains buffered once the input has ended.
+    pub(crate) fn finish(&mut self) -> Result<Vec<Value>, ParseError> {
+        let remaining = std::mem::take(&mut self.partial);
+        (self.scanned, self.in_quotes, self.field_empty) = (0, false, true);
+        self.parse(&remaining)
+    }
+
+    fn parse(&mut self, complete: &[u8]) -> Result<Vec<Value>, ParseError> {
+        let text = std::str::from_utf8(complete).map_err(|_| ParseError {
+            kind: "invalid_csv",
+            message: format!("Line {}: invalid UTF-8 data", self.line),
+        })?;
+        let (records, next_line) = parse_records_at(text, self.line)?;
+        self.line = next_line;
+
+        let mut records = records.into_iter();
+        let columns = match &self.header {
+            Some(columns) => columns,
+            None => match records.next() {
+                Some(CsvRecord { fields, .. }) => self.header.insert(fields),
+                None => return Ok(Vec::new()),
+            },
+        };
+        records
+            .map(|record| record_to_row(record, columns, &self.options))
+            .collect()
+    }
+
+    // Advance the scan to the end of the buffer, or to a byte whose meaning
+    // depends on the next chunk (a `"` in a quoted field, a trailing `\r`),
+    // returning the end of the last record break passed.
+    fn scan(&mut self) -> Option<usize> {
+        let mut complete_len = None;
+        while let Some(&byte) = self.partial.get(self.scanned) {
+            let next = self.partial.get(self.scanned + 1).copied();
+            let mut step = 1;
+            if self.in_quotes {
+                match (byte, next) {
+                    (b'"', None) => break,
+                    (b'"', Some(b'"')) => {
+                        step = 2;
+                        self.field_empty = false;
+                    }
+                    (b'"', Some(_)) => self.in_quotes = false,
+                    _ => self.field_empty = false,
+                }
+            } else {
+                match (byte, next) {
+                    (b'"', _) if self.field_empty => self.in_quotes = true,
+                    (b',', _) => self.field_empty = true,
+                    (b'\r', None) => break,
+                    (b'\r' | b'\n', _) => {
+                        if (byte, next) == (b'\r', Some(b'\n')) {
+                            step = 2;
+                        }
+                        self.field_empty = true;
+                        complete_len = Some(self.scanned + step);
+                    }
+                    _ => self.field_empty = false,
+                }
+            }
+            self.scanned += step;
+        }
+        complete_len
+    }
+}
+
+/// The incremental parser behind `begin_stream`, chosen by input format.
+pub(crate) enum StreamParser {
+    Ndjson(NdjsonStreamParser),
+    Csv(CsvStreamParser),
+}
+
+impl StreamParser {
+    pub(crate) fn push(&mut self, chunk: &[u8]) -> Result<Vec<Value>, ParseError> {
+        match self {
+            StreamParser::Ndjson(parser) => parser.push(chunk),
+            StreamParser::Csv(parser) => parser.push(chunk),
+        }
+    }
+
+    pub(crate) fn finish(&mut self) -> Result<Vec<Value>, ParseError> {
+        match self {
+            StreamParser::Ndjson(parser) => parser.finish(),
+            StreamParser::Csv(parser) => parser.finish(),
+        }
+    }
+
+    pub(crate) fn pending_len(&self) -> usize {
+        match self {
+            StreamParser::Ndjson(parser) => parser.pending_len(),
+            StreamParser::Csv(parser) => parser.pending_len(),
+        }
+    }
+
+    /// Lines skipped by `resync_on_error` (NDJSON only).
+    pub(crate) fn take_skipped(&mut self) -> Vec<String> {
+        match self {
+            StreamParser::Ndjson(parser) => std::mem::take(&mut parser.skipped),
+            StreamParser::Csv(_) => Vec::new(),
+        }
+    }
+}
+
+/// Number of records `NdjsonStreamParser` would produce for `bytes`, checking
+/// each line is valid JSON without keeping any of them.
+pub(crate) fn count_ndjson(bytes: &[u8], options: &JsonParseOptions) -> Result<usize, ParseError> {
+    if bytes.iter().find(|b| !b.is_ascii_whitespace()) == Some(&b'[') {
+        return count_json_array(bytes, options)?.ok_or_else(|| ParseError {
+            kind: "invalid_json",
+            message: "Expected a JSON array".to_string(),
+        });
+    }
+
+    let mut count = 0;
+    for (i, line) in LineRanges::new(bytes, 0).enumerate() {
+        let line = &bytes[line];
+        if line.iter().all(|b| b.is_ascii_whitespace()) {
+            continue;
+        }
+        validate_json(line, options).map_err(|e| ParseError {
+            kind: e.kind,
+            message: format!("Line {}: {}", i + 1, e.message),
+        })?;
+        count += 1;
+    }
+    Ok(count)
+}
@@ -0,0 +1,174 @@
+use serde_json::Value;
+
+// UTC timestamps as epoch milliseconds, rendered as epoch numbers or
+// ISO-8601 text. Dates use the proleptic Gregorian calendar.
+
+const MS_PER_DAY: i64 = 86_400_000;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub(crate) enum TimestampFormat {
+    /// Integer milliseconds since the Unix epoch.
+    #[default]
+    EpochMs,
+    /// Integer seconds since the Unix epoch, rounded down.
+    EpochS,
+    /// `2024-03-01T12:00:00.000Z`, as JS `Date.prototype.toISOString`.
+    Iso8601,
+}
+
+impl TimestampFormat {
+    pub(crate) fn parse(format: &str) -> Option<TimestampFormat> {
+        match format {
+            "epoch_ms" => Some(TimestampFormat::EpochMs),
+            "epoch_s" => Some(TimestampFormat::EpochS),
+            "iso8601" => Some(TimestampFormat::Iso8601),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn name(&self) -> &'static str {
+        match self {
+            TimestampFormat::EpochMs => "epoch_ms",
+            TimestampFormat::EpochS => "epoch_s",
+            TimestampFormat::Iso8601 => "iso8601",
+        }
+    }
+
+    pub(crate) fn render(&self, epoch_ms: i64) -> Value {
+        match self {
+            TimestampFormat::EpochMs => Value::from(epoch_ms),
+            TimestampFormat::EpochS => Value::from(epoch_ms.div_euclid(1000)),
+            TimestampFormat::Iso8601 => Value::String(format_iso8601(epoch_ms)),
+        }
+    }
+}
+
+/// Days since 1970-01-01 for a calendar date.
+pub(crate) fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let y = if month <= 2 { year - 1 } else { year };
+    let era = y.div_euclid(400);
+    let year_of_era = y - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146_097 + day_of_era - 719_468
+}
+
+// (year, month, day) for days since 1970-01-01; inverse of `days_from_civil`
+fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let day_of_era = z - era * 146_097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = year_of_era + era * 400 + (month <= 2) as i64;
+    (year, month, day)
+}
+
+/// `epoch_ms` as `YYYY-MM-DDTHH:MM:SS.sssZ`. Years outside 0-9999 use the
+/// signed six-digit form, as JS does.
+pub(crate) fn format_iso8601(epoch_ms: i64) -> String {
+    let (year, month, day) = civil_from_days(epoch_ms.div_euclid(MS_PER_DAY));
+    let ms_of_day = epoch_ms.rem_euclid(MS_PER_DAY);
+    let (hour, minute) = (ms_of_day / 3_600_000, ms_of_day / 60_000 % 60);
+    let (second, millis) = (ms_of_day / 1000 % 60, ms_of_day % 1000);
+    let year = if (0..=9999).contains(&year) {
+        format!("{year:04}")
+    } else {
+        format!("{year:+07}")
+    };
+    format!("{year}-{month:02}-{day:02}T{hour:02}:{minute:02}:{second:02}.{millis:03}Z")
+}
+
+/// Epoch ms for `YYYY-MM-DD`, optionally followed by `T` (or a space) and
+/// `HH:MM[:SS[.fraction]]` with a `Z` or `±HH:MM` offset. A time without an
+/// offset is taken as UTC. Fractions beyond milliseconds are truncated.
+pub(crate) fn parse_iso8601(text: &str) -> Option<i64> {
+    let text = text.trim();
+    let (date, time) = match text.find(['T', 't', ' ']) {
+        Some(at) => (&text[..at], Some(&text[at + 1..])),
+        None => (text, None),
+    };
+
+    let mut parts = date.split('-');
+    let (year, month, day) = (parts.next()?, parts.next()?, parts.next()?);
+    if parts.next().is_some() || year.len() != 4 || month.len() != 2 || day.len() != 2 {
+        return None;
+    }
+    let (year, month, day) = (digits(year)?, digits(month)?, digits(day)?);
+    if !(1..=12).contains(&month) || day < 1 || day > days_in_month(year, month) {
+        return None;
+    }
+    let mut epoch_ms = days_from_civil(year, month, day) * MS_PER_DAY;
+
+    let Some(time) = time else {
+        return Some(epoch_ms);
+    };
+    let (clock, offset_minutes) = split_offset(time)?;
+    let (hms, fraction) = match clock.split_once('.') {
+        Some((hms, fraction)) => (hms, Some(fraction)),
+        None => (clock, None),
+    };
+    let fields: Vec<&str> = hms.split(':').collect();
+    let (hour, minute, second) = match fields.as_slice() {
+        [hour, minute] => (two_digits(hour)?, two_digits(minute)?, 0),
+        [hour, minute, second] => (two_digits(hour)?, two_digits(minute)?, two_digits(second)?),
+        _ => return None,
+    };
+    if hour > 23 || minute > 59 || second > 59 || (fraction.is_some() && fields.len() != 3) {
+        return None;
+    }
+    let millis = match fraction {
+        Some(fraction) if !fraction.is_empty() => {
+            let padded = format!("{:0<3}", &fraction[..fraction.len().min(3)]);
+            digits(fraction)?;
+            digits(&padded)?
+        }
+        Some(_) => return None,
+        None => 0,
+    };
+
+    epoch_ms += ((hour * 60 + minute - offset_minutes) * 60 + second) * 1000 + millis;
+    Some(epoch_ms)
+}
+
+// The time of day and its offset from UTC in minutes
+fn split_offset(time: &str) -> Option<(&str, i64)> {
+    if let Some(clock) = time.strip_suffix(['Z', 'z']) {
+        return Some((clock, 0));
+    }
+    let Some(at) = time.rfind(['+', '-']) else {
+        return Some((time, 0));
+    };
+    let (clock, offset) = time.split_at(at);
+    let sign = if offset.starts_with('-') { -1 } else { 1 };
+    let (hours, minutes) = offset[1..].split_once(':')?;
+    let (hours, minutes) = (two_digits(hours)?, two_digits(minutes)?);
+    if hours > 23 || minutes > 59 {
+        return None;
+    }
+    Some((clock, sign * (hours * 60 + minutes)))
+}
+
+fn digits(text: &str) -> Option<i64> {
+    if text.is_empty() || !text.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    text.parse().ok()
+}
+
+fn two_digits(text: &str) -> Option<i64> {
+    (text.len() == 2).then(|| digits(text)).flatten()
+}
+
+fn days_in_month(year: i64, month: i64) -> i64 {
+    match month {
+        2 if year % 4 == 0 && (year % 100 != 0 || year % 400 == 0) => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    }
+}
@@ -0,0 +1,894 @@
+use serde_json::{Map, Value};
+use std::cmp::Ordering;
+use std::collections::{HashMap, HashSet};
+
+// Row-level reshaping and aggregation over processed result rows
+
+// Key used to group/compare JSON values; strings are used verbatim so they
+// can double as output column names.
+pub(crate) fn value_key(value: &Value) -> String {
+    match value {
+        Value::String(s) => s.clone(),
+        other => other.to_string(),
+    }
+}
+
+#[derive(Default)]
+struct Accumulator {
+    count: u64,
+    numeric_count: u64,
+    sum: f64,
+    min: Option<f64>,
+    max: Option<f64>,
+}
+
+impl Accumulator {
+    fn push(&mut self, value: Option<&Value>) {
+        self.count += 1;
+        if let Some(n) = value.and_then(|v| v.as_f64()) {
+            self.numeric_count += 1;
+            self.sum += n;
+            self.min = Some(self.min.map_or(n, |m| m.min(n)));
+            self.max = Some(self.max.map_or(n, |m| m.max(n)));
+        }
+    }
+
+    fn finish(&self, agg: &str) -> Value {
+        let number = match agg {
+            "count" => return Value::from(self.count),
+            "sum" => Some(self.sum),
+            "mean" | "avg" if self.numeric_count > 0 => Some(self.sum / self.numeric_count as f64),
+            "min" => self.min,
+            "max" => self.max,
+            _ => None,
+        };
+        number
+            .and_then(serde_json::Number::from_f64)
+            .map(Value::Number)
+            .unwrap_or(Value::Null)
+    }
+}
+
+pub(crate) const PIVOT_AGGREGATES: [&str; 6] = ["sum", "count", "mean", "avg", "min", "max"];
+
+/// Reshape `rows` so each distinct `columns` value becomes an output key
+/// holding `agg` over `values`, with one output row per distinct `index`.
+/// Returns the pivoted rows and any warnings (e.g. when columns were capped).
+pub(crate) fn pivot(
+    rows: &[Value],
+    index: &str,
+    columns: &str,
+    values: &str,
+    agg: &str,
+    max_columns: usize,
+) -> Result<(Vec<Value>, Vec<String>), String> {
+    if !PIVOT_AGGREGATES.contains(&agg) {
+        return Err(format!(
+            "Unsupported pivot aggregate '{agg}' (expected one of {})",
+            PIVOT_AGGREGATES.join(", ")
+        ));
+    }
+
+    let mut warnings = Vec::new();
+    let mut index_order: Vec<(String, Value)> = Vec::new();
+    let mut seen_index: HashSet<String> = HashSet::new();
+    let mut column_order: Vec<String> = Vec::new();
+    let mut seen_columns: HashSet<String> = HashSet::new();
+    let mut skipped_rows = 0usize;
+    let mut cells: HashMap<(String, String), Accumulator> = HashMap::new();
+
+    for row in rows {
+        let (Some(index_value), Some(column_value)) = (row.get(index), row.get(columns)) else {
+            continue;
+        };
+
+        let index_key = value_key(index_value);
+        let column_key = value_key(column_value);
+
+        if !seen_columns.contains(&column_key) {
+            if column_order.len() >= max_columns {
+                skipped_rows += 1;
+                continue;
+            }
+            seen_columns.insert(column_key.clone());
+            column_order.push(column_key.clone());
+        }
+
+        if seen_index.insert(index_key.clone()) {
+            index_order.push((index_key.clone(), index_value.clone()));
+        }
+
+        cells
+            .entry((index_key, column_key))
+            .or_default()
+            .push(row.get(values));
+    }
+
+    if skipped_rows > 0 {
+        warnings.push(format!(
+            "Pivot columns capped at {max_columns}; skipped {skipped_rows} rows with further '{columns}' values"
+        ));
+    }
+
+    let pivoted = index_order
+        .into_iter()
+        .map(|(index_key, index_value)| {
+            let mut obj = Map::new();
+            obj.insert(index.to_string(), index_value);
+            for column_key in &column_order {
+                let cell = cells
+                    .get(&(index_key.clone(), column_key.clone()))
+                    .map(|acc| acc.finish(agg))
+                    .unwrap_or(Value::Null);
+                obj.insert(column_key.clone(), cell);
+            }
+            Value::Object(obj)
+        })
+        .collect();
+
+    Ok((pivoted, warnings))
+}
+
+pub(crate) const RUNNING_STATS: [&str; 7] =
+    ["count", "sum", "min", "max", "mean", "variance", "stddev"];
+
+/// Constant-memory statistics over a stream of numbers. Mean and variance use
+/// Welford's online update, which stays accurate over long streams.
+#[derive(Default)]
+pub(crate) struct RunningStats {
+    count: u64,
+    sum: f64,
+    min: Option<f64>,
+    max: Option<f64>,
+    mean: f64,
+    m2: f64,
+}
+
+impl RunningStats {
+    pub(crate) fn push(&mut self, value: f64) {
+        self.count += 1;
+        self.sum += value;
+        self.min = Some(self.min.map_or(value, |m| m.min(value)));
+        self.max = Some(self.max.map_or(value, |m| m.max(value)));
+
+        let delta = value - self.mean;
+        self.mean += delta / self.count as f64;
+        self.m2 += delta * (value - self.mean);
+    }
+
+    /// Value of one of `RUNNING_STATS`. Variance and stddev are the sample
+    /// statistics (n - 1), null below two values; the rest are null when empty.
+    pub(crate) fn get(&self, op: &str) -> Value {
+        let number = match op {
+            "count" => return Value::from(self.count),
+            "sum" => Some(self.sum),
+            "min" => self.min,
+            "max" => self.max,
+            "mean" if self.count > 0 => Some(self.mean),
+            "variance" if self.count > 1 => Some(self.m2 / (self.count - 1) as f64),
+            "stddev" if self.count > 1 => Some((self.m2 / (self.count - 1) as f64).sqrt()),
+            _ => None,
+        };
+        number
+            .and_then(serde_json::Number::from_f64)
+            .map(Value::Number)
+            .unwrap_or(Value::Null)
+    }
+}
+
+/// Expand keys like `user.id` into nested objects. A key used both as a
+/// value and as a prefix keeps the nested object; its path is pushed to
+/// `conflicts`. Keys with an empty segment (`a..b`, `.a`) are left as-is.
+pub(crate) fn unflatten(row: &mut Value, separator: &str, conflicts: &mut Vec<String>) {
+    let Value::Object(obj) = row else {
+        return;
+    };
+    if !obj.keys().any(|key| key.contains(separator)) {
+        return;
+    }
+
+    let mut nested = Map::new();
+    for (key, value) in std::mem::take(obj) {
+        let parts: Vec<&str> = key.split(separator).collect();
+        let parts = if parts.iter().any(|part| part.is_empty()) {
+            vec![key.as_str()]
+        } else {
+            parts
+        };
+        insert_path(&mut nested, &parts, value, separator, "", conflicts);
+    }
+    *obj = nested;
+}
+
+fn insert_path(
+    map: &mut Map<String, Value>,
+    parts: &[&str],
+    value: Value,
+    separator: &str,
+    prefix: &str,
+    conflicts: &mut Vec<String>,
+) {
+    let Some((first, rest)) = parts.split_first() else {
+        return;
+    };
+    let path = if prefix.is_empty() {
+        first.to_string()
+    } else {
+        format!("{prefix}{separator}{first}")
+    };
+
+    if rest.is_empty() {
+        match (map.get_mut(*first), value) {
+            (Some(Value::Object(existing)), Value::Object(fields)) => {
+                for (key, value) in fields {
+                    insert_path(existing, &[&key], value, separator, &path, conflicts);
+                }
+            }
+            (Some(Value::Object(_)), _) => conflicts.push(path),
+            (_, value) => {
+                map.insert(first.to_string(), value);
+            }
+        }
+        return;
+    }
+
+    let entry = map
+        .entry(first.to_string())
+        .or_insert_with(|| Value::Object(Map::new()));
+    if !entry.is_object() {
+        conflicts.push(path.clone());
+        *entry = Value::Object(Map::new());
+    }
+    if let Value::Object(child) = entry {
+        insert_path(child, rest, value, separator, &path, conflicts);
+    }
+}
+
+/// Replace the string value of each of `fields` in every row with the JSON
+/// it holds. Values that aren't valid JSON stay as they are; returns how
+/// many of those each field had.
+pub(crate) fn parse_json_fields(
+    rows: &mut [Value],
+    fields: &[String],
+    options: &crate::json_parser::JsonParseOptions,
+) -> Vec<usize> {
+    let mut failures = vec![0; fields.len()];
+    for row in rows.iter_mut() {
+        for (field, failed) in fields.iter().zip(failures.iter_mut()) {
+            let Some(value) = row.get_mut(field.as_str()) else {
+                continue;
+            };
+            let Value::String(text) = value else {
+                continue;
+            };
+            match crate::json_parser::parse_json(text.as_bytes(), options) {
+                Ok(parsed) => *value = parsed,
+                Err(_) => *failed += 1,
+            }
+        }
+    }
+    failures
+}
+
+/// The value at a dotted `path` such as `user.id` or `items.0.sku`: a key
+/// spelled exactly like the path wins (already-flat input), otherwise each
+/// segment selects an object field or, if numeric, an array element.
+pub(crate) fn value_at_path<'a>(row: &'a Value, path: &str) -> Option<&'a Value> {
+    if let Some(value) = row.get(path) {
+        return Some(value);
+    }
+    path.split('.').try_fold(row, |value, segment| match value {
+        Value::Object(obj) => obj.get(segment),
+        Value::Array(items) => items.get(segment.parse::<usize>().ok()?),
+        _ => None,
+    })
+}
+
+/// How `add_row_ids` numbers rows.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum RowIdMode {
+    /// 0-based position in the output.
+    Ordinal,
+    /// Leading 16 hex digits of the SHA-256 of the row's canonical JSON, so
+    /// identical rows share an id.
+    Hash,
+}
+
+impl RowIdMode {
+    pub(crate) fn parse(mode: &str) -> Option<RowIdMode> {
+        match mode {
+            "ordinal" => Some(RowIdMode::Ordinal),
+            "hash" => Some(RowIdMode::Hash),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn name(&self) -> &'static str {
+        match self {
+            RowIdMode::Ordinal => "ordinal",
+            RowIdMode::Hash => "hash",
+        }
+    }
+}
+
+/// Set `field` on each object row to its id, numbering ordinals from
+/// `first`. The hash leaves out `field` itself and `skip_key`, so rerunning
+/// over the same input gives the same ids. Non-object rows are left as-is.
+pub(crate) fn add_row_ids(
+    rows: &mut [Value],
+    field: &str,
+    mode: RowIdMode,
+    first: usize,
+    skip_key: Option<&str>,
+) {
+    for (ordinal, row) in (first..).zip(rows.iter_mut()) {
+        let Value::Object(obj) = row else {
+            continue;
+        };
+        let id = match mode {
+            RowIdMode::Ordinal => Value::from(ordinal),
+            RowIdMode::Hash => {
+                let mut hashed = obj.clone();
+                hashed.remove(field);
+                if let Some(key) = skip_key {
+                    hashed.remove(key);
+                }
+                let mut hash = crate::export::content_hash(&[Value::Object(hashed)], None);
+                hash.truncate(16);
+                Value::String(hash)
+            }
+        };
+        obj.insert(field.to_string(), id);
+    }
+}
+
+/// Sort key taken from one field of a row. Numbers sort before strings,
+/// then booleans, then arrays/objects (by their JSON text); rows where the
+/// field is missing or null always sort last, whatever the direction.
+#[derive(Debug, PartialEq)]
+pub(crate) enum SortKey {
+    Number(f64),
+    String(String),
+    Bool(bool),
+    Other(String),
+    Missing,
+}
+
+impl SortKey {
+    pub(crate) fn of(row: &Value, field: &str) -> SortKey {
+        row.get(field).map_or(SortKey::Missing, SortKey::from_value)
+    }
+
+    pub(crate) fn from_value(value: &Value) -> SortKey {
+        match value {
+            Value::Null => SortKey::Missing,
+            Value::Number(n) => SortKey::Number(n.as_f64().unwrap_or(f64::NAN)),
+            Value::String(s) => SortKey::String(s.clone()),
+            Value::Bool(b) => SortKey::Bool(*b),
+            other => SortKey::Other(other.to_string()),
+        }
+    }
+
+    fn rank(&self) -> u8 {
+        match self {
+            SortKey::Number(_) => 0,
+            SortKey::String(_) => 1,
+            SortKey::Bool(_) => 2,
+            SortKey::Other(_) => 3,
+            SortKey::Missing => 4,
+        }
+    }
+
+    pub(crate) fn cmp_directed(&self, other: &SortKey, descending: bool) -> Ordering {
+        let ordering = match (self, other) {
+            (SortKey::Missing, SortKey::Missing) => return Ordering::Equal,
+            (SortKey::Missing, _) => return Ordering::Greater,
+            (_, SortKey::Missing) => return Ordering::Less,
+            (SortKey::Number(a), SortKey::Number(b)) => a.total_cmp(b),
+            (SortKey::String(a), SortKey::String(b)) | (SortKey::Other(a), SortKey::Other(b)) => {
+                a.cmp(b)
+            }
+            (SortKey::Bool(a), SortKey::Bool(b)) => a.cmp(b),
+            _ => self.rank().cmp(&other.rank()),
+        };
+        if descending {
+            ordering.reverse()
+        } else {
+            ordering
+        }
+    }
+}
+
+/// The `k` most frequent values of `field` with their counts, or the least
+/// frequent with `least`. Values are typed, as in `RowIndex`; rows without
+/// the field are skipped. Equal counts are ordered by value as `SortKey`
+/// sorts them ascending, so the result doesn't depend on row order.
+pub(crate) fn top_k(rows: &[Value], field: &str, k: usize, least: bool) -> Vec<(Value, usize)> {
+    let mut counts: HashMap<String, (Value, usize)> = HashMap::new();
+    for value in rows.iter().filter_map(|row| row.get(field)) {
+        counts
+            .entry(value.to_string())
+            .or_insert_with(|| (value.clone(), 0))
+            .1 += 1;
+    }
+
+    let mut ranked: Vec<(String, Value, usize)> = counts
+        .into_iter()
+        .map(|(text, (value, count))| (text, value, count))
+        .collect();
+    ranked.sort_by(|(a_text, a, a_count), (b_text, b, b_count)| {
+        let by_count = if least {
+            a_count.cmp(b_count)
+        } else {
+            b_count.cmp(a_count)
+        };
+        by_count
+            .then_with(|| SortKey::from_value(a).cmp_directed(&SortKey::from_value(b), false))
+            .then_with(|| a_text.cmp(b_text))
+    });
+    ranked
+        .into_iter()
+        .take(k)
+        .map(|(_, value, count)| (value, count))
+        .collect()
+}
+
+/// `rows` stably sorted on `field` and written as NDJSON, one sorted run
+/// for `merge_runs`.
+pub(crate) fn sorted_run(rows: Vec<Value>, field: &str, descending: bool) -> Vec<u8> {
+    let mut keyed: Vec<(SortKey, Value)> = rows
+        .into_iter()
+        .map(|row| (SortKey::of(&row, field), row))
+        .collect();
+    keyed.sort_by(|(a, _), (b, _)| a.cmp_directed(b, descending));
+
+    let mut run = Vec::new();
+    for (_, row) in keyed {
+        run.extend_from_slice(row.to_string().as_bytes());
+        run.push(b'\n');
+    }
+    run
+}
+
+// Next unmerged line of a run, ordered so `BinaryHeap` pops the line that
+// sorts first; ties go to the earlier run, keeping the merge stable.
+struct MergeHead {
+    key: SortKey,
+    run: usize,
+    line: std::ops::Range<usize>,
+    descending: bool,
+}
+
+impl PartialEq for MergeHead {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for MergeHead {}
+
+impl PartialOrd for MergeHead {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for MergeHead {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.key
+            .cmp_directed(&other.key, self.descending)
+            .then(self.run.cmp(&other.run))
+            .reverse()
+    }
+}
+
+/// K-way merge of NDJSON `runs` produced by `sorted_run`, appending the
+/// merged lines to `out`. Only one line per run is parsed at a time.
+pub(crate) fn merge_runs(
+    runs: &[&[u8]],
+    field: &str,
+    descending: bool,
+    out: &mut Vec<u8>,
+) -> Result<(), String> {
+    let next_head = |run: usize, start: usize| -> Result<Option<MergeHead>, String> {
+        let data = runs[run];
+        if start >= data.len() {
+            return Ok(None);
+        }
+        let end = data[start..]
+            .iter()
+            .position(|&b| b == b'\n')
+            .map_or(data.len(), |i| start + i);
+        let row: Value = serde_json::from_slice(&data[start..end]).map_err(|e| e.to_string())?;
+        Ok(Some(MergeHead {
+            key: SortKey::of(&row, field),
+            run,
+            line: start..end,
+            descending,
+        }))
+    };
+
+    let mut heap = std::collections::BinaryHeap::new();
+    for run in 0..runs.len() {
+        heap.extend(next_head(run, 0)?);
+    }
+    while let Some(head) = heap.pop() {
+        out.extend_from_slice(&runs[head.run][head.line.clone()]);
+        out.push(b'\n');
+        heap.extend(next_head(head.run, head.line.end + 1)?);
+    }
+    Ok(())
+}
+
+/// In-memory dimension table for enriching rows, indexed by the `value_key`
+/// of its key column. A repeated key keeps the last row.
+pub(crate) struct LookupTable {
+    columns: Vec<String>,
+    rows: HashMap<String, Map<String, Value>>,
+}
+
+impl LookupTable {
+    /// Index object `rows` by `key`; rows without it (or with a null key)
+    /// are skipped.
+    pub(crate) fn new(rows: Vec<Value>, key: &str) -> LookupTable {
+        let mut columns: Vec<String> = Vec::new();
+        let mut indexed = HashMap::new();
+        for row in rows {
+            let Value::Object(mut obj) = row else {
+                continue;
+            };
+            let Some(key_value) = obj.remove(key).filter(|v| !v.is_null()) else {
+                continue;
+            };
+            for column in obj.keys() {
+                if !columns.contains(column) {
+                    columns.push(column.clone());
+                }
+            }
+            indexed.insert(value_key(&key_value), obj);
+        }
+        LookupTable {
+            columns,
+            rows: indexed,
+        }
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.rows.len()
+    }
+
+    /// Merge the columns of the entry matching `row[on_field]` into `row`,
+    /// each named `prefix` + column. Without a match the row is unchanged, or
+    /// gets every column as null when `null_fill`. Returns whether it matched.
+    pub(crate) fn enrich(
+        &self,
+        row: &mut Value,
+        on_field: &str,
+        prefix: &str,
+        null_fill: bool,
+    ) -> bool {
+        let Value::Object(obj) = row else {
+            return false;
+        };
+        let entry = obj
+            .get(on_field)
+            .filter(|v| !v.is_null())
+            .and_then(|v| self.rows.get(&value_key(v)));
+        match entry {
+            Some(entry) => {
+                for (column, value) in entry {
+                    obj.insert(format!("{prefix}{column}"), value.clone());
+                }
+                true
+            }
+            None => {
+                if null_fill {
+                    for column in &self.columns {
+                        obj.insert(format!("{prefix}{column}"), Value::Null);
+                    }
+                }
+                false
+            }
+        }
+    }
+}
+
+/// What `upsert` did: rows replaced, appended and removed, and update rows
+/// skipped for lacking a key.
+#[derive(Debug, Default, PartialEq)]
+pub(crate) struct UpsertCounts {
+    pub updated: usize,
+    pub inserted: usize,
+    pub deleted: usize,
+    pub skipped: usize,
+}
+
+/// Merge `updates` into `base` by the typed value of `key` (as `RowIndex`).
+/// An update replaces every base row with its key, or with `merge_fields`
+/// overwrites just the fields it has; one for a new key is appended. An
+/// update whose `tombstone` field is set to anything but null or false
+/// removes its key instead. The tombstone field is left out of the output.
+/// Base rows without a key are kept as they are.
+pub(crate) fn upsert(
+    base: Vec<Value>,
+    updates: Vec<Value>,
+    key: &str,
+    merge_fields: bool,
+    tombstone: Option<&str>,
+) -> (Vec<Value>, UpsertCounts) {
+    let mut counts = UpsertCounts::default();
+    let mut rows: Vec<Option<Value>> = base.into_iter().map(Some).collect();
+    let mut positions: HashMap<String, Vec<usize>> = HashMap::new();
+    for (position, row) in rows.iter().enumerate() {
+        if let Some(key_value) = row_key(row.as_ref().and_then(|row| row.get(key))) {
+            positions.entry(key_value).or_default().push(position);
+        }
+    }
+
+    for update in updates {
+        let Value::Object(mut update) = update else {
+            counts.skipped += 1;
+            continue;
+        };
+        let Some(key_value) = row_key(update.get(key)) else {
+            counts.skipped += 1;
+            continue;
+        };
+        let deleted = tombstone
+            .and_then(|field| update.remove(field))
+            .is_some_and(|flag| !matches!(flag, Value::Null | Value::Bool(false)));
+
+        let Some(existing) = positions.get(&key_value).filter(|p| !p.is_empty()) else {
+            if !deleted {
+                positions.insert(key_value, vec![rows.len()]);
+                rows.push(Some(Value::Object(update)));
+                counts.inserted += 1;
+            }
+            continue;
+        };
+        if deleted {
+            for position in positions.remove(&key_value).unwrap_or_default() {
+                rows[position] = None;
+                counts.deleted += 1;
+            }
+            continue;
+        }
+        for &position in existing {
+            match (&mut rows[position], merge_fields) {
+                (Some(Value::Object(row)), true) => {
+                    row.extend(update.iter().map(|(k, v)| (k.clone(), v.clone())));
+                }
+                (row, _) => *row = Some(Value::Object(update.clone())),
+            }
+            counts.updated += 1;
+        }
+    }
+    (rows.into_iter().flatten().collect(), counts)
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum SetOp {
+    /// Rows of `a` whose key is also in `b`.
+    Intersect,
+    /// Rows of `a` whose key is not in `b`.
+    Except,
+    /// Rows of `a` then `b`, keeping the first row for each key.
+    Union,
+}
+
+impl SetOp {
+    pub(crate) fn parse(op: &str) -> Option<SetOp> {
+        match op {
+            "intersect" => Some(SetOp::Intersect),
+            "except" => Some(SetOp::Except),
+            "union" => Some(SetOp::Union),
+            _ => None,
+        }
+    }
+}
+
+/// `op` over `a` and `b` by the typed value of `key` (as `RowIndex`). Rows
+/// without a key, or with a null one, match nothing, as NULL does in SQL:
+/// `intersect` drops them while `except` and `union` keep them as they are.
+pub(crate) fn set_operation(a: Vec<Value>, b: Vec<Value>, key: &str, op: SetOp) -> Vec<Value> {
+    let b_keys = |b: &[Value]| -> HashSet<String> {
+        b.iter().filter_map(|row| row_key(row.get(key))).collect()
+    };
+    match op {
+        SetOp::Intersect => {
+            let b_keys = b_keys(&b);
+            a.into_iter()
+                .filter(|row| row_key(row.get(key)).is_some_and(|k| b_keys.contains(&k)))
+                .collect()
+        }
+        SetOp::Except => {
+            let b_keys = b_keys(&b);
+            a.into_iter()
+                .filter(|row| !row_key(row.get(key)).is_some_and(|k| b_keys.contains(&k)))
+                .collect()
+        }
+        SetOp::Union => {
+            let mut seen = HashSet::new();
+            a.into_iter()
+                .chain(b)
+                .filter(|row| row_key(row.get(key)).is_none_or(|k| seen.insert(k)))
+                .collect()
+        }
+    }
+}
+
+fn row_key(value: Option<&Value>) -> Option<String> {
+    value.filter(|v| !v.is_null()).map(Value::to_string)
+}
+
+/// Rows grouped by the value of one field, for point lookups. Keys are
+/// typed: `1` and `"1"` are different keys. Every row with a non-null key is
+/// kept, in input order.
+pub(crate) struct RowIndex {
+    rows: HashMap<String, Vec<Value>>,
+}
+
+impl RowIndex {
+    pub(crate) fn new(rows: Vec<Value>, key: &str) -> RowIndex {
+        let mut indexed: HashMap<String, Vec<Value>> = HashMap::new();
+        for row in rows {
+            let Some(key_value) = row.get(key).filter(|v| !v.is_null()) else {
+                continue;
+            };
+            indexed.entry(key_value.to_string()).or_default().push(row);
+        }
+        RowIndex { rows: indexed }
+    }
+
+    /// Number of distinct keys.
+    pub(crate) fn len(&self) -> usize {
+        self.rows.len()
+    }
+
+    pub(crate) fn get(&self, key: &Value) -> &[Value] {
+        self.rows.get(&key.to_string()).map_or(&[], Vec::as_slice)
+    }
+}
+
+/// Shortest number array treated as binary when it isn't declared as such,
+/// so short lists of small integers aren't mistaken for bytes.
+pub(crate) const BINARY_DETECT_MIN_LEN: usize = 16;
+
+/// The bytes of `value` if it is an array of integers 0-255.
+pub(crate) fn byte_array(value: &Value) -> Option<Vec<u8>> {
+    value
+        .as_array()?
+        .iter()
+        .map(|v| v.as_u64().and_then(|n| u8::try_from(n).ok()))
+        .collect()
+}
+
+/// Replace byte arrays in the top-level values of `row` with base64
+/// strings: those in `declared` columns, and any other byte array of at
+/// least `BINARY_DETECT_MIN_LEN` bytes.
+pub(crate) fn encode_binary(row: &mut Value, declared: &[&str]) {
+    let Value::Object(obj) = row else {
+        return;
+    };
+    for (column, value) in obj.iter_mut() {
+        let min_len = if declared.contains(&column.as_str()) {
+            0
+        } else {
+            BINARY_DETECT_MIN_LEN
+        };
+        if value.as_array().is_none_or(|items| items.len() < min_len) {
+            continue;
+        }
+        if let Some(bytes) = byte_array(value) {
+            *value = Value::String(base64_encode(&bytes));
+        }
+    }
+}
+
+/// Standard padded base64.
+pub(crate) fn base64_encode(bytes: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut encoded = String::with_capacity(bytes.len().div_ceil(3) * 4);
+    for chunk in bytes.chunks(3) {
+        let b = [
+            chunk[0],
+            *chunk.get(1).unwrap_or(&0),
+            *chunk.get(2).unwrap_or(&0),
+        ];
+        let n = u32::from(b[0]) << 16 | u32::from(b[1]) << 8 | u32::from(b[2]);
+        for i in 0..4 {
+            if i <= chunk.len() {
+                encoded.push(ALPHABET[(n >> (18 - 6 * i) & 63) as usize] as char);
+            } else {
+                encoded.push('=');
+            }
+        }
+    }
+    encoded
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub(crate) enum DeclaredType {
+    Integer,
+    Number,
+    String,
+    Boolean,
+    /// Bytes, as an array of integers 0-255 or an already-encoded string.
+    Binary,
+    /// A UTC point in time, from epoch milliseconds or ISO-8601 text.
+    /// Coerces to epoch milliseconds; the engine renders it in its
+    /// timestamp format.
+    Timestamp,
+}
+
+impl DeclaredType {
+    pub(crate) fn parse(name: &str) -> Option<DeclaredType> {
+        match name {
+            "integer" | "int" => Some(DeclaredType::Integer),
+            "number" | "float" => Some(DeclaredType::Number),
+            "string" => Some(DeclaredType::String),
+            "boolean" | "bool" => Some(DeclaredType::Boolean),
+            "binary" | "bytes" => Some(DeclaredType::Binary),
+            "timestamp" => Some(DeclaredType::Timestamp),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn name(&self) -> &'static str {
+        match self {
+            DeclaredType::Integer => "integer",
+            DeclaredType::Number => "number",
+            DeclaredType::String => "string",
+            DeclaredType::Boolean => "boolean",
+            DeclaredType::Binary => "binary",
+            DeclaredType::Timestamp => "timestamp",
+        }
+    }
+
+    /// Convert `value` to this type, or `None` if it has no sensible
+    /// conversion. Nulls are left as null.
+    pub(crate) fn coerce(&self, value: &Value) -> Option<Value> {
+        if value.is_null() {
+            return Some(Value::Null);
+        }
+        match (self, value) {
+            (DeclaredType::Integer, Value::Number(n)) => match n.as_f64() {
+                _ if n.is_i64() || n.is_u64() => Some(value.clone()),
+                Some(f) if f.fract() == 0.0 && f.abs() < 9.2e18 => Some(Value::from(f as i64)),
+                _ => None,
+            },
+            (DeclaredType::Integer, Value::String(s)) => {
+                s.trim().parse::<i64>().ok().map(Value::from)
+            }
+            (DeclaredType::Number, Value::Number(_)) => Some(value.clone()),
+            (DeclaredType::Number, Value::String(s)) => s
+                .trim()
+                .parse::<f64>()
+                .ok()
+                .and_then(serde_json::Number::from_f64)
+                .map(Value::Number),
+            (DeclaredType::String, Value::String(_)) => Some(value.clone()),
+            (DeclaredType::String, other) => Some(Value::String(value_key(other))),
+            (DeclaredType::Boolean, Value::Bool(_)) => Some(value.clone()),
+            (DeclaredType::Boolean, Value::String(s)) => {
+                match s.trim().to_ascii_lowercase().as_str() {
+                    "true" => Some(Value::Bool(true)),
+                    "false" => Some(Value::Bool(false)),
+                    _ => None,
+                }
+            }
+            (DeclaredType::Boolean, Value::Number(n)) => match n.as_f64() {
+                Some(0.0) => Some(Value::Bool(false)),
+                Some(1.0) => Some(Value::Bool(true)),
+                _ => None,
+            },
+            (DeclaredType::Binary, Value::String(_)) => Some(value.clone()),
+            (DeclaredType::Binary, Value::Array(_)) => byte_array(value).map(|_| value.clone()),
+            (DeclaredType::Timestamp, Value::Number(_)) => DeclaredType::Integer.coerce(value),
+            (DeclaredType::Timestamp, Value::String(s)) => crate::timestamp::parse_iso8601(s)
+                .or_else(|| s.trim().parse::<i64>().ok())
+                .map(Value::from),
+            _ => None,
+        }
+    }
+}
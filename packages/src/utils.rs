@@ -36,6 +36,112 @@ impl DataPrismError {
     }
 }
 
+// Error from a multi-step pipeline (fetch then process), attributing the
+// failure to the stage it happened in.
+#[derive(Serialize, Deserialize)]
+#[wasm_bindgen]
+pub struct PipelineError {
+    stage: String,
+    message: String,
+    provider: Option<String>,
+    status: Option<u16>,
+}
+
+#[wasm_bindgen]
+impl PipelineError {
+    /// One of "fetch", "http", "decompress" or "parse".
+    #[wasm_bindgen(getter)]
+    pub fn stage(&self) -> String {
+        self.stage.clone()
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn message(&self) -> String {
+        self.message.clone()
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn provider(&self) -> Option<String> {
+        self.provider.clone()
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn status(&self) -> Option<u16> {
+        self.status
+    }
+}
+
+pub(crate) fn pipeline_error(
+    stage: &str,
+    message: &str,
+    provider: Option<String>,
+    status: Option<u16>,
+) -> JsValue {
+    PipelineError {
+        stage: stage.to_string(),
+        message: message.to_string(),
+        provider,
+        status,
+    }
+    .into()
+}
+
+// A failure processing a fetched body as a `PipelineError`: stage
+// "decompress" when inflating it failed, else "parse".
+pub(crate) fn processing_error(
+    error: &JsValue,
+    provider: Option<String>,
+    status: Option<u16>,
+) -> JsValue {
+    let error_type = js_sys::Reflect::get(error, &JsValue::from_str("error_type"))
+        .ok()
+        .and_then(|t| t.as_string());
+    let stage = processing_stage(error_type.as_deref());
+    pipeline_error(stage, &error_message(error), provider, status)
+}
+
+pub(crate) fn processing_stage(error_type: Option<&str>) -> &'static str {
+    match error_type {
+        Some("invalid_gzip" | "decompression_bomb") => "decompress",
+        _ => "parse",
+    }
+}
+
+// Best-effort message from an error value coming back from JS or our own
+// error types.
+pub(crate) fn error_message(error: &JsValue) -> String {
+    if let Some(s) = error.as_string() {
+        return s;
+    }
+    js_sys::Reflect::get(error, &JsValue::from_str("message"))
+        .ok()
+        .and_then(|m| m.as_string())
+        .unwrap_or_else(|| format!("{error:?}"))
+}
+
+// Failure while parsing input; converts to a `DataPrismError`.
+#[derive(Debug)]
+pub(crate) struct ParseError {
+    /// `DataPrismError` type, e.g. "invalid_json" or the check that failed.
+    pub kind: &'static str,
+    pub message: String,
+}
+
+impl From<ParseError> for JsValue {
+    fn from(error: ParseError) -> JsValue {
+        js_error(error.kind, 400, &error.message)
+    }
+}
+
+// 100MB limit on a single input passed across the boundary
+pub(crate) const MAX_INPUT_BYTES: usize = 100_000_000;
+
+// Builds a `DataPrismError` as a `JsValue` so it can be returned as the error
+// side of an exported function.
+pub(crate) fn js_error(error_type: &str, code: u32, message: &str) -> JsValue {
+    DataPrismError::new(message, error_type, code).into()
+}
+
 #[wasm_bindgen]
 pub fn validate_input_data(data: &[u8]) -> Result<bool, JsValue> {
     // Validate input data format and size
@@ -43,14 +149,140 @@ pub fn validate_input_data(data: &[u8]) -> Result<bool, JsValue> {
         return Err(JsValue::from_str("Input data cannot be empty"));
     }
 
-    if data.len() > 100_000_000 {
-        // 100MB limit
+    if data.len() > MAX_INPUT_BYTES {
         return Err(JsValue::from_str("Input data exceeds maximum size limit"));
     }
 
     Ok(true)
 }
 
+thread_local! {
+    static LAST_PANIC: std::cell::RefCell<Option<String>> = const { std::cell::RefCell::new(None) };
+}
+
+// Panic hook installed by `init_panic_hook`: logs like
+// `console_error_panic_hook` and keeps the message for `take_last_panic`.
+pub(crate) fn record_panic(info: &std::panic::PanicHookInfo) {
+    console_error_panic_hook::hook(info);
+    LAST_PANIC.with(|last| *last.borrow_mut() = Some(info.to_string()));
+}
+
+/// A panic aborts the WASM call with a JS `RuntimeError` ("unreachable")
+/// instead of returning an error, since panics can't be caught in WASM.
+/// After catching one, call this to get the panic as an `internal_panic`
+/// `DataPrismError`; the instance should then be recreated, as its state
+/// may be inconsistent. Returns undefined if nothing has panicked.
+#[wasm_bindgen]
+pub fn take_last_panic() -> Option<DataPrismError> {
+    LAST_PANIC
+        .with(|last| last.borrow_mut().take())
+        .map(|message| DataPrismError::new(&message, "internal_panic", 500))
+}
+
+/// Apply a JSON Merge Patch (RFC 7386) to a JSON document and return the
+/// patched document as UTF-8 JSON. An empty `base` is treated as null.
+#[wasm_bindgen]
+pub fn json_merge_patch(base: &[u8], patch: &[u8]) -> Result<js_sys::Uint8Array, JsValue> {
+    let parse = |bytes: &[u8], what: &str| -> Result<serde_json::Value, JsValue> {
+        if bytes.iter().all(u8::is_ascii_whitespace) {
+            return Ok(serde_json::Value::Null);
+        }
+        serde_json::from_slice(bytes)
+            .map_err(|e| js_error("invalid_json", 400, &format!("Invalid JSON {what}: {e}")))
+    };
+
+    let mut document = parse(base, "base")?;
+    apply_merge_patch(&mut document, parse(patch, "patch")?);
+    Ok(js_sys::Uint8Array::from(document.to_string().as_bytes()))
+}
+
+// RFC 7386: an object patch merges key by key (null deletes, objects recurse);
+// any other patch replaces the target outright.
+pub(crate) fn apply_merge_patch(target: &mut serde_json::Value, patch: serde_json::Value) {
+    let serde_json::Value::Object(patch) = patch else {
+        *target = patch;
+        return;
+    };
+
+    if !target.is_object() {
+        *target = serde_json::Value::Object(serde_json::Map::new());
+    }
+    let Some(target) = target.as_object_mut() else {
+        return;
+    };
+
+    for (key, value) in patch {
+        if value.is_null() {
+            target.remove(&key);
+        } else {
+            apply_merge_patch(target.entry(key).or_insert(serde_json::Value::Null), value);
+        }
+    }
+}
+
+/// Split NDJSON into shards of `rows_per_shard` records each (the last holds
+/// the remainder), returned as an array of Uint8Arrays. Shards always end on
+/// a line boundary, so each can be passed to a separate `process_data` call.
+/// Blank lines don't count as records.
+#[wasm_bindgen]
+pub fn split_ndjson(data: &[u8], rows_per_shard: usize) -> Result<js_sys::Array, JsValue> {
+    if rows_per_shard == 0 {
+        return Err(js_error(
+            "invalid_shard_size",
+            400,
+            "rows_per_shard must be greater than zero",
+        ));
+    }
+    Ok(ndjson_shard_ranges(data, rows_per_shard)
+        .into_iter()
+        .map(|range| js_sys::Uint8Array::from(&data[range]))
+        .collect())
+}
+
+pub(crate) fn ndjson_shard_ranges(
+    data: &[u8],
+    rows_per_shard: usize,
+) -> Vec<std::ops::Range<usize>> {
+    let mut shards = Vec::new();
+    let mut shard_start = 0;
+    let mut rows = 0;
+    let mut line_start = 0;
+
+    while line_start < data.len() {
+        let line_end = data[line_start..]
+            .iter()
+            .position(|&b| b == b'\n' || b == b'\r')
+            .map_or(data.len(), |i| line_start + i + 1);
+        // Keep the `\n` of a `\r\n` with its line
+        let line_end = match data.get(line_end - 1..line_end + 1) {
+            Some(b"\r\n") => line_end + 1,
+            _ => line_end,
+        };
+        if !data[line_start..line_end]
+            .iter()
+            .all(|b| b.is_ascii_whitespace())
+        {
+            rows += 1;
+        }
+        line_start = line_end;
+
+        if rows == rows_per_shard {
+            shards.push(shard_start..line_end);
+            shard_start = line_end;
+            rows = 0;
+        }
+    }
+
+    // A blank tail joins the last shard rather than forming an empty one
+    let tail_is_blank = data[shard_start..].iter().all(|b| b.is_ascii_whitespace());
+    match shards.last_mut() {
+        Some(last) if tail_is_blank => last.end = data.len(),
+        _ if shard_start < data.len() => shards.push(shard_start..data.len()),
+        _ => {}
+    }
+    shards
+}
+
 #[wasm_bindgen]
 pub fn log_performance_metric(operation: &str, duration_ms: f64, memory_bytes: u32) {
     web_sys::console::log_3(
@@ -70,7 +302,7 @@ pub fn get_browser_info() -> JsValue {
         "performance": get_performance_info()
     });
 
-    serde_wasm_bindgen::to_value(&info).unwrap()
+    serde_wasm_bindgen::to_value(&info).unwrap_or(JsValue::NULL)
 }
 
 fn get_memory_info() -> serde_json::Value {
@@ -119,3 +351,53 @@ pub fn benchmark_end(start_time: f64, operation_name: &str) -> f64 {
     log_performance_metric(operation_name, duration, 0);
     duration
 }
+
+// Resolves after `ms` milliseconds using the global `setTimeout`, so it works
+// in both window and worker contexts.
+pub(crate) async fn sleep_ms(ms: f64) -> Result<(), JsValue> {
+    if ms <= 0.0 {
+        return Ok(());
+    }
+    set_timeout(ms).await
+}
+
+// Lets pending events, rendering and other tasks run before continuing.
+pub(crate) async fn yield_to_event_loop() -> Result<(), JsValue> {
+    set_timeout(0.0).await
+}
+
+async fn set_timeout(ms: f64) -> Result<(), JsValue> {
+    let set_timeout: js_sys::Function =
+        js_sys::Reflect::get(&js_sys::global(), &JsValue::from_str("setTimeout"))?.dyn_into()?;
+    let promise = js_sys::Promise::new(&mut |resolve, _reject| {
+        let _ = set_timeout.call2(&JsValue::NULL, &resolve, &JsValue::from_f64(ms));
+    });
+    wasm_bindgen_futures::JsFuture::from(promise).await?;
+    Ok(())
+}
+
+// `major.minor.patch`, ignoring a leading `v` and any pre-release/build
+// suffix; missing minor/patch count as 0.
+pub(crate) fn parse_version(version: &str) -> Option<(u64, u64, u64)> {
+    let core = version
+        .trim()
+        .trim_start_matches('v')
+        .split(['-', '+'])
+        .next()?;
+    let mut parts = core.split('.');
+    let mut next = || parts.next().map_or(Some(0), |p| p.parse::<u64>().ok());
+    let version = (next()?, next()?, next()?);
+    parts.next().is_none().then_some(version)
+}
+
+/// Whether `actual` satisfies the caret requirement `^expected`: same major
+/// version and at least `expected`, with 0.x minors (and 0.0.x patches)
+/// treated as breaking.
+pub(crate) fn is_version_compatible(actual: (u64, u64, u64), expected: (u64, u64, u64)) -> bool {
+    let same_line = match expected {
+        (0, 0, _) => actual == expected,
+        (0, minor, _) => actual.0 == 0 && actual.1 == minor,
+        (major, _, _) => actual.0 == major,
+    };
+    same_line && actual >= expected
+}